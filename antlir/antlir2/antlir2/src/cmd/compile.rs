@@ -0,0 +1,47 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+use antlir2_compile::CompileFeature;
+use antlir2_rootless::Rootless;
+use tracing::debug;
+
+use super::Compileish;
+use crate::Result;
+
+#[derive(clap::Parser, Debug)]
+/// Actually compile an image, applying every feature in the depgraph to the
+/// root.
+pub(crate) struct Compile {
+    #[clap(flatten)]
+    pub(super) compileish: Compileish,
+}
+
+impl Compile {
+    #[tracing::instrument(name = "compile", skip(self))]
+    pub(crate) fn run(self, rootless: Option<Rootless>) -> Result<()> {
+        let layout = self.compileish.layout()?;
+        let fingerprint = self.compileish.fingerprint()?;
+        if fingerprint.is_up_to_date(&layout.fingerprint()) {
+            debug!("root is already up to date with fingerprint {fingerprint:?}, skipping compile");
+            return Ok(());
+        }
+
+        let ctx = self.compileish.compiler_context(None)?;
+        let root_guard = rootless.map(|r| r.escalate()).transpose()?;
+        let depgraph = self.compileish.external.depgraph()?;
+        for feature in depgraph.pending_features()? {
+            feature.compile(&ctx).map_err(crate::Error::Compile)?;
+        }
+        drop(root_guard);
+
+        fingerprint
+            .write(&layout.fingerprint())
+            .map_err(crate::Error::Compile)?;
+
+        Ok(())
+    }
+}
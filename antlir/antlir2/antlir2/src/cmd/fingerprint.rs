@@ -0,0 +1,168 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Fingerprinting of an image build, modeled on Cargo's own `Metadata` hash.
+//! Before doing any (potentially expensive) compilation work, we hash
+//! everything that is able to change the output of a build. If a previous
+//! build left behind a sentinel file with a matching fingerprint, we already
+//! know the output would be identical, so the whole operation can be skipped.
+
+use std::fs;
+use std::hash::Hash;
+use std::hash::Hasher;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use buck_label::Label;
+use serde::Deserialize;
+use serde::Serialize;
+use siphasher::sip128::Hasher128;
+use siphasher::sip128::SipHasher13;
+
+/// Bump this any time the on-disk fingerprint format (or the set of inputs it
+/// covers) changes, so that stale sentinel files left behind by an older
+/// version of this binary are always treated as a cache miss instead of
+/// being misinterpreted.
+const METADATA_VERSION: u8 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) struct Fingerprint(u128);
+
+impl Fingerprint {
+    /// Compute a stable fingerprint over everything that can change the
+    /// output of a compile: the serialized depgraph, the resolved contents of
+    /// the available dnf repos, the parent-layer identity of `root` and the
+    /// version of this compiler.
+    pub(crate) fn compute(
+        root: &Path,
+        dnf_repos: &Path,
+        depgraph_json: &Path,
+    ) -> anyhow::Result<Self> {
+        let mut hasher = SipHasher13::new();
+        METADATA_VERSION.hash(&mut hasher);
+        env!("CARGO_PKG_VERSION").hash(&mut hasher);
+
+        let depgraph_contents =
+            fs::read(depgraph_json).with_context(|| format!("while reading {depgraph_json:?}"))?;
+        depgraph_contents.hash(&mut hasher);
+
+        hash_dir_contents(&mut hasher, dnf_repos)
+            .with_context(|| format!("while hashing dnf repos at {dnf_repos:?}"))?;
+        hash_identity(&mut hasher, root)
+            .with_context(|| format!("while hashing parent layer identity of {root:?}"))?;
+
+        Ok(Self(hasher.finish128().as_u128()))
+    }
+
+    /// Compute a stable fingerprint over everything that can change the
+    /// output of a `map`, before any (potentially expensive) snapshotting or
+    /// isolated compilation has happened: the image's own label, the
+    /// identity of the parent subvol it's based on (if any), the feature
+    /// JSON (depgraph) contents, the resolved contents of the available dnf
+    /// repos, and the identity of the build appliance used to interpret all
+    /// of the above.
+    pub(crate) fn compute_for_map(
+        label: &Label<'_>,
+        parent: Option<&Path>,
+        build_appliance: &Path,
+        dnf_repos: &Path,
+        depgraph_json: &Path,
+    ) -> anyhow::Result<Self> {
+        let mut hasher = SipHasher13::new();
+        METADATA_VERSION.hash(&mut hasher);
+        env!("CARGO_PKG_VERSION").hash(&mut hasher);
+
+        label.to_string().hash(&mut hasher);
+
+        let depgraph_contents =
+            fs::read(depgraph_json).with_context(|| format!("while reading {depgraph_json:?}"))?;
+        depgraph_contents.hash(&mut hasher);
+
+        hash_dir_contents(&mut hasher, dnf_repos)
+            .with_context(|| format!("while hashing dnf repos at {dnf_repos:?}"))?;
+
+        if let Some(parent) = parent {
+            hash_identity(&mut hasher, parent)
+                .with_context(|| format!("while hashing parent subvol identity of {parent:?}"))?;
+        }
+        hash_identity(&mut hasher, build_appliance).with_context(|| {
+            format!("while hashing build appliance identity of {build_appliance:?}")
+        })?;
+
+        Ok(Self(hasher.finish128().as_u128()))
+    }
+
+    /// The raw 128-bit hash value, used by [`super::layout::Layout`] to
+    /// derive a collision-free build-output subdirectory.
+    pub(crate) fn as_u128(&self) -> u128 {
+        self.0
+    }
+
+    /// Read back a previously-written fingerprint from `sentinel`, if it
+    /// exists.
+    pub(crate) fn read(sentinel: &Path) -> Option<Self> {
+        let contents = fs::read_to_string(sentinel).ok()?;
+        serde_json::from_str(&contents).ok()
+    }
+
+    /// Persist this fingerprint to the `sentinel` file.
+    pub(crate) fn write(&self, sentinel: &Path) -> anyhow::Result<()> {
+        let contents = serde_json::to_string(self).context("while serializing fingerprint")?;
+        if let Some(parent) = sentinel.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(sentinel, contents).context("while writing fingerprint sentinel")?;
+        Ok(())
+    }
+
+    /// Returns true if `sentinel` already records this exact fingerprint,
+    /// meaning the compile can be safely skipped.
+    pub(crate) fn is_up_to_date(&self, sentinel: &Path) -> bool {
+        Self::read(sentinel).as_ref() == Some(self)
+    }
+}
+
+/// Hash the relative paths, mtimes and lengths of every file found under
+/// `dir`. This is intentionally not a content hash (the repos can be huge) -
+/// it just needs to reliably detect when something has changed.
+fn hash_dir_contents(hasher: &mut SipHasher13, dir: &Path) -> anyhow::Result<()> {
+    let mut entries: Vec<_> = walkdir::WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .collect();
+    entries.sort_by(|a, b| a.path().cmp(b.path()));
+    for entry in entries {
+        let meta = entry.metadata()?;
+        entry
+            .path()
+            .strip_prefix(dir)
+            .unwrap_or_else(|_| entry.path())
+            .hash(hasher);
+        meta.len().hash(hasher);
+        if let Ok(mtime) = meta.modified() {
+            mtime.hash(hasher);
+        }
+    }
+    Ok(())
+}
+
+/// Incorporate the identity (inode plus creation time) of whatever is at
+/// `path` into the fingerprint, so that rebasing onto a different parent
+/// layer, or using a different build appliance, always invalidates the cache
+/// even if nothing else about the build changed.
+fn hash_identity(hasher: &mut SipHasher13, path: &Path) -> anyhow::Result<()> {
+    if path.exists() {
+        let meta = fs::symlink_metadata(path)?;
+        std::os::unix::fs::MetadataExt::ino(&meta).hash(hasher);
+        if let Ok(created) = meta.created() {
+            created.hash(hasher);
+        }
+    }
+    Ok(())
+}
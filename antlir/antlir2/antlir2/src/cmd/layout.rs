@@ -0,0 +1,60 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Build-output layout, modeled on Cargo's `CompilationFiles`. Instead of
+//! every compile writing straight into the user-provided `--root`, each
+//! build gets its own subdirectory derived from its [`Fingerprint`], so that
+//! concurrent compiles of different depgraphs against the same `--root`
+//! cannot collide, and intermediate artifacts always live in a deterministic
+//! place.
+
+use std::path::PathBuf;
+
+use antlir2_mount::antlir_image::path::VerifiedPath;
+
+use super::fingerprint::Fingerprint;
+
+#[derive(Debug, Clone)]
+pub(crate) struct Layout {
+    base: VerifiedPath,
+    fingerprint: Fingerprint,
+}
+
+impl Layout {
+    pub(crate) fn new(base: VerifiedPath, fingerprint: Fingerprint) -> Self {
+        Self { base, fingerprint }
+    }
+
+    /// Per-build subdirectory that all of this build's outputs are nested
+    /// under, named after its fingerprint so that two builds with different
+    /// inputs never write to the same path.
+    fn build_dir(&self) -> PathBuf {
+        self.base
+            .path()
+            .join(format!("{:032x}", self.fingerprint.as_u128()))
+    }
+
+    /// Root of the under-construction image for this build.
+    pub(crate) fn root(&self) -> PathBuf {
+        self.build_dir().join("root")
+    }
+
+    /// Scratch space for incremental/intermediate artifacts.
+    pub(crate) fn incremental(&self) -> PathBuf {
+        self.build_dir().join("incremental")
+    }
+
+    /// Sentinel file recording the fingerprint that produced [`Self::root`].
+    pub(crate) fn fingerprint(&self) -> PathBuf {
+        self.build_dir().join(".antlir2-fingerprint")
+    }
+
+    /// Serialized compiler plan for this build.
+    pub(crate) fn plan(&self) -> PathBuf {
+        self.build_dir().join("plan.json")
+    }
+}
@@ -21,6 +21,7 @@ use buck_label::Label;
 use clap::Parser;
 use tracing::debug;
 
+use super::fingerprint::Fingerprint;
 use super::plan::Plan;
 use super::plan::PlanExternal;
 use super::Compileish;
@@ -39,6 +40,10 @@ pub(crate) struct Map {
     #[clap(long)]
     /// Path to mounted build appliance image
     build_appliance: PathBuf,
+    #[clap(long = "remap-path-prefix", value_name = "FROM=TO")]
+    /// Remap build-host path prefixes before they are written into the
+    /// image. Forwarded to the isolated 'compile'/'plan' invocation.
+    remap_path_prefix: Vec<super::RemapPathPrefix>,
     /// Arguments to pass to the isolated instance of 'antlir2'
     #[clap(subcommand)]
     subcommand: Subcommand,
@@ -62,8 +67,12 @@ struct SetupArgs {
     /// buck-out path to store the reference to this volume
     output: PathBuf,
     #[clap(long)]
-    /// Directory where all available dnf repos can be found
-    dnf_repos: PathBuf,
+    /// Directory where all available dnf repos can be found, or a
+    /// `git+<url>[#rev=...]` reference to clone them from
+    dnf_repos: super::SourceRef,
+    #[clap(long)]
+    /// Revision qualifier applied on top of a `git+` `--dnf-repos` spec
+    dnf_repos_rev: Option<String>,
 }
 
 #[derive(Parser, Debug)]
@@ -78,6 +87,15 @@ enum Subcommand {
 }
 
 impl Subcommand {
+    /// The [`CompileishExternal`] (depgraph) args common to every subcommand,
+    /// needed to fingerprint a build before deciding whether to isolate.
+    fn external(&self) -> &CompileishExternal {
+        match self {
+            Self::Compile(external) => external,
+            Self::Plan { compileish, .. } => compileish,
+        }
+    }
+
     fn writable_outputs(&self) -> Result<BTreeSet<&Path>> {
         match self {
             Self::Plan {
@@ -136,10 +154,69 @@ impl Map {
         Ok(subvol)
     }
 
+    /// If `--output` already points at a subvol whose recorded fingerprint
+    /// matches `fingerprint`, return it so [`Self::run`] can reuse it as-is
+    /// instead of snapshotting, re-isolating and recompiling for no reason.
+    fn reusable_subvol(&self, fingerprint: &Fingerprint) -> Result<Option<Subvolume>> {
+        if !self.setup.output.exists() {
+            return Ok(None);
+        }
+        let existing =
+            std::fs::read_link(&self.setup.output).context("while reading output symlink")?;
+        if !fingerprint.is_up_to_date(&fingerprint_sentinel(&existing)) {
+            return Ok(None);
+        }
+        Ok(Some(
+            Subvolume::get(&existing).context("while opening up-to-date subvol")?,
+        ))
+    }
+
     #[tracing::instrument(name = "map", skip(self))]
     pub(crate) fn run(self) -> Result<()> {
         let working_volume = WorkingVolume::ensure(self.setup.working_dir.clone())
             .context("while setting up WorkingVolume")?;
+
+        // Resolve `--dnf-repos` here, in the outer (unisolated) process, so
+        // that a `git+` reference is only ever cloned once; the isolated
+        // child process is always handed a plain local path.
+        let dnf_repos = self
+            .setup
+            .dnf_repos
+            .clone()
+            .with_rev(self.setup.dnf_repos_rev.clone())
+            .resolve()
+            .context("while resolving --dnf-repos")?;
+
+        // Likewise for the feature JSON (depgraph), so it can feed the
+        // fingerprint below without waiting on isolation.
+        let depgraph = self
+            .subcommand
+            .external()
+            .depgraph
+            .clone()
+            .with_rev(self.subcommand.external().depgraph_rev.clone())
+            .resolve()
+            .context("while resolving --depgraph-json")?;
+
+        let fingerprint = Fingerprint::compute_for_map(
+            &self.label,
+            self.setup.parent.as_deref(),
+            &self.build_appliance,
+            dnf_repos.path(),
+            depgraph.path(),
+        )
+        .context("while fingerprinting map inputs")?;
+
+        if let Some(subvol) = self.reusable_subvol(&fingerprint)? {
+            debug!(
+                "output is already up to date with fingerprint {fingerprint:?}, reusing subvol {subvol:?} instead of rebuilding"
+            );
+            let _ = std::fs::remove_file(&self.setup.output);
+            std::os::unix::fs::symlink(subvol.path(), &self.setup.output)
+                .context("while refreshing symlink")?;
+            return Ok(());
+        }
+
         let mut subvol = self.create_new_subvol(&working_volume)?;
 
         let repo = find_root::find_repo_root(
@@ -171,7 +248,7 @@ impl Map {
                     // image builds all require the repo for at least the
                     // feature json paths coming from buck
                     repo.as_ref(),
-                    self.setup.dnf_repos.as_path(),
+                    dnf_repos.path(),
                     // layer dependencies require the working volume
                     self.setup.working_dir.as_path(),
                 ])
@@ -192,7 +269,9 @@ impl Map {
                         label: self.label,
                         root: subvol.path().to_owned(),
                         external,
-                        dnf_repos: self.setup.dnf_repos,
+                        dnf_repos: super::SourceRef::Local(dnf_repos.path().to_owned()),
+                        dnf_repos_rev: None,
+                        remap_path_prefix: self.remap_path_prefix.clone(),
                     }
                     .to_args(),
                 );
@@ -207,7 +286,9 @@ impl Map {
                             label: self.label,
                             root: subvol.path().to_owned(),
                             external: compileish,
-                            dnf_repos: self.setup.dnf_repos,
+                            dnf_repos: super::SourceRef::Local(dnf_repos.path().to_owned()),
+                            dnf_repos_rev: None,
+                            remap_path_prefix: self.remap_path_prefix.clone(),
                         },
                         external,
                     }
@@ -237,7 +318,16 @@ impl Map {
             let _ = std::fs::remove_file(&self.setup.output);
             std::os::unix::fs::symlink(subvol.path(), &self.setup.output)
                 .context("while making symlink")?;
+            fingerprint
+                .write(&fingerprint_sentinel(subvol.path()))
+                .context("while writing map fingerprint")?;
             Ok(())
         }
     }
+}
+
+/// Where a subvol's fingerprint sentinel lives: a sibling of the subvol
+/// itself, rather than inside it, so it never leaks into the built image.
+fn fingerprint_sentinel(subvol_path: &Path) -> PathBuf {
+    subvol_path.with_extension("fingerprint")
 }
\ No newline at end of file
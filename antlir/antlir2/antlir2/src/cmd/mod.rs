@@ -0,0 +1,191 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+use antlir2_compile::CompilerContext;
+use antlir2_depgraph::Graph;
+use antlir2_mount::antlir_image::path::VerifiedPath;
+use buck_label::Label;
+use clap::Parser;
+
+use crate::Error;
+use crate::Result;
+
+mod compile;
+mod emit;
+mod fingerprint;
+mod layout;
+mod map;
+mod plan;
+mod remap;
+mod source;
+pub(crate) use compile::Compile;
+pub(crate) use map::Map;
+pub(crate) use plan::Plan;
+use fingerprint::Fingerprint;
+use layout::Layout;
+use remap::PathMapping;
+use remap::RemapPathPrefix;
+use source::SourceRef;
+
+/// Args that are common to "compileish" commands (for now, 'compile' and
+/// 'plan', but maybe others in the future)
+#[derive(Parser, Debug)]
+pub(self) struct Compileish {
+    #[clap(long)]
+    /// Label of the image being built
+    pub(crate) label: Label<'static>,
+    #[clap(long)]
+    /// Root directory of under-construction image. Must already exist (either
+    /// empty or as a snapshot of a parent layer)
+    pub(crate) root: PathBuf,
+    #[clap(flatten)]
+    pub(crate) external: CompileishExternal,
+    #[clap(long)]
+    /// Path to available dnf repositories, or a `git+<url>[#rev=...]`
+    /// reference to clone them from
+    pub(crate) dnf_repos: SourceRef,
+    #[clap(long)]
+    /// Revision qualifier applied on top of a `git+` `--dnf-repos` spec
+    pub(crate) dnf_repos_rev: Option<String>,
+    #[clap(long = "remap-path-prefix", value_name = "FROM=TO")]
+    /// Remap build-host path prefixes before they are written into the
+    /// image, for bit-for-bit reproducible builds. May be repeated; the
+    /// longest matching `FROM` always wins.
+    pub(crate) remap_path_prefix: Vec<RemapPathPrefix>,
+}
+
+#[derive(Parser, Debug)]
+/// Compile arguments that are _always_ passed from external sources (in other
+/// words, by buck2 actions) and are never generated by internal code in the
+/// 'isolate' subcommand.
+pub(self) struct CompileishExternal {
+    #[clap(long = "depgraph-json")]
+    /// Path to input depgraph json file, or a `git+<url>[#rev=...]`
+    /// reference to clone it from
+    pub(crate) depgraph: SourceRef,
+    #[clap(long = "depgraph-rev")]
+    /// Revision qualifier applied on top of a `git+` `--depgraph-json` spec
+    pub(crate) depgraph_rev: Option<String>,
+}
+
+impl CompileishExternal {
+    /// Resolve and parse the depgraph, cloning it first if it was given as a
+    /// `git+` reference.
+    pub(self) fn depgraph(&self) -> Result<Graph<'static>> {
+        let path = self
+            .depgraph
+            .clone()
+            .with_rev(self.depgraph_rev.clone())
+            .resolve()
+            .map_err(Error::Compile)?;
+        Graph::open(path.path()).map_err(Error::Compile)
+    }
+}
+
+impl Compileish {
+    /// Resolve the dnf repos source (cloning it first, if it was given as a
+    /// `git+` reference) into a path on the local filesystem.
+    pub(self) fn dnf_repos(&self) -> Result<VerifiedPath> {
+        self.dnf_repos
+            .clone()
+            .with_rev(self.dnf_repos_rev.clone())
+            .resolve()
+            .map_err(Error::Compile)
+    }
+
+    #[deny(unused_variables)]
+    pub(self) fn to_args(&self) -> Vec<OsString> {
+        let Self {
+            label,
+            external:
+                CompileishExternal {
+                    depgraph,
+                    depgraph_rev,
+                },
+            root,
+            dnf_repos,
+            dnf_repos_rev,
+            remap_path_prefix,
+        } = self;
+        let mut args = vec![
+            OsString::from("--label"),
+            OsString::from(label.as_str()),
+            OsString::from("--depgraph-json"),
+            OsString::from(depgraph.to_string()),
+            OsString::from("--root"),
+            root.clone().into_os_string(),
+            OsString::from("--dnf-repos"),
+            OsString::from(dnf_repos.to_string()),
+        ];
+        if let Some(rev) = depgraph_rev {
+            args.push(OsString::from("--depgraph-rev"));
+            args.push(OsString::from(rev));
+        }
+        if let Some(rev) = dnf_repos_rev {
+            args.push(OsString::from("--dnf-repos-rev"));
+            args.push(OsString::from(rev));
+        }
+        for prefix in remap_path_prefix {
+            args.push(OsString::from("--remap-path-prefix"));
+            args.push(OsString::from(prefix.to_string()));
+        }
+        args
+    }
+
+    /// The [`PathMapping`] built from this command's `--remap-path-prefix`
+    /// flags, applied longest-match-first to any path the compiler writes
+    /// into the image.
+    pub(self) fn path_mapping(&self) -> PathMapping {
+        PathMapping::new(self.remap_path_prefix.clone())
+    }
+
+    /// Compute the fingerprint of this image build, based on everything that
+    /// can change the output: the depgraph contents, the available dnf
+    /// repos, the identity (inode and creation time) of `--root` and the
+    /// compiler version.
+    pub(self) fn fingerprint(&self) -> Result<Fingerprint> {
+        let depgraph_path = self
+            .external
+            .depgraph
+            .clone()
+            .with_rev(self.external.depgraph_rev.clone())
+            .resolve()
+            .map_err(Error::Compile)?;
+        let dnf_repos_path = self.dnf_repos()?;
+        Fingerprint::compute(&self.root, dnf_repos_path.path(), depgraph_path.path())
+            .map_err(Error::Compile)
+    }
+
+    /// Build-output [`Layout`] for this compile, rooted at `--root` but with
+    /// a per-build-fingerprint subdirectory so concurrent compiles of
+    /// different depgraphs against the same `--root` can't collide.
+    pub(self) fn layout(&self) -> Result<Layout> {
+        let fingerprint = self.fingerprint()?;
+        let base = VerifiedPath::create(self.root.clone())
+            .map_err(anyhow::Error::from)
+            .map_err(Error::Compile)?;
+        Ok(Layout::new(base, fingerprint))
+    }
+
+    pub(super) fn compiler_context(
+        &self,
+        plan: Option<antlir2_compile::plan::Plan>,
+    ) -> Result<CompilerContext> {
+        let layout = self.layout()?;
+        let dnf_repos = self.dnf_repos()?;
+        CompilerContext::new(
+            layout.root(),
+            dnf_repos.path().to_owned(),
+            plan,
+            self.path_mapping(),
+        )
+        .map_err(Error::Compile)
+    }
+}
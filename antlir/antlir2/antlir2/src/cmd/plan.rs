@@ -5,15 +5,16 @@
  * LICENSE file in the root directory of this source tree.
  */
 
+use std::path::Path;
 use std::path::PathBuf;
 
 use antlir2_compile::CompileFeature;
-use antlir2_depgraph::Graph;
 use antlir2_rootless::Rootless;
 use anyhow::Context;
 use clap::Parser;
 use itertools::Itertools;
 
+use super::emit::EmitSpec;
 use super::Compileish;
 use crate::Error;
 use crate::Result;
@@ -42,6 +43,10 @@ pub(super) struct PlanExternal {
     #[clap(long)]
     /// Output path for serialized compiler plan
     pub(super) plan: PathBuf,
+    #[clap(long = "emit", value_name = "KIND[=PATH]")]
+    /// Additional plan outputs to emit, beyond the default `--plan` json.
+    /// May be repeated. Supported kinds: `json`, `dot`, `deps`.
+    pub(super) emit: Vec<EmitSpec>,
 }
 
 impl Plan {
@@ -50,7 +55,7 @@ impl Plan {
         let ctx = self.compileish.compiler_context(None)?;
 
         let root_guard = rootless.map(|r| r.escalate()).transpose()?;
-        let depgraph = Graph::open(self.compileish.external.depgraph)?;
+        let depgraph = self.compileish.external.depgraph()?;
         let items: Vec<_> = depgraph
             .pending_features()?
             .map(|f| f.plan(&ctx).map_err(Error::Compile))
@@ -63,6 +68,17 @@ impl Plan {
         let f = std::fs::File::create(&self.external.plan).context("while creating plan file")?;
         serde_json::to_writer_pretty(f, &plan).context("while serializing plan")?;
 
+        let default_dir = self
+            .external
+            .plan
+            .parent()
+            .map(Path::to_path_buf)
+            .unwrap_or_default();
+        for emit in &self.external.emit {
+            emit.write(&default_dir, &plan, &depgraph)
+                .context("while emitting additional plan output")?;
+        }
+
         Ok(())
     }
 }
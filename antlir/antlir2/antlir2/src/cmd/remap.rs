@@ -0,0 +1,73 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Path remapping for reproducible images, modeled on rustc's
+//! `FilePathMapping`/`--remap-path-prefix`. Build-host paths like `--root`
+//! or scratch directories should never leak into the compiled artifact, so
+//! any path the compiler writes into the image is first passed through a
+//! [`PathMapping`].
+
+use std::fmt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("invalid --remap-path-prefix {0:?}, expected FROM=TO")]
+pub(crate) struct RemapPathPrefixParseError(String);
+
+/// A single `FROM=TO` substitution parsed from a `--remap-path-prefix` flag.
+#[derive(Debug, Clone)]
+pub(crate) struct RemapPathPrefix {
+    from: PathBuf,
+    to: PathBuf,
+}
+
+impl FromStr for RemapPathPrefix {
+    type Err = RemapPathPrefixParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((from, to)) => Ok(Self {
+                from: PathBuf::from(from),
+                to: PathBuf::from(to),
+            }),
+            None => Err(RemapPathPrefixParseError(s.to_owned())),
+        }
+    }
+}
+
+impl fmt::Display for RemapPathPrefix {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}={}", self.from.display(), self.to.display())
+    }
+}
+
+/// An ordered set of path substitutions, applied longest-prefix-first so
+/// that a more specific remap always wins over a shorter one.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct PathMapping {
+    prefixes: Vec<RemapPathPrefix>,
+}
+
+impl PathMapping {
+    pub(crate) fn new(mut prefixes: Vec<RemapPathPrefix>) -> Self {
+        prefixes.sort_by(|a, b| b.from.as_os_str().len().cmp(&a.from.as_os_str().len()));
+        Self { prefixes }
+    }
+
+    /// Apply the longest matching `FROM=TO` substitution to `path`, if any
+    /// of them match. Paths with no matching prefix are returned unchanged.
+    pub(crate) fn remap(&self, path: &Path) -> PathBuf {
+        for prefix in &self.prefixes {
+            if let Ok(suffix) = path.strip_prefix(&prefix.from) {
+                return prefix.to.join(suffix);
+            }
+        }
+        path.to_owned()
+    }
+}
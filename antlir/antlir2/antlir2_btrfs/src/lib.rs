@@ -29,6 +29,7 @@ use tracing::trace_span;
 const INO_SUBVOL: u64 = 256;
 
 mod ioctl;
+mod tree_search;
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -42,6 +43,12 @@ pub enum Error {
     CannotCreateRoot,
     #[error(transparent)]
     Io(#[from] std::io::Error),
+    #[error("failed to delete nested subvolume {}: {source}", path.display())]
+    RecursiveDeleteFailed {
+        path: PathBuf,
+        #[source]
+        source: Box<Error>,
+    },
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
@@ -255,6 +262,54 @@ impl Subvolume {
             }
         }
     }
+
+    /// Like [Subvolume::delete], but first deletes every subvolume nested
+    /// underneath this one. A plain `delete` on a subvolume containing
+    /// children fails with EBUSY/ENOTEMPTY, since the kernel refuses to
+    /// destroy a root that's still referenced by descendants.
+    ///
+    /// Descendants are deleted deepest-path-first, so a child is always
+    /// gone before the subvolume that contains it is attempted. If any
+    /// child fails to delete, the whole operation stops and reports which
+    /// one, leaving this subvolume (and any remaining children) in place
+    /// for the caller to fall back on (e.g. a recursive `rm`).
+    pub fn delete_recursive(self) -> std::result::Result<(), (Self, Error)> {
+        let mut descendants = match tree_search::descendant_subvolumes(&self.fd, self.id, &self.opened_path)
+        {
+            Ok(d) => d,
+            Err(e) => return Err((self, e.into())),
+        };
+        // deepest paths first, so a nested child is always removed before
+        // the subvolume that contains it
+        descendants.sort_by_key(|(_, path)| std::cmp::Reverse(path.components().count()));
+
+        for (_, path) in descendants {
+            match Subvolume::open(&path) {
+                Ok(child) => {
+                    if let Err((_, e)) = child.delete() {
+                        return Err((
+                            self,
+                            Error::RecursiveDeleteFailed {
+                                path,
+                                source: Box::new(e),
+                            },
+                        ));
+                    }
+                }
+                Err(e) => {
+                    return Err((
+                        self,
+                        Error::RecursiveDeleteFailed {
+                            path,
+                            source: Box::new(e),
+                        },
+                    ));
+                }
+            }
+        }
+
+        self.delete()
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,215 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Enumerating the subvolumes nested under another subvolume, via the btrfs
+//! `TREE_SEARCH_V2` ioctl over the filesystem's root tree. `btrfs-progs`
+//! builds `subvolume list` the same way: `ROOT_BACKREF` items give the
+//! (child id, parent id) edges, and the co-located `ROOT_REF` item's name
+//! gives the child's directory entry name under that parent, so a path can
+//! be built up without a second lookup per subvolume.
+
+use std::collections::HashMap;
+use std::os::fd::AsRawFd;
+use std::os::fd::RawFd;
+
+use nix::dir::Dir;
+
+const BTRFS_IOCTL_MAGIC: u8 = 0x94;
+const BTRFS_ROOT_TREE_OBJECTID: u64 = 1;
+const BTRFS_ROOT_BACKREF_KEY: u32 = 144;
+const BTRFS_ROOT_REF_KEY: u32 = 156;
+
+// Large enough to hold a page or two of search results per ioctl call; we
+// just loop if a filesystem has more nested subvolumes than fit.
+const SEARCH_BUF_SIZE: usize = 16 * 1024;
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default)]
+struct SearchKey {
+    tree_id: u64,
+    min_objectid: u64,
+    max_objectid: u64,
+    min_offset: u64,
+    max_offset: u64,
+    min_transid: u64,
+    max_transid: u64,
+    min_type: u32,
+    max_type: u32,
+    nr_items: u32,
+    unused: u32,
+    unused1: u32,
+    unused2: u32,
+    unused3: u32,
+    unused4: u32,
+}
+
+#[repr(C)]
+struct SearchArgsV2 {
+    key: SearchKey,
+    buf_size: u64,
+    buf: [u8; SEARCH_BUF_SIZE],
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct SearchHeader {
+    transid: u64,
+    objectid: u64,
+    offset: u64,
+    ty: u32,
+    len: u32,
+}
+
+nix::ioctl_readwrite!(tree_search_v2, BTRFS_IOCTL_MAGIC, 17, SearchArgsV2);
+
+/// One edge discovered via a `ROOT_BACKREF`/`ROOT_REF` pair: `child` is a
+/// subvolume id whose directory entry is named `name` inside subvolume
+/// `parent`.
+struct ChildEdge {
+    parent: u64,
+    child: u64,
+    name: String,
+}
+
+/// Walk every `ROOT_BACKREF` item in the root tree, returning every edge in
+/// the filesystem (not just descendants of any particular subvolume -- the
+/// caller filters that out by walking the graph from the id it cares about).
+fn all_child_edges(root_fd: RawFd) -> std::io::Result<Vec<ChildEdge>> {
+    let mut edges = Vec::new();
+    let mut args = Box::new(SearchArgsV2 {
+        key: SearchKey {
+            tree_id: BTRFS_ROOT_TREE_OBJECTID,
+            min_objectid: 0,
+            max_objectid: u64::MAX,
+            min_offset: 0,
+            max_offset: u64::MAX,
+            min_transid: 0,
+            max_transid: u64::MAX,
+            min_type: BTRFS_ROOT_BACKREF_KEY,
+            max_type: BTRFS_ROOT_REF_KEY,
+            nr_items: u32::MAX,
+            ..Default::default()
+        },
+        buf_size: SEARCH_BUF_SIZE as u64,
+        buf: [0; SEARCH_BUF_SIZE],
+    });
+
+    // Pending backref (child id, parent id) waiting to be paired with the
+    // co-located ROOT_REF item that carries the directory entry name.
+    let mut pending_name: HashMap<(u64, u64), String> = HashMap::new();
+
+    loop {
+        args.key.nr_items = u32::MAX;
+        // SAFETY: `args` is a correctly-sized, repr(C) buffer matching the
+        // kernel's `btrfs_ioctl_search_args_v2` layout, and `root_fd` is a
+        // valid, open fd for a directory on this filesystem.
+        unsafe { tree_search_v2(root_fd, args.as_mut() as *mut SearchArgsV2) }
+            .map_err(std::io::Error::from)?;
+        // The kernel overwrites `key.nr_items` with the number of items
+        // actually found (which may be 0, meaning the search is exhausted).
+        let found = args.key.nr_items;
+        if found == 0 {
+            break;
+        }
+
+        let mut offset = 0usize;
+        let mut last_key = None;
+        for _ in 0..found {
+            let header: SearchHeader = unsafe {
+                std::ptr::read_unaligned(args.buf[offset..].as_ptr() as *const SearchHeader)
+            };
+            offset += std::mem::size_of::<SearchHeader>();
+            let item = &args.buf[offset..offset + header.len as usize];
+
+            match header.ty {
+                BTRFS_ROOT_BACKREF_KEY => {
+                    // objectid = child root id, offset = parent root id
+                    if let Some(name) = pending_name.remove(&(header.objectid, header.offset)) {
+                        edges.push(ChildEdge {
+                            parent: header.offset,
+                            child: header.objectid,
+                            name,
+                        });
+                    } else {
+                        pending_name.insert((header.objectid, header.offset), String::new());
+                    }
+                }
+                BTRFS_ROOT_REF_KEY => {
+                    // Unlike ROOT_BACKREF, ROOT_REF points the other way:
+                    // objectid = parent root id, offset = child root id.
+                    // dirid(8) + sequence(8) + name_len(2), then the name.
+                    if item.len() >= 18 {
+                        let name_len = u16::from_le_bytes([item[16], item[17]]) as usize;
+                        let name = String::from_utf8_lossy(
+                            &item[18..18 + name_len.min(item.len().saturating_sub(18))],
+                        )
+                        .into_owned();
+                        let (parent, child) = (header.objectid, header.offset);
+                        let key = (child, parent);
+                        match pending_name.remove(&key) {
+                            Some(_) => edges.push(ChildEdge {
+                                parent,
+                                child,
+                                name,
+                            }),
+                            None => {
+                                pending_name.insert(key, name);
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+
+            offset += header.len as usize;
+            last_key = Some((header.objectid, header.ty, header.offset));
+        }
+
+        // Keys are ordered lexicographically by (objectid, type, offset), so
+        // resuming just past the last item seen -- regardless of whether
+        // the next page rolls over to a new type or objectid -- is enough
+        // to make forward progress without re-visiting or skipping items.
+        match last_key {
+            Some((objectid, ty, offset)) if offset != u64::MAX => {
+                args.key.min_objectid = objectid;
+                args.key.min_type = ty;
+                args.key.min_offset = offset + 1;
+            }
+            _ => break,
+        }
+    }
+
+    Ok(edges)
+}
+
+/// Return the ids of every subvolume nested (at any depth) under the
+/// subvolume with id `root_id`, each paired with its path under `root_path`
+/// (the already-known path of that subvolume), built by joining the
+/// `ROOT_REF` entry names onto it.
+pub(crate) fn descendant_subvolumes(
+    fd: &Dir,
+    root_id: u64,
+    root_path: &std::path::Path,
+) -> std::io::Result<Vec<(u64, std::path::PathBuf)>> {
+    let edges = all_child_edges(fd.as_raw_fd())?;
+
+    let mut children_of: HashMap<u64, Vec<&ChildEdge>> = HashMap::new();
+    for edge in &edges {
+        children_of.entry(edge.parent).or_default().push(edge);
+    }
+
+    let mut descendants = Vec::new();
+    let mut frontier = vec![(root_id, root_path.to_path_buf())];
+    while let Some((id, path)) = frontier.pop() {
+        for edge in children_of.get(&id).into_iter().flatten() {
+            let child_path = path.join(&edge.name);
+            descendants.push((edge.child, child_path.clone()));
+            frontier.push((edge.child, child_path));
+        }
+    }
+    Ok(descendants)
+}
@@ -0,0 +1,136 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! CRIU-based checkpoint/restore of an isolated process tree, backing
+//! `Ephemeral::Checkpoint`. This lets a long build be paused mid-execution
+//! (and later resumed, possibly after the dump was shipped somewhere else)
+//! instead of always running start-to-finish in one invocation.
+
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::process::Child;
+use std::process::Command;
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use nix::sys::signal::kill;
+use nix::sys::signal::Signal;
+use nix::unistd::Pid;
+use tracing::warn;
+
+/// Written into `image_dir` once a dump has fully completed, so a directory
+/// left behind by a dump that died partway through is never mistaken for a
+/// usable checkpoint.
+const COMPLETE_SENTINEL: &str = ".checkpoint-complete";
+
+/// True if `image_dir` holds a complete, restorable checkpoint.
+pub(crate) fn is_restorable(image_dir: &Path) -> bool {
+    image_dir.join(COMPLETE_SENTINEL).exists()
+}
+
+/// Stop `pid` itself with `SIGSTOP`, then hand its process tree to CRIU
+/// to dump memory, open file descriptors and the mount table into
+/// `image_dir`. The caller is responsible for leaving the ephemeral
+/// writable layer in place only once this returns `Ok` - on failure, any
+/// partial image files are removed here, the same way a failed ephemeral
+/// snapshot is torn down instead of left dangling.
+pub(crate) fn checkpoint(pid: Pid, image_dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(image_dir)
+        .with_context(|| format!("while creating {}", image_dir.display()))?;
+
+    kill(pid, Signal::SIGSTOP).context("while freezing process tree before dump")?;
+
+    let dumped = Command::new("criu")
+        .arg("dump")
+        .arg("--tree")
+        .arg(pid.to_string())
+        .arg("--images-dir")
+        .arg(image_dir)
+        .arg("--shell-job")
+        .status();
+
+    match dumped {
+        Ok(status) if status.success() => {
+            std::fs::write(image_dir.join(COMPLETE_SENTINEL), b"")
+                .context("while writing checkpoint completion sentinel")?;
+            Ok(())
+        }
+        Ok(status) => {
+            // A successful dump defaults to killing the tree; a failed one
+            // leaves it frozen, so it needs to be resumed before anything
+            // else can use it.
+            let _ = kill(pid, Signal::SIGCONT);
+            cleanup_partial(image_dir);
+            bail!("criu dump exited with {status}");
+        }
+        Err(e) => {
+            let _ = kill(pid, Signal::SIGCONT);
+            cleanup_partial(image_dir);
+            Err(e).context("while spawning criu dump")
+        }
+    }
+}
+
+/// Remove whatever partial image files a failed dump left behind.
+fn cleanup_partial(image_dir: &Path) {
+    if let Err(e) = std::fs::remove_dir_all(image_dir) {
+        warn!(
+            "failed to clean up partial checkpoint at {}: {e}",
+            image_dir.display()
+        );
+    }
+}
+
+/// Re-create the process tree dumped into `image_dir` and resume it. The
+/// bind mounts and working directory from the original `IsolationContext`
+/// must already be re-applied before calling this, exactly as they were at
+/// dump time, or CRIU will fail to resolve the file descriptors it's
+/// restoring.
+///
+/// Deliberately does *not* pass `--restore-detached`: the caller treats the
+/// returned `Child` as the restored workload itself (waiting on it,
+/// checkpointing its pid again, ...), which only holds if `criu restore`
+/// stays in the foreground and execs into the restored root task instead of
+/// forking it off and exiting.
+pub(crate) fn restore(image_dir: &Path) -> Result<Child> {
+    if !is_restorable(image_dir) {
+        bail!(
+            "{} does not contain a complete checkpoint",
+            image_dir.display()
+        );
+    }
+    Command::new("criu")
+        .arg("restore")
+        .arg("--images-dir")
+        .arg(image_dir)
+        .arg("--shell-job")
+        .spawn()
+        .context("while spawning criu restore")
+}
+
+/// Serialize every file under `image_dir` to `writer` as a tar stream, so a
+/// checkpoint can be shipped to remote storage instead of only ever living
+/// in a local directory.
+pub(crate) fn write_stream<W: Write>(image_dir: &Path, writer: W) -> Result<()> {
+    let mut builder = tar::Builder::new(writer);
+    builder
+        .append_dir_all(".", image_dir)
+        .with_context(|| format!("while archiving checkpoint at {}", image_dir.display()))?;
+    builder.finish().context("while finishing checkpoint archive")
+}
+
+/// Inverse of [`write_stream`]: unpack a previously streamed checkpoint into
+/// `image_dir`, creating it first.
+pub(crate) fn read_stream<R: Read>(image_dir: &Path, reader: R) -> Result<()> {
+    std::fs::create_dir_all(image_dir)
+        .with_context(|| format!("while creating {}", image_dir.display()))?;
+    tar::Archive::new(reader)
+        .unpack(image_dir)
+        .with_context(|| format!("while unpacking checkpoint into {}", image_dir.display()))
+}
@@ -0,0 +1,174 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Deterministic, content-addressed naming for ephemeral snapshots/overlays,
+//! mirroring the rebel runner's canonical-JSON + BLAKE3 content-addressing
+//! approach. Naming the ephemeral copy `.{layer}.ephemeral.{pid}` (the old
+//! scheme) is non-deterministic and leaks a stale subvolume/overlay behind
+//! every invocation that gets killed before cleanup runs; hashing the parts
+//! of the isolation context that determine the writable view instead gives
+//! every invocation of the same build a stable name, and a dead invocation's
+//! leftovers can be recognized (and reaped, see [gc]) without needing to
+//! remember which pid made them.
+
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use anyhow::Result;
+use isolate_cfg::IsolationContext;
+use nix::errno::Errno;
+use nix::fcntl::FlockArg;
+use nix::fcntl::flock;
+use nix::mount::MntFlags;
+use nix::mount::umount2;
+use serde_json::json;
+use tracing::warn;
+
+/// Hex prefix length used in ephemeral snapshot/lock names -- enough to
+/// make accidental collisions between distinct contexts vanishingly
+/// unlikely while keeping directory names short.
+const HASH_PREFIX_LEN: usize = 16;
+
+/// Hash the subset of `ctx` that determines the writable view pid1 gets
+/// (layer identity, mounts, and the env that affects it), serialized as
+/// canonical JSON, and return a short hex prefix of the BLAKE3 digest.
+///
+/// `serde_json::Map` is backed by a `BTreeMap` (this crate doesn't enable
+/// the `preserve_order` feature), so `serde_json::to_vec` already produces
+/// object keys in lexicographic order with no insignificant whitespace --
+/// exactly the canonical form this needs, with no separate normalization
+/// pass required.
+pub fn ephemeral_hash(ctx: &IsolationContext<'_>, layer_path: &Path) -> Result<String> {
+    let canonical = json!({
+        "layer": layer_path.to_string_lossy(),
+        "mounts": &ctx.mounts,
+        "env": &ctx.env,
+    });
+    let bytes =
+        serde_json::to_vec(&canonical).context("while serializing canonical context json")?;
+    let digest = blake3::hash(&bytes);
+    Ok(digest.to_hex()[..HASH_PREFIX_LEN].to_string())
+}
+
+/// An exclusive, advisory lock on a `.ephemeral.{hash}.lock` file, held for
+/// the lifetime of this value. Acquiring it blocks until any other live
+/// holder (another invocation with an identical content hash) releases
+/// theirs, so concurrent identical invocations serialize around creating
+/// (and tearing down) the snapshot/overlay that name refers to, rather than
+/// racing each other.
+pub struct EphemeralLock {
+    // Kept only to hold the flock for this value's lifetime; never read.
+    _file: File,
+    path: PathBuf,
+}
+
+impl EphemeralLock {
+    pub fn acquire(path: PathBuf) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(&path)
+            .with_context(|| format!("while opening lock file {}", path.display()))?;
+        flock(file.as_raw_fd(), FlockArg::LockExclusive)
+            .with_context(|| format!("while locking {}", path.display()))?;
+        // Best-effort, purely so a human inspecting the directory of a
+        // stuck build can see which pid is holding a given lock.
+        let _ = std::fs::write(&path, std::process::id().to_string());
+        Ok(Self { _file: file, path })
+    }
+
+    /// Non-blocking: true if some other process currently holds the lock
+    /// at `path` (i.e. the invocation that created it, if any, is still
+    /// alive). A missing lock file counts as not held.
+    fn is_held(path: &Path) -> Result<bool> {
+        let file = match OpenOptions::new().write(true).open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(false),
+            Err(e) => {
+                return Err(e).with_context(|| format!("while opening {}", path.display()));
+            }
+        };
+        match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            Ok(()) => {
+                let _ = flock(file.as_raw_fd(), FlockArg::Unlock);
+                Ok(false)
+            }
+            Err(Errno::EWOULDBLOCK) => Ok(true),
+            Err(e) => Err(e).with_context(|| format!("while probing lock on {}", path.display())),
+        }
+    }
+}
+
+impl Drop for EphemeralLock {
+    fn drop(&mut self) {
+        // The flock itself is released when `_file` closes; also remove
+        // the file so completed invocations don't pile up lock files next
+        // to every layer forever. Best-effort: another invocation may have
+        // already raced us to removing it.
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+/// Scan `parent_dir` for `.ephemeral.` entries left behind by invocations
+/// that were killed before their own cleanup ran, and reap them. An entry
+/// is only reaped once its paired `<entry>.lock` file is confirmed to not
+/// be held by anyone -- a live invocation (even a slow one) is left alone.
+pub fn gc(parent_dir: &Path) -> Result<()> {
+    for entry in std::fs::read_dir(parent_dir)
+        .with_context(|| format!("while reading {}", parent_dir.display()))?
+    {
+        let entry = entry.context("while reading directory entry")?;
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if !name.contains(".ephemeral.") || name.ends_with(".lock") {
+            continue;
+        }
+
+        let lock_path = parent_dir.join(format!("{name}.lock"));
+        if EphemeralLock::is_held(&lock_path)? {
+            continue;
+        }
+
+        let path = entry.path();
+        reap(&path)?;
+        let _ = std::fs::remove_file(&lock_path);
+    }
+    Ok(())
+}
+
+/// Tear down one stale ephemeral snapshot or overlay scratch dir, the same
+/// way `do_main` tears down its own when pid1 exits.
+fn reap(path: &Path) -> Result<()> {
+    match antlir2_btrfs::Subvolume::open(path) {
+        Ok(subvol) => {
+            if let Err((_, e)) = subvol.delete_recursive() {
+                warn!(
+                    "btrfs delete of stale ephemeral snapshot {} failed: {e}, falling back to a recursive removal",
+                    path.display()
+                );
+                std::fs::remove_dir_all(path)
+                    .with_context(|| format!("while removing {}", path.display()))?;
+            }
+        }
+        // Not a btrfs subvolume: a stale overlay scratch dir (or a
+        // half-constructed one). Detach its mount, if still mounted, before
+        // removing it, mirroring `do_main`'s overlay cleanup path.
+        Err(_) => {
+            let merged = path.join("merged");
+            if let Err(e) = umount2(&merged, MntFlags::MNT_DETACH) {
+                warn!("failed to unmount stale overlay at {}: {e}", merged.display());
+            }
+            std::fs::remove_dir_all(path)
+                .with_context(|| format!("while removing {}", path.display()))?;
+        }
+    }
+    Ok(())
+}
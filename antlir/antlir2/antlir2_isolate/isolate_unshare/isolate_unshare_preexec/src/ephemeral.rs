@@ -0,0 +1,105 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Auto-detection of the filesystem backing a layer, so that
+//! `Ephemeral::Auto` can use a zero-copy btrfs snapshot where available and
+//! fall back to an overlayfs mount everywhere else, instead of hard-failing
+//! with `antlir2_btrfs::Error::NotBtrfs` the way explicit `Ephemeral::Btrfs`
+//! does.
+
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use anyhow::Context;
+use anyhow::Result;
+use rustix::fs::CWD;
+use rustix::mount::FsMountFlags;
+use rustix::mount::FsOpenFlags;
+use rustix::mount::MountAttrFlags;
+use rustix::mount::MoveMountFlags;
+use rustix::mount::fsconfig_create;
+use rustix::mount::fsconfig_set_string;
+use rustix::mount::fsmount;
+use rustix::mount::fsopen;
+use rustix::mount::move_mount;
+
+// `statfs(2)` `f_type` magic numbers, from linux/magic.h. Matches the way
+// Mercurial's dirstate code special-cases filesystems by magic rather than
+// by name.
+const BTRFS_SUPER_MAGIC: i64 = 0x9123683e;
+const OVERLAYFS_SUPER_MAGIC: i64 = 0x794c7630;
+const TMPFS_MAGIC: i64 = 0x0102_1994;
+const NFS_SUPER_MAGIC: i64 = 0x6969;
+
+/// What kind of filesystem a layer directory lives on, as far as ephemeral
+/// mode auto-detection cares.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backing {
+    Btrfs,
+    Nfs,
+    /// overlayfs, tmpfs, ext4, xfs, or anything else an overlay mount can be
+    /// built on top of.
+    Other,
+}
+
+/// `statfs(2)` the directory at `path` and classify its backing filesystem.
+pub fn probe(path: &Path) -> Result<Backing> {
+    let path_c =
+        CString::new(path.as_os_str().as_bytes()).context("while making CString of path")?;
+    let mut buf: libc::statfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `path_c` is a valid, NUL-terminated path, and `buf` is a
+    // correctly-sized, zeroed destination for the kernel to fill in.
+    let rc = unsafe { libc::statfs(path_c.as_ptr(), &mut buf) };
+    if rc != 0 {
+        return Err(std::io::Error::last_os_error())
+            .with_context(|| format!("while statfs'ing {}", path.display()));
+    }
+    // overlayfs and tmpfs are both named explicitly (rather than just
+    // falling into the wildcard arm below) so that it's clear they were
+    // considered and are intentionally treated the same as any other
+    // non-btrfs, non-NFS filesystem: just another base an overlay mount can
+    // be built on top of.
+    #[allow(clippy::match_same_arms)]
+    Ok(match buf.f_type as i64 {
+        BTRFS_SUPER_MAGIC => Backing::Btrfs,
+        NFS_SUPER_MAGIC => Backing::Nfs,
+        OVERLAYFS_SUPER_MAGIC | TMPFS_MAGIC => Backing::Other,
+        _ => Backing::Other,
+    })
+}
+
+/// Mount an overlayfs with `lower` as the (read-only) lowerdir and
+/// `upper`/`work` as a fresh upperdir/workdir, merged at `target`.
+pub fn mount_overlay(lower: &Path, upper: &Path, work: &Path, target: &Path) -> Result<()> {
+    let fs_fd =
+        fsopen("overlay", FsOpenFlags::FSOPEN_CLOEXEC).context("fsopen(\"overlay\") failed")?;
+    fsconfig_set_string(&fs_fd, "lowerdir", path_str(lower)?)
+        .context("fsconfig_set_string(lowerdir) failed")?;
+    fsconfig_set_string(&fs_fd, "upperdir", path_str(upper)?)
+        .context("fsconfig_set_string(upperdir) failed")?;
+    fsconfig_set_string(&fs_fd, "workdir", path_str(work)?)
+        .context("fsconfig_set_string(workdir) failed")?;
+    fsconfig_create(&fs_fd).context("fsconfig_create failed")?;
+
+    let mnt_fd = fsmount(&fs_fd, FsMountFlags::FSMOUNT_CLOEXEC, MountAttrFlags::empty())
+        .context("fsmount failed")?;
+    move_mount(
+        &mnt_fd,
+        "",
+        CWD,
+        target,
+        MoveMountFlags::MOVE_MOUNT_F_EMPTY_PATH,
+    )
+    .context("move_mount failed")?;
+    Ok(())
+}
+
+fn path_str(path: &Path) -> Result<&str> {
+    path.to_str()
+        .with_context(|| format!("{} is not valid UTF-8", path.display()))
+}
@@ -0,0 +1,82 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Per-namespace setup that has to run in the isolated process itself, right
+//! after `unshare`: user-namespace identity mapping (`IsolationContext::user_ns`)
+//! and time-namespace clock offsets (`IsolationContext::time_offset`).
+
+use std::path::Path;
+
+use anyhow::Context;
+use anyhow::Result;
+use nix::unistd::getgid;
+use nix::unistd::getuid;
+
+/// One `<inside_id> <outside_id> <count>` line of `/proc/<pid>/{uid,gid}_map`.
+pub type IdMapRange = (u32, u32, u32);
+
+/// Write `/proc/self/{setgroups,uid_map,gid_map}` for the user namespace
+/// this process just unshared into. Must be called exactly once, and only
+/// after `unshare(CLONE_NEWUSER)` has already run on this (single-threaded)
+/// process -- having just created the namespace, we hold every capability
+/// within it, which is what lets us write our own mapping here rather than
+/// needing some other, still-unprivileged process to do it for us.
+///
+/// `uid_map`/`gid_map` default to mapping the single invoking uid/gid to
+/// root (id 0) inside the namespace when empty.
+pub fn write_id_maps(uid_map: &[IdMapRange], gid_map: &[IdMapRange]) -> Result<()> {
+    let default_uid_map = [(0, getuid().as_raw(), 1)];
+    let default_gid_map = [(0, getgid().as_raw(), 1)];
+    let uid_map = if uid_map.is_empty() {
+        &default_uid_map[..]
+    } else {
+        uid_map
+    };
+    let gid_map = if gid_map.is_empty() {
+        &default_gid_map[..]
+    } else {
+        gid_map
+    };
+
+    // `setgroups` must be denied before `gid_map` can be written by an
+    // unprivileged mapping (see user_namespaces(7)), and each of these
+    // three files may only be written to once per namespace.
+    std::fs::write("/proc/self/setgroups", b"deny").context("while denying setgroups")?;
+    write_map_file("/proc/self/uid_map", uid_map).context("while writing uid_map")?;
+    write_map_file("/proc/self/gid_map", gid_map).context("while writing gid_map")?;
+    Ok(())
+}
+
+fn write_map_file(path: impl AsRef<Path>, map: &[IdMapRange]) -> Result<()> {
+    let path = path.as_ref();
+    let contents = map
+        .iter()
+        .map(|(inside, outside, count)| format!("{inside} {outside} {count}\n"))
+        .collect::<String>();
+    std::fs::write(path, contents)
+        .with_context(|| format!("while writing {}", path.display()))
+}
+
+/// A `(seconds, nanoseconds)` offset to apply to one clock inside a time
+/// namespace.
+pub type ClockOffset = (i64, i64);
+
+/// Write `/proc/self/timens_offsets` for the time namespace this process
+/// just unshared into, pinning `CLOCK_MONOTONIC` and `CLOCK_BOOTTIME` to a
+/// fixed offset from the host's clocks. Must be called after
+/// `unshare(CLONE_NEWTIME)` and before any process that should observe the
+/// offset `exec`s -- per timens(7), the offsets become immutable once a
+/// process in the namespace has started, and the namespace-creating process
+/// itself only picks up the new view of time at its own next `exec`.
+pub fn write_timens_offsets(monotonic: ClockOffset, boottime: ClockOffset) -> Result<()> {
+    let (mono_secs, mono_nanos) = monotonic;
+    let (boot_secs, boot_nanos) = boottime;
+    let contents =
+        format!("monotonic {mono_secs} {mono_nanos}\nboottime {boot_secs} {boot_nanos}\n");
+    std::fs::write("/proc/self/timens_offsets", contents)
+        .context("while writing /proc/self/timens_offsets")
+}
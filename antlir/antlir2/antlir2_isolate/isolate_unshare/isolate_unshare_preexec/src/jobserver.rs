@@ -0,0 +1,210 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! A GNU-make jobserver client (the POSIX pipe protocol -- see
+//! https://www.gnu.org/software/make/manual/html_node/POSIX-Jobserver.html)
+//! so that many isolated invocations spawned in parallel by a top-level
+//! build share one global concurrency budget, rather than each one
+//! assuming the whole machine is theirs to use.
+//!
+//! Every process already holds one implicit slot -- its own -- so
+//! [Jobserver::acquire] only needs to be called before doing *additional*
+//! parallel work on top of that. With no jobserver handed down via
+//! `MAKEFLAGS` (eg when run standalone, outside of `make`), falls back to
+//! an internal pool sized to the number of available CPUs.
+
+use std::env;
+use std::fs::OpenOptions;
+use std::os::fd::AsRawFd;
+use std::os::fd::FromRawFd;
+use std::os::fd::OwnedFd;
+use std::os::fd::RawFd;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use anyhow::Context;
+use anyhow::Result;
+use anyhow::bail;
+use nix::errno::Errno;
+use nix::fcntl::FcntlArg;
+use nix::fcntl::OFlag;
+use nix::fcntl::fcntl;
+
+enum TokenInner {
+    /// Keeps the shared write end alive (and remembers which byte to give
+    /// back) until this token is returned.
+    Pipe { write_fd: Arc<OwnedFd>, byte: u8 },
+    Internal(mpsc::SyncSender<()>),
+}
+
+/// A single held job slot beyond this process's own implicit one. Returns
+/// its token on drop -- including while unwinding on a panic or an early
+/// `?` return -- so a bug elsewhere in the call stack can never leak the
+/// shared concurrency budget.
+pub struct Token(TokenInner);
+
+impl Drop for Token {
+    fn drop(&mut self) {
+        match &self.0 {
+            TokenInner::Pipe { write_fd, byte } => {
+                // best-effort: there's nothing useful to do if the parent
+                // make's pipe has already gone away
+                let _ = retry_eintr(|| nix::unistd::write(write_fd.as_raw_fd(), &[*byte]));
+            }
+            TokenInner::Internal(tx) => {
+                let _ = tx.send(());
+            }
+        }
+    }
+}
+
+enum Source {
+    Pipe {
+        read_fd: OwnedFd,
+        write_fd: Arc<OwnedFd>,
+    },
+    Internal {
+        rx: mpsc::Receiver<()>,
+        tx: mpsc::SyncSender<()>,
+    },
+}
+
+/// A client for the shared job slot pool: either the real pipe handed down
+/// by `make`, or (if none was handed down) an internal fallback pool.
+pub struct Jobserver {
+    source: Source,
+}
+
+impl Jobserver {
+    /// Parse `--jobserver-auth=<r>,<w>` / `--jobserver-auth=fifo:<path>`
+    /// (and the older `--jobserver-fds=`) out of `MAKEFLAGS`, falling back
+    /// to an internal pool sized to [thread::available_parallelism] if
+    /// none is present.
+    pub fn from_env() -> Result<Self> {
+        match Self::parse_makeflags(&env::var("MAKEFLAGS").unwrap_or_default())? {
+            Some(source) => Ok(Self { source }),
+            None => {
+                let n = thread::available_parallelism().map_or(1, |n| n.get());
+                Ok(Self::internal_pool(n))
+            }
+        }
+    }
+
+    fn parse_makeflags(makeflags: &str) -> Result<Option<Source>> {
+        for flag in makeflags.split_whitespace() {
+            if let Some(auth) = flag
+                .strip_prefix("--jobserver-auth=")
+                .or_else(|| flag.strip_prefix("--jobserver-fds="))
+            {
+                return Self::open_auth(auth).map(Some);
+            }
+        }
+        Ok(None)
+    }
+
+    fn open_auth(auth: &str) -> Result<Source> {
+        let (read_fd, write_fd) = if let Some(path) = auth.strip_prefix("fifo:") {
+            let path = PathBuf::from(path);
+            let read_fd: OwnedFd = OpenOptions::new()
+                .read(true)
+                .open(&path)
+                .with_context(|| format!("while opening jobserver fifo {}", path.display()))?
+                .into();
+            let write_fd: OwnedFd = OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .with_context(|| format!("while opening jobserver fifo {}", path.display()))?
+                .into();
+            (read_fd, write_fd)
+        } else {
+            let (r, w) = auth
+                .split_once(',')
+                .with_context(|| format!("malformed --jobserver-auth value '{auth}'"))?;
+            // SAFETY: these fds were opened by the parent `make` process
+            // specifically to be inherited by us for this purpose, per the
+            // jobserver protocol
+            unsafe {
+                (
+                    OwnedFd::from_raw_fd(parse_fd(r)?),
+                    OwnedFd::from_raw_fd(parse_fd(w)?),
+                )
+            }
+        };
+        // non-blocking + retry on every acquire (rather than one blocking
+        // read()) so a signal delivered to the acquiring thread can't wedge
+        // it forever in the kernel waiting on a byte that may never come,
+        // eg if every other token is currently held
+        fcntl(read_fd.as_raw_fd(), FcntlArg::F_SETFL(OFlag::O_NONBLOCK))
+            .context("while making jobserver read fd non-blocking")?;
+        Ok(Source::Pipe {
+            read_fd,
+            write_fd: Arc::new(write_fd),
+        })
+    }
+
+    fn internal_pool(n: usize) -> Self {
+        let n = n.max(1);
+        let (tx, rx) = mpsc::sync_channel(n);
+        // this process's own slot is implicit and free; only the other
+        // n-1 need a token handed out before use
+        for _ in 0..n.saturating_sub(1) {
+            tx.send(()).expect("channel was just created with capacity n");
+        }
+        Self {
+            source: Source::Internal { rx, tx },
+        }
+    }
+
+    /// Block until a job slot beyond this process's own implicit one is
+    /// available.
+    pub fn acquire(&self) -> Result<Token> {
+        match &self.source {
+            Source::Pipe { read_fd, write_fd } => {
+                let fd = read_fd.as_raw_fd();
+                let mut buf = [0u8; 1];
+                loop {
+                    match nix::unistd::read(fd, &mut buf) {
+                        Ok(1) => {
+                            return Ok(Token(TokenInner::Pipe {
+                                write_fd: Arc::clone(write_fd),
+                                byte: buf[0],
+                            }));
+                        }
+                        Ok(_) => continue,
+                        Err(Errno::EAGAIN) => thread::sleep(Duration::from_millis(10)),
+                        Err(Errno::EINTR) => continue,
+                        Err(e) => bail!("while reading jobserver token: {e}"),
+                    }
+                }
+            }
+            Source::Internal { rx, tx } => {
+                rx.recv().context("jobserver's internal pool channel closed")?;
+                Ok(Token(TokenInner::Internal(tx.clone())))
+            }
+        }
+    }
+}
+
+fn parse_fd(s: &str) -> Result<RawFd> {
+    s.trim()
+        .parse::<RawFd>()
+        .with_context(|| format!("'{s}' is not a valid jobserver fd"))
+}
+
+fn retry_eintr<T>(
+    mut f: impl FnMut() -> std::result::Result<T, Errno>,
+) -> std::result::Result<T, Errno> {
+    loop {
+        match f() {
+            Err(Errno::EINTR) => continue,
+            other => return other,
+        }
+    }
+}
@@ -9,30 +9,79 @@
 
 use std::borrow::Cow;
 use std::ffi::OsString;
+use std::os::unix::process::ExitStatusExt;
 use std::path::PathBuf;
 use std::process::Command;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
 
 use anyhow::Context;
 use anyhow::Result;
 use anyhow::anyhow;
+use anyhow::bail;
 use clap::Parser;
 use isolate_cfg::Ephemeral;
 use isolate_cfg::IsolationContext;
 use json_arg::Json;
+use nix::mount::MntFlags;
+use nix::mount::umount2;
 use nix::sched::CloneFlags;
 use nix::sched::unshare;
+use nix::sys::signal::SigHandler;
+use nix::sys::signal::Signal;
+use nix::sys::signal::kill;
+use nix::sys::signal::signal;
+use nix::unistd::Pid;
+use nix::unistd::getpid;
+use tracing::info;
 use tracing::warn;
 
+mod checkpoint;
+mod content_hash;
+mod ephemeral;
 mod isolation;
+mod jobserver;
 pub(crate) mod net;
 pub(crate) mod new_mount_api;
 mod pid1;
 use pid1::Pid1Args;
 
+/// Exit code `do_main` uses to signal "the process tree was checkpointed and
+/// paused, not run to completion" - distinct from a normal 0 (success) or
+/// pid1's own exit code (failure), so a caller polling on exit status can
+/// tell the two apart.
+const CHECKPOINTED_EXIT_CODE: i32 = 75;
+
+/// Exit code used whenever the isolation wrapper itself fails (namespace
+/// setup, bind mounting, ephemeral snapshotting, ...) rather than the
+/// program it was asked to run -- borrowed from the same convention `docker
+/// run` uses for "the container runtime failed", so it's distinguishable
+/// from a real exit code the inner program chose for itself. A program that
+/// is killed by a signal is reported by re-raising that signal on this
+/// process instead, so it never shows up as this code either.
+const ISOLATION_ERROR_EXIT_CODE: i32 = 125;
+
+/// Set by the `SIGUSR1` handler installed in [`do_main`]; polled in the
+/// pid1 wait loop so a checkpoint can be requested asynchronously without
+/// racing the handler against the rest of the function.
+static CHECKPOINT_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_checkpoint(_signal: libc::c_int) {
+    CHECKPOINT_REQUESTED.store(true, Ordering::SeqCst);
+}
+
 #[derive(Debug, Parser)]
 enum Subcommand {
     Main(Main),
     Pid1(Pid1Args),
+    Gc(GcArgs),
+    /// Archive a checkpoint's image files to stdout, so they can be shipped
+    /// to remote storage instead of only ever living in a local directory.
+    CheckpointExport(CheckpointExportArgs),
+    /// Inverse of `checkpoint-export`: unpack a checkpoint archive read from
+    /// stdin into a local directory.
+    CheckpointImport(CheckpointImportArgs),
 }
 
 #[derive(Debug, Parser)]
@@ -43,6 +92,40 @@ struct Main {
     program_args: Vec<OsString>,
 }
 
+#[derive(Debug, Parser)]
+struct CheckpointExportArgs {
+    /// `image_dir` of a previously-completed `Ephemeral::Checkpoint` dump.
+    image_dir: PathBuf,
+}
+
+#[derive(Debug, Parser)]
+struct CheckpointImportArgs {
+    /// Directory to unpack the streamed checkpoint into, for a later
+    /// `Ephemeral::Checkpoint` restore.
+    image_dir: PathBuf,
+}
+
+/// Reap ephemeral snapshots/overlays left behind by invocations that were
+/// killed before they could clean up after themselves.
+#[derive(Debug, Parser)]
+struct GcArgs {
+    /// Directory to scan for stale `.ephemeral.*` entries (typically a
+    /// layer's parent directory).
+    dir: PathBuf,
+}
+
+/// What kind of writable copy of the layer ephemeral mode made, so `do_main`
+/// knows how to tear it back down once pid1 exits. Holds the per-invocation
+/// lock for its own name, so the lock is only released (and the entry
+/// becomes eligible for `gc`) once cleanup has actually run.
+enum EphemeralCleanup {
+    /// A btrfs snapshot at this path.
+    Btrfs(PathBuf, content_hash::EphemeralLock),
+    /// An overlayfs mount at `<path>/merged`, with `<path>/{upper,work}` as
+    /// its upperdir/workdir.
+    Overlay(PathBuf, content_hash::EphemeralLock),
+}
+
 fn main() {
     tracing_subscriber::fmt()
         .with_writer(std::io::stderr)
@@ -51,76 +134,225 @@ fn main() {
     if let Err(e) = match args {
         Subcommand::Main(args) => do_main(args),
         Subcommand::Pid1(args) => pid1::handler(args),
+        Subcommand::Gc(args) => content_hash::gc(&args.dir),
+        Subcommand::CheckpointExport(args) => {
+            checkpoint::write_stream(&args.image_dir, std::io::stdout().lock())
+        }
+        Subcommand::CheckpointImport(args) => {
+            checkpoint::read_stream(&args.image_dir, std::io::stdin().lock())
+        }
     } {
         let e = format!("{e:#?}");
         eprintln!("{e}");
-        std::process::exit(1);
+        std::process::exit(ISOLATION_ERROR_EXIT_CODE);
     }
 }
 
 fn do_main(args: Main) -> Result<()> {
-    // Unshare into new pid namespace first, then the rest of the isolation is
-    // performed by the first forked process (pid 1) in that namespace
-    unshare(CloneFlags::CLONE_NEWPID).context("while unsharing into new pid namespace")?;
+    // Share a global concurrency budget with however many other isolated
+    // invocations a top-level build spawned in parallel, rather than
+    // assuming this pid1 (and everything running inside it) has the whole
+    // machine to itself. Held for the rest of this function, including
+    // while pid1 runs.
+    let jobserver = jobserver::Jobserver::from_env().context("while setting up jobserver client")?;
+    let _job_token = jobserver
+        .acquire()
+        .context("while acquiring a jobserver slot")?;
 
     let mut ctx = args.isolation.into_inner();
-    let mut snapshot_dir: Option<PathBuf> = None;
 
-    // If Btrfs ephemeral mode is requested, create a writable snapshot of the
-    // layer before spawning pid1. The snapshot becomes the new layer with no
-    // overlayfs needed (ephemeral is set to None).
-    if ctx.ephemeral == Some(Ephemeral::Btrfs) {
+    // Unshare into new namespaces first, then the rest of the isolation is
+    // performed by the first forked process (pid 1) in that namespace. A
+    // user namespace is opt-in (`ctx.user_ns`): when requested, bundle in
+    // CLONE_NEWUSER (and CLONE_NEWNS, since a mount namespace is owned by
+    // the user namespace it's created in) so that pid1 and everything it
+    // spawns run as a mapped identity rather than the caller's real uid/gid
+    // against host-owned files.
+    let mut unshare_flags = CloneFlags::CLONE_NEWPID;
+    if ctx.user_ns {
+        unshare_flags |= CloneFlags::CLONE_NEWUSER | CloneFlags::CLONE_NEWNS;
+    }
+    // An unset or all-zero `time_offset` leaves behavior unchanged:
+    // CLONE_NEWTIME is only requested when a real offset was asked for, so
+    // existing callers never pay for (or notice) a time namespace they
+    // didn't ask for.
+    let time_offset = ctx
+        .time_offset
+        .as_ref()
+        .filter(|t| t.monotonic != (0, 0) || t.boottime != (0, 0));
+    if time_offset.is_some() {
+        unshare_flags |= CloneFlags::CLONE_NEWTIME;
+    }
+    unshare(unshare_flags).context("while unsharing into new namespaces")?;
+
+    if ctx.user_ns {
+        isolation::write_id_maps(&ctx.uid_map, &ctx.gid_map)
+            .context("while writing uid/gid mappings for the new user namespace")?;
+    }
+
+    if let Some(time_offset) = time_offset {
+        isolation::write_timens_offsets(time_offset.monotonic, time_offset.boottime)
+            .context("while writing timens_offsets for the new time namespace")?;
+    }
+
+    let mut ephemeral_cleanup: Option<EphemeralCleanup> = None;
+    // Set only for `Ephemeral::Checkpoint`: where to dump to (or restore
+    // from) a CRIU checkpoint of the pid1 process tree.
+    let mut checkpoint_image_dir: Option<PathBuf> = None;
+
+    // If ephemeral mode is requested, give pid1 a writable copy of the layer
+    // to run against (ephemeral is set to None once that's done). `Auto`
+    // statfs's the layer to pick a strategy: a writable btrfs snapshot is
+    // zero-copy and preferred where available, otherwise fall back to an
+    // overlayfs mount (lowerdir = the real layer, upperdir/workdir fresh).
+    // NFS supports neither reliably, so it's refused outright rather than
+    // left to fail deep inside a btrfs ioctl or an overlay mount error.
+    // `Checkpoint` always uses a btrfs snapshot too (to leave a zero-copy
+    // writable layer behind across a pause), and additionally records
+    // where its CRIU images live.
+    if let Some(mode) = ctx.ephemeral {
         let layer_path = ctx
             .layer
             .canonicalize()
             .with_context(|| format!("while canonicalizing layer path {}", ctx.layer.display()))?;
+
+        let use_btrfs = match &mode {
+            Ephemeral::Btrfs | Ephemeral::Checkpoint { .. } => true,
+            Ephemeral::Auto => match ephemeral::probe(&layer_path)? {
+                ephemeral::Backing::Btrfs => true,
+                ephemeral::Backing::Nfs => bail!(
+                    "ephemeral mode was auto-detected, but {} is on NFS, where neither \
+                     btrfs snapshots nor overlayfs upperdirs work reliably",
+                    layer_path.display()
+                ),
+                ephemeral::Backing::Other => false,
+            },
+        };
+        if let Ephemeral::Checkpoint { image_dir } = mode {
+            checkpoint_image_dir = Some(image_dir);
+        }
+
         let layer_parent = layer_path
             .parent()
             .context("cannot use / as ephemeral source")?;
         let layer_name = layer_path.file_name().context("layer has no file name")?;
-        let snap_name = format!(
-            ".{}.ephemeral.{}",
-            layer_name.to_string_lossy(),
-            std::process::id()
-        );
-        let snap_path = layer_parent.join(&snap_name);
-
-        let subvol = antlir2_btrfs::Subvolume::open(&layer_path).with_context(|| {
-            format!(
-                "while opening layer as btrfs subvolume: {}",
-                layer_path.display()
-            )
-        })?;
-        subvol
-            .snapshot(&snap_path, antlir2_btrfs::SnapshotFlags::empty())
-            .with_context(|| format!("while creating btrfs snapshot at {}", snap_path.display()))?;
-
-        snapshot_dir = Some(snap_path.clone());
-        ctx.layer = Cow::Owned(snap_path);
+
+        // Content-addressed instead of pid-keyed: the same layer/mounts/env
+        // always hash to the same name, so a build killed mid-run leaves
+        // behind something `gc` can recognize on its own, rather than a
+        // name tied to a pid that's long gone by the time anyone looks.
+        let hash = content_hash::ephemeral_hash(&ctx, &layer_path)
+            .context("while hashing isolation context for ephemeral naming")?;
+        let ephemeral_name = format!(".{}.ephemeral.{}", layer_name.to_string_lossy(), hash);
+        let lock_path = layer_parent.join(format!("{ephemeral_name}.lock"));
+        // Blocks until any other invocation with this exact content hash
+        // has released the name, so two concurrent identical invocations
+        // serialize around creating/tearing down the same snapshot/overlay
+        // instead of racing each other.
+        let lock = content_hash::EphemeralLock::acquire(lock_path)
+            .context("while acquiring ephemeral snapshot lock")?;
+
+        if use_btrfs {
+            let snap_path = layer_parent.join(&ephemeral_name);
+
+            if !snap_path.exists() {
+                let subvol = antlir2_btrfs::Subvolume::open(&layer_path).with_context(|| {
+                    format!(
+                        "while opening layer as btrfs subvolume: {}",
+                        layer_path.display()
+                    )
+                })?;
+                subvol
+                    .snapshot(&snap_path, antlir2_btrfs::SnapshotFlags::empty())
+                    .with_context(|| {
+                        format!("while creating btrfs snapshot at {}", snap_path.display())
+                    })?;
+            }
+
+            ctx.layer = Cow::Owned(snap_path.clone());
+            ephemeral_cleanup = Some(EphemeralCleanup::Btrfs(snap_path, lock));
+        } else {
+            let tmp = layer_parent.join(&ephemeral_name);
+            let upper = tmp.join("upper");
+            let work = tmp.join("work");
+            let merged = tmp.join("merged");
+            if !merged.exists() {
+                for dir in [&upper, &work, &merged] {
+                    std::fs::create_dir_all(dir)
+                        .with_context(|| format!("while creating {}", dir.display()))?;
+                }
+                ephemeral::mount_overlay(&layer_path, &upper, &work, &merged).with_context(
+                    || format!("while mounting overlayfs at {}", merged.display()),
+                )?;
+            }
+
+            ctx.layer = Cow::Owned(merged);
+            ephemeral_cleanup = Some(EphemeralCleanup::Overlay(tmp, lock));
+        }
         ctx.ephemeral = None;
     }
 
-    let mut pid1 = Command::new(std::env::current_exe().context("while getting current exe")?);
-    pid1.arg("pid1")
-        .arg(serde_json::to_string(&ctx).context("while serializing isolation info")?);
-    if ctx.invocation_type.booted() {
-        pid1.arg("--exec-init");
-    }
-    if let Some(ref snap) = snapshot_dir {
-        pid1.arg("--snapshot-dir").arg(snap);
-    }
-    pid1.arg(args.program).arg("--").args(args.program_args);
-    let mut pid1 = pid1.spawn().context("while spawning pid1")?;
-    let status = pid1.wait().context("while waiting for pid1")?;
-
-    // Fallback cleanup: if pid1 failed to delete the snapshot (e.g. EBUSY or
-    // EPERM), try to remove it here.
-    if let Some(snap) = &snapshot_dir {
-        if snap.exists() {
-            // Try btrfs delete first, fall back to a recursive delete
+    // A restorable checkpoint replaces the usual pid1 spawn entirely: CRIU
+    // recreates the dumped process tree (including its namespaces and open
+    // file descriptors) directly from the images, so there's no `program`
+    // left to exec fresh, and no pid1 invocation to hand anything off to.
+    let mut pid1 = match &checkpoint_image_dir {
+        Some(image_dir) if checkpoint::is_restorable(image_dir) => {
+            info!("restoring checkpoint from {}", image_dir.display());
+            checkpoint::restore(image_dir).context("while restoring checkpointed process tree")?
+        }
+        _ => {
+            let mut cmd =
+                Command::new(std::env::current_exe().context("while getting current exe")?);
+            cmd.arg("pid1")
+                .arg(serde_json::to_string(&ctx).context("while serializing isolation info")?);
+            if ctx.invocation_type.booted() {
+                cmd.arg("--exec-init");
+            }
+            if let Some(EphemeralCleanup::Btrfs(snap, _)) = &ephemeral_cleanup {
+                cmd.arg("--snapshot-dir").arg(snap);
+            }
+            cmd.arg(args.program).arg("--").args(args.program_args);
+            cmd.spawn().context("while spawning pid1")?
+        }
+    };
+
+    let status = if let Some(image_dir) = &checkpoint_image_dir {
+        // SAFETY: `request_checkpoint` only stores to an `AtomicBool`, which
+        // is async-signal-safe.
+        unsafe { signal(Signal::SIGUSR1, SigHandler::Handler(request_checkpoint)) }
+            .context("while installing SIGUSR1 handler for checkpoint requests")?;
+        loop {
+            if CHECKPOINT_REQUESTED.swap(false, Ordering::SeqCst) {
+                checkpoint::checkpoint(Pid::from_raw(pid1.id() as i32), image_dir)
+                    .context("while checkpointing pid1's process tree")?;
+                info!("checkpoint saved to {}", image_dir.display());
+                // The ephemeral snapshot is left in place (not torn down
+                // below) so a later restore has a writable layer to resume
+                // into; the lock is released for free when this process
+                // exits and its fd is closed.
+                std::mem::forget(ephemeral_cleanup);
+                std::process::exit(CHECKPOINTED_EXIT_CODE);
+            }
+            match pid1.try_wait().context("while polling pid1")? {
+                Some(status) => break status,
+                None => std::thread::sleep(Duration::from_millis(200)),
+            }
+        }
+    } else {
+        pid1.wait().context("while waiting for pid1")?
+    };
+
+    // Fallback cleanup: if pid1 failed to tear the ephemeral copy down (e.g.
+    // EBUSY or EPERM), try to remove it here.
+    match &ephemeral_cleanup {
+        Some(EphemeralCleanup::Btrfs(snap, _)) if snap.exists() => {
+            // delete_recursive handles any nested subvolumes the snapshot
+            // picked up (e.g. if the layer itself had child subvolumes);
+            // recursive-rm is only the last resort if even that fails.
             match antlir2_btrfs::Subvolume::open(snap) {
                 Ok(subvol) => {
-                    if let Err((_, e)) = subvol.delete() {
+                    if let Err((_, e)) = subvol.delete_recursive() {
                         warn!(
                             "btrfs snapshot delete failed: {e}, falling back to a recursive removal"
                         );
@@ -141,13 +373,53 @@ fn do_main(args: Main) -> Result<()> {
                 }
             }
         }
+        Some(EphemeralCleanup::Overlay(tmp, _)) => {
+            let merged = tmp.join("merged");
+            if let Err(e) = umount2(&merged, MntFlags::MNT_DETACH) {
+                warn!("failed to unmount overlay at {}: {e}", merged.display());
+            }
+            if let Err(e) = std::fs::remove_dir_all(tmp) {
+                warn!(
+                    "failed to remove overlay tmpdir {}: {e}, leaving it in place",
+                    tmp.display()
+                );
+            }
+        }
+        _ => {}
     }
+    // Release the ephemeral lock (removing its lock file) now: the failure
+    // paths below end the process without running destructors.
+    drop(ephemeral_cleanup);
 
     if status.success() {
         Ok(())
     } else if let Some(code) = status.code() {
         std::process::exit(code);
+    } else if let Some(sig) = status.signal() {
+        // Re-raise the same signal on ourselves instead of collapsing it
+        // into a synthetic 128+N exit code, so a caller inspecting our own
+        // exit status (e.g. via `ExitStatusExt::signal`) sees exactly what
+        // killed pid1 -- including whether it core dumped -- not just "it
+        // failed somehow".
+        if status.core_dumped() {
+            warn!("pid1 was killed by signal {sig} (core dumped)");
+        } else {
+            warn!("pid1 was killed by signal {sig}");
+        }
+        let sig = Signal::try_from(sig).with_context(|| format!("unknown signal number {sig}"))?;
+        // Restore the default disposition first: SIGUSR1 is caught above to
+        // request a checkpoint, and raising a caught/ignored signal on
+        // ourselves would silently do nothing instead of terminating us the
+        // same way it terminated pid1.
+        // SIGKILL's disposition cannot be changed (it is always fatal), so it
+        // is raised as-is.
+        if sig != Signal::SIGKILL {
+            unsafe { signal(sig, SigHandler::SigDfl) }
+                .with_context(|| format!("while resetting disposition of {sig}"))?;
+        }
+        kill(getpid(), sig).with_context(|| format!("while re-raising {sig} on ourselves"))?;
+        unreachable!("raising {sig} on ourselves with its default disposition should be fatal");
     } else {
-        Err(anyhow!("pid1 failed: {status}"))
+        Err(anyhow!("pid1 exited with unknown status: {status}"))
     }
 }
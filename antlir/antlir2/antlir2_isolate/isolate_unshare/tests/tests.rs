@@ -7,12 +7,14 @@
 
 #![feature(io_error_more)]
 
+use std::os::unix::process::ExitStatusExt;
 use std::path::Path;
 use std::thread;
 use std::time::Duration;
 
 use antlir2_isolate::Ephemeral;
 use antlir2_isolate::IsolationContext;
+use antlir2_isolate::TimeOffset;
 use antlir2_isolate::unshare;
 use nix::mount::MsFlags;
 use nix::mount::mount;
@@ -188,6 +190,122 @@ fn loopback_interface() {
     std::net::TcpListener::bind("[::1]:0").expect("failed to bind to socket");
 }
 
+/// First field of `/proc/uptime` (seconds since boot, per `CLOCK_BOOTTIME`)
+/// observed inside an isolation built from `isol`.
+fn observed_uptime_secs(isol: IsolationContext<'_>) -> f64 {
+    let out = unshare(isol)
+        .expect("failed to prepare unshare")
+        .command("cat")
+        .expect("failed to create command")
+        .arg("/proc/uptime")
+        .output()
+        .expect("failed to run command");
+    assert_cmd_success(&out);
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    stdout
+        .split_whitespace()
+        .next()
+        .expect("/proc/uptime had no fields")
+        .parse()
+        .expect("first field of /proc/uptime was not a float")
+}
+
+/// `time_offset` pins `CLOCK_BOOTTIME` to a fixed offset, observable via
+/// `/proc/uptime`.
+#[test]
+fn time_offset_pins_boottime() {
+    let isol = IsolationContext::builder(Path::new("/isolated"))
+        .ephemeral(false)
+        .working_directory(Path::new("/"))
+        .time_offset(TimeOffset {
+            monotonic: (0, 0),
+            boottime: (100_000, 0),
+        })
+        .build();
+    let host_uptime: f64 = std::fs::read_to_string("/proc/uptime")
+        .expect("failed to read host /proc/uptime")
+        .split_whitespace()
+        .next()
+        .expect("/proc/uptime had no fields")
+        .parse()
+        .expect("first field of /proc/uptime was not a float");
+    let uptime = observed_uptime_secs(isol);
+    let offset = uptime - host_uptime;
+    assert!(
+        (100_000.0..100_010.0).contains(&offset),
+        "expected /proc/uptime to read ~100000s ahead of the host's with the requested offset, got {uptime} (host: {host_uptime})"
+    );
+}
+
+/// Pinning the offset doesn't freeze the clock -- it should still advance at
+/// the normal rate afterwards.
+#[test]
+fn time_offset_clock_still_advances() {
+    let isol = IsolationContext::builder(Path::new("/isolated"))
+        .ephemeral(false)
+        .working_directory(Path::new("/"))
+        .time_offset(TimeOffset {
+            monotonic: (0, 0),
+            boottime: (100_000, 0),
+        })
+        .build();
+    let out = unshare(isol)
+        .expect("failed to prepare unshare")
+        .command("bash")
+        .expect("failed to create command")
+        .arg("-c")
+        .arg("cat /proc/uptime && sleep 2 && cat /proc/uptime")
+        .output()
+        .expect("failed to run command");
+    assert_cmd_success(&out);
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    let mut readings = stdout.lines().map(|line| {
+        line.split_whitespace()
+            .next()
+            .expect("/proc/uptime had no fields")
+            .parse::<f64>()
+            .expect("first field of /proc/uptime was not a float")
+    });
+    let before = readings.next().expect("missing first /proc/uptime reading");
+    let after = readings.next().expect("missing second /proc/uptime reading");
+    let elapsed = after - before;
+    assert!(
+        (1.5..3.0).contains(&elapsed),
+        "expected ~2s to have elapsed between readings, got {elapsed}s (before={before}, after={after})"
+    );
+}
+
+/// With an all-zero `time_offset`, no time namespace should be created at
+/// all, so the isolated process shares the host's time namespace.
+#[test]
+fn zero_time_offset_leaves_time_namespace_unchanged() {
+    let host_ns = std::fs::read_link("/proc/self/ns/time").expect("failed to read host time ns");
+
+    let isol = IsolationContext::builder(Path::new("/isolated"))
+        .ephemeral(false)
+        .working_directory(Path::new("/"))
+        .time_offset(TimeOffset {
+            monotonic: (0, 0),
+            boottime: (0, 0),
+        })
+        .build();
+    let out = unshare(isol)
+        .expect("failed to prepare unshare")
+        .command("readlink")
+        .expect("failed to create command")
+        .arg("/proc/self/ns/time")
+        .output()
+        .expect("failed to run command");
+    assert_cmd_success(&out);
+    let isolated_ns = String::from_utf8_lossy(&out.stdout).trim().to_string();
+
+    assert_eq!(
+        host_ns.to_string_lossy(),
+        isolated_ns,
+        "only a zero time_offset was requested, so no new time namespace should have been created"
+    );
+}
+
 /// Find any ephemeral snapshots for the given layer path, returning their names.
 fn find_ephemeral_snapshots(layer: &Path) -> Vec<String> {
     let layer = layer
@@ -270,6 +388,85 @@ fn btrfs_ephemeral_cleanup_on_failure() {
     );
 }
 
+/// A child that `kill -9`s itself should be reported as killed by `SIGKILL`,
+/// not as some synthetic 128+N exit code.
+#[test]
+fn propagates_sigkill() {
+    let isol = IsolationContext::builder(Path::new("/isolated"))
+        .ephemeral(false)
+        .working_directory(Path::new("/"))
+        .build();
+    let out = unshare(isol)
+        .expect("failed to prepare unshare")
+        .command("bash")
+        .expect("failed to create command")
+        .arg("-c")
+        .arg("kill -KILL $$")
+        .output()
+        .expect("failed to run command");
+    assert_cmd_fail(&out);
+    assert_eq!(out.status.code(), None, "a signal death has no exit code");
+    assert_eq!(
+        out.status.signal(),
+        Some(nix::sys::signal::Signal::SIGKILL as i32)
+    );
+}
+
+/// A child that raises `SIGSEGV` should be reported as killed by `SIGSEGV`,
+/// with the exact signal (and whether it core dumped) preserved rather than
+/// collapsed into a generic failure.
+#[test]
+fn propagates_sigsegv() {
+    let isol = IsolationContext::builder(Path::new("/isolated"))
+        .ephemeral(false)
+        .working_directory(Path::new("/"))
+        .build();
+    let out = unshare(isol)
+        .expect("failed to prepare unshare")
+        .command("bash")
+        .expect("failed to create command")
+        .arg("-c")
+        .arg("kill -SEGV $$")
+        .output()
+        .expect("failed to run command");
+    assert_cmd_fail(&out);
+    assert_eq!(out.status.code(), None, "a signal death has no exit code");
+    assert_eq!(
+        out.status.signal(),
+        Some(nix::sys::signal::Signal::SIGSEGV as i32)
+    );
+}
+
+/// Ephemeral btrfs cleanup must still run when the inner process is killed
+/// by a signal, exactly as it does when the inner process exits normally
+/// (see `btrfs_ephemeral_cleanup_on_failure`).
+#[test]
+fn btrfs_ephemeral_cleanup_on_signal_death() {
+    let layer = Path::new("/nested/dir/for/symlink/isolated_symlink");
+    let isol = IsolationContext::builder(layer)
+        .ephemeral(Ephemeral::Btrfs)
+        .working_directory(Path::new("/"))
+        .build();
+    let out = unshare(isol)
+        .expect("failed to prepare unshare")
+        .command("bash")
+        .expect("failed to create command")
+        .arg("-c")
+        .arg("touch /ephemeral_test_file && kill -KILL $$")
+        .output()
+        .expect("failed to run command");
+    assert_cmd_fail(&out);
+    assert_eq!(
+        out.status.signal(),
+        Some(nix::sys::signal::Signal::SIGKILL as i32)
+    );
+    assert_no_ephemeral_snapshots(layer);
+    assert!(
+        !layer.join("ephemeral_test_file").exists(),
+        "write inside ephemeral container should not persist to original layer"
+    );
+}
+
 /// Verify that the ephemeral subvolume is actually visible while a long-running
 /// command is executing. This gives us confidence that assert_no_ephemeral_snapshots
 /// is looking at the right path and that cleanup assertions are meaningful.
@@ -8,12 +8,42 @@
 use std::path::Path;
 use std::path::PathBuf;
 
+use sha2::Digest;
+use sha2::Sha256;
+
 #[derive(Debug, thiserror::Error)]
 pub enum PathError {
     #[error("Provided path {0:?} doesn't exist")]
     NotFound(PathBuf),
     #[error("Failed to create requested path {0:?}: {1:?}")]
     FailedToMkdir(PathBuf, std::io::Error),
+    #[error("Failed to read {0:?} to verify its contents: {1:?}")]
+    ReadFailed(PathBuf, std::io::Error),
+    #[error("{path:?} did not match its expected digest: expected {expected}, got {actual}")]
+    DigestMismatch {
+        path: PathBuf,
+        expected: String,
+        actual: String,
+    },
+}
+
+/// Content-hash algorithms that a path's contents can be checked against,
+/// named after rustc's own `SourceFileHashAlgorithm`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SourceFileHashAlgorithm {
+    Sha256,
+}
+
+impl SourceFileHashAlgorithm {
+    fn digest(self, contents: &[u8]) -> String {
+        match self {
+            Self::Sha256 => {
+                let mut hasher = Sha256::new();
+                hasher.update(contents);
+                format!("{:x}", hasher.finalize())
+            }
+        }
+    }
 }
 
 /// This is a path that is guaranteed to exist. We can know this either:
@@ -49,6 +79,31 @@ impl VerifiedPath {
         }
     }
 
+    /// Verify that `path`'s contents hash to `expected` under `algorithm`,
+    /// returning a [`VerifiedPath`] if they match. Lets image features assert
+    /// that pre-seeded files (e.g. repo GPG keys under `--dnf-repos`) are
+    /// exactly the bytes they were built against.
+    pub fn new_verified(
+        path: PathBuf,
+        algorithm: SourceFileHashAlgorithm,
+        expected: impl Into<String>,
+    ) -> Result<Self, PathError> {
+        let verified = Self::new_checked(path)?;
+        let contents = std::fs::read(verified.path())
+            .map_err(|e| PathError::ReadFailed(verified.path().to_path_buf(), e))?;
+        let expected = expected.into();
+        let actual = algorithm.digest(&contents);
+        if actual == expected {
+            Ok(verified)
+        } else {
+            Err(PathError::DigestMismatch {
+                path: verified.0,
+                expected,
+                actual,
+            })
+        }
+    }
+
     pub fn path(&self) -> &Path {
         &self.0
     }
@@ -66,7 +121,7 @@ pub trait AntlirPaths {}
 
 #[macro_export]
 macro_rules! generate_paths {
-    ($name:ident { $($path_name:ident ($path_type:ty, $path:tt)),* $(,)* }) => {
+    ($name:ident { $($path_name:ident ($path_type:ty, $path:tt $(, verify($algo:expr, $digest:expr))?)),* $(,)* }) => {
         pub struct $name {
             base: $crate::antlir_image::path::VerifiedPath,
         }
@@ -80,13 +135,32 @@ macro_rules! generate_paths {
             }
 
             $(
-                #[allow(dead_code)]
-                pub fn $path_name(&self) -> $path_type {
-                    <$path_type>::new_unchecked(
-                        self.base.path().join($path)
-                    )
-                }
+                $crate::generate_paths!(@method $path_name, $path_type, $path $(, verify($algo, $digest))?);
             )*
         }
-    }
+    };
+
+    // A plain path entry with no expected digest: infallible, as before.
+    (@method $path_name:ident, $path_type:ty, $path:tt) => {
+        #[allow(dead_code)]
+        pub fn $path_name(&self) -> $path_type {
+            <$path_type>::new_unchecked(
+                self.base.path().join($path)
+            )
+        }
+    };
+
+    // A path entry that also asserts its contents hash to an expected
+    // digest, e.g. for pre-seeded files like repo GPG keys.
+    (@method $path_name:ident, $path_type:ty, $path:tt, verify($algo:expr, $digest:expr)) => {
+        #[allow(dead_code)]
+        pub fn $path_name(&self) -> Result<$path_type, $crate::antlir_image::path::PathError> {
+            let verified = $crate::antlir_image::path::VerifiedPath::new_verified(
+                self.base.path().join($path),
+                $algo,
+                $digest,
+            )?;
+            Ok(<$path_type>::new_unchecked(verified.path().to_path_buf()))
+        }
+    };
 }
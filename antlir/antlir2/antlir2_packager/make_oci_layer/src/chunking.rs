@@ -0,0 +1,172 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Content-defined chunking of the layer tar byte stream, so that between a
+//! `--parent` and `--child` only the chunks that actually changed need to be
+//! stored or shipped, rather than re-emitting (or re-uploading) the whole
+//! tar. This mirrors the dynamic chunk-index design used by pxar-style
+//! backup formats: chunk boundaries are picked with a buzhash rolling hash
+//! over the trailing [WINDOW_SIZE] bytes rather than fixed-size blocks, so a
+//! single byte changed near the start of a large file only perturbs the
+//! chunks around the edit instead of reshuffling every chunk after it.
+//!
+//! A child layer that shares unchanged regions of the stream with its
+//! parent ends up emitting the same chunk digests, so as long as both are
+//! chunked against the same `--chunk-store`, the child's chunks that
+//! already exist in the store (written while packaging the parent) are
+//! skipped rather than rewritten.
+
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use anyhow::Result;
+use sha2::Digest;
+use sha2::Sha256;
+
+/// Target average chunk size of 2^21 bytes (~2 MiB): a boundary is declared
+/// whenever the rolling hash's low [TARGET_CHUNK_BITS] bits are all set,
+/// which happens with probability `1 / 2^TARGET_CHUNK_BITS`.
+const TARGET_CHUNK_BITS: u32 = 21;
+const BOUNDARY_MASK: u64 = (1 << TARGET_CHUNK_BITS) - 1;
+/// Hard clamps so that pathological input (eg all-zero regions, which defeat
+/// the rolling hash) can't produce degenerate chunk sizes.
+const MIN_CHUNK_SIZE: usize = 512 * 1024;
+const MAX_CHUNK_SIZE: usize = 8 * 1024 * 1024;
+const WINDOW_SIZE: usize = 64;
+
+/// A buzhash rolling hash over the trailing [WINDOW_SIZE] bytes of the
+/// stream, used to pick content-defined chunk boundaries in constant time
+/// per byte (no need to rehash the whole window on every step).
+struct RollingHash {
+    table: [u64; 256],
+    window: [u8; WINDOW_SIZE],
+    /// Index in `window` that the *next* incoming byte will occupy (and
+    /// whose current occupant is the byte falling out of the window).
+    next: usize,
+    hash: u64,
+}
+
+impl RollingHash {
+    fn new() -> Self {
+        // The table just needs to be a fixed, well-distributed mapping from
+        // byte value to a 64-bit constant; it isn't a cryptographic
+        // primitive, so a small deterministic PRNG seeding it is enough to
+        // avoid pathological boundary clustering on structured input.
+        let mut table = [0u64; 256];
+        let mut seed: u64 = 0x9E3779B97F4A7C15;
+        for (i, slot) in table.iter_mut().enumerate() {
+            seed ^= seed << 13;
+            seed ^= seed >> 7;
+            seed ^= seed << 17;
+            *slot = seed.wrapping_add(i as u64);
+        }
+        Self {
+            table,
+            window: [0u8; WINDOW_SIZE],
+            next: 0,
+            hash: 0,
+        }
+    }
+
+    /// Roll `byte` into the window, returning the updated hash.
+    fn roll(&mut self, byte: u8) -> u64 {
+        let outgoing = self.window[self.next];
+        self.window[self.next] = byte;
+        self.next = (self.next + 1) % WINDOW_SIZE;
+        self.hash = self.hash.rotate_left(1)
+            ^ self.table[outgoing as usize].rotate_left(WINDOW_SIZE as u32)
+            ^ self.table[byte as usize];
+        self.hash
+    }
+}
+
+/// Wraps an inner [Write] so that every byte written through it is also
+/// accumulated into content-defined chunks, each hashed and stored (if not
+/// already present) under `chunk_store`, with an ordered `(end_offset,
+/// digest)` index written to `chunk_index`.
+pub struct ChunkingWriter<W> {
+    inner: W,
+    store_dir: PathBuf,
+    index: File,
+    rolling: RollingHash,
+    current_chunk: Vec<u8>,
+    offset: u64,
+}
+
+impl<W: Write> ChunkingWriter<W> {
+    pub fn new(inner: W, store_dir: &Path, index_path: &Path) -> Result<Self> {
+        std::fs::create_dir_all(store_dir)
+            .with_context(|| format!("while creating chunk store {}", store_dir.display()))?;
+        let index = File::create(index_path)
+            .with_context(|| format!("while creating chunk index {}", index_path.display()))?;
+        Ok(Self {
+            inner,
+            store_dir: store_dir.to_owned(),
+            index,
+            rolling: RollingHash::new(),
+            current_chunk: Vec::with_capacity(MIN_CHUNK_SIZE),
+            offset: 0,
+        })
+    }
+
+    /// Hash `self.current_chunk`, write it to the chunk store if this is the
+    /// first time this digest has been seen, and append its `(end_offset,
+    /// digest)` pair to the index. Called whenever a boundary is found, and
+    /// once more at the end for whatever's left in the final partial chunk.
+    fn flush_chunk(&mut self) -> Result<()> {
+        if self.current_chunk.is_empty() {
+            return Ok(());
+        }
+        let digest = format!("{:x}", Sha256::digest(&self.current_chunk));
+        let chunk_path = self.store_dir.join(&digest);
+        if !chunk_path.exists() {
+            std::fs::write(&chunk_path, &self.current_chunk)
+                .with_context(|| format!("while writing chunk {}", chunk_path.display()))?;
+        }
+        writeln!(self.index, "{} {digest}", self.offset)
+            .context("while appending to chunk index")?;
+        self.current_chunk.clear();
+        Ok(())
+    }
+
+    /// Flush whatever's left in the final partial chunk and finish the
+    /// index. Must be called once after all writes are done (eg by taking
+    /// the writer back out of a [tar::Builder] via `into_inner`); dropping
+    /// this without calling `finish` silently loses the trailing chunk.
+    pub fn finish(mut self) -> Result<W> {
+        self.flush_chunk()?;
+        self.index.flush().context("while flushing chunk index")?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for ChunkingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write_all(buf)?;
+        for &byte in buf {
+            self.current_chunk.push(byte);
+            self.offset += 1;
+            let hash = self.rolling.roll(byte);
+            let len = self.current_chunk.len();
+            let at_boundary = (len >= MIN_CHUNK_SIZE && hash & BOUNDARY_MASK == BOUNDARY_MASK)
+                || len >= MAX_CHUNK_SIZE;
+            if at_boundary {
+                self.flush_chunk()
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -7,12 +7,16 @@
 
 use std::collections::HashMap;
 use std::collections::HashSet;
+use std::collections::VecDeque;
 use std::ffi::OsString;
 use std::fs::File;
 use std::io::BufWriter;
 use std::io::Seek;
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
 use std::path::PathBuf;
+use std::sync::mpsc;
 
 use antlir2_change_stream::Iter;
 use antlir2_change_stream::Operation;
@@ -21,6 +25,8 @@ use anyhow::Result;
 use anyhow::bail;
 use anyhow::ensure;
 use clap::Parser;
+use clap::ValueEnum;
+use flate2::write::GzEncoder;
 use nix::sys::stat::SFlag;
 use nix::sys::stat::major;
 use nix::sys::stat::minor;
@@ -28,6 +34,16 @@ use tar::Builder;
 use tar::EntryType;
 use tar::Header;
 
+mod chunking;
+mod prefetch;
+mod sparse;
+mod xattr;
+
+use chunking::ChunkingWriter;
+use prefetch::ChildResolution;
+use prefetch::PrefetchPool;
+use sparse::SparseReader;
+
 /// Fixed mtime for reproducible tar archives.
 /// Timestamps make things non-deterministic even if everything else is 100% equal.
 /// To get around this (and to preempt any bugs from tools that don't tolerate
@@ -44,6 +60,129 @@ struct Args {
     out: PathBuf,
     #[clap(long)]
     rootless: bool,
+    #[clap(long, requires = "chunk_index")]
+    /// Directory to write content-addressed, deduplicated chunks of the
+    /// emitted tar stream into, for incremental transfer between layers.
+    /// Must be passed together with --chunk-index.
+    chunk_store: Option<PathBuf>,
+    #[clap(long, requires = "chunk_store")]
+    /// Path to write the ordered `(end_offset, digest)` index of chunks
+    /// produced for this layer. Must be passed together with --chunk-store.
+    chunk_index: Option<PathBuf>,
+    #[clap(long, value_enum, default_value_t = Compression::None)]
+    /// Compress the emitted tar, producing an
+    /// `application/vnd.oci.image.layer.v1.tar+{gzip,zstd}` blob directly.
+    compression: Compression,
+    #[clap(long)]
+    /// Compression level for --compression gzip/zstd. Defaults to each
+    /// encoder's own default level if unset. Ignored for --compression none.
+    compression_level: Option<i32>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Compression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl std::fmt::Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::None => "none",
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+        })
+    }
+}
+
+/// The compressing (or not) layer directly underneath the tar stream. Kept
+/// reproducible on top of [FIXED_MTIME]: `GzEncoder` embeds no mtime/OS/name
+/// in its header by default, and the zstd encoder is only ever given a
+/// fixed level, so byte-identical tar input still yields byte-identical
+/// compressed output.
+enum CompressedWriter {
+    None(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+    Zstd(zstd::stream::write::Encoder<'static, BufWriter<File>>),
+}
+
+impl Write for CompressedWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::None(w) => w.write(buf),
+            Self::Gzip(w) => w.write(buf),
+            Self::Zstd(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::None(w) => w.flush(),
+            Self::Gzip(w) => w.flush(),
+            Self::Zstd(w) => w.flush(),
+        }
+    }
+}
+
+impl CompressedWriter {
+    fn new(out: BufWriter<File>, compression: Compression, level: Option<i32>) -> Result<Self> {
+        Ok(match compression {
+            Compression::None => Self::None(out),
+            Compression::Gzip => Self::Gzip(GzEncoder::new(
+                out,
+                flate2::Compression::new(level.unwrap_or(6).clamp(0, 9) as u32),
+            )),
+            Compression::Zstd => Self::Zstd(
+                zstd::stream::write::Encoder::new(out, level.unwrap_or(0))
+                    .context("while creating zstd encoder")?,
+            ),
+        })
+    }
+
+    fn finish(self) -> Result<()> {
+        match self {
+            Self::None(mut w) => w.flush().context("while flushing output file"),
+            Self::Gzip(w) => w.finish().map(drop).context("while finishing gzip stream"),
+            Self::Zstd(w) => w.finish().map(drop).context("while finishing zstd stream"),
+        }
+    }
+}
+
+/// The tar stream's underlying sink: either just the (possibly compressed)
+/// output file, or that tee'd through a [ChunkingWriter] when
+/// `--chunk-store` was requested.
+enum OutputWriter {
+    Plain(CompressedWriter),
+    Chunked(ChunkingWriter<CompressedWriter>),
+}
+
+impl Write for OutputWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Plain(w) => w.write(buf),
+            Self::Chunked(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Plain(w) => w.flush(),
+            Self::Chunked(w) => w.flush(),
+        }
+    }
+}
+
+impl OutputWriter {
+    /// Flush the trailing partial chunk (if this is [Self::Chunked]) and
+    /// finish the underlying compressor (if any), now that every byte of
+    /// the tar stream has been written through it.
+    fn finish(self) -> Result<()> {
+        match self {
+            Self::Plain(w) => w.finish(),
+            Self::Chunked(w) => w.finish()?.finish(),
+        }
+    }
 }
 
 struct Entry {
@@ -70,6 +209,225 @@ enum Contents {
     File(File),
 }
 
+/// What actually ends up in the tar for a given entry (including, for
+/// `Contents::Unset`, metadata read from the child layer) so that any long
+/// path/linkname PAX records can be attached before
+/// [Builder::append_pax_extensions] is called.
+enum Resolved {
+    Link(PathBuf),
+    File(File),
+    Dir,
+}
+
+impl Resolved {
+    /// A [ChildResolution] that came back from the prefetch pool still needs
+    /// its entry type set, same as the synchronous `Contents::Unset` path
+    /// used to do inline as soon as it learned what the child layer had.
+    fn from_child(header: &mut Header, resolution: ChildResolution) -> Self {
+        match resolution {
+            ChildResolution::File(f) => Self::File(f),
+            ChildResolution::Dir => {
+                header.set_entry_type(EntryType::Directory);
+                Self::Dir
+            }
+            ChildResolution::Link(target) => {
+                header.set_entry_type(EntryType::Symlink);
+                Self::Link(target)
+            }
+        }
+    }
+}
+
+/// An entry whose [Resolved] contents may still be in flight on the
+/// [PrefetchPool], queued up in the order its `Close` was seen so the tar
+/// stream stays deterministic even though the I/O to resolve it can finish
+/// out of order.
+enum PendingContents {
+    Ready(Resolved),
+    FromChild(mpsc::Receiver<std::io::Result<ChildResolution>>),
+}
+
+impl PendingContents {
+    /// Non-blocking: if this is still [Self::FromChild], check whether the
+    /// prefetch has landed yet and, if so, promote it in place. Returns
+    /// whether it is (now) ready to be written.
+    fn poll_ready(&mut self, header: &mut Header, path: &Path) -> Result<bool> {
+        match self {
+            Self::Ready(_) => Ok(true),
+            Self::FromChild(rx) => match rx.try_recv() {
+                Ok(result) => {
+                    let resolution = result.with_context(|| {
+                        format!("while prefetching contents of {}", path.display())
+                    })?;
+                    *self = Self::Ready(Resolved::from_child(header, resolution));
+                    Ok(true)
+                }
+                Err(mpsc::TryRecvError::Empty) => Ok(false),
+                Err(mpsc::TryRecvError::Disconnected) => {
+                    bail!("prefetch worker for {} vanished", path.display())
+                }
+            },
+        }
+    }
+
+    /// Blocking version of [Self::poll_ready]'s promotion, used once the
+    /// change stream is exhausted and every remaining entry must be written
+    /// regardless of whether its prefetch has completed yet.
+    fn into_resolved(self, header: &mut Header, path: &Path) -> Result<Resolved> {
+        match self {
+            Self::Ready(resolved) => Ok(resolved),
+            Self::FromChild(rx) => {
+                let resolution = rx
+                    .recv()
+                    .with_context(|| format!("prefetch worker for {} vanished", path.display()))?
+                    .with_context(|| format!("while prefetching contents of {}", path.display()))?;
+                Ok(Resolved::from_child(header, resolution))
+            }
+        }
+    }
+}
+
+/// A `Close`'d entry waiting in order to be written to the tar, holding
+/// everything [write_entry] needs once its contents are resolved.
+struct PendingEntry {
+    path: PathBuf,
+    header: Header,
+    extensions: Vec<(String, Vec<u8>)>,
+    contents: PendingContents,
+}
+
+/// Block (if necessary) for `entry`'s contents to resolve, then write it to
+/// the tar: attach any long path/linkname PAX records, the PAX extensions
+/// block, and finally the entry itself.
+fn write_entry(builder: &mut Builder<OutputWriter>, entry: PendingEntry) -> Result<()> {
+    let PendingEntry {
+        path,
+        mut header,
+        mut extensions,
+        contents,
+    } = entry;
+    let resolved = contents.into_resolved(&mut header, &path)?;
+
+    let header_path = ensure_name_fits(&mut header, &path, &mut extensions)?;
+    if let Resolved::Link(ref target) = resolved {
+        ensure_link_name_fits(&mut header, target, &mut extensions)?;
+    }
+
+    extensions.sort();
+    builder.append_pax_extensions(extensions.iter().map(|(k, v)| (k.as_str(), v.as_slice())))?;
+
+    match resolved {
+        Resolved::Link(target) => {
+            builder.append_link(&mut header, &header_path, target)?;
+        }
+        Resolved::File(mut f) => {
+            // Stream file contents instead of loading into memory to handle
+            // large files. We manually set entry type to Regular (not Sparse)
+            // to avoid GNU sparse headers (type 'S' = 83) which some container
+            // runtimes (podman/skopeo) cannot handle.
+            // Seek to beginning in case file handle is not at start
+            f.rewind()?;
+            let metadata = f.metadata()?;
+            header.set_size(metadata.len());
+            header.set_entry_type(EntryType::Regular);
+            // walk only the populated regions via SEEK_DATA/SEEK_HOLE so
+            // large hole-punched files (disk images, databases) don't
+            // need their zero regions faulted in and read off disk
+            let mut sparse = SparseReader::new(f)?;
+            builder.append_data(&mut header, &header_path, &mut sparse)?;
+        }
+        Resolved::Dir => {
+            builder.append_data(&mut header, &header_path, std::io::empty())?;
+        }
+    }
+    Ok(())
+}
+
+/// Opportunistically write out however many entries at the front of
+/// `pending` have already finished resolving, without blocking on any that
+/// haven't -- this is what lets the encode loop keep discovering (and
+/// prefetching) later entries while an earlier one is still waiting on its
+/// child-layer open/stat.
+fn drain_ready(
+    builder: &mut Builder<OutputWriter>,
+    pending: &mut VecDeque<PendingEntry>,
+) -> Result<()> {
+    while let Some(front) = pending.front_mut() {
+        if !front.contents.poll_ready(&mut front.header, &front.path)? {
+            break;
+        }
+        let entry = pending.pop_front().expect("front was just confirmed ready");
+        write_entry(builder, entry)?;
+    }
+    Ok(())
+}
+
+/// Write out every remaining pending entry in order, blocking on whichever
+/// prefetches haven't landed yet. Called once the change stream is
+/// exhausted.
+fn drain_all(
+    builder: &mut Builder<OutputWriter>,
+    pending: &mut VecDeque<PendingEntry>,
+) -> Result<()> {
+    while let Some(entry) = pending.pop_front() {
+        write_entry(builder, entry)?;
+    }
+    Ok(())
+}
+
+/// ustar's `name`/`prefix` header fields can only hold a combined 256 bytes
+/// (split across a `/` boundary), which container filesystems routinely
+/// exceed. If `path` doesn't fit, attach a PAX `path` extension record
+/// carrying the real value and return a short placeholder that's
+/// guaranteed to fit in the legacy ustar field instead.
+fn ensure_name_fits(
+    header: &mut Header,
+    path: &Path,
+    extensions: &mut Vec<(String, Vec<u8>)>,
+) -> Result<PathBuf> {
+    match header.set_path(path) {
+        Ok(()) => Ok(path.to_owned()),
+        Err(_) => {
+            extensions.push(("path".to_owned(), path.as_os_str().as_bytes().to_vec()));
+            let placeholder = pax_placeholder(path);
+            header.set_path(&placeholder)?;
+            Ok(placeholder)
+        }
+    }
+}
+
+/// Same as [ensure_name_fits], but for the ustar `linkname` field (capped at
+/// 100 bytes, no prefix split available), pushing a PAX `linkpath` record
+/// instead.
+fn ensure_link_name_fits(
+    header: &mut Header,
+    target: &Path,
+    extensions: &mut Vec<(String, Vec<u8>)>,
+) -> Result<PathBuf> {
+    match header.set_link_name(target) {
+        Ok(()) => Ok(target.to_owned()),
+        Err(_) => {
+            extensions.push(("linkpath".to_owned(), target.as_os_str().as_bytes().to_vec()));
+            let placeholder = pax_placeholder(target);
+            header.set_link_name(&placeholder)?;
+            Ok(placeholder)
+        }
+    }
+}
+
+/// A short, legal ustar name to stand in for a path/linkname that overflowed
+/// the legacy fields. Any PAX-aware reader (which is all of them, by now)
+/// ignores this in favor of the extension record pushed alongside it.
+fn pax_placeholder(path: &Path) -> PathBuf {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::Hash;
+    use std::hash::Hasher;
+
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    PathBuf::from(format!("pax-long-name-{:x}", hasher.finish()))
+}
+
 struct Entries {
     entries: HashMap<PathBuf, Entry>,
     finished_paths: HashSet<PathBuf>,
@@ -118,7 +476,15 @@ fn main() -> Result<()> {
         None => Iter::from_empty(&args.child)?,
     };
 
-    let mut builder = Builder::new(BufWriter::new(File::create(&args.out)?));
+    let out_file = BufWriter::new(File::create(&args.out)?);
+    let compressed = CompressedWriter::new(out_file, args.compression, args.compression_level)?;
+    let writer = match (&args.chunk_store, &args.chunk_index) {
+        (Some(store), Some(index)) => {
+            OutputWriter::Chunked(ChunkingWriter::new(compressed, store, index)?)
+        }
+        _ => OutputWriter::Plain(compressed),
+    };
+    let mut builder = Builder::new(writer);
 
     let mut entries = Entries::new();
     // separately track which paths had times set, so we can see if *only* the
@@ -127,6 +493,13 @@ fn main() -> Result<()> {
     // Track pending whiteout markers - only write them at the end if the file wasn't recreated
     let mut pending_whiteouts: HashSet<PathBuf> = HashSet::new();
 
+    // resolves metadata-only (`Contents::Unset`) entries' child-layer
+    // open/stat in the background, off the critical path of the
+    // single-threaded tar write below
+    let prefetch = PrefetchPool::new(args.child.clone());
+    // `Close`d entries waiting (in order) on their contents to resolve
+    let mut pending: VecDeque<PendingEntry> = VecDeque::new();
+
     for change in stream {
         let change = change?;
         let path = change.path().to_owned();
@@ -221,12 +594,28 @@ fn main() -> Result<()> {
             Operation::SetXattr { name, value } => {
                 // Xattr is being set - remove from pending whiteouts if present
                 pending_whiteouts.remove(&path);
-                let entry = entries.entry(path)?;
+                let name_str = name
+                    .to_str()
+                    .with_context(|| format!("xattr name '{name:?}' is not valid UTF-8"))?;
+                // these carry binary, fixed little-endian
+                // payloads; validate their structure now so a malformed
+                // value fails the build instead of producing a layer that
+                // extracts but misbehaves (denies access, or worse, grants
+                // capabilities the blob didn't actually mean to)
+                match name_str {
+                    "system.posix_acl_access" | "system.posix_acl_default" => {
+                        xattr::validate_posix_acl(&value)
+                            .with_context(|| format!("invalid {name_str} xattr on {path:?}"))?;
+                    }
+                    "security.capability" => {
+                        xattr::validate_capability(&value)
+                            .with_context(|| format!("invalid {name_str} xattr on {path:?}"))?;
+                    }
+                    _ => {}
+                }
                 let mut key = "SCHILY.xattr.".to_owned();
-                key.push_str(
-                    name.to_str()
-                        .with_context(|| format!("xattr name '{name:?}' is not valid UTF-8"))?,
-                );
+                key.push_str(name_str);
+                let entry = entries.entry(path)?;
                 entry.extensions.push((key, value))
             }
             // Removals are represented with special whiteout marker files
@@ -237,7 +626,7 @@ fn main() -> Result<()> {
             }
             Operation::Close => {
                 // we're done with an entry file, it can go into the tar now
-                let mut entry = match entries.remove(path.clone()) {
+                let entry = match entries.remove(path.clone()) {
                     Some(entry) => entry,
                     None => {
                         if had_set_times.contains(&path) {
@@ -258,71 +647,44 @@ fn main() -> Result<()> {
                     continue;
                 }
 
-                // PAX extensions go ahead of the full entry header
-                entry.extensions.sort();
-                builder.append_pax_extensions(
-                    entry
-                        .extensions
-                        .iter()
-                        .map(|(k, v)| (k.as_str(), v.as_slice())),
-                )?;
-                match entry.contents {
-                    Contents::Link(target) => {
-                        builder.append_link(&mut entry.header, path, target)?;
-                    }
-                    Contents::File(mut f) => {
-                        // Stream file contents instead of loading into memory to handle
-                        // large files. We manually set entry type to Regular (not Sparse)
-                        // to avoid GNU sparse headers (type 'S' = 83) which some container
-                        // runtimes (podman/skopeo) cannot handle.
-                        // Use the accumulated entry.header which contains metadata from
-                        // change stream operations (Create, Chmod, Chown, etc.)
-                        // Seek to beginning in case file handle is not at start
-                        f.rewind()?;
-                        let metadata = f.metadata()?;
-                        entry.header.set_size(metadata.len());
-                        entry.header.set_entry_type(EntryType::Regular);
-                        builder.append_data(&mut entry.header, path, &mut f)?;
-                        drop(f);
-                    }
+                // entry.header.set_entry_type for Link was already set by
+                // whichever Operation (HardLink/Symlink) created this; for
+                // Contents::Unset it's set once the prefetch resolves below.
+                let contents = match entry.contents {
+                    Contents::Link(target) => PendingContents::Ready(Resolved::Link(target)),
+                    Contents::File(f) => PendingContents::Ready(Resolved::File(f)),
                     Contents::Unset => {
-                        // Metadata only change, but the OCI spec says that any change
-                        // must send the entire contents, so open it up from the child
-                        // layer.
-                        let meta = std::fs::symlink_metadata(args.child.join(&path))?;
-                        if meta.is_file() {
-                            // Stream file contents instead of loading into memory to handle
-                            // large files. We manually set entry type to Regular (not Sparse)
-                            // to avoid GNU sparse headers (type 'S' = 83) which some container
-                            // runtimes (podman/skopeo) cannot handle.
-                            // Use entry.header which contains metadata from change stream
-                            // operations (Chmod, Chown, etc.) and only set the size.
-                            let mut f = File::open(args.child.join(&path))?;
-                            let f_meta = f.metadata()?;
-                            entry.header.set_size(f_meta.len());
-                            entry.header.set_entry_type(EntryType::Regular);
-                            builder.append_data(&mut entry.header, path, &mut f)?;
-                        } else if meta.is_dir() {
-                            // For metadata-only directory changes, ensure entry type is set
-                            entry.header.set_entry_type(EntryType::Directory);
-                            builder.append_data(&mut entry.header, path, std::io::empty())?;
-                        } else if meta.is_symlink() {
-                            // For metadata-only symlink changes, ensure entry type is set
-                            entry.header.set_entry_type(EntryType::Symlink);
-                            let target = std::fs::read_link(args.child.join(&path))?;
-                            builder.append_link(&mut entry.header, path, target)?;
-                        } else {
-                            bail!(
-                                "not sure what to do with unset contents on filetype {:?}",
-                                meta.file_type(),
-                            );
-                        }
+                        // Metadata only change, but the OCI spec says that any
+                        // change must send the entire contents. Hand the
+                        // open/stat of the child layer's copy off to the
+                        // prefetch pool rather than blocking this thread on
+                        // it, so later stream operations (and other entries'
+                        // prefetches) can keep moving while this one's I/O is
+                        // in flight; the result is picked up in order below.
+                        PendingContents::FromChild(prefetch.submit(path.clone()))
                     }
-                }
+                };
+
+                pending.push_back(PendingEntry {
+                    path,
+                    header: entry.header,
+                    extensions: entry.extensions,
+                    contents,
+                });
+
+                // opportunistically flush whatever's already resolved at the
+                // front of the queue; the tar write stays ordered and
+                // single-threaded, but we don't block here on entries still
+                // waiting on the prefetch pool
+                drain_ready(&mut builder, &mut pending)?;
             }
         }
     }
 
+    // the change stream is exhausted: block on whatever prefetches are still
+    // outstanding and write out the rest of the queue in order
+    drain_all(&mut builder, &mut pending)?;
+
     // Write all pending whiteout markers for files that were deleted and not recreated.
     // Skip redundant nested whiteouts - if a parent directory is being deleted,
     // we don't need whiteout markers for its children.
@@ -357,5 +719,15 @@ fn main() -> Result<()> {
             .collect::<Vec<_>>()
             .join(", ")
     );
+
+    // retrieve the writer to flush whatever's left in the chunker's final
+    // partial chunk (with --chunk-store) and to finish the gzip/zstd stream
+    // or flush the plain output file
+    builder
+        .into_inner()
+        .context("while finishing tar writer")?
+        .finish()
+        .context("while finishing chunk store")?;
+
     Ok(())
 }
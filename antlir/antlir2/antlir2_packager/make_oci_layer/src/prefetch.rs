@@ -0,0 +1,99 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Bounded worker pool that prefetches `--child` layer file contents for
+//! metadata-only (`Contents::Unset`) entries off the critical path. Layers
+//! with thousands of metadata-only changes are otherwise dominated by
+//! open/stat/read latency on the single thread doing the tar write; this
+//! overlaps that I/O with the rest of the encode loop instead, the same way
+//! Mercurial caps its parallel `status` walk at a fixed worker count rather
+//! than spawning one thread per file.
+
+use std::fs::File;
+use std::io;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::mpsc;
+use std::thread;
+
+/// Matches Mercurial's cap on its parallel `status` walk workers.
+const WORKERS: usize = 16;
+
+/// What a prefetched path under the child layer root turned out to be.
+pub enum ChildResolution {
+    File(File),
+    Dir,
+    Link(PathBuf),
+}
+
+fn resolve(child_root: &Path, rel: &Path) -> io::Result<ChildResolution> {
+    let abs = child_root.join(rel);
+    let meta = std::fs::symlink_metadata(&abs)?;
+    if meta.is_file() {
+        Ok(ChildResolution::File(File::open(&abs)?))
+    } else if meta.is_dir() {
+        Ok(ChildResolution::Dir)
+    } else if meta.is_symlink() {
+        Ok(ChildResolution::Link(std::fs::read_link(&abs)?))
+    } else {
+        Err(io::Error::new(
+            io::ErrorKind::Other,
+            format!(
+                "not sure what to do with unset contents on filetype {:?}",
+                meta.file_type()
+            ),
+        ))
+    }
+}
+
+type Job = (PathBuf, mpsc::SyncSender<io::Result<ChildResolution>>);
+
+/// A fixed pool of [WORKERS] threads that open/stat paths under the child
+/// layer root on request. The request channel's capacity equals the worker
+/// count, so [PrefetchPool::submit] only blocks its caller once that many
+/// lookups are queued on top of the ones the workers are already running
+/// (at most twice [WORKERS] outstanding), which in turn bounds how far the
+/// encode loop can race ahead of the single-threaded tar write.
+pub struct PrefetchPool {
+    jobs: mpsc::SyncSender<Job>,
+}
+
+impl PrefetchPool {
+    pub fn new(child_root: PathBuf) -> Self {
+        let (jobs, rx) = mpsc::sync_channel::<Job>(WORKERS);
+        let rx = Arc::new(Mutex::new(rx));
+        for _ in 0..WORKERS {
+            let rx = Arc::clone(&rx);
+            let child_root = child_root.clone();
+            thread::spawn(move || {
+                while let Ok((rel, resp)) = {
+                    let rx = rx.lock().expect("prefetch worker mutex poisoned");
+                    rx.recv()
+                } {
+                    // the writer side may have already moved on (eg unwinding
+                    // out of main() on an earlier error); nothing to do if so
+                    let _ = resp.send(resolve(&child_root, &rel));
+                }
+            });
+        }
+        Self { jobs }
+    }
+
+    /// Submit `rel` (relative to the child layer root) to be opened/stat'd
+    /// by the next free worker, returning a receiver that yields the result
+    /// once it's ready. Blocks the caller only once [WORKERS] lookups are
+    /// queued beyond the ones the workers are already running.
+    pub fn submit(&self, rel: PathBuf) -> mpsc::Receiver<io::Result<ChildResolution>> {
+        let (resp, result) = mpsc::sync_channel(1);
+        self.jobs
+            .send((rel, resp))
+            .expect("prefetch workers never exit while the pool is alive");
+        result
+    }
+}
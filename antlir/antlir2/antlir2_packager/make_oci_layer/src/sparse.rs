@@ -0,0 +1,113 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! A sparse-aware [Read] over a [File], used so that copying large,
+//! hole-punched files (disk images, databases) into the layer tar doesn't
+//! have to fault in or read back the zero regions from disk. The logical
+//! size written out is unchanged (the tar entry stays a normal regular-type
+//! member, not a GNU sparse one), only how we get the zero bytes differs: for
+//! holes, they're synthesized directly instead of actually read from the
+//! source file. This is the same `sparse_copy` technique proxmox uses on the
+//! extraction side, applied here to the encode path instead.
+
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::os::unix::io::AsRawFd;
+
+use nix::unistd::Whence;
+use nix::unistd::lseek;
+
+pub struct SparseReader {
+    file: File,
+    pos: u64,
+    len: u64,
+    /// End offset (exclusive) of whichever region `pos` currently falls in.
+    region_end: u64,
+    in_data: bool,
+}
+
+impl SparseReader {
+    pub fn new(file: File) -> io::Result<Self> {
+        let len = file.metadata()?.len();
+        let mut reader = Self {
+            file,
+            pos: 0,
+            len,
+            region_end: 0,
+            in_data: false,
+        };
+        reader.locate_region()?;
+        Ok(reader)
+    }
+
+    /// Figure out whether `self.pos` currently sits in a data region or a
+    /// hole, and how far that region extends, via `SEEK_DATA`/`SEEK_HOLE`.
+    /// Falls back to treating the rest of the file as one big data region
+    /// if the filesystem doesn't support sparse seeking (eg tmpfs, or
+    /// `ENXIO` meaning there's no more data after `pos`).
+    fn locate_region(&mut self) -> io::Result<()> {
+        if self.pos >= self.len {
+            self.in_data = false;
+            self.region_end = self.len;
+            return Ok(());
+        }
+        let fd = self.file.as_raw_fd();
+        match lseek(fd, self.pos as i64, Whence::SeekData) {
+            Ok(off) if off as u64 == self.pos => {
+                self.in_data = true;
+                self.region_end = match lseek(fd, self.pos as i64, Whence::SeekHole) {
+                    Ok(off) => (off as u64).min(self.len),
+                    Err(_) => self.len,
+                };
+            }
+            Ok(off) => {
+                // `pos` was inside a hole; the next data region starts at `off`
+                self.in_data = false;
+                self.region_end = (off as u64).min(self.len);
+            }
+            Err(_) => {
+                // no SEEK_DATA support, or no more data after `pos` (ENXIO):
+                // either way, treat everything from here to EOF as data so
+                // we never silently drop real bytes.
+                self.in_data = true;
+                self.region_end = self.len;
+            }
+        }
+        // the lseek(2) calls above move the underlying fd's offset; restore
+        // it before the next real read() of the file
+        self.file.seek(SeekFrom::Start(self.pos))?;
+        Ok(())
+    }
+}
+
+impl Read for SparseReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.len {
+            return Ok(0);
+        }
+        if self.pos >= self.region_end {
+            self.locate_region()?;
+        }
+        let want = buf.len().min((self.region_end - self.pos) as usize);
+        if want == 0 {
+            return Ok(0);
+        }
+        if self.in_data {
+            let n = self.file.read(&mut buf[..want])?;
+            self.pos += n as u64;
+            Ok(n)
+        } else {
+            // a hole: synthesize zeros rather than reading them off disk
+            buf[..want].fill(0);
+            self.pos += want as u64;
+            Ok(want)
+        }
+    }
+}
@@ -0,0 +1,109 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Validation for security-sensitive xattr payloads before they're embedded
+//! as PAX `SCHILY.xattr.*` records. POSIX ACLs and file capabilities are
+//! raw, fixed little-endian binary structures: a corrupt or
+//! truncated payload here doesn't fail the build, it silently produces a
+//! layer that extracts "fine" but denies access (or grants too much) at
+//! runtime. Validate the structure up front and fail loudly instead,
+//! mirroring the discipline proxmox's extraction path applies on the way
+//! back out.
+
+use anyhow::Result;
+use anyhow::bail;
+use anyhow::ensure;
+
+/// The only version `posix_acl_xattr_header.a_version` has ever had.
+const ACL_EA_VERSION: u32 = 0x0002;
+
+// `e_tag` values, from linux/posix_acl_xattr.h
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+
+/// Sentinel `e_id` for entries that aren't tied to a specific uid/gid.
+const ACL_UNDEFINED_ID: u32 = 0xffff_ffff;
+
+/// Validate `system.posix_acl_{access,default}`'s on-disk format: a 4-byte
+/// little-endian version header followed by 8-byte `(tag, perm, id)`
+/// entries.
+pub fn validate_posix_acl(data: &[u8]) -> Result<()> {
+    ensure!(
+        data.len() >= 4,
+        "posix_acl xattr is shorter than its version header"
+    );
+    let version = u32::from_le_bytes(data[0..4].try_into().unwrap());
+    ensure!(
+        version == ACL_EA_VERSION,
+        "unsupported posix_acl version {version:#x}"
+    );
+
+    let entries = &data[4..];
+    ensure!(
+        entries.len() % 8 == 0,
+        "posix_acl xattr has a truncated entry (length {} is not a multiple of 8)",
+        entries.len()
+    );
+    for entry in entries.chunks_exact(8) {
+        let tag = u16::from_le_bytes(entry[0..2].try_into().unwrap());
+        let id = u32::from_le_bytes(entry[4..8].try_into().unwrap());
+        match tag {
+            ACL_USER_OBJ | ACL_GROUP_OBJ | ACL_MASK | ACL_OTHER => {
+                ensure!(
+                    id == ACL_UNDEFINED_ID,
+                    "posix_acl entry with tag {tag:#x} must have an undefined id, got {id}"
+                );
+            }
+            ACL_USER | ACL_GROUP => {
+                ensure!(
+                    id != ACL_UNDEFINED_ID,
+                    "posix_acl entry with tag {tag:#x} is missing a uid/gid"
+                );
+            }
+            other => bail!("posix_acl entry has unknown tag {other:#x}"),
+        }
+    }
+    Ok(())
+}
+
+// `vfs_cap_data` revisions, from linux/capability.h. The revision is packed
+// into the high byte of `magic_etc` and fixes how many trailing bytes the
+// rest of the struct must have.
+const VFS_CAP_REVISION_1: u32 = 0x0100_0000;
+const VFS_CAP_REVISION_2: u32 = 0x0200_0000;
+const VFS_CAP_REVISION_3: u32 = 0x0300_0000;
+const VFS_CAP_REVISION_MASK: u32 = 0xff00_0000;
+
+/// Validate `security.capability`'s `vfs_cap_data` header: `magic_etc`
+/// encodes a revision that fixes the total payload length (one
+/// `(permitted, inheritable)` pair of `u32`s per capability word -- one
+/// word for revision 1, two for revisions 2 and 3 -- plus a trailing root
+/// uid for revision 3).
+pub fn validate_capability(data: &[u8]) -> Result<()> {
+    ensure!(
+        data.len() >= 4,
+        "capability xattr is shorter than its header"
+    );
+    let magic_etc = u32::from_le_bytes(data[0..4].try_into().unwrap());
+    let revision = magic_etc & VFS_CAP_REVISION_MASK;
+    let expected_len = match revision {
+        VFS_CAP_REVISION_1 => 4 + 8,
+        VFS_CAP_REVISION_2 => 4 + 2 * 8,
+        VFS_CAP_REVISION_3 => 4 + 2 * 8 + 4,
+        other => bail!("unsupported capability revision {other:#x}"),
+    };
+    ensure!(
+        data.len() == expected_len,
+        "capability xattr revision {revision:#x} should be {expected_len} bytes, got {}",
+        data.len()
+    );
+    Ok(())
+}
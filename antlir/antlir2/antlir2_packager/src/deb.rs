@@ -0,0 +1,252 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Build a Debian `.deb` package: an `ar` archive (magic `"!<arch>\n"`)
+//! containing exactly three members in order -- `debian-binary`,
+//! `control.tar.zst`, `data.tar.zst`. Both inner tarballs have to preserve
+//! the layer's uid/gid/mode/xattrs, which needs root, so (like
+//! [squashfs](crate::squashfs)) they're built inside the isolated build
+//! appliance; only the final `ar` container is assembled locally, since
+//! that's just bytes on disk.
+
+use std::fmt::Write as _;
+use std::fs::File;
+use std::fs::Permissions;
+use std::io::Write as _;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Stdio;
+
+use antlir2_isolate::nspawn;
+use antlir2_isolate::IsolationContext;
+use anyhow::Context;
+use anyhow::Result;
+use serde::Deserialize;
+use tempfile::TempDir;
+
+use crate::run_cmd;
+use crate::sbom;
+use crate::PackageFormat;
+
+/// Maintainer scripts `dpkg` runs around install/removal. All optional --
+/// a `.deb` with none of these is perfectly valid.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Scripts {
+    preinst: Option<PathBuf>,
+    postinst: Option<PathBuf>,
+    prerm: Option<PathBuf>,
+    postrm: Option<PathBuf>,
+}
+
+impl Scripts {
+    fn iter(&self) -> impl Iterator<Item = (&'static str, &Path)> {
+        [
+            ("preinst", self.preinst.as_deref()),
+            ("postinst", self.postinst.as_deref()),
+            ("prerm", self.prerm.as_deref()),
+            ("postrm", self.postrm.as_deref()),
+        ]
+        .into_iter()
+        .filter_map(|(name, path)| path.map(|p| (name, p)))
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Deb {
+    build_appliance: PathBuf,
+    layer: PathBuf,
+    package: String,
+    version: String,
+    architecture: String,
+    maintainer: String,
+    description: String,
+    #[serde(default)]
+    depends: Vec<String>,
+    #[serde(default)]
+    pre_depends: Vec<String>,
+    #[serde(default)]
+    conflicts: Vec<String>,
+    /// Absolute in-layer paths `dpkg` should preserve across upgrades
+    /// instead of overwriting, written verbatim into `control.tar`'s
+    /// `conffiles` member.
+    #[serde(default)]
+    conffiles: Vec<PathBuf>,
+    #[serde(default)]
+    scripts: Scripts,
+    /// Also write an SPDX 2.3 JSON SBOM covering every file in `layer` to
+    /// this path. See [crate::sbom].
+    #[serde(default)]
+    emit_sbom: Option<PathBuf>,
+}
+
+impl Deb {
+    /// Render the `control` file's `Key: Value` fields, wrapping
+    /// `description` per Debian's multi-line convention: the first line is
+    /// the synopsis, continuation lines are indented with a leading space,
+    /// and a blank line is encoded as a lone `.` so it isn't mistaken for
+    /// the field's end.
+    fn control_file(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "Package: {}", self.package);
+        let _ = writeln!(out, "Version: {}", self.version);
+        let _ = writeln!(out, "Architecture: {}", self.architecture);
+        let _ = writeln!(out, "Maintainer: {}", self.maintainer);
+        if !self.depends.is_empty() {
+            let _ = writeln!(out, "Depends: {}", self.depends.join(", "));
+        }
+        if !self.pre_depends.is_empty() {
+            let _ = writeln!(out, "Pre-Depends: {}", self.pre_depends.join(", "));
+        }
+        if !self.conflicts.is_empty() {
+            let _ = writeln!(out, "Conflicts: {}", self.conflicts.join(", "));
+        }
+        let mut lines = self.description.lines();
+        let _ = writeln!(out, "Description: {}", lines.next().unwrap_or_default());
+        for line in lines {
+            if line.is_empty() {
+                let _ = writeln!(out, " .");
+            } else {
+                let _ = writeln!(out, " {line}");
+            }
+        }
+        out
+    }
+}
+
+impl PackageFormat for Deb {
+    fn build(&self, out: &Path) -> Result<()> {
+        let layer_abs_path = self
+            .layer
+            .canonicalize()
+            .context("failed to build absolute path to layer")?;
+
+        let staging = TempDir::new().context("while creating staging directory")?;
+        let control_dir = staging.path().join("control");
+        std::fs::create_dir(&control_dir).context("while creating control directory")?;
+        std::fs::write(control_dir.join("control"), self.control_file())
+            .context("while writing control file")?;
+
+        if !self.conffiles.is_empty() {
+            let mut body = String::new();
+            for path in &self.conffiles {
+                let _ = writeln!(body, "{}", path.display());
+            }
+            std::fs::write(control_dir.join("conffiles"), body)
+                .context("while writing conffiles")?;
+        }
+
+        for (name, src) in self.scripts.iter() {
+            let dst = control_dir.join(name);
+            std::fs::copy(src, &dst)
+                .with_context(|| format!("while copying maintainer script '{name}'"))?;
+            std::fs::set_permissions(&dst, Permissions::from_mode(0o755))
+                .with_context(|| format!("while setting permissions on '{name}'"))?;
+        }
+
+        let control_tar = staging.path().join("control.tar.zst");
+        let data_tar = staging.path().join("data.tar.zst");
+        File::create(&control_tar).context("failed to create control tarball")?;
+        File::create(&data_tar).context("failed to create data tarball")?;
+
+        let sbom = self
+            .emit_sbom
+            .is_some()
+            .then(|| sbom::collection(staging.path(), &layer_abs_path.display().to_string()));
+
+        let mut outputs = vec![
+            control_dir.as_path(),
+            control_tar.as_path(),
+            data_tar.as_path(),
+        ];
+        if let Some(sbom) = &sbom {
+            outputs.push(sbom.manifest.as_path());
+            outputs.push(sbom.licenses.as_path());
+        }
+
+        let isol_context = IsolationContext::builder(&self.build_appliance)
+            .inputs([layer_abs_path.as_path(), control_dir.as_path()])
+            .outputs(outputs)
+            .working_directory(std::env::current_dir().context("while getting cwd")?)
+            .build();
+
+        let deb_script = format!(
+            "set -ue -o pipefail; \
+                (cd {layer} && find . -mindepth 1 -type f -printf '%P\\0' | sort -z \
+                    | xargs -0 md5sum) > {control_dir}/md5sums; \
+                (cd {control_dir} && find . -mindepth 1 -printf '%P\\0' | sort -z \
+                    | tar --null -T - --numeric-owner --owner=0 --group=0 -cf -) \
+                    | zstd -q -f -o {control_tar}; \
+                (cd {layer} && find . -mindepth 1 -printf '%P\\0' | sort -z \
+                    | tar --null -T - --xattrs --xattrs-include='*' --numeric-owner -cf -) \
+                    | zstd -q -f -o {data_tar}{sbom_suffix}",
+            layer = layer_abs_path.display(),
+            control_dir = control_dir.display(),
+            control_tar = control_tar.display(),
+            data_tar = data_tar.display(),
+            sbom_suffix = sbom
+                .as_ref()
+                .map(|s| format!("; {}", s.script))
+                .unwrap_or_default(),
+        );
+
+        run_cmd(
+            nspawn(isol_context)?
+                .command("/bin/bash")?
+                .arg("-c")
+                .arg(deb_script)
+                .stdout(Stdio::piped()),
+        )
+        .context("failed to build deb control/data tarballs")?;
+
+        if let Some(sbom_path) = &self.emit_sbom {
+            let sbom = sbom.expect("collected above whenever emit_sbom is set");
+            sbom::write_spdx(
+                sbom_path,
+                &self.package,
+                &self.version,
+                &sbom.manifest,
+                &sbom.licenses,
+            )?;
+        }
+
+        let mut ar = File::create(out).context("failed to create output file")?;
+        ar.write_all(b"!<arch>\n")?;
+        write_ar_member(&mut ar, "debian-binary", b"2.0\n")?;
+        write_ar_member(
+            &mut ar,
+            "control.tar.zst",
+            &std::fs::read(&control_tar).context("while reading control tarball")?,
+        )?;
+        write_ar_member(
+            &mut ar,
+            "data.tar.zst",
+            &std::fs::read(&data_tar).context("while reading data tarball")?,
+        )?;
+
+        Ok(())
+    }
+}
+
+/// Write one member of a common/GNU `ar` archive: a 60-byte header (name,
+/// mtime, uid, gid, mode, size, then the `` ` `` + `\n` end-of-header magic)
+/// followed by the member's bytes, padded with a trailing `\n` if the
+/// content length is odd (every member has to start on an even offset).
+fn write_ar_member(out: &mut impl std::io::Write, name: &str, data: &[u8]) -> Result<()> {
+    write!(out, "{name:<16}")?;
+    write!(out, "{:<12}", 0)?; // mtime: deterministic output, not "now"
+    write!(out, "{:<6}", 0)?; // uid
+    write!(out, "{:<6}", 0)?; // gid
+    write!(out, "{:<8}", "100644")?; // mode, octal digits as ASCII text
+    write!(out, "{:<10}", data.len())?;
+    out.write_all(b"`\n")?;
+    out.write_all(data)?;
+    if data.len() % 2 == 1 {
+        out.write_all(b"\n")?;
+    }
+    Ok(())
+}
@@ -0,0 +1,42 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+use std::process::Command;
+use std::process::Output;
+
+use anyhow::ensure;
+use anyhow::Context;
+use anyhow::Result;
+
+pub mod deb;
+pub mod pacman;
+mod sbom;
+pub mod squashfs;
+pub mod unprivileged_dir;
+
+pub use deb::Deb;
+pub use pacman::Pacman;
+pub use squashfs::Squashfs;
+
+/// A packaging format that turns a built layer subvol into a single output
+/// file, e.g. a squashfs image, a `.deb`, or a `.pkg.tar.zst`.
+pub trait PackageFormat {
+    fn build(&self, out: &std::path::Path) -> Result<()>;
+}
+
+/// Run `cmd` to completion, returning its captured output, or failing with
+/// its stderr attached if it didn't exit successfully.
+pub(crate) fn run_cmd(cmd: &mut Command) -> Result<Output> {
+    let output = cmd.output().context("while spawning command")?;
+    ensure!(
+        output.status.success(),
+        "command exited with {}: {}",
+        output.status,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    Ok(output)
+}
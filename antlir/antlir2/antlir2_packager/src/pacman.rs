@@ -0,0 +1,185 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Build an Arch Linux `.pkg.tar.zst`: a zstd-compressed tar of the layer's
+//! file tree, with `.PKGINFO`, `.BUILDINFO` and `.MTREE` placed alongside it
+//! at the archive root. Like [squashfs](crate::squashfs), walking the layer
+//! (to read every file's owner/mode and hash its contents) needs root, so
+//! that happens inside the isolated build appliance rather than locally.
+
+use std::fmt::Write as _;
+use std::fs::File;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Stdio;
+
+use antlir2_isolate::nspawn;
+use antlir2_isolate::IsolationContext;
+use anyhow::Context;
+use anyhow::Result;
+use serde::Deserialize;
+use tempfile::TempDir;
+
+use crate::run_cmd;
+use crate::sbom;
+use crate::PackageFormat;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Pacman {
+    build_appliance: PathBuf,
+    layer: PathBuf,
+    pkgname: String,
+    pkgver: String,
+    pkgdesc: String,
+    url: String,
+    arch: String,
+    #[serde(default)]
+    depends: Vec<String>,
+    #[serde(default)]
+    provides: Vec<String>,
+    #[serde(default)]
+    conflicts: Vec<String>,
+    /// Also write an SPDX 2.3 JSON SBOM covering every file in `layer` to
+    /// this path. See [crate::sbom].
+    #[serde(default)]
+    emit_sbom: Option<PathBuf>,
+}
+
+impl Pacman {
+    /// Every `.PKGINFO` field except `size`, which can only be known once
+    /// the layer has actually been walked inside the build appliance.
+    fn pkginfo_header(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "pkgname = {}", self.pkgname);
+        let _ = writeln!(out, "pkgver = {}", self.pkgver);
+        let _ = writeln!(out, "pkgdesc = {}", self.pkgdesc);
+        let _ = writeln!(out, "url = {}", self.url);
+        let _ = writeln!(out, "arch = {}", self.arch);
+        for depend in &self.depends {
+            let _ = writeln!(out, "depend = {depend}");
+        }
+        for provides in &self.provides {
+            let _ = writeln!(out, "provides = {provides}");
+        }
+        for conflict in &self.conflicts {
+            let _ = writeln!(out, "conflict = {conflict}");
+        }
+        out
+    }
+
+    fn buildinfo(&self) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "format = 2");
+        let _ = writeln!(out, "pkgname = {}", self.pkgname);
+        let _ = writeln!(out, "pkgbase = {}", self.pkgname);
+        let _ = writeln!(out, "pkgver = {}", self.pkgver);
+        let _ = writeln!(out, "pkgarch = {}", self.arch);
+        out
+    }
+}
+
+impl PackageFormat for Pacman {
+    fn build(&self, out: &Path) -> Result<()> {
+        File::create(out).context("failed to create output file")?;
+
+        let layer_abs_path = self
+            .layer
+            .canonicalize()
+            .context("failed to build absolute path to layer")?;
+
+        let output_abs_path = out
+            .canonicalize()
+            .context("failed to build abs path to output")?;
+
+        let staging = TempDir::new().context("while creating staging directory")?;
+        let sbom = self
+            .emit_sbom
+            .is_some()
+            .then(|| sbom::collection(staging.path(), &layer_abs_path.display().to_string()));
+
+        let mut outputs = vec![staging.path(), output_abs_path.as_path()];
+        if let Some(sbom) = &sbom {
+            outputs.push(sbom.manifest.as_path());
+            outputs.push(sbom.licenses.as_path());
+        }
+
+        let isol_context = IsolationContext::builder(&self.build_appliance)
+            .inputs([layer_abs_path.as_path()])
+            .outputs(outputs)
+            .working_directory(std::env::current_dir().context("while getting cwd")?)
+            .build();
+
+        let pacman_script = format!(
+            "set -ue -o pipefail; \
+                cd {layer}; \
+                SIZE=$(du -sb --apparent-size . | cut -f1); \
+                (printf '%s' {pkginfo_header}; printf 'size = %s\\n' \"$SIZE\") \
+                    > {staging}/.PKGINFO; \
+                printf '%s' {buildinfo} > {staging}/.BUILDINFO; \
+                (find . -print0 | while IFS= read -r -d '' path; do \
+                    rel=\"./${{path#./}}\"; \
+                    [ \"$rel\" = \"./.\" ] && continue; \
+                    if [ -L \"$path\" ]; then type=link; \
+                    elif [ -d \"$path\" ]; then type=dir; \
+                    else type=file; fi; \
+                    mode=$(stat -c '%a' \"$path\"); \
+                    uid=$(stat -c '%u' \"$path\"); \
+                    gid=$(stat -c '%g' \"$path\"); \
+                    if [ \"$type\" = file ]; then \
+                        size=$(stat -c '%s' \"$path\"); \
+                        sha256=$(sha256sum \"$path\" | cut -d' ' -f1); \
+                        printf '%s type=%s mode=%s uid=%s gid=%s size=%s sha256digest=%s\\n' \
+                            \"$rel\" \"$type\" \"$mode\" \"$uid\" \"$gid\" \"$size\" \"$sha256\"; \
+                    else \
+                        printf '%s type=%s mode=%s uid=%s gid=%s\\n' \
+                            \"$rel\" \"$type\" \"$mode\" \"$uid\" \"$gid\"; \
+                    fi; \
+                done) | gzip -q > {staging}/.MTREE; \
+                tar -C {staging} -cf - .PKGINFO .BUILDINFO .MTREE -C {layer} . \
+                    | zstd -q -f -o {output}{sbom_suffix}",
+            layer = layer_abs_path.display(),
+            staging = staging.path().display(),
+            output = output_abs_path.display(),
+            pkginfo_header = shell_quote(&self.pkginfo_header()),
+            buildinfo = shell_quote(&self.buildinfo()),
+            sbom_suffix = sbom
+                .as_ref()
+                .map(|s| format!("; {}", s.script))
+                .unwrap_or_default(),
+        );
+
+        run_cmd(
+            nspawn(isol_context)?
+                .command("/bin/bash")?
+                .arg("-c")
+                .arg(pacman_script)
+                .stdout(Stdio::piped()),
+        )
+        .context("failed to build pacman package")?;
+
+        if let Some(sbom_path) = &self.emit_sbom {
+            let sbom = sbom.expect("collected above whenever emit_sbom is set");
+            sbom::write_spdx(
+                sbom_path,
+                &self.pkgname,
+                &self.pkgver,
+                &sbom.manifest,
+                &sbom.licenses,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Single-quote `s` for interpolation into a shell command line, the way
+/// `printf '%s' {pkginfo_header}` above needs: closing the quote, escaping
+/// any embedded `'`, and reopening it, since single quotes admit no other
+/// escape of their own.
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
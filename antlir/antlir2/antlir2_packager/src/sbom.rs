@@ -0,0 +1,166 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Emit an SPDX 2.3 JSON SBOM alongside a built package artifact. Hashing
+//! every installed file needs to read the layer as root, so -- like the
+//! build itself -- the per-file walk runs inside the isolated build
+//! appliance via [collection]'s shell fragment, appended to whatever script
+//! the calling format is already running; only the JSON document itself
+//! ([write_spdx]) is assembled locally from that walk's output.
+
+use std::path::Path;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use anyhow::Result;
+use serde_json::json;
+use sha1::Digest as _;
+use sha1::Sha1;
+
+/// Paths (inside `staging`) that [collection]'s shell fragment writes to --
+/// each caller adds both to its isolation outputs -- and the fragment
+/// itself -- append `script` to
+/// the format's own build script so the whole build still runs as one
+/// isolated command.
+pub(crate) struct Collection {
+    pub(crate) manifest: PathBuf,
+    pub(crate) licenses: PathBuf,
+    pub(crate) script: String,
+}
+
+/// Walk `layer` (an absolute, already-canonicalized path) and build the
+/// shell fragment that collects everything [write_spdx] needs: a
+/// `sha256\tsha1\tpath` line per regular file, and a deduplicated list of
+/// SPDX license ids found under `/usr/share/licenses`.
+pub(crate) fn collection(staging: &Path, layer: &str) -> Collection {
+    let manifest = staging.join("sbom-files.tsv");
+    let licenses = staging.join("sbom-licenses.txt");
+    let script = format!(
+        "(cd {layer} && find . -mindepth 1 -type f -printf '%P\\0' | sort -z \
+            | while IFS= read -r -d '' f; do \
+                sha256=$(sha256sum \"$f\" | cut -d' ' -f1); \
+                sha1=$(sha1sum \"$f\" | cut -d' ' -f1); \
+                printf '%s\\t%s\\t%s\\n' \"$sha256\" \"$sha1\" \"$f\"; \
+            done) > {manifest}; \
+            (find {layer}/usr/share/licenses -mindepth 1 -maxdepth 1 -type d \
+                -printf '%f\\n' 2>/dev/null | sort -u) > {licenses}",
+        manifest = manifest.display(),
+        licenses = licenses.display(),
+    );
+    Collection {
+        manifest,
+        licenses,
+        script,
+    }
+}
+
+struct FileRecord {
+    sha256: String,
+    sha1: String,
+    path: String,
+}
+
+fn parse_manifest(manifest: &Path) -> Result<Vec<FileRecord>> {
+    let text = std::fs::read_to_string(manifest)
+        .with_context(|| format!("while reading '{}'", manifest.display()))?;
+    Ok(text
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| {
+            let mut fields = line.splitn(3, '\t');
+            FileRecord {
+                sha256: fields.next().unwrap_or_default().to_owned(),
+                sha1: fields.next().unwrap_or_default().to_owned(),
+                path: fields.next().unwrap_or_default().to_owned(),
+            }
+        })
+        .collect())
+}
+
+/// Write an SPDX 2.3 JSON SBOM to `out`: one `File` element per line of
+/// `manifest` (see [collection]), rolled up under a single root `Package`
+/// element whose `packageVerificationCode` is the SHA1 over the sorted
+/// per-file SHA1s, per the SPDX spec. Any license ids harvested into
+/// `licenses` are applied to every file, since this walk can't attribute a
+/// license to one file over another any more precisely than that.
+pub(crate) fn write_spdx(
+    out: &Path,
+    name: &str,
+    version: &str,
+    manifest: &Path,
+    licenses: &Path,
+) -> Result<()> {
+    let records = parse_manifest(manifest)?;
+
+    let license_ids: Vec<String> = std::fs::read_to_string(licenses)
+        .unwrap_or_default()
+        .lines()
+        .filter(|line| !line.is_empty())
+        .map(|line| line.to_owned())
+        .collect();
+    let license_expr = if license_ids.is_empty() {
+        "NOASSERTION".to_owned()
+    } else {
+        license_ids.join(" AND ")
+    };
+
+    let mut sha1s: Vec<&str> = records.iter().map(|r| r.sha1.as_str()).collect();
+    sha1s.sort_unstable();
+    let mut verification_hasher = Sha1::new();
+    for sha1 in &sha1s {
+        verification_hasher.update(sha1.as_bytes());
+    }
+    let verification_code = hex::encode(verification_hasher.finalize());
+
+    let files: Vec<_> = records
+        .iter()
+        .enumerate()
+        .map(|(i, record)| {
+            json!({
+                "SPDXID": format!("SPDXRef-File-{i}"),
+                "fileName": format!("./{}", record.path),
+                "checksums": [
+                    {"algorithm": "SHA256", "checksumValue": record.sha256},
+                ],
+                "licenseInfoInFile": [license_expr.clone()],
+            })
+        })
+        .collect();
+    let file_ids: Vec<_> = (0..records.len())
+        .map(|i| format!("SPDXRef-File-{i}"))
+        .collect();
+
+    let document = json!({
+        "spdxVersion": "SPDX-2.3",
+        "dataLicense": "CC0-1.0",
+        "SPDXID": "SPDXRef-DOCUMENT",
+        "name": format!("{name}-{version}-sbom"),
+        "documentNamespace": format!("urn:spdx:{name}-{version}"),
+        "creationInfo": {
+            "creators": ["Tool: antlir2_packager"],
+        },
+        "packages": [{
+            "SPDXID": "SPDXRef-Package",
+            "name": name,
+            "versionInfo": version,
+            "downloadLocation": "NOASSERTION",
+            "licenseConcluded": license_expr,
+            "packageVerificationCode": {
+                "packageVerificationCodeValue": verification_code,
+            },
+            "hasFiles": file_ids,
+        }],
+        "files": files,
+        "documentDescribes": ["SPDXRef-Package"],
+    });
+
+    std::fs::write(
+        out,
+        serde_json::to_string_pretty(&document).context("while serializing SPDX document")?,
+    )
+    .with_context(|| format!("while writing '{}'", out.display()))
+}
@@ -5,6 +5,7 @@
  * LICENSE file in the root directory of this source tree.
  */
 
+use std::fmt::Write as _;
 use std::fs::File;
 use std::path::Path;
 use std::path::PathBuf;
@@ -15,14 +16,93 @@ use antlir2_isolate::IsolationContext;
 use anyhow::Context;
 use anyhow::Result;
 use serde::Deserialize;
+use tempfile::TempDir;
 
 use crate::run_cmd;
+use crate::sbom;
 use crate::PackageFormat;
 
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compressor {
+    Gzip,
+    Lzo,
+    Lz4,
+    Xz,
+    Zstd,
+}
+
+impl Default for Compressor {
+    fn default() -> Self {
+        Self::Zstd
+    }
+}
+
+impl Compressor {
+    fn as_mksquashfs_arg(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Lzo => "lzo",
+            Self::Lz4 => "lz4",
+            Self::Xz => "xz",
+            Self::Zstd => "zstd",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Deserialize)]
 pub struct Squashfs {
     build_appliance: PathBuf,
     layer: PathBuf,
+    #[serde(default)]
+    compressor: Compressor,
+    /// Passed as `-Xcompression-level`; only meaningful for compressors
+    /// that support it (`gzip`/`zstd`).
+    compression_level: Option<u32>,
+    /// Passed as `-b`, in bytes.
+    block_size: Option<u32>,
+    /// Normalize ownership and timestamps so two builds of the same layer
+    /// produce a byte-identical image, a prerequisite for
+    /// content-addressed caching and signed image distribution. Without
+    /// this, `mksquashfs` stamps every inode with its real uid/gid/mtime
+    /// from the layer and the build time itself.
+    #[serde(default)]
+    reproducible: bool,
+    /// Used instead of `-all-root` when `reproducible` is set, for layers
+    /// that need to ship with a uid/gid other than 0.
+    force_uid: Option<u32>,
+    force_gid: Option<u32>,
+    /// Also write an SPDX 2.3 JSON SBOM covering every file in `layer` to
+    /// this path. See [crate::sbom].
+    #[serde(default)]
+    emit_sbom: Option<PathBuf>,
+}
+
+impl Squashfs {
+    fn mksquashfs_args(&self) -> String {
+        let mut args = format!("-comp {}", self.compressor.as_mksquashfs_arg());
+        if let Some(level) = self.compression_level {
+            let _ = write!(args, " -Xcompression-level {level}");
+        }
+        if let Some(block_size) = self.block_size {
+            let _ = write!(args, " -b {block_size}");
+        }
+        if self.reproducible {
+            match (self.force_uid, self.force_gid) {
+                (None, None) => args.push_str(" -all-root"),
+                (uid, gid) => {
+                    let _ = write!(
+                        args,
+                        " -force-uid {} -force-gid {}",
+                        uid.unwrap_or(0),
+                        gid.unwrap_or(0)
+                    );
+                }
+            }
+            args.push_str(" -mkfs-time 0 -all-time 0");
+        }
+        args
+    }
 }
 
 impl PackageFormat for Squashfs {
@@ -38,17 +118,34 @@ impl PackageFormat for Squashfs {
             .canonicalize()
             .context("failed to build abs path to output")?;
 
+        let staging = TempDir::new().context("while creating staging directory")?;
+        let sbom = self
+            .emit_sbom
+            .is_some()
+            .then(|| sbom::collection(staging.path(), &layer_abs_path.display().to_string()));
+
+        let mut outputs = vec![output_abs_path.as_path()];
+        if let Some(sbom) = &sbom {
+            outputs.push(sbom.manifest.as_path());
+            outputs.push(sbom.licenses.as_path());
+        }
+
         let isol_context = IsolationContext::builder(&self.build_appliance)
             .inputs([layer_abs_path.as_path()])
-            .outputs([output_abs_path.as_path()])
+            .outputs(outputs)
             .working_directory(std::env::current_dir().context("while getting cwd")?)
             .build();
 
         let squashfs_script = format!(
             "set -ue -o pipefail; \
-                /usr/sbin/mksquashfs {} {} -comp zstd -noappend -one-file-system",
+                /usr/sbin/mksquashfs {} {} {} -noappend -one-file-system{sbom_suffix}",
             layer_abs_path.as_path().display(),
-            output_abs_path.as_path().display()
+            output_abs_path.as_path().display(),
+            self.mksquashfs_args(),
+            sbom_suffix = sbom
+                .as_ref()
+                .map(|s| format!("; {}", s.script))
+                .unwrap_or_default(),
         );
 
         run_cmd(
@@ -60,6 +157,21 @@ impl PackageFormat for Squashfs {
         )
         .context("Failed to build cpio archive")?;
 
+        if let Some(sbom_path) = &self.emit_sbom {
+            let sbom = sbom.expect("collected above whenever emit_sbom is set");
+            let name = out
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "package".to_owned());
+            sbom::write_spdx(
+                sbom_path,
+                &name,
+                "NOASSERTION",
+                &sbom.manifest,
+                &sbom.licenses,
+            )?;
+        }
+
         Ok(())
     }
 }
@@ -7,10 +7,17 @@
 
 use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::ffi::OsString;
+use std::fs::File;
 use std::fs::Permissions;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::fs::MetadataExt;
 use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
 use std::path::Path;
 use std::path::PathBuf;
 
@@ -18,7 +25,15 @@ use anyhow::Context;
 use anyhow::Result;
 use base64::Engine as _;
 use base64::engine::general_purpose::URL_SAFE;
+use filetime::FileTime;
+use nix::errno::Errno;
+use nix::unistd::Whence;
+use nix::unistd::lseek;
 use serde::Deserialize;
+use serde::Serialize;
+use tar::Builder;
+use tar::EntryType;
+use tar::Header;
 use walkdir::WalkDir;
 
 /// Check if a filename contains characters that Buck2 doesn't allow.
@@ -27,10 +42,278 @@ fn needs_escaping(component: &std::ffi::OsStr) -> bool {
     component.as_bytes().contains(&b'\\')
 }
 
+/// `security.*` and `trusted.*` xattrs can only be written with
+/// `CAP_SYS_ADMIN`, which an unprivileged build doesn't have.
+fn is_privileged_xattr(name: &std::ffi::OsStr) -> bool {
+    let name = name.as_bytes();
+    name.starts_with(b"security.") || name.starts_with(b"trusted.")
+}
+
+/// Copy every xattr on `src` over to `dst`. The `xattr` crate operates via
+/// `lgetxattr`/`lsetxattr`, so when `src` is a symlink its own attributes are
+/// read and written rather than the attributes of whatever it points at.
+/// When `can_write_privileged_xattrs` is false (no root escalation happened),
+/// `security.*`/`trusted.*` attributes are silently skipped instead of
+/// failing the whole build on a permission error.
+fn copy_xattrs(src: &Path, dst: &Path, can_write_privileged_xattrs: bool) -> Result<()> {
+    for name in
+        xattr::list(src).with_context(|| format!("while listing xattrs on '{}'", src.display()))?
+    {
+        if !can_write_privileged_xattrs && is_privileged_xattr(&name) {
+            continue;
+        }
+        if let Some(value) = xattr::get(src, &name)
+            .with_context(|| format!("while reading xattr '{name:?}' on '{}'", src.display()))?
+        {
+            xattr::set(dst, &name, &value).with_context(|| {
+                format!("while setting xattr '{name:?}' on '{}'", dst.display())
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Above this size, `build` copies files via [sparse_copy] so holes in a
+/// disk image or preallocated database don't get materialized into real
+/// bytes in the output tree; below it, the overhead of probing with
+/// `SEEK_DATA`/`SEEK_HOLE` isn't worth it.
+const SPARSE_COPY_THRESHOLD: u64 = 1024 * 1024;
+
+/// Copy `src` to `dst`, reproducing holes instead of materializing them:
+/// `dst` is truncated to `src`'s full logical size up front (an implicit
+/// hole the whole way), then only the byte ranges `SEEK_DATA` reports as
+/// populated are written, so the rest stays unallocated. Falls back to a
+/// plain [std::fs::copy] for files under [SPARSE_COPY_THRESHOLD], or if the
+/// source filesystem doesn't support `SEEK_HOLE` (`ENOTSUP`/`EINVAL`).
+fn sparse_copy(src: &Path, dst: &Path) -> Result<()> {
+    let mut src_file =
+        File::open(src).with_context(|| format!("while opening '{}'", src.display()))?;
+    let len = src_file.metadata()?.len();
+    if len < SPARSE_COPY_THRESHOLD {
+        std::fs::copy(src, dst)?;
+        return Ok(());
+    }
+
+    let fd = src_file.as_raw_fd();
+    match lseek(fd, 0, Whence::SeekData) {
+        // ENXIO: no data anywhere, the whole file is one hole
+        Ok(_) | Err(Errno::ENXIO) => {}
+        Err(Errno::ENOTSUP) | Err(Errno::EINVAL) => {
+            std::fs::copy(src, dst)?;
+            return Ok(());
+        }
+        Err(e) => return Err(e).context("while probing SEEK_DATA support"),
+    }
+
+    let mut dst_file =
+        File::create(dst).with_context(|| format!("while creating '{}'", dst.display()))?;
+    dst_file
+        .set_len(len)
+        .with_context(|| format!("while truncating '{}' to its full size", dst.display()))?;
+
+    let mut pos = 0u64;
+    let mut buf = [0u8; 64 * 1024];
+    while pos < len {
+        let data_start = match lseek(fd, pos as i64, Whence::SeekData) {
+            Ok(off) => off as u64,
+            // no more data between `pos` and the end of the file
+            Err(Errno::ENXIO) => break,
+            Err(e) => return Err(e).context("while seeking to the next data region"),
+        };
+        if data_start >= len {
+            break;
+        }
+        let data_end = match lseek(fd, data_start as i64, Whence::SeekHole) {
+            Ok(off) => (off as u64).min(len),
+            Err(_) => len,
+        };
+
+        src_file.seek(SeekFrom::Start(data_start))?;
+        dst_file.seek(SeekFrom::Start(data_start))?;
+        let mut remaining = data_end - data_start;
+        while remaining > 0 {
+            let want = buf.len().min(remaining as usize);
+            src_file.read_exact(&mut buf[..want])?;
+            dst_file.write_all(&buf[..want])?;
+            remaining -= want as u64;
+        }
+
+        pos = data_end;
+    }
+
+    Ok(())
+}
+
+/// Above this size, a file is assumed not to be a small SPDX-tagged text
+/// file and isn't scanned for an `SPDX-License-Identifier:` line.
+const SPDX_SCAN_THRESHOLD: u64 = 4096;
+
+/// Best-effort license detection for the provenance manifest: a whole
+/// `LICENSE*`/`COPYING*` file is interned verbatim, otherwise small text
+/// files are scanned for an `SPDX-License-Identifier:` tag. Returns `None`
+/// if neither applies, or if the file isn't valid UTF-8 text.
+fn detect_license(path: &Path, metadata: &std::fs::Metadata) -> Result<Option<String>> {
+    let file_name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    if file_name.starts_with("license") || file_name.starts_with("copying") {
+        let Ok(contents) = std::fs::read_to_string(path) else {
+            return Ok(None);
+        };
+        return Ok(Some(contents.trim().to_owned()));
+    }
+
+    if metadata.len() > SPDX_SCAN_THRESHOLD {
+        return Ok(None);
+    }
+    let Ok(contents) = std::fs::read_to_string(path) else {
+        return Ok(None);
+    };
+    Ok(contents.lines().find_map(|line| {
+        line.trim()
+            .strip_prefix("SPDX-License-Identifier:")
+            .map(|id| id.trim().to_owned())
+    }))
+}
+
+/// Interns `text` into `licenses`/`seen`, returning the id it was assigned
+/// (or already had), so identical license texts across thousands of files
+/// are recorded once rather than once per file.
+fn intern_license(
+    licenses: &mut Vec<String>,
+    seen: &mut HashMap<String, usize>,
+    text: String,
+) -> usize {
+    if let Some(&id) = seen.get(&text) {
+        return id;
+    }
+    let id = licenses.len();
+    seen.insert(text.clone(), id);
+    licenses.push(text);
+    id
+}
+
+/// In-memory accumulator for the manifest's path tree, built incrementally
+/// during the same `WalkDir` pass `build` already does. Converted to
+/// [ManifestNode]s (with full relative paths) only once the walk is done.
+enum ManifestTreeNode {
+    Directory(BTreeMap<OsString, ManifestTreeNode>),
+    File(Option<usize>),
+}
+
+impl ManifestTreeNode {
+    fn insert(
+        &mut self,
+        mut components: std::path::Components,
+        is_dir: bool,
+        license: Option<usize>,
+    ) {
+        let Self::Directory(children) = self else {
+            return;
+        };
+        let Some(component) = components.next() else {
+            return;
+        };
+        let name = component.as_os_str().to_owned();
+        if components.clone().next().is_none() {
+            if is_dir {
+                children
+                    .entry(name)
+                    .or_insert_with(|| Self::Directory(BTreeMap::new()));
+            } else {
+                children.insert(name, Self::File(license));
+            }
+        } else {
+            children
+                .entry(name)
+                .or_insert_with(|| Self::Directory(BTreeMap::new()))
+                .insert(components, is_dir, license);
+        }
+    }
+
+    /// Flatten into the serializable tree, joining `prefix` onto each node's
+    /// name to produce the full relative path the request asks for.
+    fn into_nodes(
+        children: &BTreeMap<OsString, ManifestTreeNode>,
+        prefix: &Path,
+    ) -> Vec<ManifestNode> {
+        children
+            .iter()
+            .map(|(name, node)| {
+                let path = prefix.join(name);
+                match node {
+                    Self::Directory(children) => ManifestNode::Directory {
+                        directory: path.clone(),
+                        children: Self::into_nodes(children, &path),
+                    },
+                    Self::File(license) => ManifestNode::File {
+                        file: path,
+                        license: *license,
+                    },
+                }
+            })
+            .collect()
+    }
+}
+
+/// A node in the provenance manifest's collapsed path tree. Untagged so
+/// directories and files serialize exactly as `{"directory":..,"children":..}`
+/// / `{"file":..,"license":..}` rather than carrying an extra tag field.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+enum ManifestNode {
+    Directory {
+        directory: PathBuf,
+        children: Vec<ManifestNode>,
+    },
+    File {
+        file: PathBuf,
+        license: Option<usize>,
+    },
+}
+
+/// The license/provenance manifest written to `UnprivilegedDir::manifest`:
+/// a path tree alongside a side table of interned license ids, so identical
+/// licenses across thousands of files are recorded once.
+#[derive(Debug, Clone, Serialize)]
+struct Manifest {
+    tree: Vec<ManifestNode>,
+    licenses: BTreeMap<usize, String>,
+}
+
 #[derive(Debug, Clone, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct UnprivilegedDir {
     base64_encoded_filenames: Option<PathBuf>,
+    /// Instead of writing the layer out as a directory tree (which is
+    /// subject to Buck2's filename restrictions), stream it into a single
+    /// POSIX tar archive at this path. PAX extended records carry any path
+    /// that doesn't fit ustar's legacy fields, so this sidesteps the
+    /// restriction entirely instead of working around it with
+    /// `base64_encoded_filenames`'s lossy escaped-path map.
+    output_tar: Option<PathBuf>,
+    /// Carry extended attributes (SELinux labels, POSIX capabilities,
+    /// `user.*` metadata) from the layer onto the output tree. Without this,
+    /// `build` carries over contents but no xattrs (mode bits and ownership
+    /// are always rewritten by `build`, not preserved).
+    #[serde(default)]
+    preserve_xattrs: bool,
+    /// Carry `atime`/`mtime` from the layer onto the output tree, instead of
+    /// leaving every entry stamped with whatever time it was written at.
+    /// Directory times are applied in a second pass once every entry has
+    /// been written, since writing a child bumps its parent's mtime back.
+    #[serde(default)]
+    preserve_times: bool,
+    /// Emit a machine-readable provenance manifest at this path: a collapsed
+    /// directory tree where each file records an interned license id (from a
+    /// `LICENSE*`/`COPYING*` file or an `SPDX-License-Identifier:` tag),
+    /// alongside a side table mapping ids back to the raw license text. This
+    /// gives downstream compliance tooling an auditable record of where each
+    /// file came from and what license governs it, as a by-product of the
+    /// same walk `build` already does rather than a second traversal.
+    #[serde(default)]
+    manifest: Option<PathBuf>,
 }
 
 impl UnprivilegedDir {
@@ -42,6 +325,10 @@ impl UnprivilegedDir {
     ) -> Result<()> {
         let layer = layer.canonicalize()?;
 
+        if let Some(output_tar) = &self.output_tar {
+            return Self::build_tar(output_tar, &layer);
+        }
+
         // Track escaped paths: escaped_relative_path -> original_relative_path
         // such that they can be reconstructed by consumers of the dir
         let mut escaped_paths: BTreeMap<PathBuf, PathBuf> = BTreeMap::new();
@@ -50,6 +337,17 @@ impl UnprivilegedDir {
         // Key: (device, inode), Value: first destination path for this inode
         let mut inode_to_dst: HashMap<(u64, u64), PathBuf> = HashMap::new();
 
+        // Directories whose times need to be set once every entry (including
+        // their children) has been written, so creating those children
+        // doesn't bump the parent's mtime back to "now" afterwards.
+        let mut dir_times: Vec<(PathBuf, FileTime, FileTime)> = Vec::new();
+
+        // Accumulators for the provenance manifest, populated in the same
+        // walk below rather than a second traversal over the layer.
+        let mut manifest_tree = ManifestTreeNode::Directory(BTreeMap::new());
+        let mut license_texts: Vec<String> = Vec::new();
+        let mut license_ids: HashMap<String, usize> = HashMap::new();
+
         std::fs::create_dir(out).context("while creating root")?;
 
         std::os::unix::fs::lchown(
@@ -97,10 +395,27 @@ impl UnprivilegedDir {
                 std::fs::create_dir(&dst)
                     .with_context(|| format!("while creating directory '{}'", dst.display()))?;
                 std::fs::set_permissions(&dst, Permissions::from_mode(0o755))?;
+                if self.preserve_times {
+                    let metadata = entry.metadata()?;
+                    dir_times.push((
+                        dst.clone(),
+                        FileTime::from_last_access_time(&metadata),
+                        FileTime::from_last_modification_time(&metadata),
+                    ));
+                }
             } else if entry.file_type().is_symlink() {
                 let target = std::fs::read_link(entry.path())?;
                 std::os::unix::fs::symlink(target, &dst)
                     .with_context(|| format!("while creating symlink '{}'", dst.display()))?;
+                if self.preserve_times {
+                    let metadata = std::fs::symlink_metadata(entry.path())?;
+                    filetime::set_symlink_file_times(
+                        &dst,
+                        FileTime::from_last_access_time(&metadata),
+                        FileTime::from_last_modification_time(&metadata),
+                    )
+                    .with_context(|| format!("while setting times on '{}'", dst.display()))?;
+                }
             } else if entry.file_type().is_file() {
                 let metadata = entry.metadata()?;
                 let nlink = metadata.nlink();
@@ -124,7 +439,7 @@ impl UnprivilegedDir {
                     })?;
                 } else {
                     // First occurrence of this inode (or not a hardlink) - copy the file
-                    std::fs::copy(entry.path(), &dst).with_context(|| {
+                    sparse_copy(entry.path(), &dst).with_context(|| {
                         format!(
                             "while copying file '{}' -> '{}'",
                             entry.path().display(),
@@ -141,6 +456,14 @@ impl UnprivilegedDir {
                     // remove write bits
                     mode &= !0o222;
                     std::fs::set_permissions(&dst, Permissions::from_mode(mode))?;
+                    if self.preserve_times {
+                        filetime::set_file_times(
+                            &dst,
+                            FileTime::from_last_access_time(&metadata),
+                            FileTime::from_last_modification_time(&metadata),
+                        )
+                        .with_context(|| format!("while setting times on '{}'", dst.display()))?;
+                    }
 
                     // Track this inode for future hardlinks
                     if nlink > 1 {
@@ -148,6 +471,19 @@ impl UnprivilegedDir {
                     }
                 }
             }
+            if self.manifest.is_some() {
+                let license = if entry.file_type().is_file() {
+                    detect_license(entry.path(), &entry.metadata()?)?
+                        .map(|text| intern_license(&mut license_texts, &mut license_ids, text))
+                } else {
+                    None
+                };
+                manifest_tree.insert(relpath.components(), entry.file_type().is_dir(), license);
+            }
+            if self.preserve_xattrs {
+                copy_xattrs(entry.path(), &dst, root_guard.is_some())
+                    .with_context(|| format!("while preserving xattrs on '{}'", dst.display()))?;
+            }
             std::os::unix::fs::lchown(
                 &dst,
                 root_guard
@@ -162,6 +498,14 @@ impl UnprivilegedDir {
             .with_context(|| format!("while chowning '{}'", dst.display()))?;
         }
 
+        // Now that every entry (including nested children) has been
+        // written, directory times can be set without a later child write
+        // bumping them back to "now".
+        for (dst, atime, mtime) in dir_times {
+            filetime::set_file_times(&dst, atime, mtime)
+                .with_context(|| format!("while setting times on '{}'", dst.display()))?;
+        }
+
         if let Some(base64_encoded_filenames) = &self.base64_encoded_filenames {
             std::fs::write(
                 base64_encoded_filenames,
@@ -172,14 +516,190 @@ impl UnprivilegedDir {
             .context("while writing escaped paths mapping")?;
         }
 
+        if let Some(manifest) = &self.manifest {
+            let ManifestTreeNode::Directory(children) = &manifest_tree else {
+                unreachable!("manifest_tree root is always a Directory");
+            };
+            let manifest_doc = Manifest {
+                tree: ManifestTreeNode::into_nodes(children, Path::new("")),
+                licenses: license_texts.into_iter().enumerate().collect(),
+            };
+            std::fs::write(
+                manifest,
+                serde_json::to_string_pretty(&manifest_doc)
+                    .context("while serializing provenance manifest")?
+                    .as_bytes(),
+            )
+            .context("while writing provenance manifest")?;
+        }
+
         Ok(())
     }
+
+    /// Stream `layer` into a single POSIX tar archive at `output_tar` instead
+    /// of writing out a directory tree, sidestepping Buck2's filename
+    /// restrictions entirely rather than working around them with an
+    /// escaped-path map. Long or illegal paths get a PAX extension record
+    /// (via [Builder::append_pax_extensions]) alongside a short ustar
+    /// placeholder name, and repeated hardlinks are emitted as tar hardlink
+    /// entries pointing at the first path seen for their `(dev, ino)`.
+    fn build_tar(output_tar: &Path, layer: &Path) -> Result<()> {
+        let file = File::create(output_tar)
+            .with_context(|| format!("while creating '{}'", output_tar.display()))?;
+        let mut builder = Builder::new(file);
+
+        // Key: (device, inode), Value: first tar path seen for this inode
+        let mut inode_to_path: HashMap<(u64, u64), PathBuf> = HashMap::new();
+
+        for entry in WalkDir::new(layer) {
+            let entry = entry?;
+            let relpath = entry.path().strip_prefix(layer)?;
+            if relpath == Path::new("") {
+                continue;
+            }
+
+            let mut header = Header::new_ustar();
+            let mut extensions: Vec<(String, Vec<u8>)> = Vec::new();
+
+            if entry.file_type().is_dir() {
+                header.set_entry_type(EntryType::Directory);
+                header.set_mode(0o755);
+                header.set_size(0);
+                let header_path = ensure_name_fits(&mut header, relpath, &mut extensions)?;
+                extensions.sort();
+                builder.append_pax_extensions(
+                    extensions.iter().map(|(k, v)| (k.as_str(), v.as_slice())),
+                )?;
+                builder.append_data(&mut header, &header_path, std::io::empty())?;
+            } else if entry.file_type().is_symlink() {
+                let target = std::fs::read_link(entry.path())?;
+                header.set_entry_type(EntryType::Symlink);
+                header.set_size(0);
+                let header_path = ensure_name_fits(&mut header, relpath, &mut extensions)?;
+                let link_name = ensure_link_name_fits(&mut header, &target, &mut extensions)?;
+                extensions.sort();
+                builder.append_pax_extensions(
+                    extensions.iter().map(|(k, v)| (k.as_str(), v.as_slice())),
+                )?;
+                builder.append_link(&mut header, &header_path, &link_name)?;
+            } else if entry.file_type().is_file() {
+                let metadata = entry.metadata()?;
+                let nlink = metadata.nlink();
+                let inode_key = (metadata.dev(), metadata.ino());
+                let existing_path = if nlink > 1 {
+                    inode_to_path.get(&inode_key).cloned()
+                } else {
+                    None
+                };
+
+                let mut mode = metadata.mode();
+                // preserve executable bit
+                if (mode & 0o111) != 0 {
+                    mode |= 0o111;
+                }
+                // always allow read
+                mode |= 0o444;
+                // remove write bits
+                mode &= !0o222;
+                header.set_mode(mode);
+
+                if let Some(existing_path) = existing_path {
+                    header.set_entry_type(EntryType::Link);
+                    header.set_size(0);
+                    let header_path = ensure_name_fits(&mut header, relpath, &mut extensions)?;
+                    let link_name =
+                        ensure_link_name_fits(&mut header, &existing_path, &mut extensions)?;
+                    extensions.sort();
+                    builder.append_pax_extensions(
+                        extensions.iter().map(|(k, v)| (k.as_str(), v.as_slice())),
+                    )?;
+                    builder.append_link(&mut header, &header_path, &link_name)?;
+                } else {
+                    header.set_entry_type(EntryType::Regular);
+                    header.set_size(metadata.len());
+                    let header_path = ensure_name_fits(&mut header, relpath, &mut extensions)?;
+                    extensions.sort();
+                    builder.append_pax_extensions(
+                        extensions.iter().map(|(k, v)| (k.as_str(), v.as_slice())),
+                    )?;
+                    let mut f = File::open(entry.path())
+                        .with_context(|| format!("while opening '{}'", entry.path().display()))?;
+                    builder.append_data(&mut header, &header_path, &mut f)?;
+
+                    if nlink > 1 {
+                        inode_to_path.insert(inode_key, relpath.to_owned());
+                    }
+                }
+            }
+        }
+
+        builder.finish().context("while finishing tar archive")?;
+        Ok(())
+    }
+}
+
+/// ustar's `name`/`prefix` header fields can only hold a combined 256 bytes
+/// (split across a `/` boundary) and reject bytes like embedded backslashes
+/// that Buck2's own filename rules already flag. If `path` doesn't fit,
+/// attach a PAX `path` extension record carrying the real value and return a
+/// short placeholder that's guaranteed to fit in the legacy ustar field
+/// instead.
+fn ensure_name_fits(
+    header: &mut Header,
+    path: &Path,
+    extensions: &mut Vec<(String, Vec<u8>)>,
+) -> Result<PathBuf> {
+    match header.set_path(path) {
+        Ok(()) => Ok(path.to_owned()),
+        Err(_) => {
+            extensions.push(("path".to_owned(), path.as_os_str().as_bytes().to_vec()));
+            let placeholder = pax_placeholder(path);
+            header.set_path(&placeholder)?;
+            Ok(placeholder)
+        }
+    }
+}
+
+/// Same as [ensure_name_fits], but for the ustar `linkname` field (capped at
+/// 100 bytes, no prefix split available), pushing a PAX `linkpath` record
+/// instead.
+fn ensure_link_name_fits(
+    header: &mut Header,
+    target: &Path,
+    extensions: &mut Vec<(String, Vec<u8>)>,
+) -> Result<PathBuf> {
+    match header.set_link_name(target) {
+        Ok(()) => Ok(target.to_owned()),
+        Err(_) => {
+            extensions.push((
+                "linkpath".to_owned(),
+                target.as_os_str().as_bytes().to_vec(),
+            ));
+            let placeholder = pax_placeholder(target);
+            header.set_link_name(&placeholder)?;
+            Ok(placeholder)
+        }
+    }
+}
+
+/// A short, legal ustar name to stand in for a path/linkname that overflowed
+/// the legacy fields. Any PAX-aware reader (which is all of them, by now)
+/// ignores this in favor of the extension record pushed alongside it.
+fn pax_placeholder(path: &Path) -> PathBuf {
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::Hash;
+    use std::hash::Hasher;
+
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    PathBuf::from(format!("pax-long-name-{:x}", hasher.finish()))
 }
 
 #[cfg(test)]
 mod tests {
     use std::ffi::OsStr;
     use std::fs::File;
+    use std::io::Read;
     use std::io::Write;
     use std::os::unix::ffi::OsStrExt;
     use std::os::unix::fs::MetadataExt;
@@ -214,6 +734,10 @@ mod tests {
 
         let unprivileged_dir = UnprivilegedDir {
             base64_encoded_filenames: None,
+            output_tar: None,
+            preserve_xattrs: false,
+            preserve_times: false,
+            manifest: None,
         };
         unprivileged_dir.build(&out_path, layer.path(), None)?;
 
@@ -263,6 +787,10 @@ mod tests {
 
         let unprivileged_dir = UnprivilegedDir {
             base64_encoded_filenames: Some(mapping_file.clone()),
+            output_tar: None,
+            preserve_xattrs: false,
+            preserve_times: false,
+            manifest: None,
         };
 
         unprivileged_dir.build(&out_path, layer.path(), None)?;
@@ -304,6 +832,10 @@ mod tests {
 
         let unprivileged_dir = UnprivilegedDir {
             base64_encoded_filenames: None,
+            output_tar: None,
+            preserve_xattrs: false,
+            preserve_times: false,
+            manifest: None,
         };
 
         unprivileged_dir.build(&out_path, layer.path(), None)?;
@@ -318,4 +850,268 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn build_tar_streams_an_archive_with_pax_long_names_and_hardlinks() -> Result<()> {
+        let layer = TempDir::new()?;
+        let out = TempDir::new()?;
+        let output_tar = out.path().join("output.tar");
+
+        File::create(layer.path().join("short.txt"))?.write_all(b"hello world")?;
+
+        let long_name = "a".repeat(150) + ".txt";
+        File::create(layer.path().join(&long_name))?.write_all(b"long name contents")?;
+
+        std::os::unix::fs::symlink("short.txt", layer.path().join("link.txt"))?;
+
+        let mut file = File::create(layer.path().join("original.txt"))?;
+        file.write_all(b"shared by many")?;
+        drop(file);
+        std::fs::hard_link(
+            layer.path().join("original.txt"),
+            layer.path().join("hardlink.txt"),
+        )?;
+
+        let unprivileged_dir = UnprivilegedDir {
+            base64_encoded_filenames: None,
+            output_tar: Some(output_tar.clone()),
+            preserve_xattrs: false,
+            preserve_times: false,
+            manifest: None,
+        };
+        unprivileged_dir.build(&out.path().join("unused"), layer.path(), None)?;
+
+        let mut archive = tar::Archive::new(File::open(&output_tar)?);
+        let mut contents_by_path: BTreeMap<PathBuf, String> = BTreeMap::new();
+        let mut hardlink: Option<(PathBuf, PathBuf)> = None;
+        let mut symlink_target: Option<PathBuf> = None;
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = entry.path()?.into_owned();
+            match entry.header().entry_type() {
+                EntryType::Regular => {
+                    let mut buf = String::new();
+                    entry.read_to_string(&mut buf)?;
+                    contents_by_path.insert(path, buf);
+                }
+                EntryType::Link => {
+                    let target = entry.link_name()?.map(|p| p.into_owned());
+                    hardlink = target.map(|target| (path, target));
+                }
+                EntryType::Symlink => {
+                    if path == Path::new("link.txt") {
+                        symlink_target = entry.link_name()?.map(|p| p.into_owned());
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        assert_eq!(
+            contents_by_path.get(Path::new("short.txt")),
+            Some(&"hello world".to_owned())
+        );
+        assert_eq!(
+            contents_by_path.get(Path::new(&long_name)),
+            Some(&"long name contents".to_owned())
+        );
+        assert_eq!(symlink_target, Some(PathBuf::from("short.txt")));
+        // The directory walk is unsorted, so either name may be the one
+        // written out in full; the other must be a link to it.
+        let (link_path, link_target) = hardlink.expect("a hardlink entry");
+        assert!(
+            (link_path == Path::new("hardlink.txt") && link_target == Path::new("original.txt"))
+                || (link_path == Path::new("original.txt")
+                    && link_target == Path::new("hardlink.txt"))
+        );
+        assert_eq!(
+            contents_by_path.get(&link_target),
+            Some(&"shared by many".to_owned())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn build_preserves_xattrs_when_enabled() -> Result<()> {
+        let layer = TempDir::new()?;
+        let out = TempDir::new()?;
+        let out_path = out.path().join("output");
+
+        File::create(layer.path().join("test.txt"))?.write_all(b"hello world")?;
+        xattr::set(layer.path().join("test.txt"), "user.antlir.test", b"hello")?;
+
+        let unprivileged_dir = UnprivilegedDir {
+            base64_encoded_filenames: None,
+            output_tar: None,
+            preserve_xattrs: true,
+            preserve_times: false,
+            manifest: None,
+        };
+        unprivileged_dir.build(&out_path, layer.path(), None)?;
+
+        assert_eq!(
+            xattr::get(out_path.join("test.txt"), "user.antlir.test")?,
+            Some(b"hello".to_vec())
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn build_preserves_times_when_enabled() -> Result<()> {
+        let layer = TempDir::new()?;
+        let out = TempDir::new()?;
+        let out_path = out.path().join("output");
+
+        std::fs::create_dir(layer.path().join("subdir"))?;
+        File::create(layer.path().join("subdir").join("test.txt"))?.write_all(b"hello world")?;
+        std::os::unix::fs::symlink("test.txt", layer.path().join("subdir").join("link.txt"))?;
+
+        let old_time = FileTime::from_unix_time(1_000_000, 0);
+        filetime::set_file_times(layer.path().join("subdir").join("test.txt"), old_time, old_time)?;
+        filetime::set_symlink_file_times(
+            layer.path().join("subdir").join("link.txt"),
+            old_time,
+            old_time,
+        )?;
+        filetime::set_file_times(layer.path().join("subdir"), old_time, old_time)?;
+
+        let unprivileged_dir = UnprivilegedDir {
+            base64_encoded_filenames: None,
+            output_tar: None,
+            preserve_xattrs: false,
+            preserve_times: true,
+            manifest: None,
+        };
+        unprivileged_dir.build(&out_path, layer.path(), None)?;
+
+        assert_eq!(
+            FileTime::from_last_modification_time(&std::fs::metadata(
+                out_path.join("subdir").join("test.txt")
+            )?),
+            old_time
+        );
+        assert_eq!(
+            FileTime::from_last_modification_time(&std::fs::symlink_metadata(
+                out_path.join("subdir").join("link.txt")
+            )?),
+            old_time
+        );
+        assert_eq!(
+            FileTime::from_last_modification_time(&std::fs::metadata(out_path.join("subdir"))?),
+            old_time
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn build_copies_a_large_sparse_file_without_corrupting_it() -> Result<()> {
+        let layer = TempDir::new()?;
+        let out = TempDir::new()?;
+        let out_path = out.path().join("output");
+
+        // A file comfortably over SPARSE_COPY_THRESHOLD, with data at the
+        // start and end and a hole in between.
+        let len = SPARSE_COPY_THRESHOLD * 2;
+        let head: &[u8] = b"start of the file";
+        let tail: &[u8] = b"the end of the file";
+        let mut file = File::create(layer.path().join("sparse.img"))?;
+        file.write_all(head)?;
+        file.set_len(len)?;
+        file.seek(SeekFrom::Start(len - tail.len() as u64))?;
+        file.write_all(tail)?;
+        drop(file);
+
+        let unprivileged_dir = UnprivilegedDir {
+            base64_encoded_filenames: None,
+            output_tar: None,
+            preserve_xattrs: false,
+            preserve_times: false,
+            manifest: None,
+        };
+        unprivileged_dir.build(&out_path, layer.path(), None)?;
+
+        let copied = out_path.join("sparse.img");
+        assert_eq!(std::fs::metadata(&copied)?.len(), len);
+        let mut contents = Vec::new();
+        File::open(&copied)?.read_to_end(&mut contents)?;
+        assert_eq!(&contents[..head.len()], head);
+        assert_eq!(&contents[(len - tail.len() as u64) as usize..], tail);
+        assert!(
+            contents[head.len()..(len - tail.len() as u64) as usize]
+                .iter()
+                .all(|&b| b == 0)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn build_emits_a_deduplicated_license_manifest() -> Result<()> {
+        let layer = TempDir::new()?;
+        let out = TempDir::new()?;
+        let out_path = out.path().join("output");
+        let manifest_path = out.path().join("manifest.json");
+
+        std::fs::create_dir(layer.path().join("pkg"))?;
+        File::create(layer.path().join("pkg/LICENSE"))?.write_all(b"MIT License text")?;
+        File::create(layer.path().join("pkg/main.rs"))?
+            .write_all(b"// SPDX-License-Identifier: MIT\nfn main() {}")?;
+        std::fs::create_dir(layer.path().join("pkg/vendor"))?;
+        File::create(layer.path().join("pkg/vendor/lib.rs"))?
+            .write_all(b"// SPDX-License-Identifier: MIT\nfn lib() {}")?;
+        File::create(layer.path().join("unlicensed.txt"))?.write_all(b"no license marker here")?;
+
+        let unprivileged_dir = UnprivilegedDir {
+            base64_encoded_filenames: None,
+            output_tar: None,
+            preserve_xattrs: false,
+            preserve_times: false,
+            manifest: Some(manifest_path.clone()),
+        };
+        unprivileged_dir.build(&out_path, layer.path(), None)?;
+
+        let manifest: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&manifest_path)?)?;
+        let licenses = manifest["licenses"].as_object().expect("licenses table");
+        // "pkg/LICENSE" is interned verbatim; "main.rs" and "vendor/lib.rs"
+        // share one interned "MIT" id from their SPDX tags, and the
+        // unlicensed file contributes no entry at all.
+        assert_eq!(licenses.len(), 2);
+        let mit_id: u64 = licenses
+            .iter()
+            .find_map(|(id, text)| (text == "MIT").then(|| id.parse().unwrap()))
+            .expect("an interned 'MIT' entry");
+
+        let tree = manifest["tree"].as_array().expect("tree array");
+        let pkg = tree
+            .iter()
+            .find(|node| node["directory"] == "pkg")
+            .expect("pkg directory node");
+        let pkg_children = pkg["children"].as_array().expect("pkg children");
+
+        let main_rs = pkg_children
+            .iter()
+            .find(|node| node["file"] == "pkg/main.rs")
+            .expect("pkg/main.rs node");
+        assert_eq!(main_rs["license"].as_u64().unwrap(), mit_id);
+
+        let vendor = pkg_children
+            .iter()
+            .find(|node| node["directory"] == "pkg/vendor")
+            .expect("pkg/vendor directory node");
+        let lib_rs = vendor["children"][0].clone();
+        assert_eq!(lib_rs["file"], "pkg/vendor/lib.rs");
+        assert_eq!(lib_rs["license"].as_u64().unwrap(), mit_id);
+
+        let unlicensed = tree
+            .iter()
+            .find(|node| node["file"] == "unlicensed.txt")
+            .expect("unlicensed.txt node");
+        assert!(unlicensed["license"].is_null());
+
+        Ok(())
+    }
 }
@@ -0,0 +1,144 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Content-address the *semantic* content of a sendstream, as opposed to
+//! [digest]'s per-path hash of the bytes it writes: two streams that
+//! describe the same sequence of operations hash identically here even if
+//! whatever produced them framed, chunked, or ordered the wire bytes
+//! slightly differently -- which is what a build cache wants when deciding
+//! whether it's already seen "this" stream.
+//!
+//! Each command is serialized to JSON, tagged with its
+//! [CommandType][wire::CommandType] since [Command] itself is
+//! `#[serde(untagged)]` and several command kinds (e.g. [Command::Mkfifo]
+//! and [Command::Mksock]) share the same field shape and would otherwise
+//! collide, then written into a single SHA-256 hash one newline-delimited
+//! line per command, so a hash boundary can never be confused with a JSON
+//! value boundary. Object keys are sorted and re-serialized by hand (see
+//! [write_canonical]) rather than relying on `serde_json`'s own map
+//! ordering, so the result doesn't depend on whether `preserve_order` is
+//! enabled somewhere else in the dependency tree.
+
+use std::fmt::Write as _;
+
+use serde::Serialize;
+use sha2::Digest;
+use sha2::Sha256;
+
+use crate::wire;
+use crate::Command;
+
+/// `command` tagged with its [wire::CommandType], so canonicalizing an
+/// untagged [Command] can't conflate two differently-typed commands that
+/// happen to serialize to the same JSON shape.
+#[derive(Serialize)]
+struct Tagged<'a> {
+    #[serde(rename = "type")]
+    ty: wire::CommandType,
+    #[serde(rename = "command")]
+    command: &'a Command,
+}
+
+/// Serialize `value` as compact JSON with object keys sorted, appending to
+/// `out`. This is hand-rolled (rather than just calling `serde_json`'s own
+/// `to_string`) so the result is canonical regardless of whichever `Map`
+/// implementation `serde_json` happens to be compiled with elsewhere in the
+/// dependency tree.
+fn write_canonical(value: &serde_json::Value, out: &mut String) {
+    match value {
+        serde_json::Value::Null => out.push_str("null"),
+        serde_json::Value::Bool(b) => {
+            out.push_str(if *b { "true" } else { "false" });
+        }
+        serde_json::Value::Number(n) => {
+            // infallible: `out` is a `String`, which never errors on write
+            let _ = write!(out, "{n}");
+        }
+        serde_json::Value::String(s) => write_json_string(s, out),
+        serde_json::Value::Array(items) => {
+            out.push('[');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_canonical(item, out);
+            }
+            out.push(']');
+        }
+        serde_json::Value::Object(map) => {
+            let mut entries: Vec<(&String, &serde_json::Value)> = map.iter().collect();
+            entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+            out.push('{');
+            for (i, (key, val)) in entries.into_iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_json_string(key, out);
+                out.push(':');
+                write_canonical(val, out);
+            }
+            out.push('}');
+        }
+    }
+}
+
+fn write_json_string(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                // infallible: `out` is a `String`, which never errors on write
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// Hash the semantic content of `commands`: a stable, deterministic digest
+/// that's the same for two command sequences describing the same
+/// operations, regardless of incidental framing in whatever stream they
+/// came from. See the [module](self) docs for exactly how.
+pub fn canonical_hash(commands: &[Command]) -> crate::Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    let mut line = String::new();
+    for command in commands {
+        let tagged = Tagged {
+            ty: command.command_type(),
+            command,
+        };
+        let value =
+            serde_json::to_value(&tagged).map_err(|e| crate::Error::Unparsable(e.to_string()))?;
+        line.clear();
+        write_canonical(&value, &mut line);
+        hasher.update(line.as_bytes());
+        hasher.update(b"\n");
+    }
+    Ok(hasher.finalize().into())
+}
+
+/// Parse `reader` and hash its commands' semantic content in one pass. See
+/// [canonical_hash].
+pub async fn digest_stream<R>(reader: R) -> crate::Result<[u8; 32]>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    use futures::StreamExt;
+
+    let mut commands = Vec::new();
+    let mut stream = Box::pin(wire::parse(reader));
+    while let Some(command) = stream.next().await {
+        commands.push(command?);
+    }
+    canonical_hash(&commands)
+}
@@ -0,0 +1,272 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! An index over a parsed sendstream that answers "which commands touch path
+//! X" and "what command is at offset N" in O(log n), without re-parsing or
+//! holding the whole stream in memory. This is the same sorted-array index
+//! pxar uses to make its archives randomly accessible: build it once during a
+//! parse pass, then binary search it as many times as needed.
+
+use std::collections::HashMap;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::path::PathBuf;
+
+use bytes::Bytes;
+use nom::Parser;
+
+#[cfg(feature = "serde")]
+use serde::Deserialize;
+#[cfg(feature = "serde")]
+use serde::Serialize;
+
+use crate::Command;
+use crate::wire;
+use crate::wire::cmd::CommandParseError;
+
+/// One parsed command as recorded in a [Catalog]: where it is in the stream,
+/// what kind of command it is, and the path it primarily operates on (if
+/// any -- e.g. [Command::End] touches no path). `path` is the command's
+/// *final* path, after resolving any `TemporaryPath` placeholder through
+/// whatever later `Rename` eventually moved it -- see [Catalog::build].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct CatalogEntry {
+    offset: u64,
+    command_type: wire::CommandType,
+    path: Option<PathBuf>,
+}
+
+impl CatalogEntry {
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    pub fn command_type(&self) -> wire::CommandType {
+        self.command_type
+    }
+
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+}
+
+/// A sorted index over a parsed sendstream. See the [module](self) docs.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct Catalog {
+    /// All entries, in stream order (i.e. sorted by offset).
+    entries: Vec<CatalogEntry>,
+    /// `(path, index into entries)`, sorted by path so that
+    /// [Catalog::commands_for_path] can binary search instead of scanning.
+    by_path: Vec<(PathBuf, usize)>,
+    /// The sendstream version commands were encoded at, needed to reparse an
+    /// individual command from raw bytes via [Catalog::commands_for].
+    sendstream_version: u32,
+}
+
+impl Catalog {
+    /// Build a catalog from a sequence of already-parsed commands and the
+    /// offset each one started at, as produced by [wire::parse_with_offsets].
+    /// `commands` is expected to be in stream order (increasing offset).
+    ///
+    /// A sendstream is emitted in inode order, so a file or directory is
+    /// often created under an opaque `TemporaryPath` placeholder before a
+    /// later `Rename` moves it to its real location. Indexing by the
+    /// as-emitted path would scatter a single file's commands across both
+    /// its temporary and final names, so this replays every `Rename` first
+    /// and records each command under the path it ends up at once the whole
+    /// stream has been applied. `Link` and `Unlink` don't change what a path
+    /// resolves to -- they only add or remove a reference at whatever name
+    /// was already live at that point -- so only `Rename` needs replaying.
+    /// Only exact paths are looked up: a command on a path beneath a renamed
+    /// directory is recorded under the path it was emitted with.
+    pub fn build(
+        commands: impl IntoIterator<Item = (u64, Command)>,
+        sendstream_version: u32,
+    ) -> Self {
+        let commands: Vec<(u64, Command)> = commands.into_iter().collect();
+
+        // Walk the renames backward: by the time we reach `from`, `to` has
+        // already been resolved to wherever it (transitively) ends up, so
+        // `from` can just inherit that.
+        let mut final_path: HashMap<PathBuf, PathBuf> = HashMap::new();
+        for (_, command) in commands.iter().rev() {
+            if let Command::Rename(rename) = command {
+                let to = final_path
+                    .get(rename.to())
+                    .cloned()
+                    .unwrap_or_else(|| rename.to().to_path_buf());
+                final_path.insert(rename.from().to_path_buf(), to);
+            }
+        }
+        let resolve = |path: &Path| -> PathBuf {
+            final_path
+                .get(path)
+                .cloned()
+                .unwrap_or_else(|| path.to_owned())
+        };
+
+        let entries: Vec<CatalogEntry> = commands
+            .into_iter()
+            .map(|(offset, command)| CatalogEntry {
+                command_type: command.command_type(),
+                path: primary_path(&command).map(resolve),
+                offset,
+            })
+            .collect();
+        let mut by_path: Vec<(PathBuf, usize)> = entries
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, entry)| entry.path.clone().map(|path| (path, idx)))
+            .collect();
+        by_path.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Self {
+            entries,
+            by_path,
+            sendstream_version,
+        }
+    }
+
+    /// All commands whose final path is exactly `path`, in stream order.
+    pub fn commands_for_path<'a>(
+        &'a self,
+        path: &Path,
+    ) -> impl Iterator<Item = &'a CatalogEntry> {
+        let start = self.by_path.partition_point(|(p, _)| p.as_path() < path);
+        self.by_path[start..]
+            .iter()
+            .take_while(move |(p, _)| p == path)
+            .map(move |(_, idx)| &self.entries[*idx])
+    }
+
+    /// The command whose header begins at exactly `offset`, if any.
+    pub fn command_at(&self, offset: u64) -> Option<&CatalogEntry> {
+        let idx = self
+            .entries
+            .binary_search_by_key(&offset, |entry| entry.offset)
+            .ok()?;
+        self.entries.get(idx)
+    }
+
+    /// All commands whose final path is exactly `path`, reparsed one at a
+    /// time by seeking `reader` to each recorded offset and reading just
+    /// that command's bytes, rather than holding the whole stream in memory.
+    pub fn commands_for<'a, R: Read + Seek>(
+        &'a self,
+        reader: &'a mut R,
+        path: &Path,
+    ) -> impl Iterator<Item = crate::Result<Command>> + 'a {
+        let version = self.sendstream_version;
+        let offsets: Vec<u64> = self
+            .commands_for_path(path)
+            .map(CatalogEntry::offset)
+            .collect();
+        offsets
+            .into_iter()
+            .map(move |offset| parse_command_at(&mut *reader, offset, version))
+    }
+}
+
+/// Parse `reader` in a single pass, building a [Catalog] alongside the parse
+/// instead of requiring a second pass over the stream afterward.
+pub async fn build_catalog<R>(reader: R) -> crate::Result<Catalog>
+where
+    R: tokio::io::AsyncRead + Unpin,
+{
+    use futures::StreamExt;
+
+    let mut commands = Vec::new();
+    let mut sendstream_version = 0;
+    let mut stream = Box::pin(wire::parse_with_version(reader));
+    while let Some(item) = stream.next().await {
+        match item? {
+            wire::VersionedItem::Version(version) => sendstream_version = version,
+            wire::VersionedItem::Command(offset, command) => commands.push((offset, command)),
+        }
+    }
+    Ok(Catalog::build(commands, sendstream_version))
+}
+
+/// Read and parse exactly one command out of `reader`, which must be seeked
+/// to exactly a command header's start. Reads just the 10-byte header first
+/// to learn the command's length, then exactly that many more bytes -- no
+/// speculative buffering the way the streaming decoder in [wire::framed]
+/// needs to support partial reads from an async source.
+fn parse_command_at<R: Read + Seek>(
+    reader: &mut R,
+    offset: u64,
+    sendstream_version: u32,
+) -> crate::Result<Command> {
+    reader.seek(SeekFrom::Start(offset))?;
+    let mut header = [0u8; 10];
+    reader.read_exact(&mut header)?;
+    let len = u32::from_le_bytes(header[0..4].try_into().expect("exactly 4 bytes")) as usize;
+
+    let mut buf = header.to_vec();
+    buf.resize(10 + len, 0);
+    reader.read_exact(&mut buf[10..])?;
+
+    let input: wire::NomBytes = Bytes::from(buf).into();
+    match Command::parser(sendstream_version, true).parse(input) {
+        Ok((_remaining, command)) => Ok(command),
+        Err(
+            nom::Err::Error(CommandParseError::ChecksumMismatch {
+                command_type,
+                expected,
+                actual,
+            })
+            | nom::Err::Failure(CommandParseError::ChecksumMismatch {
+                command_type,
+                expected,
+                actual,
+            }),
+        ) => Err(crate::Error::ChecksumMismatch {
+            command_type: command_type.into(),
+            expected,
+            actual,
+        }),
+        Err(e) => Err(crate::Error::Unparsable(e.to_string())),
+    }
+}
+
+/// The path each command variant primarily operates on, used to index a
+/// [Catalog] by path. For commands with both a source and destination path
+/// (e.g. [Command::Clone], [Command::Rename]), the destination is treated as
+/// primary since that's the path that ends up existing in the result.
+pub(crate) fn primary_path(command: &Command) -> Option<&Path> {
+    match command {
+        Command::Chmod(c) => Some(c.path()),
+        Command::Chown(c) => Some(c.path()),
+        Command::Clone(c) => Some(c.dst_path()),
+        Command::EnableVerity(c) => Some(c.path()),
+        Command::EncodedWrite(c) => Some(c.path()),
+        Command::End => None,
+        Command::Fallocate(c) => Some(c.path()),
+        Command::Fileattr(c) => Some(c.path()),
+        Command::Link(c) => Some(c.link_name().as_ref()),
+        Command::Mkdir(c) => Some(c.path().as_ref()),
+        Command::Mkfifo(c) => Some(c.path().as_ref()),
+        Command::Mkfile(c) => Some(c.path().as_ref()),
+        Command::Mknod(c) => Some(c.path().as_ref()),
+        Command::Mksock(c) => Some(c.path().as_ref()),
+        Command::RemoveXattr(c) => Some(c.path()),
+        Command::Rename(c) => Some(c.to()),
+        Command::Rmdir(c) => Some(c.path()),
+        Command::SetXattr(c) => Some(c.path()),
+        Command::Snapshot(c) => Some(c.path()),
+        Command::Subvol(c) => Some(c.path()),
+        Command::Symlink(c) => Some(c.link_name()),
+        Command::Truncate(c) => Some(c.path()),
+        Command::Unlink(c) => Some(c.path()),
+        Command::UpdateExtent(c) => Some(c.path()),
+        Command::Utimes(c) => Some(c.path()),
+        Command::Write(c) => Some(c.path()),
+    }
+}
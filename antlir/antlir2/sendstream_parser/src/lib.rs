@@ -16,6 +16,7 @@
 
 use std::borrow::Cow;
 use std::ffi::OsStr;
+use std::io::Read as _;
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::prelude::PermissionsExt;
 use std::path::Path;
@@ -30,11 +31,23 @@ use nix::unistd::Uid;
 #[cfg(feature = "serde")]
 use serde::Deserialize;
 #[cfg(feature = "serde")]
+use serde::Deserializer;
+#[cfg(feature = "serde")]
 use serde::Serialize;
+#[cfg(feature = "serde")]
+use serde::Serializer;
 use uuid::Uuid;
 
+#[cfg(feature = "serde")]
+pub mod canonical;
+pub mod catalog;
+pub mod digest;
+pub mod receiver;
 #[cfg(feature = "serde")]
 mod ser;
+#[cfg(feature = "server")]
+pub mod server;
+pub mod upgrade;
 pub mod wire;
 
 #[derive(Debug, thiserror::Error)]
@@ -49,6 +62,17 @@ pub enum Error {
     IO(#[from] std::io::Error),
     #[error("Sendstream contains unparsable bytes: {0}")]
     Unparsable(String),
+    #[error(
+        "command (type {command_type}) has a bad checksum: expected {expected:#010x}, got {actual:#010x}"
+    )]
+    ChecksumMismatch {
+        command_type: u16,
+        expected: u32,
+        actual: u32,
+    },
+    #[cfg(feature = "server")]
+    #[error("http server error: {0}")]
+    Server(String),
 }
 
 pub type Result<R> = std::result::Result<R, Error>;
@@ -60,7 +84,11 @@ pub enum Command {
     Chmod(Chmod),
     Chown(Chown),
     Clone(Clone),
+    EnableVerity(EnableVerity),
+    EncodedWrite(EncodedWrite),
     End,
+    Fallocate(Fallocate),
+    Fileattr(Fileattr),
     Link(Link),
     Mkdir(Mkdir),
     Mkfifo(Mkfifo),
@@ -82,15 +110,19 @@ pub enum Command {
 }
 
 impl Command {
-    /// Exposed for tests to ensure that the demo sendstream is exhaustive and
-    /// exercises all commands
-    #[cfg(test)]
-    pub(crate) fn command_type(&self) -> wire::cmd::CommandType {
+    /// The wire [CommandType] of this command, e.g. for indexing or
+    /// filtering a parsed stream without matching on every [Command]
+    /// variant.
+    pub fn command_type(&self) -> wire::CommandType {
         match self {
             Self::Chmod(_) => wire::cmd::CommandType::Chmod,
             Self::Chown(_) => wire::cmd::CommandType::Chown,
             Self::Clone(_) => wire::cmd::CommandType::Clone,
+            Self::EnableVerity(_) => wire::cmd::CommandType::EnableVerity,
+            Self::EncodedWrite(_) => wire::cmd::CommandType::EncodedWrite,
             Self::End => wire::cmd::CommandType::End,
+            Self::Fallocate(_) => wire::cmd::CommandType::Fallocate,
+            Self::Fileattr(_) => wire::cmd::CommandType::Fileattr,
             Self::Link(_) => wire::cmd::CommandType::Link,
             Self::Mkdir(_) => wire::cmd::CommandType::Mkdir,
             Self::Mkfifo(_) => wire::cmd::CommandType::Mkfifo,
@@ -152,8 +184,6 @@ macro_rules! getters {
 }
 
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord)]
-#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
-#[cfg_attr(feature = "serde", serde(transparent))]
 pub struct BytesPath(Bytes);
 
 impl AsRef<Path> for BytesPath {
@@ -162,6 +192,20 @@ impl AsRef<Path> for BytesPath {
     }
 }
 
+#[cfg(feature = "serde")]
+impl Serialize for BytesPath {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        ser::serialize_bytes(&self.0, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for BytesPath {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        ser::deserialize_bytes(deserializer).map(Self)
+    }
+}
+
 impl Deref for BytesPath {
     type Target = Path;
 
@@ -445,6 +489,20 @@ impl Deref for XattrName {
     }
 }
 
+#[cfg(feature = "serde")]
+impl Serialize for XattrName {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        ser::serialize_bytes(&self.0, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for XattrName {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        ser::deserialize_bytes(deserializer).map(Self)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, AsRef, From)]
 #[as_ref(forward)]
 #[from(forward)]
@@ -466,6 +524,20 @@ impl Deref for XattrData {
     }
 }
 
+#[cfg(feature = "serde")]
+impl Serialize for XattrData {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        ser::serialize_bytes(&self.0, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for XattrData {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        ser::deserialize_bytes(deserializer).map(Self)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 #[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 pub struct SetXattr {
@@ -530,6 +602,31 @@ macro_rules! time_alias {
         #[as_ref(forward)]
         #[repr(transparent)]
         pub struct $a(std::time::SystemTime);
+
+        impl $a {
+            /// Seconds since the Unix epoch, same as the `sec` half of the
+            /// wire timespec this was parsed from -- mirrors `st_mtime` and
+            /// friends on unix. Sendstreams can't encode a time before the
+            /// epoch (see `write_timespec`), so this clamps the same way.
+            pub fn secs(&self) -> i64 {
+                self.0
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap_or(std::time::Duration::ZERO)
+                    .as_secs() as i64
+            }
+
+            /// The sub-second nanosecond component, always in
+            /// `[0, 1_000_000_000)` -- mirrors `st_mtime_nsec` and friends
+            /// on unix. [SystemTime][std::time::SystemTime] doesn't lose
+            /// this precision internally, so it round-trips exactly from
+            /// the wire `nsec` field through to here.
+            pub fn nsecs(&self) -> u32 {
+                self.0
+                    .duration_since(std::time::UNIX_EPOCH)
+                    .unwrap_or(std::time::Duration::ZERO)
+                    .subsec_nanos()
+            }
+        }
     };
 }
 
@@ -604,6 +701,20 @@ impl std::fmt::Debug for Data {
     }
 }
 
+#[cfg(feature = "serde")]
+impl Serialize for Data {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        ser::serialize_bytes(&self.0, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for Data {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        ser::deserialize_bytes(deserializer).map(Self)
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 #[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
 pub struct Write {
@@ -614,6 +725,243 @@ pub struct Write {
 from_cmd!(Write);
 getters! {Write, [(path, Path, borrow), (offset, FileOffset, copy), (data, Data, borrow)]}
 
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, AsRef, Deref)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct UnencodedFileLen(u64);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, AsRef, Deref)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct UnencodedLen(u64);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, AsRef, Deref)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct UnencodedOffset(u64);
+
+/// `BTRFS_ENCODED_IO_COMPRESSION_*` from linux/fs/btrfs/ioctl.h. Unrecognized
+/// values round-trip through [Compression::Unknown] rather than failing to
+/// parse, since new algorithms are occasionally added.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, num_enum::FromPrimitive, num_enum::IntoPrimitive)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[repr(u32)]
+pub enum Compression {
+    None = 0,
+    Zlib = 1,
+    Zstd = 2,
+    Lzo = 3,
+    #[num_enum(catch_all)]
+    Unknown(u32),
+}
+
+impl Compression {
+    pub(crate) fn from_u32(u: u32) -> Self {
+        <Self as num_enum::FromPrimitive>::from_primitive(u)
+    }
+}
+
+/// `BTRFS_ENCODED_IO_ENCRYPTION_*` from linux/fs/btrfs/ioctl.h.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, num_enum::FromPrimitive, num_enum::IntoPrimitive)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[repr(u32)]
+pub enum Encryption {
+    None = 0,
+    #[num_enum(catch_all)]
+    Unknown(u32),
+}
+
+impl Encryption {
+    pub(crate) fn from_u32(u: u32) -> Self {
+        <Self as num_enum::FromPrimitive>::from_primitive(u)
+    }
+}
+
+/// The send v2 counterpart to [Write] used for `btrfs send --compressed-data`
+/// streams: instead of plain file bytes, `data` is `unencoded_len` bytes of
+/// the original file run through `compression` (and, if `encryption` is set,
+/// encrypted afterward -- something this parser has no key material to
+/// undo). See [EncodedWrite::decode_data] to recover the real file bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct EncodedWrite {
+    pub(crate) path: BytesPath,
+    pub(crate) offset: FileOffset,
+    pub(crate) unencoded_file_len: UnencodedFileLen,
+    pub(crate) unencoded_len: UnencodedLen,
+    pub(crate) unencoded_offset: UnencodedOffset,
+    pub(crate) compression: Compression,
+    pub(crate) encryption: Option<Encryption>,
+    pub(crate) data: Data,
+}
+from_cmd!(EncodedWrite);
+getters! {EncodedWrite, [
+    (path, Path, borrow),
+    (offset, FileOffset, copy),
+    (unencoded_file_len, UnencodedFileLen, copy),
+    (unencoded_len, UnencodedLen, copy),
+    (unencoded_offset, UnencodedOffset, copy),
+    (compression, Compression, copy),
+    (data, Data, borrow)
+]}
+
+impl EncodedWrite {
+    pub fn encryption(&self) -> Option<Encryption> {
+        self.encryption
+    }
+
+    /// Recover the real file bytes this write represents: inflate `data`
+    /// back out to `unencoded_len` bytes per `compression`. Errors out for
+    /// encrypted blocks (no key material to undo) or compression algorithms
+    /// this parser doesn't know how to invert.
+    pub fn decode_data(&self) -> Result<Bytes> {
+        if !matches!(self.encryption, None | Some(Encryption::None)) {
+            return Err(Error::Unparsable(format!(
+                "cannot decode an encrypted EncodedWrite ({:?})",
+                self.encryption
+            )));
+        }
+        let mut out = Vec::with_capacity(self.unencoded_len.0 as usize);
+        match self.compression {
+            Compression::None => out.extend_from_slice(self.data.as_slice()),
+            Compression::Zlib => {
+                flate2::read::ZlibDecoder::new(self.data.as_slice()).read_to_end(&mut out)?;
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd => {
+                zstd::stream::read::Decoder::new(self.data.as_slice())?.read_to_end(&mut out)?;
+            }
+            #[cfg(feature = "lzo")]
+            Compression::Lzo => out = decode_lzo_segments(self.data.as_slice())?,
+            other => {
+                return Err(Error::Unparsable(format!(
+                    "don't know how to decode compression {other:?}"
+                )));
+            }
+        }
+        Ok(out.into())
+    }
+}
+
+/// btrfs doesn't LZO-compress an extent as a single stream: the kernel
+/// splits it into (up to) 4KiB pages and compresses each independently, so
+/// it can decompress a single page without the whole extent. The wire
+/// format is a 4-byte LE total compressed length, followed by one or more
+/// segments, each a 4-byte LE segment length followed by that many bytes of
+/// raw LZO1X-1 output for one page. See `fs/btrfs/lzo.c` in the kernel.
+#[cfg(feature = "lzo")]
+fn decode_lzo_segments(data: &[u8]) -> Result<Vec<u8>> {
+    let total_len = data
+        .get(0..4)
+        .map(|b| u32::from_le_bytes(b.try_into().expect("exactly 4 bytes")) as usize)
+        .ok_or_else(|| Error::Unparsable("truncated lzo header".to_owned()))?;
+    let end = 4 + total_len.min(data.len().saturating_sub(4));
+
+    let mut out = Vec::new();
+    let mut pos = 4;
+    while pos + 4 <= end {
+        let seg_len =
+            u32::from_le_bytes(data[pos..pos + 4].try_into().expect("exactly 4 bytes")) as usize;
+        pos += 4;
+        let seg = data
+            .get(pos..pos + seg_len)
+            .ok_or_else(|| Error::Unparsable("truncated lzo segment".to_owned()))?;
+        out.extend_from_slice(
+            &minilzo::decompress(seg, 4096)
+                .map_err(|e| Error::Unparsable(format!("failed to decompress lzo segment: {e}")))?,
+        );
+        pos += seg_len;
+    }
+    Ok(out)
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, AsRef, Deref)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct FallocateMode(u32);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct Fallocate {
+    pub(crate) path: BytesPath,
+    pub(crate) mode: FallocateMode,
+    pub(crate) offset: FileOffset,
+    pub(crate) len: u64,
+}
+from_cmd!(Fallocate);
+getters! {Fallocate, [(path, Path, borrow), (mode, FallocateMode, copy), (offset, FileOffset, copy), (len, u64, copy)]}
+
+/// Inode attribute flags (the `FS_*_FL` bits from `ioctl(FS_IOC_GETFLAGS)`),
+/// carried verbatim rather than decoded since the set of valid bits depends
+/// on the destination filesystem.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, AsRef, Deref)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct InodeFlags(u32);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct Fileattr {
+    pub(crate) path: BytesPath,
+    pub(crate) flags: InodeFlags,
+}
+from_cmd!(Fileattr);
+getters! {Fileattr, [(path, Path, borrow), (flags, InodeFlags, copy)]}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, AsRef, Deref)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct VerityAlgorithm(u8);
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, AsRef, Deref)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
+pub struct VerityBlockSize(u32);
+
+#[derive(Debug, Clone, PartialEq, Eq, AsRef, From)]
+#[as_ref(forward)]
+#[from(forward)]
+pub struct VeritySalt(Bytes);
+
+impl VeritySalt {
+    #[inline]
+    pub fn as_slice(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, AsRef, From)]
+#[as_ref(forward)]
+#[from(forward)]
+pub struct VeritySignature(Bytes);
+
+impl VeritySignature {
+    #[inline]
+    pub fn as_slice(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// `fs-verity` metadata attached to a file, as emitted by `btrfs send`'s send
+/// v2 stream when the source file has verity enabled.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Deserialize, Serialize))]
+pub struct EnableVerity {
+    pub(crate) path: BytesPath,
+    pub(crate) algorithm: VerityAlgorithm,
+    pub(crate) block_size: VerityBlockSize,
+    pub(crate) salt: VeritySalt,
+    pub(crate) signature: VeritySignature,
+}
+from_cmd!(EnableVerity);
+getters! {EnableVerity, [
+    (path, Path, borrow),
+    (algorithm, VerityAlgorithm, copy),
+    (block_size, VerityBlockSize, copy),
+    (salt, VeritySalt, borrow),
+    (signature, VeritySignature, borrow)
+]}
+
 #[allow(clippy::expect_used)]
 #[cfg(test)]
 mod tests {
@@ -0,0 +1,449 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! A software implementation of `btrfs receive`: fold a parsed [Command]
+//! stream into an in-memory [Tree] instead of applying it to a real btrfs
+//! mount, useful for diffing two snapshots or verifying a stream's contents
+//! in a sandbox that doesn't have one. Because the stream is emitted in
+//! inode order, a node usually first appears under a [crate::TemporaryPath]
+//! placeholder and is moved to its real location by a later [crate::Rename]
+//! -- see [Receiver::apply]'s handling of that for the core of the
+//! algorithm.
+
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+
+use bytes::Bytes;
+use nix::sys::stat::SFlag;
+use nix::unistd::Gid;
+use nix::unistd::Uid;
+use uuid::Uuid;
+
+use crate::Atime;
+use crate::Command;
+use crate::Ctime;
+use crate::Ctransid;
+use crate::Mode;
+use crate::Mtime;
+use crate::Rdev;
+use crate::XattrData;
+use crate::XattrName;
+
+/// One filesystem entry as reconstructed so far. `mode`/`uid`/`gid`/times
+/// are `None` until the corresponding `Chmod`/`Chown`/`Utimes` command has
+/// been applied -- a sendstream doesn't necessarily set all of them for
+/// every entry (e.g. a `Mkfifo` with no later `Chmod` just keeps the
+/// default permissions the kernel gave it).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Entry {
+    kind: EntryKind,
+    mode: Option<Mode>,
+    uid: Option<Uid>,
+    gid: Option<Gid>,
+    xattrs: BTreeMap<XattrName, XattrData>,
+    atime: Option<Atime>,
+    mtime: Option<Mtime>,
+    ctime: Option<Ctime>,
+}
+
+impl Entry {
+    pub fn kind(&self) -> &EntryKind {
+        &self.kind
+    }
+
+    pub fn mode(&self) -> Option<Mode> {
+        self.mode
+    }
+
+    pub fn uid(&self) -> Option<Uid> {
+        self.uid
+    }
+
+    pub fn gid(&self) -> Option<Gid> {
+        self.gid
+    }
+
+    pub fn xattrs(&self) -> &BTreeMap<XattrName, XattrData> {
+        &self.xattrs
+    }
+
+    pub fn atime(&self) -> Option<Atime> {
+        self.atime
+    }
+
+    pub fn mtime(&self) -> Option<Mtime> {
+        self.mtime
+    }
+
+    pub fn ctime(&self) -> Option<Ctime> {
+        self.ctime
+    }
+}
+
+/// What kind of filesystem object an [Entry] is, plus whatever content that
+/// kind carries -- a directory has none, a file has its reconstructed
+/// bytes, a symlink has its target, and the device kinds have their `rdev`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EntryKind {
+    Directory,
+    File(Bytes),
+    Symlink(PathBuf),
+    Fifo,
+    Socket,
+    CharDevice(Rdev),
+    BlockDevice(Rdev),
+}
+
+/// The reconstructed filesystem [Receiver::finish] produces: every entry
+/// that existed at the end of the stream, keyed by its final path. Iterates
+/// in path order, so walking it top-down (e.g. to mirror it onto a real
+/// directory) never visits a child before its parent.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Tree {
+    entries: BTreeMap<PathBuf, Entry>,
+}
+
+impl Tree {
+    pub fn get(&self, path: &Path) -> Option<&Entry> {
+        self.entries.get(path)
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (&Path, &Entry)> {
+        self.entries
+            .iter()
+            .map(|(path, entry)| (path.as_path(), entry))
+    }
+
+    /// The reconstructed bytes of the file at `path`, if it exists and is a
+    /// regular file. Used to resolve a [crate::Command::Clone] whose source
+    /// subvolume is this tree.
+    fn file_data(&self, path: &Path) -> Option<&[u8]> {
+        match &self.get(path)?.kind {
+            EntryKind::File(data) => Some(data),
+            _ => None,
+        }
+    }
+}
+
+/// A node as currently known while applying a stream: unlike [Entry], its
+/// file content is a growable `Vec` rather than the final frozen `Bytes`,
+/// since `Write`/`Truncate`/`Clone` all mutate it in place.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Node {
+    kind: NodeKind,
+    mode: Option<Mode>,
+    uid: Option<Uid>,
+    gid: Option<Gid>,
+    xattrs: BTreeMap<XattrName, XattrData>,
+    atime: Option<Atime>,
+    mtime: Option<Mtime>,
+    ctime: Option<Ctime>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum NodeKind {
+    Directory,
+    File(Vec<u8>),
+    Symlink(PathBuf),
+    Fifo,
+    Socket,
+    CharDevice(Rdev),
+    BlockDevice(Rdev),
+}
+
+impl From<NodeKind> for EntryKind {
+    fn from(kind: NodeKind) -> Self {
+        match kind {
+            NodeKind::Directory => Self::Directory,
+            NodeKind::File(data) => Self::File(data.into()),
+            NodeKind::Symlink(target) => Self::Symlink(target),
+            NodeKind::Fifo => Self::Fifo,
+            NodeKind::Socket => Self::Socket,
+            NodeKind::CharDevice(rdev) => Self::CharDevice(rdev),
+            NodeKind::BlockDevice(rdev) => Self::BlockDevice(rdev),
+        }
+    }
+}
+
+/// Materializes the filesystem a [Command] stream describes, one command at
+/// a time. See the [module](self) docs for the overall algorithm.
+#[derive(Debug, Default)]
+pub struct Receiver {
+    /// Nodes known so far, keyed by their *current* path -- a
+    /// [crate::TemporaryPath] placeholder until a [crate::Rename] moves the
+    /// node to where it's eventually meant to live.
+    nodes: BTreeMap<PathBuf, Node>,
+    /// Already-reconstructed subvolumes, keyed by `(uuid, ctransid)`, used
+    /// to resolve a `Clone` whose source isn't in this stream -- most
+    /// commonly the parent of an incremental stream. Populate via
+    /// [Receiver::with_source] before applying such a stream.
+    sources: HashMap<(Uuid, Ctransid), Tree>,
+    uuid: Option<Uuid>,
+    ctransid: Option<Ctransid>,
+}
+
+impl Receiver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register an already-finished [Tree] so a later `Clone` naming it by
+    /// `uuid`/`ctransid` as its source can pull bytes out of it.
+    pub fn with_source(mut self, uuid: Uuid, ctransid: Ctransid, tree: Tree) -> Self {
+        self.sources.insert((uuid, ctransid), tree);
+        self
+    }
+
+    /// The `uuid` of the subvolume this receiver is building, once a
+    /// `Subvol`/`Snapshot` command has set it.
+    pub fn uuid(&self) -> Option<Uuid> {
+        self.uuid
+    }
+
+    pub fn ctransid(&self) -> Option<Ctransid> {
+        self.ctransid
+    }
+
+    /// Fold one more command into the filesystem being reconstructed.
+    pub fn apply(&mut self, command: &Command) -> crate::Result<()> {
+        match command {
+            Command::Subvol(c) => {
+                self.uuid = Some(c.uuid());
+                self.ctransid = Some(c.ctransid());
+                self.create(c.path(), NodeKind::Directory);
+            }
+            Command::Snapshot(c) => {
+                self.uuid = Some(c.uuid());
+                self.ctransid = Some(c.ctransid());
+                self.create(c.path(), NodeKind::Directory);
+            }
+            Command::Mkdir(c) => self.create(c.path().as_ref(), NodeKind::Directory),
+            Command::Mkfile(c) => self.create(c.path().as_ref(), NodeKind::File(Vec::new())),
+            Command::Mkfifo(c) => self.create(c.path().as_ref(), NodeKind::Fifo),
+            Command::Mksock(c) => self.create(c.path().as_ref(), NodeKind::Socket),
+            Command::Mknod(c) => {
+                let kind = match c.mode().file_type() {
+                    SFlag::S_IFBLK => NodeKind::BlockDevice(c.rdev()),
+                    _ => NodeKind::CharDevice(c.rdev()),
+                };
+                self.create(c.path().as_ref(), kind);
+            }
+            Command::Symlink(c) => self.create(
+                c.link_name(),
+                NodeKind::Symlink(c.target().as_path().to_path_buf()),
+            ),
+            Command::Link(c) => self.link(c.target().as_path(), c.link_name().as_ref())?,
+            Command::Rename(c) => self.rename(c.from(), c.to())?,
+            Command::Unlink(c) => {
+                self.nodes.remove(c.path());
+            }
+            Command::Rmdir(c) => {
+                self.nodes.remove(c.path());
+            }
+            Command::Chmod(c) => self.with_node(c.path(), |node| node.mode = Some(c.mode()))?,
+            Command::Chown(c) => self.with_node(c.path(), |node| {
+                node.uid = Some(c.uid());
+                node.gid = Some(c.gid());
+            })?,
+            Command::SetXattr(c) => self.with_node(c.path(), |node| {
+                node.xattrs.insert(c.name().clone(), c.data().clone());
+            })?,
+            Command::RemoveXattr(c) => self.with_node(c.path(), |node| {
+                node.xattrs.remove(c.name());
+            })?,
+            Command::Utimes(c) => self.with_node(c.path(), |node| {
+                node.atime = Some(c.atime());
+                node.mtime = Some(c.mtime());
+                node.ctime = Some(c.ctime());
+            })?,
+            Command::Write(c) => self.write(c.path(), c.offset().as_u64(), c.data().as_slice())?,
+            Command::Truncate(c) => self.truncate(c.path(), c.size())?,
+            Command::Clone(c) => self.apply_clone(c)?,
+            Command::EncodedWrite(c) => {
+                self.write(c.path(), c.offset().as_u64(), &c.decode_data()?)?
+            }
+            // Metadata/extent-level hints that don't change an entry's
+            // logical content as modeled by [EntryKind].
+            Command::End
+            | Command::Fallocate(_)
+            | Command::Fileattr(_)
+            | Command::EnableVerity(_)
+            | Command::UpdateExtent(_) => {}
+        }
+        Ok(())
+    }
+
+    /// All commands applied so far, applied in order.
+    pub fn apply_all<'a>(
+        &mut self,
+        commands: impl IntoIterator<Item = &'a Command>,
+    ) -> crate::Result<()> {
+        for command in commands {
+            self.apply(command)?;
+        }
+        Ok(())
+    }
+
+    /// Freeze the filesystem built so far into a walkable [Tree].
+    pub fn finish(self) -> Tree {
+        Tree {
+            entries: self
+                .nodes
+                .into_iter()
+                .map(|(path, node)| {
+                    (
+                        path,
+                        Entry {
+                            kind: node.kind.into(),
+                            mode: node.mode,
+                            uid: node.uid,
+                            gid: node.gid,
+                            xattrs: node.xattrs,
+                            atime: node.atime,
+                            mtime: node.mtime,
+                            ctime: node.ctime,
+                        },
+                    )
+                })
+                .collect(),
+        }
+    }
+
+    fn create(&mut self, path: &Path, kind: NodeKind) {
+        self.nodes.insert(
+            path.to_path_buf(),
+            Node {
+                kind,
+                mode: None,
+                uid: None,
+                gid: None,
+                xattrs: BTreeMap::new(),
+                atime: None,
+                mtime: None,
+                ctime: None,
+            },
+        );
+    }
+
+    fn node(&self, path: &Path) -> crate::Result<&Node> {
+        self.nodes
+            .get(path)
+            .ok_or_else(|| crate::Error::Unparsable(format!("no such node {}", path.display())))
+    }
+
+    fn node_mut(&mut self, path: &Path) -> crate::Result<&mut Node> {
+        self.nodes
+            .get_mut(path)
+            .ok_or_else(|| crate::Error::Unparsable(format!("no such node {}", path.display())))
+    }
+
+    fn with_node(&mut self, path: &Path, f: impl FnOnce(&mut Node)) -> crate::Result<()> {
+        f(self.node_mut(path)?);
+        Ok(())
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) -> crate::Result<()> {
+        let node = self.nodes.remove(from).ok_or_else(|| {
+            crate::Error::Unparsable(format!("Rename source {} not found", from.display()))
+        })?;
+        // a renamed directory's descendants are keyed by full path, so they
+        // move along with it
+        let descendants: Vec<PathBuf> = self
+            .nodes
+            .keys()
+            .filter(|path| path.starts_with(from))
+            .cloned()
+            .collect();
+        for old in descendants {
+            if let (Ok(rel), Some(child)) = (old.strip_prefix(from), self.nodes.remove(&old)) {
+                self.nodes.insert(to.join(rel), child);
+            }
+        }
+        self.nodes.insert(to.to_path_buf(), node);
+        Ok(())
+    }
+
+    fn link(&mut self, target: &Path, link_name: &Path) -> crate::Result<()> {
+        let node = self.node(target)?.clone();
+        self.nodes.insert(link_name.to_path_buf(), node);
+        Ok(())
+    }
+
+    fn write(&mut self, path: &Path, offset: u64, data: &[u8]) -> crate::Result<()> {
+        let offset = offset as usize;
+        match &mut self.node_mut(path)?.kind {
+            NodeKind::File(contents) => {
+                let end = offset + data.len();
+                if contents.len() < end {
+                    contents.resize(end, 0);
+                }
+                contents[offset..end].copy_from_slice(data);
+                Ok(())
+            }
+            other => Err(crate::Error::Unparsable(format!(
+                "Write targeted non-file {} ({other:?})",
+                path.display()
+            ))),
+        }
+    }
+
+    fn truncate(&mut self, path: &Path, size: u64) -> crate::Result<()> {
+        match &mut self.node_mut(path)?.kind {
+            NodeKind::File(contents) => {
+                contents.resize(size as usize, 0);
+                Ok(())
+            }
+            other => Err(crate::Error::Unparsable(format!(
+                "Truncate targeted non-file {} ({other:?})",
+                path.display()
+            ))),
+        }
+    }
+
+    /// Copy `src_offset..src_offset+len` out of the subvolume `clone` names
+    /// as its source (either one registered via [Receiver::with_source], or
+    /// -- the common case for reflink/dedup within one stream -- the
+    /// subvolume currently being built) and write it at `clone`'s
+    /// destination.
+    fn apply_clone(&mut self, clone: &crate::Clone) -> crate::Result<()> {
+        let src_offset = clone.src_offset().as_u64() as usize;
+        let len = clone.len().as_u64() as usize;
+        let data = match self.sources.get(&(clone.uuid(), clone.ctransid())) {
+            Some(tree) => tree
+                .file_data(clone.src_path())
+                .and_then(|data| data.get(src_offset..src_offset + len))
+                .ok_or_else(|| {
+                    crate::Error::Unparsable(format!(
+                        "Clone source {} not found in subvolume {}",
+                        clone.src_path().display(),
+                        clone.uuid()
+                    ))
+                })?
+                .to_vec(),
+            None => match &self.node(clone.src_path())?.kind {
+                NodeKind::File(contents) => contents
+                    .get(src_offset..src_offset + len)
+                    .ok_or_else(|| {
+                        crate::Error::Unparsable(format!(
+                            "Clone range out of bounds for {}",
+                            clone.src_path().display()
+                        ))
+                    })?
+                    .to_vec(),
+                other => {
+                    return Err(crate::Error::Unparsable(format!(
+                        "Clone source {} is not a file ({other:?})",
+                        clone.src_path().display()
+                    )));
+                }
+            },
+        };
+        self.write(clone.dst_path(), clone.dst_offset().as_u64(), &data)
+    }
+}
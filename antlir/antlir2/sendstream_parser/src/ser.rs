@@ -0,0 +1,109 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Serde helpers for types that don't map onto serde's data model on their
+//! own: nix's `uid`/`gid` wrappers (plain integers under the hood, but
+//! without their own serde impls), and this crate's various `Bytes`-backed
+//! wrapper types (paths, xattr names/data, file contents). The latter are
+//! encoded as a single byte string for binary formats -- much smaller and
+//! faster than serde's default per-element `u8` sequence -- and as a hex
+//! string for human-readable ones like JSON, so a dumped sendstream stays
+//! legible. See [serialize_bytes]/[deserialize_bytes].
+
+use std::fmt;
+
+use serde::de::Visitor;
+use serde::Deserializer;
+use serde::Serializer;
+
+pub(crate) mod uid {
+    use nix::unistd::Uid;
+    use serde::Deserialize;
+    use serde::Deserializer;
+    use serde::Serializer;
+
+    pub(crate) fn serialize<S: Serializer>(uid: &Uid, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u32(uid.as_raw())
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Uid, D::Error> {
+        Ok(Uid::from_raw(u32::deserialize(deserializer)?))
+    }
+}
+
+pub(crate) mod gid {
+    use nix::unistd::Gid;
+    use serde::Deserialize;
+    use serde::Deserializer;
+    use serde::Serializer;
+
+    pub(crate) fn serialize<S: Serializer>(gid: &Gid, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u32(gid.as_raw())
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Gid, D::Error> {
+        Ok(Gid::from_raw(u32::deserialize(deserializer)?))
+    }
+}
+
+/// Serialize `bytes` as a single byte string for binary formats (so
+/// bincode/CBOR emit one length-prefixed blob instead of a `u8` sequence),
+/// or as a hex string for human-readable ones like JSON.
+pub(crate) fn serialize_bytes<S>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    if serializer.is_human_readable() {
+        serializer.serialize_str(&hex::encode(bytes))
+    } else {
+        serializer.serialize_bytes(bytes)
+    }
+}
+
+struct BytesVisitor;
+
+impl<'de> Visitor<'de> for BytesVisitor {
+    type Value = Vec<u8>;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("a byte string, or a hex string in a human-readable format")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        hex::decode(v).map_err(serde::de::Error::custom)
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        Ok(v.to_vec())
+    }
+
+    fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        Ok(v)
+    }
+}
+
+/// The inverse of [serialize_bytes].
+pub(crate) fn deserialize_bytes<'de, D>(deserializer: D) -> Result<bytes::Bytes, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let bytes = if deserializer.is_human_readable() {
+        deserializer.deserialize_str(BytesVisitor)?
+    } else {
+        deserializer.deserialize_bytes(BytesVisitor)?
+    };
+    Ok(bytes.into())
+}
@@ -0,0 +1,171 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Serve a parsed sendstream over HTTP chunked transfer encoding as
+//! newline-delimited JSON, one line per [Command], so inventory and
+//! introspection tooling can consume a `btrfs send` pipe's structure
+//! incrementally across the network instead of buffering the whole thing.
+//!
+//! [parse][wire::parse]'s output `Stream` already stops pulling from its
+//! underlying [AsyncRead] as soon as the consumer stops polling it (see
+//! [wire]'s `early_exit` test) -- [serve] maps the HTTP response body
+//! directly onto that stream, so a client that disconnects (or just stops
+//! reading) propagates all the way back to the reader, instead of this
+//! server quietly buffering the rest of the stream behind its back.
+
+use std::convert::Infallible;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use base64::Engine as _;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use bytes::Bytes;
+use futures::Stream;
+use futures::StreamExt;
+use hyper::Body;
+use hyper::Request;
+use hyper::Response;
+use hyper::Server;
+use hyper::service::make_service_fn;
+use hyper::service::service_fn;
+use serde::Serialize;
+use tokio::io::AsyncRead;
+
+use crate::Command;
+use crate::catalog;
+use crate::wire;
+
+/// How much of each command's payload (if any -- most commands don't carry
+/// one) to include in its served line.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PayloadMode {
+    /// Omit payload bytes entirely; only `payload_len` is reported.
+    #[default]
+    Omit,
+    /// Base64-encode the payload inline.
+    Base64,
+}
+
+/// Knobs controlling what [serve] includes in each served line.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ServeOptions {
+    pub payload: PayloadMode,
+    /// Content-address each command's own payload chunk with blake3 and
+    /// include it as a hex digest. This is a per-command chunk digest, not
+    /// the whole-file digest [crate::digest] computes.
+    pub include_digest: bool,
+}
+
+/// One served line: a single parsed command's metadata.
+#[derive(Debug, Serialize)]
+struct CommandLine {
+    offset: u64,
+    command_type: wire::CommandType,
+    path: Option<PathBuf>,
+    payload_len: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    digest: Option<String>,
+}
+
+/// The payload bytes a command carries, or an empty slice for the (most)
+/// commands that don't carry one.
+fn payload(command: &Command) -> &[u8] {
+    match command {
+        Command::Write(c) => c.data().as_slice(),
+        Command::EncodedWrite(c) => c.data().as_slice(),
+        _ => &[],
+    }
+}
+
+/// Encode one parsed command as a `CommandLine` JSON object followed by a
+/// newline, ready to append to an HTTP chunk.
+fn render_line(offset: u64, command: &Command, opts: &ServeOptions) -> serde_json::Result<Bytes> {
+    let payload_bytes = payload(command);
+    let line = CommandLine {
+        offset,
+        command_type: command.command_type(),
+        path: catalog::primary_path(command).map(|p| p.to_path_buf()),
+        payload_len: payload_bytes.len(),
+        payload: match opts.payload {
+            PayloadMode::Omit => None,
+            PayloadMode::Base64 => Some(BASE64.encode(payload_bytes)),
+        },
+        digest: opts
+            .include_digest
+            .then(|| blake3::hash(payload_bytes).to_hex().to_string()),
+    };
+    let mut json = serde_json::to_vec(&line)?;
+    json.push(b'\n');
+    Ok(Bytes::from(json))
+}
+
+/// Map `reader`'s parsed commands onto a stream of newline-delimited JSON
+/// chunks, in the shape an HTTP chunked response body expects.
+pub fn ndjson_body<R>(
+    reader: R,
+    opts: ServeOptions,
+) -> impl Stream<Item = std::io::Result<Bytes>> + Send + 'static
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    wire::parse_with_offsets(reader).map(move |res| {
+        let (offset, command) =
+            res.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))?;
+        render_line(offset, &command, &opts)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))
+    })
+}
+
+/// Serve `reader`'s parsed commands as chunked newline-delimited JSON at
+/// `addr` until the given future resolves. There's exactly one stream to
+/// serve (this isn't a multi-client inventory service, just a way to get a
+/// single `btrfs send` pipe's structure off-box), so only the first request
+/// gets the single pass over `reader`; every later request is answered with
+/// `409 Conflict`.
+pub async fn serve<R>(
+    addr: SocketAddr,
+    reader: R,
+    opts: ServeOptions,
+    shutdown: impl std::future::Future<Output = ()>,
+) -> crate::Result<()>
+where
+    R: AsyncRead + Unpin + Send + 'static,
+{
+    let reader = std::sync::Arc::new(tokio::sync::Mutex::new(Some(reader)));
+    let make_svc = make_service_fn(move |_conn| {
+        let reader = reader.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |_req: Request<Body>| {
+                let reader = reader.clone();
+                async move {
+                    let taken = reader.lock().await.take();
+                    let response = match taken {
+                        Some(reader) => {
+                            let body = Body::wrap_stream(ndjson_body(reader, opts));
+                            Response::new(body)
+                        }
+                        None => Response::builder()
+                            .status(hyper::StatusCode::CONFLICT)
+                            .body(Body::from(
+                                "sendstream has already been served to another client\n",
+                            ))
+                            .expect("static response is well-formed"),
+                    };
+                    Ok::<_, Infallible>(response)
+                }
+            }))
+        }
+    });
+
+    Server::bind(&addr)
+        .serve(make_svc)
+        .with_graceful_shutdown(shutdown)
+        .await
+        .map_err(|e| crate::Error::Server(e.to_string()))
+}
@@ -0,0 +1,231 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Transcode a parsed command sequence between sendstream protocol versions.
+//!
+//! v1 and v2 streams describe the same filesystem operations, but v2 adds
+//! [EncodedWrite], which carries a file's bytes pre-compressed instead of as
+//! plain [Write] commands. Downgrading re-expands each `EncodedWrite` back
+//! into one or more `Write`s (reusing [EncodedWrite::decode_data]);
+//! upgrading can, if asked, coalesce adjacent `Write`s that target the same
+//! file into a single compressed `EncodedWrite`. Everything else passes
+//! through unchanged.
+//!
+//! Unlike [crate::wire], which only knows how to parse one version at a
+//! time, [transcode] is given an explicit source and target version and
+//! negotiates a concrete rewrite between them, the same way a protocol crate
+//! settles on one wire format instead of exposing an opaque capability set.
+
+use bytes::Bytes;
+use std::io::Write as _;
+
+use crate::BytesPath;
+use crate::Command;
+use crate::Compression;
+use crate::Data;
+use crate::EncodedWrite;
+use crate::FileOffset;
+use crate::Result;
+use crate::UnencodedFileLen;
+use crate::UnencodedLen;
+use crate::UnencodedOffset;
+use crate::Write;
+
+/// btrfs send v1 caps a single `Write` command's data at 48KiB
+/// (`BTRFS_SEND_READ_SIZE` in the kernel), which also keeps it within the
+/// `u16` length of the v1 `Data` TLV; a decoded `EncodedWrite` can be
+/// larger than that, so downgrading has to re-chunk it.
+const MAX_WRITE_CHUNK: usize = 48 * 1024;
+
+/// Drives a single [transcode] pass: the version to produce and, when
+/// upgrading, how to compress any new [EncodedWrite]s.
+#[derive(Debug, Clone, Copy)]
+pub struct UpgradeOptions {
+    /// The sendstream version to produce.
+    pub target_version: u32,
+    /// Compression to use when coalescing `Write`s into `EncodedWrite`s
+    /// during a v1 -> v2 upgrade. Has no effect when downgrading, or when
+    /// set to [Compression::None] (the upgrade then leaves `Write`s alone).
+    pub compression: Compression,
+}
+
+impl Default for UpgradeOptions {
+    fn default() -> Self {
+        Self {
+            target_version: 2,
+            compression: Compression::None,
+        }
+    }
+}
+
+/// What a [transcode] pass actually did, so callers can tell whether
+/// anything was rewritten without diffing the output themselves.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UpgradeStats {
+    /// Number of rewrites performed: each `EncodedWrite` expanded into
+    /// `Write`s when downgrading, and each `EncodedWrite` produced from a run
+    /// of `Write`s when upgrading, counts once.
+    pub commands_rewritten: usize,
+    /// Total bytes saved by compression while upgrading. Always `0` when
+    /// downgrading.
+    pub bytes_saved: u64,
+}
+
+/// State threaded through a single [transcode] call: the options driving it
+/// and the stats accumulated so far.
+struct UpgradeContext {
+    options: UpgradeOptions,
+    stats: UpgradeStats,
+}
+
+/// Rewrite `commands`, which are assumed to be a valid sequence parsed at
+/// `source_version`, into an equivalent sequence for `options.target_version`.
+pub fn transcode(
+    commands: Vec<Command>,
+    source_version: u32,
+    options: UpgradeOptions,
+) -> Result<(Vec<Command>, UpgradeStats)> {
+    let mut ctx = UpgradeContext {
+        options,
+        stats: UpgradeStats::default(),
+    };
+    let out = match options.target_version.cmp(&source_version) {
+        std::cmp::Ordering::Less => downgrade(commands, &mut ctx)?,
+        std::cmp::Ordering::Greater => upgrade(commands, &mut ctx),
+        std::cmp::Ordering::Equal => commands,
+    };
+    Ok((out, ctx.stats))
+}
+
+/// v2 -> v1: expand every [EncodedWrite] into plain, decompressed [Write]s.
+fn downgrade(commands: Vec<Command>, ctx: &mut UpgradeContext) -> Result<Vec<Command>> {
+    let mut out = Vec::with_capacity(commands.len());
+    for command in commands {
+        match command {
+            Command::EncodedWrite(ew) => {
+                ctx.stats.commands_rewritten += 1;
+                let decoded = ew.decode_data()?;
+                out.extend(split_into_writes(ew.path.clone(), ew.offset(), &decoded));
+            }
+            other => out.push(other),
+        }
+    }
+    Ok(out)
+}
+
+fn split_into_writes(path: BytesPath, offset: FileOffset, data: &[u8]) -> Vec<Command> {
+    if data.is_empty() {
+        return Vec::new();
+    }
+    data.chunks(MAX_WRITE_CHUNK)
+        .enumerate()
+        .map(|(i, chunk)| {
+            Command::Write(Write {
+                path: path.clone(),
+                offset: FileOffset(offset.as_u64() + (i * MAX_WRITE_CHUNK) as u64),
+                data: Data(Bytes::copy_from_slice(chunk)),
+            })
+        })
+        .collect()
+}
+
+/// v1 -> v2: when asked to compress, fold runs of adjacent `Write`s against
+/// the same path into a single `EncodedWrite`. With
+/// `options.compression == Compression::None`, this is a no-op rewrite: the
+/// commands are already valid at any v2 reader since `Write` never changed
+/// meaning between versions.
+fn upgrade(commands: Vec<Command>, ctx: &mut UpgradeContext) -> Vec<Command> {
+    if ctx.options.compression == Compression::None {
+        return commands;
+    }
+
+    let mut out = Vec::with_capacity(commands.len());
+    let mut pending: Option<(BytesPath, FileOffset, Vec<u8>)> = None;
+    for command in commands {
+        match command {
+            Command::Write(w) => match &mut pending {
+                Some((path, start, buf))
+                    if *path == w.path
+                        && start.as_u64() + buf.len() as u64 == w.offset().as_u64() =>
+                {
+                    buf.extend_from_slice(w.data().as_slice());
+                }
+                _ => {
+                    flush_pending(pending.take(), &mut out, ctx);
+                    pending = Some((w.path.clone(), w.offset(), w.data().as_slice().to_vec()));
+                }
+            },
+            other => {
+                flush_pending(pending.take(), &mut out, ctx);
+                out.push(other);
+            }
+        }
+    }
+    flush_pending(pending.take(), &mut out, ctx);
+    out
+}
+
+fn flush_pending(
+    pending: Option<(BytesPath, FileOffset, Vec<u8>)>,
+    out: &mut Vec<Command>,
+    ctx: &mut UpgradeContext,
+) {
+    if let Some((path, offset, raw)) = pending {
+        out.push(encode_write(path, offset, raw, ctx));
+    }
+}
+
+fn encode_write(
+    path: BytesPath,
+    offset: FileOffset,
+    raw: Vec<u8>,
+    ctx: &mut UpgradeContext,
+) -> Command {
+    ctx.stats.commands_rewritten += 1;
+    let (compression, payload) = compress(&raw, ctx.options.compression);
+    ctx.stats.bytes_saved += (raw.len() as u64).saturating_sub(payload.len() as u64);
+    let len = raw.len() as u64;
+    Command::EncodedWrite(EncodedWrite {
+        path,
+        offset,
+        // We don't track each file's full size across the command sequence
+        // here, so report this chunk's own length; callers that need the
+        // real file size should patch it in from the preceding `Truncate`.
+        unencoded_file_len: UnencodedFileLen(len),
+        unencoded_len: UnencodedLen(len),
+        unencoded_offset: UnencodedOffset(0),
+        compression,
+        encryption: None,
+        data: Data(payload.into()),
+    })
+}
+
+/// Compress `raw` with `requested`, falling back to [Compression::None] if
+/// compressing doesn't actually shrink it.
+fn compress(raw: &[u8], requested: Compression) -> (Compression, Vec<u8>) {
+    let compressed = match requested {
+        Compression::None => None,
+        Compression::Zlib => {
+            let mut encoder =
+                flate2::write::ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder
+                .write_all(raw)
+                .and_then(|_| encoder.finish())
+                .ok()
+        }
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => zstd::stream::encode_all(raw, 0).ok(),
+        #[cfg(not(feature = "zstd"))]
+        Compression::Zstd => None,
+        Compression::Lzo => None,
+        Compression::Unknown(_) => None,
+    };
+    match compressed {
+        Some(compressed) if compressed.len() < raw.len() => (requested, compressed),
+        _ => (Compression::None, raw.to_vec()),
+    }
+}
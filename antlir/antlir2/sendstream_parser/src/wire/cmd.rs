@@ -15,6 +15,8 @@ use crate::wire::tlv::attr_types;
 use crate::wire::tlv::parse_tlv;
 use crate::wire::tlv::parse_tlv_opt;
 use crate::wire::tlv::parse_tlv_with_attr;
+use crate::wire::tlv::write_tlv;
+use crate::wire::tlv::write_tlv_with_attr;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub(crate) struct CommandHeader {
@@ -51,11 +53,12 @@ impl CommandHeader {
     Eq,
     PartialOrd,
     Ord,
-    num_enum::FromPrimitive
+    num_enum::FromPrimitive,
+    num_enum::IntoPrimitive
 )]
 #[cfg_attr(feature = "serde", derive(::serde::Deserialize, ::serde::Serialize))]
 #[repr(u16)]
-pub(crate) enum CommandType {
+pub enum CommandType {
     Unspecified = 0,
     Subvol = 1,
     Snapshot = 2,
@@ -117,15 +120,241 @@ where
     }
 }
 
+/// The inverse of [ParseCommand]: serialize a command's fields into its
+/// body, as the TLVs `parse` above would read back.
+trait WriteCommand: Sized {
+    fn write_body(&self, out: &mut Vec<u8>);
+}
+
+/// The inverse of [ParseCommandVersion].
+trait WriteCommandVersion: Sized {
+    fn write_body(&self, out: &mut Vec<u8>, sendstream_version: u32);
+}
+
+impl<T> WriteCommandVersion for T
+where
+    T: WriteCommand,
+{
+    fn write_body(&self, out: &mut Vec<u8>, _sendstream_version: u32) {
+        <T as WriteCommand>::write_body(self, out)
+    }
+}
+
+/// Error type for [crate::Command::parser]: the usual nom parse errors,
+/// plus a distinct checksum-mismatch case carrying enough detail to build a
+/// [crate::Error::ChecksumMismatch].
+#[derive(Debug)]
+pub(crate) enum CommandParseError {
+    Nom(nom::error::Error<NomBytes>),
+    ChecksumMismatch {
+        command_type: CommandType,
+        expected: u32,
+        actual: u32,
+    },
+}
+
+impl std::fmt::Display for CommandParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Nom(e) => e.fmt(f),
+            Self::ChecksumMismatch {
+                command_type,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "bad checksum for {command_type:?} command (expected {expected:#010x}, got {actual:#010x})"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CommandParseError {}
+
+impl nom::error::ParseError<NomBytes> for CommandParseError {
+    fn from_error_kind(input: NomBytes, kind: nom::error::ErrorKind) -> Self {
+        Self::Nom(nom::error::Error::from_error_kind(input, kind))
+    }
+
+    fn append(input: NomBytes, kind: nom::error::ErrorKind, other: Self) -> Self {
+        match other {
+            Self::Nom(e) => Self::Nom(nom::error::Error::append(input, kind, e)),
+            other => other,
+        }
+    }
+}
+
+/// Peek the [CommandHeader] at the front of `input` (without consuming
+/// anything -- the real header parsing still happens right after in
+/// [ParseCommandVersion]) and verify its `crc32` against a CRC32C computed
+/// over the full command buffer (header + body) with the 4 checksum bytes
+/// zeroed, per the `CommandHeader::crc32` doc comment. The header is
+/// `len` (4 bytes) + `ty` (2 bytes) + `crc32` (4 bytes), so the checksum
+/// bytes to zero are at offset 6..10, not 8..12 as the bare "zero out the
+/// checksum field" description might suggest if you assumed a padded
+/// header.
+fn verify_command_checksum(input: &NomBytes) -> IResult<NomBytes, (), CommandParseError> {
+    let (after_header, hdr) =
+        CommandHeader::parse(input.clone()).map_err(|e| e.map(CommandParseError::Nom))?;
+    let (_, frame) = nom::bytes::streaming::take(10 + hdr.len)
+        .parse(input.clone())
+        .map_err(|e| e.map(CommandParseError::Nom))?;
+
+    let mut scratch: Vec<u8> = frame.to_vec();
+    scratch[6..10].fill(0);
+    let actual = crate::wire::crc32c::crc32c(&scratch);
+
+    if actual != hdr.crc32 {
+        return Err(nom::Err::Failure(CommandParseError::ChecksumMismatch {
+            command_type: hdr.ty,
+            expected: hdr.crc32,
+            actual,
+        }));
+    }
+    Ok((after_header, ()))
+}
+
 impl crate::Command {
+    /// `verify_checksum` gates the CRC32C check described on
+    /// [verify_command_checksum]; callers that don't care about detecting
+    /// corrupt streams (or that have already validated the bytes some other
+    /// way) can pass `false` to skip it.
     pub(crate) fn parser(
         sendstream_version: u32,
-    ) -> impl nom::Parser<NomBytes, Output = Self, Error = nom::error::Error<NomBytes>> {
-        move |input: NomBytes| -> IResult<NomBytes, Self> {
-            let (input, cmd) = <Self as ParseCommandVersion>::parse(input, sendstream_version)?;
+        verify_checksum: bool,
+    ) -> impl nom::Parser<NomBytes, Output = Self, Error = CommandParseError> {
+        move |input: NomBytes| -> IResult<NomBytes, Self, CommandParseError> {
+            if verify_checksum {
+                verify_command_checksum(&input)?;
+            }
+            let (input, cmd) = <Self as ParseCommandVersion>::parse(input, sendstream_version)
+                .map_err(|e| e.map(CommandParseError::Nom))?;
             Ok((input, cmd))
         }
     }
+
+    /// Serialize this command back to its on-wire form: a [CommandHeader]
+    /// (with `len` computed from the serialized body and `crc32` backfilled
+    /// over the complete command buffer) followed by the body `WriteCommand`/
+    /// `WriteCommandVersion` produce. The inverse of
+    /// `ParseCommandVersion for crate::Command`.
+    pub fn to_bytes(&self, sendstream_version: u32) -> Bytes {
+        let mut body = Vec::new();
+        let ty = match self {
+            Self::Chmod(c) => {
+                c.write_body(&mut body, sendstream_version);
+                CommandType::Chmod
+            }
+            Self::Chown(c) => {
+                c.write_body(&mut body, sendstream_version);
+                CommandType::Chown
+            }
+            Self::Clone(c) => {
+                c.write_body(&mut body, sendstream_version);
+                CommandType::Clone
+            }
+            Self::EnableVerity(c) => {
+                c.write_body(&mut body, sendstream_version);
+                CommandType::EnableVerity
+            }
+            Self::EncodedWrite(c) => {
+                c.write_body(&mut body, sendstream_version);
+                CommandType::EncodedWrite
+            }
+            Self::End => CommandType::End,
+            Self::Fallocate(c) => {
+                c.write_body(&mut body, sendstream_version);
+                CommandType::Fallocate
+            }
+            Self::Fileattr(c) => {
+                c.write_body(&mut body, sendstream_version);
+                CommandType::Fileattr
+            }
+            Self::Link(c) => {
+                c.write_body(&mut body, sendstream_version);
+                CommandType::Link
+            }
+            Self::Mkdir(c) => {
+                c.write_body(&mut body, sendstream_version);
+                CommandType::Mkdir
+            }
+            Self::Mkfifo(c) => {
+                c.write_body(&mut body, sendstream_version);
+                CommandType::Mkfifo
+            }
+            Self::Mkfile(c) => {
+                c.write_body(&mut body, sendstream_version);
+                CommandType::Mkfile
+            }
+            Self::Mknod(c) => {
+                c.write_body(&mut body, sendstream_version);
+                CommandType::Mknod
+            }
+            Self::Mksock(c) => {
+                c.write_body(&mut body, sendstream_version);
+                CommandType::Mksock
+            }
+            Self::RemoveXattr(c) => {
+                c.write_body(&mut body, sendstream_version);
+                CommandType::RemoveXattr
+            }
+            Self::Rename(c) => {
+                c.write_body(&mut body, sendstream_version);
+                CommandType::Rename
+            }
+            Self::Rmdir(c) => {
+                c.write_body(&mut body, sendstream_version);
+                CommandType::Rmdir
+            }
+            Self::SetXattr(c) => {
+                c.write_body(&mut body, sendstream_version);
+                CommandType::SetXattr
+            }
+            Self::Snapshot(c) => {
+                c.write_body(&mut body, sendstream_version);
+                CommandType::Snapshot
+            }
+            Self::Subvol(c) => {
+                c.write_body(&mut body, sendstream_version);
+                CommandType::Subvol
+            }
+            Self::Symlink(c) => {
+                c.write_body(&mut body, sendstream_version);
+                CommandType::Symlink
+            }
+            Self::Truncate(c) => {
+                c.write_body(&mut body, sendstream_version);
+                CommandType::Truncate
+            }
+            Self::Unlink(c) => {
+                c.write_body(&mut body, sendstream_version);
+                CommandType::Unlink
+            }
+            Self::UpdateExtent(c) => {
+                c.write_body(&mut body, sendstream_version);
+                CommandType::UpdateExtent
+            }
+            Self::Utimes(c) => {
+                c.write_body(&mut body, sendstream_version);
+                CommandType::Utimes
+            }
+            Self::Write(c) => {
+                c.write_body(&mut body, sendstream_version);
+                CommandType::Write
+            }
+        };
+
+        let mut buf = Vec::with_capacity(10 + body.len());
+        buf.extend_from_slice(&(body.len() as u32).to_le_bytes());
+        buf.extend_from_slice(&u16::from(ty).to_le_bytes());
+        buf.extend_from_slice(&0u32.to_le_bytes()); // crc32 placeholder, backfilled below
+        buf.extend_from_slice(&body);
+
+        let crc = crate::wire::crc32c::crc32c(&buf);
+        buf[6..10].copy_from_slice(&crc.to_le_bytes());
+
+        buf.into()
+    }
 }
 
 macro_rules! parse_subtypes {
@@ -172,12 +401,34 @@ parse_subtypes!(
     Utimes,
     Write,
     EncodedWrite,
-    End
+    End,
+    Fallocate,
+    Fileattr,
+    EnableVerity
 );
 
+/// Send-stream v2 introduced these opcodes; encountering one in a stream
+/// that declared itself v1 means either a corrupt stream or a parser bug in
+/// how the version was read, so it's rejected rather than parsed anyway.
+fn is_v2_only(ty: CommandType) -> bool {
+    matches!(
+        ty,
+        CommandType::EncodedWrite
+            | CommandType::Fallocate
+            | CommandType::Fileattr
+            | CommandType::EnableVerity
+    )
+}
+
 impl ParseCommandVersion for crate::Command {
     fn parse(input: NomBytes, sendstream_version: u32) -> IResult<NomBytes, Self> {
         let (input, hdr) = CommandHeader::parse(input)?;
+        if sendstream_version < 2 && is_v2_only(hdr.ty) {
+            return Err(nom::Err::Failure(nom::error::Error::from_error_kind(
+                input,
+                nom::error::ErrorKind::Verify,
+            )));
+        }
         let (input, cmd_data) = nom::bytes::streaming::take(hdr.len).parse(input)?;
         let (cmd_remaining, cmd) = parse_subtypes(hdr, cmd_data, sendstream_version)?;
         assert!(
@@ -369,6 +620,52 @@ impl ParseCommand for crate::Truncate {
     }
 }
 
+impl ParseCommand for crate::Fallocate {
+    fn parse(input: NomBytes) -> IResult<NomBytes, Self> {
+        let (input, path) = parse_tlv(input)?;
+        let (input, mode) = parse_tlv(input)?;
+        let (input, offset) = parse_tlv(input)?;
+        let (input, len) = parse_tlv(input)?;
+        Ok((
+            input,
+            Self {
+                path,
+                mode,
+                offset,
+                len,
+            },
+        ))
+    }
+}
+
+impl ParseCommand for crate::Fileattr {
+    fn parse(input: NomBytes) -> IResult<NomBytes, Self> {
+        let (input, path) = parse_tlv(input)?;
+        let (input, flags) = parse_tlv(input)?;
+        Ok((input, Self { path, flags }))
+    }
+}
+
+impl ParseCommand for crate::EnableVerity {
+    fn parse(input: NomBytes) -> IResult<NomBytes, Self> {
+        let (input, path) = parse_tlv(input)?;
+        let (input, algorithm) = parse_tlv(input)?;
+        let (input, block_size) = parse_tlv(input)?;
+        let (input, salt) = parse_tlv(input)?;
+        let (input, signature) = parse_tlv(input)?;
+        Ok((
+            input,
+            Self {
+                path,
+                algorithm,
+                block_size,
+                salt,
+                signature,
+            },
+        ))
+    }
+}
+
 impl ParseCommand for crate::Snapshot {
     fn parse(input: NomBytes) -> IResult<NomBytes, Self> {
         let (input, path) = parse_tlv(input)?;
@@ -482,3 +779,213 @@ impl ParseCommand for crate::End {
         Ok((input, Self))
     }
 }
+
+impl WriteCommand for crate::Subvol {
+    fn write_body(&self, out: &mut Vec<u8>) {
+        write_tlv(&self.path, out);
+        write_tlv(&self.uuid, out);
+        write_tlv(&self.ctransid, out);
+    }
+}
+
+impl WriteCommand for crate::Chmod {
+    fn write_body(&self, out: &mut Vec<u8>) {
+        write_tlv(&self.path, out);
+        write_tlv(&self.mode, out);
+    }
+}
+
+impl WriteCommand for crate::Chown {
+    fn write_body(&self, out: &mut Vec<u8>) {
+        write_tlv(&self.path, out);
+        write_tlv(&self.uid, out);
+        write_tlv(&self.gid, out);
+    }
+}
+
+impl WriteCommand for crate::Clone {
+    fn write_body(&self, out: &mut Vec<u8>) {
+        write_tlv(&self.dst_offset, out);
+        write_tlv(&self.len, out);
+        write_tlv(&self.dst_path, out);
+        write_tlv_with_attr::<_, 16, attr_types::CloneUuid>(&self.uuid, out);
+        write_tlv_with_attr::<_, 8, attr_types::CloneCtransid>(&self.ctransid, out);
+        write_tlv_with_attr::<_, 0, attr_types::ClonePath>(&self.src_path, out);
+        write_tlv_with_attr::<_, 8, attr_types::CloneOffset>(&self.src_offset, out);
+    }
+}
+
+impl WriteCommand for crate::Link {
+    fn write_body(&self, out: &mut Vec<u8>) {
+        write_tlv(&self.link_name, out);
+        write_tlv(&self.target, out);
+    }
+}
+
+impl WriteCommand for crate::Symlink {
+    fn write_body(&self, out: &mut Vec<u8>) {
+        write_tlv(&self.link_name, out);
+        write_tlv(&self.ino, out);
+        write_tlv(&self.target, out);
+    }
+}
+
+impl WriteCommand for crate::Mkdir {
+    fn write_body(&self, out: &mut Vec<u8>) {
+        write_tlv(&self.path, out);
+        write_tlv(&self.ino, out);
+    }
+}
+
+impl WriteCommand for crate::Mkfile {
+    fn write_body(&self, out: &mut Vec<u8>) {
+        write_tlv(&self.path, out);
+        write_tlv(&self.ino, out);
+    }
+}
+
+impl WriteCommand for crate::Mkspecial {
+    fn write_body(&self, out: &mut Vec<u8>) {
+        write_tlv(&self.path, out);
+        write_tlv(&self.ino, out);
+        write_tlv(&self.rdev, out);
+        write_tlv(&self.mode, out);
+    }
+}
+
+macro_rules! mkspecial_write {
+    ($t:ident) => {
+        impl WriteCommand for crate::$t {
+            fn write_body(&self, out: &mut Vec<u8>) {
+                <crate::Mkspecial as WriteCommand>::write_body(&self.0, out)
+            }
+        }
+    };
+}
+
+mkspecial_write!(Mknod);
+mkspecial_write!(Mkfifo);
+mkspecial_write!(Mksock);
+
+impl WriteCommand for crate::RemoveXattr {
+    fn write_body(&self, out: &mut Vec<u8>) {
+        write_tlv(&self.path, out);
+        write_tlv(&self.name, out);
+    }
+}
+
+impl WriteCommand for crate::Rename {
+    fn write_body(&self, out: &mut Vec<u8>) {
+        write_tlv(&self.from, out);
+        write_tlv_with_attr::<_, 0, attr_types::PathTo>(&self.to, out);
+    }
+}
+
+impl WriteCommand for crate::Rmdir {
+    fn write_body(&self, out: &mut Vec<u8>) {
+        write_tlv(&self.path, out);
+    }
+}
+
+impl WriteCommand for crate::SetXattr {
+    fn write_body(&self, out: &mut Vec<u8>) {
+        write_tlv(&self.path, out);
+        write_tlv(&self.name, out);
+        write_tlv(&self.data, out);
+    }
+}
+
+impl WriteCommand for crate::Truncate {
+    fn write_body(&self, out: &mut Vec<u8>) {
+        write_tlv(&self.path, out);
+        write_tlv(&self.size, out);
+    }
+}
+
+impl WriteCommand for crate::Fallocate {
+    fn write_body(&self, out: &mut Vec<u8>) {
+        write_tlv(&self.path, out);
+        write_tlv(&self.mode, out);
+        write_tlv(&self.offset, out);
+        write_tlv(&self.len, out);
+    }
+}
+
+impl WriteCommand for crate::Fileattr {
+    fn write_body(&self, out: &mut Vec<u8>) {
+        write_tlv(&self.path, out);
+        write_tlv(&self.flags, out);
+    }
+}
+
+impl WriteCommand for crate::EnableVerity {
+    fn write_body(&self, out: &mut Vec<u8>) {
+        write_tlv(&self.path, out);
+        write_tlv(&self.algorithm, out);
+        write_tlv(&self.block_size, out);
+        write_tlv(&self.salt, out);
+        write_tlv(&self.signature, out);
+    }
+}
+
+impl WriteCommand for crate::Snapshot {
+    fn write_body(&self, out: &mut Vec<u8>) {
+        write_tlv(&self.path, out);
+        write_tlv(&self.uuid, out);
+        write_tlv(&self.ctransid, out);
+        write_tlv_with_attr::<_, 16, attr_types::CloneUuid>(&self.clone_uuid, out);
+        write_tlv_with_attr::<_, 8, attr_types::CloneCtransid>(&self.clone_ctransid, out);
+    }
+}
+
+impl WriteCommand for crate::Unlink {
+    fn write_body(&self, out: &mut Vec<u8>) {
+        write_tlv(&self.path, out);
+    }
+}
+
+impl WriteCommand for crate::UpdateExtent {
+    fn write_body(&self, out: &mut Vec<u8>) {
+        write_tlv(&self.path, out);
+        write_tlv(&self.offset, out);
+        write_tlv(&self.len, out);
+    }
+}
+
+impl WriteCommand for crate::Utimes {
+    fn write_body(&self, out: &mut Vec<u8>) {
+        write_tlv(&self.path, out);
+        write_tlv(&self.atime, out);
+        write_tlv(&self.mtime, out);
+        write_tlv(&self.ctime, out);
+    }
+}
+
+impl WriteCommandVersion for crate::Write {
+    fn write_body(&self, out: &mut Vec<u8>, sendstream_version: u32) {
+        write_tlv(&self.path, out);
+        write_tlv(&self.offset, out);
+        if sendstream_version >= 2 {
+            out.extend_from_slice(&Attr::Data.tag());
+            out.extend_from_slice(self.data.as_slice());
+        } else {
+            write_tlv(&self.data, out);
+        }
+    }
+}
+
+impl WriteCommand for crate::EncodedWrite {
+    fn write_body(&self, out: &mut Vec<u8>) {
+        write_tlv(&self.path, out);
+        write_tlv(&self.offset, out);
+        write_tlv(&self.unencoded_file_len, out);
+        write_tlv(&self.unencoded_len, out);
+        write_tlv(&self.unencoded_offset, out);
+        write_tlv(&self.compression, out);
+        if let Some(encryption) = &self.encryption {
+            write_tlv(encryption, out);
+        }
+        out.extend_from_slice(&Attr::Data.tag());
+        out.extend_from_slice(self.data.as_slice());
+    }
+}
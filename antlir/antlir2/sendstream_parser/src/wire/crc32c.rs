@@ -0,0 +1,30 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! btrfs send checksums commands with CRC32C (the Castagnoli polynomial),
+//! not the more common CRC32 (IEEE), computed like the kernel's
+//! `crc32c(0, ...)`: seeded with 0 and with no final inversion, unlike the
+//! usual CRC32C's `!0` seed and inverted result. Implemented bit-by-bit
+//! rather than with a lookup table since sendstream commands are small and
+//! there's no existing crc crate dependency to reuse.
+
+const CASTAGNOLI_POLY: u32 = 0x82F63B78;
+
+pub(crate) fn crc32c(data: &[u8]) -> u32 {
+    let mut crc = 0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ CASTAGNOLI_POLY
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    crc
+}
@@ -0,0 +1,74 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Real `btrfs send` pipelines are frequently piped through `zstd` or
+//! `gzip` before reaching this parser. [sniff] peeks a reader's first few
+//! bytes for a zstd or gzip magic number and wraps it in the matching
+//! decompressor, so [super::parse] and friends can transparently accept
+//! compressed input without the caller having to know or care.
+
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+
+use tokio::io::AsyncBufRead;
+use tokio::io::AsyncRead;
+use tokio::io::BufReader;
+use tokio::io::ReadBuf;
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+#[cfg(feature = "zstd")]
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// `reader`, transparently decompressed if its leading bytes matched a
+/// known compression framing, otherwise passed through unchanged.
+pub(super) enum Decompressed<R> {
+    Raw(BufReader<R>),
+    Gzip(async_compression::tokio::bufread::GzipDecoder<BufReader<R>>),
+    #[cfg(feature = "zstd")]
+    Zstd(async_compression::tokio::bufread::ZstdDecoder<BufReader<R>>),
+}
+
+/// Peek `reader`'s leading bytes and wrap it in the decompressor its magic
+/// number indicates, without consuming anything if none match.
+pub(super) async fn sniff<R>(reader: R) -> crate::Result<Decompressed<R>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut reader = BufReader::new(reader);
+    let peeked = reader.fill_buf().await?;
+    if peeked.starts_with(&GZIP_MAGIC) {
+        return Ok(Decompressed::Gzip(
+            async_compression::tokio::bufread::GzipDecoder::new(reader),
+        ));
+    }
+    #[cfg(feature = "zstd")]
+    if peeked.starts_with(&ZSTD_MAGIC) {
+        return Ok(Decompressed::Zstd(
+            async_compression::tokio::bufread::ZstdDecoder::new(reader),
+        ));
+    }
+    Ok(Decompressed::Raw(reader))
+}
+
+impl<R> AsyncRead for Decompressed<R>
+where
+    R: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Self::Raw(r) => Pin::new(r).poll_read(cx, buf),
+            Self::Gzip(r) => Pin::new(r).poll_read(cx, buf),
+            #[cfg(feature = "zstd")]
+            Self::Zstd(r) => Pin::new(r).poll_read(cx, buf),
+        }
+    }
+}
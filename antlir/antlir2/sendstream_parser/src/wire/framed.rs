@@ -13,16 +13,41 @@ use tokio_util::codec::Decoder;
 
 use crate::Command;
 use crate::Error;
+use crate::wire::MAGIC_HEADER;
 use crate::wire::NomBytes;
+use crate::wire::cmd::CommandParseError;
 
 pub(super) struct SendstreamDecoder {
     state: State,
+    verify_checksum: bool,
+    /// Total bytes consumed from the underlying reader so far, used to tag
+    /// each emitted command with the stream offset at which it starts.
+    position: u64,
 }
 
 impl SendstreamDecoder {
+    /// Recomputes and checks each command's CRC32C before it's emitted, so a
+    /// corrupted stream is caught as a
+    /// [CommandParseError::ChecksumMismatch] instead of surfacing garbage
+    /// (or a confusing downstream parse failure) later on. This is the
+    /// default for every real caller.
     pub(super) fn new() -> Self {
         Self {
             state: State::Empty,
+            verify_checksum: true,
+            position: 0,
+        }
+    }
+
+    /// The zero-copy fast path: commands are handed back as soon as they're
+    /// parsed, without checking their CRC32C. Only for callers that have
+    /// their own reason not to pay for verification (eg they already trust
+    /// the source, or verify some other way).
+    #[allow(dead_code)]
+    pub(super) fn new_unchecked() -> Self {
+        Self {
+            verify_checksum: false,
+            ..Self::new()
         }
     }
 }
@@ -38,11 +63,11 @@ pub(super) enum Item {
     /// Magic header that starts a sendstream - the only data here is the
     /// sendstream version
     SendstreamStart(#[allow(dead_code)] u32),
-    Command(Command),
+    /// A parsed command along with the offset (from the start of the
+    /// underlying reader) at which its header begins.
+    Command(u64, Command),
 }
 
-static MAGIC_HEADER: &[u8] = b"btrfs-stream\0";
-
 /// Parse a chunk of bytes to see if we can extract the header expected atop each sendstream.
 fn sendstream_header(input: NomBytes) -> IResult<NomBytes, u32> {
     let (remainder, (_magic, version)) = (
@@ -64,7 +89,9 @@ impl Decoder for SendstreamDecoder {
         match self.state {
             State::Empty => match sendstream_header(parsable) {
                 Ok((remaining, version)) => {
-                    src.advance(starting_len - remaining.len());
+                    let consumed = starting_len - remaining.len();
+                    src.advance(consumed);
+                    self.position += consumed as u64;
                     self.state = State::Parsing(version);
                     Ok(Some(Item::SendstreamStart(version)))
                 }
@@ -77,14 +104,19 @@ impl Decoder for SendstreamDecoder {
                 Err(e) => Err(Error::Unparsable(e.to_string())),
             },
             State::Parsing(version) => {
+                let position = self.position;
                 match nom::branch::alt((
-                    sendstream_header.map(Item::SendstreamStart),
-                    Command::parser(version).map(Item::Command),
+                    (|i| sendstream_header(i).map_err(|e| e.map(CommandParseError::Nom)))
+                        .map(Item::SendstreamStart),
+                    Command::parser(version, self.verify_checksum)
+                        .map(move |c| Item::Command(position, c)),
                 ))
                 .parse(parsable)
                 {
                     Ok((remaining, item)) => {
-                        src.advance(starting_len - remaining.len());
+                        let consumed = starting_len - remaining.len();
+                        src.advance(consumed);
+                        self.position += consumed as u64;
                         if let Item::SendstreamStart(version) = item {
                             self.state = State::Parsing(version);
                         }
@@ -96,6 +128,22 @@ impl Decoder for SendstreamDecoder {
                         }
                         Ok(None)
                     }
+                    Err(
+                        nom::Err::Error(CommandParseError::ChecksumMismatch {
+                            command_type,
+                            expected,
+                            actual,
+                        })
+                        | nom::Err::Failure(CommandParseError::ChecksumMismatch {
+                            command_type,
+                            expected,
+                            actual,
+                        }),
+                    ) => Err(Error::ChecksumMismatch {
+                        command_type: command_type.into(),
+                        expected,
+                        actual,
+                    }),
                     Err(e) => Err(Error::Unparsable(e.to_string())),
                 }
             }
@@ -12,10 +12,19 @@ use tokio::io::AsyncRead;
 use tokio_util::codec::FramedRead;
 
 pub(crate) mod cmd;
+mod crc32c;
+mod decompress;
 mod framed;
 mod nombytes;
 mod tlv;
+mod writer;
+pub use cmd::CommandType;
 pub use nombytes::NomBytes;
+pub use writer::write;
+
+/// Every sendstream starts with this magic header, before the version and
+/// then the commands themselves.
+pub(crate) static MAGIC_HEADER: &[u8] = b"btrfs-stream\0";
 
 /// Parse an async source of bytes, expecting to find it to contain one or more sendstreams.
 /// Because the parsed commands reference data owned by the source, we do not collect the commands.
@@ -28,20 +37,87 @@ pub use nombytes::NomBytes;
 /// will be emitted into the stream as long as it could be read and parsed.
 ///
 /// See https://btrfs.readthedocs.io/en/latest/dev/dev-send-stream.html for reference.
+///
+/// [write] is the inverse: it takes commands and produces the bytes this
+/// function expects to parse.
+///
+/// Each command's CRC32C checksum is verified before it's yielded (see
+/// [framed::SendstreamDecoder::new]), so a corrupted or truncated stream is
+/// caught early instead of surfacing garbage (or a confusing downstream
+/// parse failure) later on.
 pub fn parse<R>(reader: R) -> impl Stream<Item = crate::Result<crate::Command>>
 where
-    R: AsyncRead,
+    R: AsyncRead + Unpin,
+{
+    parse_with_offsets(reader).map(|res| res.map(|(_offset, command)| command))
+}
+
+/// Like [parse], but also yields the byte offset (from the start of the
+/// underlying reader) at which each command's header begins. [crate::catalog]
+/// uses this to build its index in the same pass as parsing, without having
+/// to re-read the stream to recover offsets afterward.
+pub fn parse_with_offsets<R>(
+    reader: R,
+) -> impl Stream<Item = crate::Result<(u64, crate::Command)>>
+where
+    R: AsyncRead + Unpin,
 {
-    let reader = FramedRead::new(reader, framed::SendstreamDecoder::new());
-    reader.filter_map(|item_res| {
+    parse_with_version(reader).filter_map(|item_res| {
         future::ready(match item_res {
-            Ok(framed::Item::Command(command)) => Some(Ok(command)),
-            Ok(framed::Item::SendstreamStart(_)) => None,
+            Ok(VersionedItem::Command(offset, command)) => Some(Ok((offset, command))),
+            Ok(VersionedItem::Version(_)) => None,
             Err(e) => Some(Err(e)),
         })
     })
 }
 
+/// One item from [parse_with_version]: either the sendstream version read
+/// from the magic header, or a parsed command with its offset.
+pub enum VersionedItem {
+    Version(u32),
+    Command(u64, crate::Command),
+}
+
+/// Like [parse_with_offsets], but also surfaces the sendstream version
+/// instead of silently dropping it. [crate::catalog] needs the version to
+/// reparse an individual command by offset later on, since some commands
+/// (e.g. [crate::Command::Write]) encode differently depending on it.
+///
+/// `reader`'s leading bytes are sniffed for zstd/gzip framing first (see
+/// [decompress]) -- real `btrfs send` pipelines are frequently piped through
+/// one or the other, and this lets every entry point here accept that
+/// transparently instead of making every caller decompress beforehand.
+pub fn parse_with_version<R>(reader: R) -> impl Stream<Item = crate::Result<VersionedItem>>
+where
+    R: AsyncRead + Unpin,
+{
+    parse_with_version_inner(reader, framed::SendstreamDecoder::new())
+}
+
+fn parse_with_version_inner<R>(
+    reader: R,
+    decoder: framed::SendstreamDecoder,
+) -> impl Stream<Item = crate::Result<VersionedItem>>
+where
+    R: AsyncRead + Unpin,
+{
+    futures::stream::once(decompress::sniff(reader))
+        .map(move |res| match res {
+            Ok(reader) => FramedRead::new(reader, decoder)
+                .map(|item_res| {
+                    item_res.map(|item| match item {
+                        framed::Item::SendstreamStart(version) => VersionedItem::Version(version),
+                        framed::Item::Command(offset, command) => {
+                            VersionedItem::Command(offset, command)
+                        }
+                    })
+                })
+                .left_stream(),
+            Err(e) => futures::stream::once(future::ready(Err(e))).right_stream(),
+        })
+        .flatten()
+}
+
 #[cfg(test)]
 #[allow(clippy::expect_used)]
 mod tests {
@@ -0,0 +1,604 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! TLV (type-length-value) primitives underlying every sendstream command
+//! body: each attribute is a `u16` attr code, a `u16` length, then that many
+//! bytes of value. Attribute codes are copied from `BTRFS_SEND_A_*` in
+//! linux/fs/btrfs/send.h.
+//!
+//! [TlvValue] maps a Rust type to the attr code it's encoded/decoded as by
+//! default; a handful of commands reuse the same Rust type under a different
+//! attr code (e.g. `Clone`'s `uuid` is wire-compatible with `Subvol`'s, but
+//! tagged `CLONE_UUID` instead of `UUID`), which is where
+//! [parse_tlv_with_attr]/[write_tlv_with_attr] and the marker types in
+//! [attr_types] come in.
+
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use bytes::Bytes;
+use nom::IResult;
+use nom::Parser as _;
+use uuid::Uuid;
+
+use super::NomBytes;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub(crate) enum Attr {
+    Uuid,
+    Ctransid,
+    Ino,
+    Size,
+    Mode,
+    Uid,
+    Gid,
+    Rdev,
+    Ctime,
+    Mtime,
+    Atime,
+    XattrName,
+    XattrData,
+    Path,
+    PathTo,
+    PathLink,
+    FileOffset,
+    Data,
+    CloneUuid,
+    CloneCtransid,
+    ClonePath,
+    CloneOffset,
+    CloneLen,
+    FallocateMode,
+    Fileattr,
+    VerityAlgorithm,
+    VerityBlockSize,
+    VeritySalt,
+    VeritySignature,
+    UnencodedFileLen,
+    UnencodedLen,
+    UnencodedOffset,
+    Compression,
+    Encryption,
+}
+
+impl Attr {
+    fn code(self) -> u16 {
+        match self {
+            Self::Uuid => 1,
+            Self::Ctransid => 2,
+            Self::Ino => 3,
+            Self::Size => 4,
+            Self::Mode => 5,
+            Self::Uid => 6,
+            Self::Gid => 7,
+            Self::Rdev => 8,
+            Self::Ctime => 9,
+            Self::Mtime => 10,
+            Self::Atime => 11,
+            Self::XattrName => 13,
+            Self::XattrData => 14,
+            Self::Path => 15,
+            Self::PathTo => 16,
+            Self::PathLink => 17,
+            Self::FileOffset => 18,
+            Self::Data => 19,
+            Self::CloneUuid => 20,
+            Self::CloneCtransid => 21,
+            Self::ClonePath => 22,
+            Self::CloneOffset => 23,
+            Self::CloneLen => 24,
+            Self::FallocateMode => 25,
+            Self::Fileattr => 26,
+            Self::UnencodedFileLen => 27,
+            Self::UnencodedLen => 28,
+            Self::UnencodedOffset => 29,
+            Self::Compression => 30,
+            Self::Encryption => 31,
+            Self::VerityAlgorithm => 32,
+            Self::VerityBlockSize => 33,
+            Self::VeritySalt => 34,
+            Self::VeritySignature => 35,
+        }
+    }
+
+    /// Just the attr code, little-endian -- used on its own (with no length
+    /// or value following) for the trailing raw `Data` attribute that
+    /// sendstream v2 appends to `Write`/`EncodedWrite` instead of a normal
+    /// TLV.
+    pub(crate) fn tag(self) -> [u8; 2] {
+        self.code().to_le_bytes()
+    }
+}
+
+/// Overrides the attr code a [TlvValue] is tagged with, for the handful of
+/// fields that reuse another field's Rust type under a different attr (e.g.
+/// `Clone::src_path` is a `BytesPath` like any other path, but tagged
+/// `CLONE_PATH` rather than `PATH`). Implemented by the marker types in
+/// [attr_types].
+pub(crate) trait AttrOverride {
+    const ATTR: Attr;
+}
+
+pub(crate) mod attr_types {
+    use super::Attr;
+    use super::AttrOverride;
+
+    pub(crate) struct CloneUuid;
+    impl AttrOverride for CloneUuid {
+        const ATTR: Attr = Attr::CloneUuid;
+    }
+
+    pub(crate) struct CloneCtransid;
+    impl AttrOverride for CloneCtransid {
+        const ATTR: Attr = Attr::CloneCtransid;
+    }
+
+    pub(crate) struct ClonePath;
+    impl AttrOverride for ClonePath {
+        const ATTR: Attr = Attr::ClonePath;
+    }
+
+    pub(crate) struct CloneOffset;
+    impl AttrOverride for CloneOffset {
+        const ATTR: Attr = Attr::CloneOffset;
+    }
+
+    pub(crate) struct PathTo;
+    impl AttrOverride for PathTo {
+        const ATTR: Attr = Attr::PathTo;
+    }
+}
+
+/// A Rust type that can appear as a TLV value, under its default attr code.
+pub(crate) trait TlvValue: Sized {
+    const DEFAULT_ATTR: Attr;
+
+    /// Parse `self` from exactly the bytes of one TLV's value (the length
+    /// was already consumed from the TLV header). Any unconsumed bytes are
+    /// treated the same way the rest of this parser treats short reads of a
+    /// command body: a sign the parser itself is wrong, not malformed input.
+    fn parse_tlv_value(input: NomBytes) -> IResult<NomBytes, Self>;
+
+    fn write_tlv_value(&self, out: &mut Vec<u8>);
+}
+
+fn parse_tlv_attr<T: TlvValue>(input: NomBytes, attr: Attr) -> IResult<NomBytes, T> {
+    let (input, ty) = nom::number::streaming::le_u16(input)?;
+    let (input, len) = nom::number::streaming::le_u16(input)?;
+    let (input, value) = nom::bytes::streaming::take(len).parse(input)?;
+    if ty != attr.code() {
+        return Err(nom::Err::Failure(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::Verify,
+        )));
+    }
+    let (remaining, value) = T::parse_tlv_value(value)?;
+    assert!(
+        remaining.is_empty(),
+        "tlv value for {attr:?} not fully consumed ({} bytes left), parser is broken",
+        remaining.len()
+    );
+    Ok((input, value))
+}
+
+pub(crate) fn parse_tlv<T: TlvValue>(input: NomBytes) -> IResult<NomBytes, T> {
+    parse_tlv_attr(input, T::DEFAULT_ATTR)
+}
+
+/// Like [parse_tlv], but only consumes the next TLV if its attr code matches
+/// `T::DEFAULT_ATTR` -- used for trailing optional fields (`EncodedWrite`'s
+/// `encryption`) where the next TLV might instead be something else
+/// entirely (or the command body might just end here).
+pub(crate) fn parse_tlv_opt<T: TlvValue>(input: NomBytes) -> IResult<NomBytes, Option<T>> {
+    let (_, ty) = nom::number::streaming::le_u16(input.clone())?;
+    if ty == T::DEFAULT_ATTR.code() {
+        let (input, value) = parse_tlv::<T>(input)?;
+        Ok((input, Some(value)))
+    } else {
+        Ok((input, None))
+    }
+}
+
+/// Like [parse_tlv], but tagged with an overridden attr code from
+/// [AttrOverride] instead of `T::DEFAULT_ATTR`. `N` records the attr's fixed
+/// wire-format length for documentation (`0` for variable-length values like
+/// paths).
+pub(crate) fn parse_tlv_with_attr<T: TlvValue, const N: usize, A: AttrOverride>(
+    input: NomBytes,
+) -> IResult<NomBytes, T> {
+    parse_tlv_attr(input, A::ATTR)
+}
+
+fn write_tlv_attr<T: TlvValue>(attr: Attr, value: &T, out: &mut Vec<u8>) {
+    let mut body = Vec::new();
+    value.write_tlv_value(&mut body);
+    out.extend_from_slice(&attr.tag());
+    out.extend_from_slice(&(body.len() as u16).to_le_bytes());
+    out.extend_from_slice(&body);
+}
+
+/// Inverse of [parse_tlv]: append `value`'s TLV (attr code, length, then the
+/// encoded value) to `out`.
+pub(crate) fn write_tlv<T: TlvValue>(value: &T, out: &mut Vec<u8>) {
+    write_tlv_attr(T::DEFAULT_ATTR, value, out)
+}
+
+/// Inverse of [parse_tlv_with_attr].
+pub(crate) fn write_tlv_with_attr<T: TlvValue, const N: usize, A: AttrOverride>(
+    value: &T,
+    out: &mut Vec<u8>,
+) {
+    write_tlv_attr(A::ATTR, value, out)
+}
+
+fn parse_timespec(input: NomBytes) -> IResult<NomBytes, SystemTime> {
+    let (input, sec) = nom::number::streaming::le_u64(input)?;
+    let (input, nsec) = nom::number::streaming::le_u32(input)?;
+    Ok((input, UNIX_EPOCH + Duration::new(sec, nsec)))
+}
+
+fn write_timespec(time: &SystemTime, out: &mut Vec<u8>) {
+    // Sendstreams can't represent times before the epoch; clamp rather than
+    // fail a whole command encode over it.
+    let duration = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::ZERO);
+    out.extend_from_slice(&duration.as_secs().to_le_bytes());
+    out.extend_from_slice(&duration.subsec_nanos().to_le_bytes());
+}
+
+impl TlvValue for crate::BytesPath {
+    const DEFAULT_ATTR: Attr = Attr::Path;
+
+    fn parse_tlv_value(input: NomBytes) -> IResult<NomBytes, Self> {
+        Ok((NomBytes::from(Bytes::new()), Self(input.into())))
+    }
+
+    fn write_tlv_value(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.0);
+    }
+}
+
+impl TlvValue for crate::TemporaryPath {
+    const DEFAULT_ATTR: Attr = Attr::Path;
+
+    fn parse_tlv_value(input: NomBytes) -> IResult<NomBytes, Self> {
+        let (remaining, path) = crate::BytesPath::parse_tlv_value(input)?;
+        Ok((remaining, Self(path)))
+    }
+
+    fn write_tlv_value(&self, out: &mut Vec<u8>) {
+        self.0.write_tlv_value(out)
+    }
+}
+
+impl TlvValue for crate::LinkTarget {
+    const DEFAULT_ATTR: Attr = Attr::PathLink;
+
+    fn parse_tlv_value(input: NomBytes) -> IResult<NomBytes, Self> {
+        let (remaining, path) = crate::BytesPath::parse_tlv_value(input)?;
+        Ok((remaining, Self(path)))
+    }
+
+    fn write_tlv_value(&self, out: &mut Vec<u8>) {
+        self.0.write_tlv_value(out)
+    }
+}
+
+impl TlvValue for crate::XattrName {
+    const DEFAULT_ATTR: Attr = Attr::XattrName;
+
+    fn parse_tlv_value(input: NomBytes) -> IResult<NomBytes, Self> {
+        Ok((NomBytes::from(Bytes::new()), Self(input.into())))
+    }
+
+    fn write_tlv_value(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.as_slice());
+    }
+}
+
+impl TlvValue for crate::XattrData {
+    const DEFAULT_ATTR: Attr = Attr::XattrData;
+
+    fn parse_tlv_value(input: NomBytes) -> IResult<NomBytes, Self> {
+        Ok((NomBytes::from(Bytes::new()), Self(input.into())))
+    }
+
+    fn write_tlv_value(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.as_slice());
+    }
+}
+
+impl TlvValue for crate::Data {
+    const DEFAULT_ATTR: Attr = Attr::Data;
+
+    fn parse_tlv_value(input: NomBytes) -> IResult<NomBytes, Self> {
+        Ok((NomBytes::from(Bytes::new()), Self(input.into())))
+    }
+
+    fn write_tlv_value(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.as_slice());
+    }
+}
+
+impl TlvValue for Uuid {
+    const DEFAULT_ATTR: Attr = Attr::Uuid;
+
+    fn parse_tlv_value(input: NomBytes) -> IResult<NomBytes, Self> {
+        nom::combinator::map_res(nom::bytes::streaming::take(16usize), |b: NomBytes| {
+            Uuid::from_slice(&b)
+        })
+        .parse(input)
+    }
+
+    fn write_tlv_value(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.as_bytes());
+    }
+}
+
+impl TlvValue for crate::Ctransid {
+    const DEFAULT_ATTR: Attr = Attr::Ctransid;
+
+    fn parse_tlv_value(input: NomBytes) -> IResult<NomBytes, Self> {
+        nom::combinator::map(nom::number::streaming::le_u64, Self).parse(input)
+    }
+
+    fn write_tlv_value(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.0.to_le_bytes());
+    }
+}
+
+impl TlvValue for crate::Mode {
+    const DEFAULT_ATTR: Attr = Attr::Mode;
+
+    fn parse_tlv_value(input: NomBytes) -> IResult<NomBytes, Self> {
+        nom::combinator::map(nom::number::streaming::le_u32, Self).parse(input)
+    }
+
+    fn write_tlv_value(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.0.to_le_bytes());
+    }
+}
+
+impl TlvValue for nix::unistd::Uid {
+    const DEFAULT_ATTR: Attr = Attr::Uid;
+
+    fn parse_tlv_value(input: NomBytes) -> IResult<NomBytes, Self> {
+        nom::combinator::map(nom::number::streaming::le_u32, nix::unistd::Uid::from_raw)
+            .parse(input)
+    }
+
+    fn write_tlv_value(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.as_raw().to_le_bytes());
+    }
+}
+
+impl TlvValue for nix::unistd::Gid {
+    const DEFAULT_ATTR: Attr = Attr::Gid;
+
+    fn parse_tlv_value(input: NomBytes) -> IResult<NomBytes, Self> {
+        nom::combinator::map(nom::number::streaming::le_u32, nix::unistd::Gid::from_raw)
+            .parse(input)
+    }
+
+    fn write_tlv_value(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.as_raw().to_le_bytes());
+    }
+}
+
+impl TlvValue for crate::Rdev {
+    const DEFAULT_ATTR: Attr = Attr::Rdev;
+
+    fn parse_tlv_value(input: NomBytes) -> IResult<NomBytes, Self> {
+        nom::combinator::map(nom::number::streaming::le_u64, Self).parse(input)
+    }
+
+    fn write_tlv_value(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.0.to_le_bytes());
+    }
+}
+
+impl TlvValue for crate::Ino {
+    const DEFAULT_ATTR: Attr = Attr::Ino;
+
+    fn parse_tlv_value(input: NomBytes) -> IResult<NomBytes, Self> {
+        nom::combinator::map(nom::number::streaming::le_u64, Self).parse(input)
+    }
+
+    fn write_tlv_value(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.0.to_le_bytes());
+    }
+}
+
+impl TlvValue for crate::FileOffset {
+    const DEFAULT_ATTR: Attr = Attr::FileOffset;
+
+    fn parse_tlv_value(input: NomBytes) -> IResult<NomBytes, Self> {
+        nom::combinator::map(nom::number::streaming::le_u64, Self).parse(input)
+    }
+
+    fn write_tlv_value(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.0.to_le_bytes());
+    }
+}
+
+impl TlvValue for crate::CloneLen {
+    const DEFAULT_ATTR: Attr = Attr::CloneLen;
+
+    fn parse_tlv_value(input: NomBytes) -> IResult<NomBytes, Self> {
+        nom::combinator::map(nom::number::streaming::le_u64, Self).parse(input)
+    }
+
+    fn write_tlv_value(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.0.to_le_bytes());
+    }
+}
+
+impl TlvValue for u64 {
+    const DEFAULT_ATTR: Attr = Attr::Size;
+
+    fn parse_tlv_value(input: NomBytes) -> IResult<NomBytes, Self> {
+        nom::number::streaming::le_u64(input)
+    }
+
+    fn write_tlv_value(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.to_le_bytes());
+    }
+}
+
+macro_rules! time_tlv {
+    ($t:ident, $attr:ident) => {
+        impl TlvValue for crate::$t {
+            const DEFAULT_ATTR: Attr = Attr::$attr;
+
+            fn parse_tlv_value(input: NomBytes) -> IResult<NomBytes, Self> {
+                let (input, time) = parse_timespec(input)?;
+                Ok((input, Self(time)))
+            }
+
+            fn write_tlv_value(&self, out: &mut Vec<u8>) {
+                write_timespec(&self.0, out);
+            }
+        }
+    };
+}
+time_tlv!(Atime, Atime);
+time_tlv!(Mtime, Mtime);
+time_tlv!(Ctime, Ctime);
+
+impl TlvValue for crate::FallocateMode {
+    const DEFAULT_ATTR: Attr = Attr::FallocateMode;
+
+    fn parse_tlv_value(input: NomBytes) -> IResult<NomBytes, Self> {
+        nom::combinator::map(nom::number::streaming::le_u32, Self).parse(input)
+    }
+
+    fn write_tlv_value(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.0.to_le_bytes());
+    }
+}
+
+impl TlvValue for crate::InodeFlags {
+    const DEFAULT_ATTR: Attr = Attr::Fileattr;
+
+    fn parse_tlv_value(input: NomBytes) -> IResult<NomBytes, Self> {
+        nom::combinator::map(nom::number::streaming::le_u32, Self).parse(input)
+    }
+
+    fn write_tlv_value(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.0.to_le_bytes());
+    }
+}
+
+impl TlvValue for crate::VerityAlgorithm {
+    const DEFAULT_ATTR: Attr = Attr::VerityAlgorithm;
+
+    fn parse_tlv_value(input: NomBytes) -> IResult<NomBytes, Self> {
+        nom::combinator::map(nom::number::streaming::u8, Self).parse(input)
+    }
+
+    fn write_tlv_value(&self, out: &mut Vec<u8>) {
+        out.push(self.0);
+    }
+}
+
+impl TlvValue for crate::VerityBlockSize {
+    const DEFAULT_ATTR: Attr = Attr::VerityBlockSize;
+
+    fn parse_tlv_value(input: NomBytes) -> IResult<NomBytes, Self> {
+        nom::combinator::map(nom::number::streaming::le_u32, Self).parse(input)
+    }
+
+    fn write_tlv_value(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.0.to_le_bytes());
+    }
+}
+
+impl TlvValue for crate::VeritySalt {
+    const DEFAULT_ATTR: Attr = Attr::VeritySalt;
+
+    fn parse_tlv_value(input: NomBytes) -> IResult<NomBytes, Self> {
+        Ok((NomBytes::from(Bytes::new()), Self(input.into())))
+    }
+
+    fn write_tlv_value(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.as_slice());
+    }
+}
+
+impl TlvValue for crate::VeritySignature {
+    const DEFAULT_ATTR: Attr = Attr::VeritySignature;
+
+    fn parse_tlv_value(input: NomBytes) -> IResult<NomBytes, Self> {
+        Ok((NomBytes::from(Bytes::new()), Self(input.into())))
+    }
+
+    fn write_tlv_value(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(self.as_slice());
+    }
+}
+
+impl TlvValue for crate::UnencodedFileLen {
+    const DEFAULT_ATTR: Attr = Attr::UnencodedFileLen;
+
+    fn parse_tlv_value(input: NomBytes) -> IResult<NomBytes, Self> {
+        nom::combinator::map(nom::number::streaming::le_u64, Self).parse(input)
+    }
+
+    fn write_tlv_value(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.0.to_le_bytes());
+    }
+}
+
+impl TlvValue for crate::UnencodedLen {
+    const DEFAULT_ATTR: Attr = Attr::UnencodedLen;
+
+    fn parse_tlv_value(input: NomBytes) -> IResult<NomBytes, Self> {
+        nom::combinator::map(nom::number::streaming::le_u64, Self).parse(input)
+    }
+
+    fn write_tlv_value(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.0.to_le_bytes());
+    }
+}
+
+impl TlvValue for crate::UnencodedOffset {
+    const DEFAULT_ATTR: Attr = Attr::UnencodedOffset;
+
+    fn parse_tlv_value(input: NomBytes) -> IResult<NomBytes, Self> {
+        nom::combinator::map(nom::number::streaming::le_u64, Self).parse(input)
+    }
+
+    fn write_tlv_value(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&self.0.to_le_bytes());
+    }
+}
+
+impl TlvValue for crate::Compression {
+    const DEFAULT_ATTR: Attr = Attr::Compression;
+
+    fn parse_tlv_value(input: NomBytes) -> IResult<NomBytes, Self> {
+        nom::combinator::map(nom::number::streaming::le_u32, crate::Compression::from_u32)
+            .parse(input)
+    }
+
+    fn write_tlv_value(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&u32::from(*self).to_le_bytes());
+    }
+}
+
+impl TlvValue for crate::Encryption {
+    const DEFAULT_ATTR: Attr = Attr::Encryption;
+
+    fn parse_tlv_value(input: NomBytes) -> IResult<NomBytes, Self> {
+        nom::combinator::map(nom::number::streaming::le_u32, crate::Encryption::from_u32)
+            .parse(input)
+    }
+
+    fn write_tlv_value(&self, out: &mut Vec<u8>) {
+        out.extend_from_slice(&u32::from(*self).to_le_bytes());
+    }
+}
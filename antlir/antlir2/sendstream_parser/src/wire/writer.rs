@@ -0,0 +1,81 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Inverse of [super::parse]: emit a byte-identical BTRFS sendstream (magic
+//! header, version, then each command's TLV framing via
+//! [crate::Command::to_bytes]) to any `AsyncWrite`. Unlike the parser side,
+//! there's no need to stream commands in lazily -- callers already have the
+//! full `Vec<Command>` (or any other iterable) in hand, so `write` just
+//! takes an `IntoIterator` rather than a `Stream`.
+
+use tokio::io::AsyncWrite;
+use tokio::io::AsyncWriteExt;
+
+use super::MAGIC_HEADER;
+use crate::Command;
+use crate::Result;
+
+/// Write `commands` to `writer` as a complete sendstream at `version`: the
+/// magic header and version, followed by each command's on-wire encoding in
+/// order. Does not write an [crate::Command::End] on the caller's behalf --
+/// include one in `commands` if the stream should be terminated normally.
+pub async fn write<W>(
+    mut writer: W,
+    version: u32,
+    commands: impl IntoIterator<Item = Command>,
+) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    writer.write_all(MAGIC_HEADER).await?;
+    writer.write_all(&version.to_le_bytes()).await?;
+    for command in commands {
+        writer.write_all(&command.to_bytes(version)).await?;
+    }
+    writer.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::expect_used)]
+mod tests {
+    use std::io::Cursor;
+
+    use futures::StreamExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn round_trips_the_demo_sendstream() {
+        let src = include_bytes!("../../testdata/demo.sendstream");
+        // The header (13-byte magic + u32 version) is stripped out by
+        // `parse`, so pull the version back out of the raw bytes to encode
+        // at the same version the fixture was generated with.
+        let version = u32::from_le_bytes(
+            src[MAGIC_HEADER.len()..MAGIC_HEADER.len() + 4]
+                .try_into()
+                .expect("demo.sendstream is missing its version"),
+        );
+        let original: Vec<Command> = crate::wire::parse(Cursor::new(src))
+            .map(|res| res.expect("while parsing original"))
+            .collect()
+            .await;
+
+        let mut encoded = Vec::new();
+        write(&mut encoded, version, original.clone())
+            .await
+            .expect("while encoding");
+
+        let round_tripped: Vec<Command> = crate::wire::parse(Cursor::new(&encoded))
+            .map(|res| res.expect("while parsing round-tripped"))
+            .collect()
+            .await;
+
+        assert_eq!(original, round_tripped);
+        assert_eq!(&encoded, src);
+    }
+}
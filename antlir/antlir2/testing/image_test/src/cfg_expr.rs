@@ -0,0 +1,297 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! A small `cfg()` predicate grammar, modeled on `cargo-platform`'s cfg
+//! matcher, used to implement `--skip-if-cfg`. Grammar:
+//!   expr := "all" "(" list ")"
+//!         | "any" "(" list ")"
+//!         | "not" "(" expr ")"
+//!         | ident "=" string
+//!         | ident
+//!   list := (expr ("," expr)*)?
+
+use std::collections::BTreeMap;
+
+use anyhow::Context;
+use anyhow::Result;
+
+use crate::Test;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cfg {
+    All(Vec<Cfg>),
+    Any(Vec<Cfg>),
+    Not(Box<Cfg>),
+    Equal(String, String),
+    Ident(String),
+}
+
+#[derive(Debug, Clone, PartialEq, thiserror::Error)]
+pub enum CfgParseError {
+    #[error("unexpected character {0:?} in cfg expression")]
+    UnexpectedChar(char),
+    #[error("unexpected end of cfg expression")]
+    UnexpectedEof,
+    #[error("unexpected token {0:?}")]
+    UnexpectedToken(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Str(String),
+    Equals,
+    Comma,
+    LParen,
+    RParen,
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>, CfgParseError> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' => {
+                chars.next();
+                tokens.push(Token::LParen);
+            }
+            ')' => {
+                chars.next();
+                tokens.push(Token::RParen);
+            }
+            ',' => {
+                chars.next();
+                tokens.push(Token::Comma);
+            }
+            '=' => {
+                chars.next();
+                tokens.push(Token::Equals);
+            }
+            '"' => {
+                chars.next();
+                let mut value = String::new();
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        Some(c) => value.push(c),
+                        None => return Err(CfgParseError::UnexpectedEof),
+                    }
+                }
+                tokens.push(Token::Str(value));
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                let mut ident = String::new();
+                while let Some(&c) = chars.peek() {
+                    if c.is_alphanumeric() || c == '_' {
+                        ident.push(c);
+                        chars.next();
+                    } else {
+                        break;
+                    }
+                }
+                tokens.push(Token::Ident(ident));
+            }
+            other => return Err(CfgParseError::UnexpectedChar(other)),
+        }
+    }
+    Ok(tokens)
+}
+
+struct TokenParser<'a> {
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl<'a> TokenParser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn bump(&mut self) -> Option<&Token> {
+        let tok = self.tokens.get(self.pos);
+        self.pos += 1;
+        tok
+    }
+
+    fn expect(&mut self, want: &Token) -> Result<(), CfgParseError> {
+        match self.bump() {
+            Some(tok) if tok == want => Ok(()),
+            Some(tok) => Err(CfgParseError::UnexpectedToken(format!("{tok:?}"))),
+            None => Err(CfgParseError::UnexpectedEof),
+        }
+    }
+
+    fn parse_expr(&mut self) -> Result<Cfg, CfgParseError> {
+        match self.bump().cloned().ok_or(CfgParseError::UnexpectedEof)? {
+            Token::Ident(ident) => match ident.as_str() {
+                "all" => Ok(Cfg::All(self.parse_list()?)),
+                "any" => Ok(Cfg::Any(self.parse_list()?)),
+                "not" => {
+                    self.expect(&Token::LParen)?;
+                    let inner = self.parse_expr()?;
+                    self.expect(&Token::RParen)?;
+                    Ok(Cfg::Not(Box::new(inner)))
+                }
+                key => {
+                    if self.peek() == Some(&Token::Equals) {
+                        self.bump();
+                        match self.bump().cloned() {
+                            Some(Token::Str(value)) => Ok(Cfg::Equal(key.to_owned(), value)),
+                            Some(tok) => Err(CfgParseError::UnexpectedToken(format!("{tok:?}"))),
+                            None => Err(CfgParseError::UnexpectedEof),
+                        }
+                    } else {
+                        Ok(Cfg::Ident(key.to_owned()))
+                    }
+                }
+            },
+            other => Err(CfgParseError::UnexpectedToken(format!("{other:?}"))),
+        }
+    }
+
+    fn parse_list(&mut self) -> Result<Vec<Cfg>, CfgParseError> {
+        self.expect(&Token::LParen)?;
+        let mut items = Vec::new();
+        if self.peek() == Some(&Token::RParen) {
+            self.bump();
+            return Ok(items);
+        }
+        loop {
+            items.push(self.parse_expr()?);
+            match self.bump().cloned() {
+                Some(Token::Comma) => continue,
+                Some(Token::RParen) => break,
+                Some(tok) => return Err(CfgParseError::UnexpectedToken(format!("{tok:?}"))),
+                None => return Err(CfgParseError::UnexpectedEof),
+            }
+        }
+        Ok(items)
+    }
+}
+
+impl std::str::FromStr for Cfg {
+    type Err = CfgParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let tokens = tokenize(s)?;
+        let mut parser = TokenParser {
+            tokens: &tokens,
+            pos: 0,
+        };
+        let expr = parser.parse_expr()?;
+        match parser.peek() {
+            None => Ok(expr),
+            Some(tok) => Err(CfgParseError::UnexpectedToken(format!("{tok:?}"))),
+        }
+    }
+}
+
+impl Cfg {
+    /// Evaluate this predicate against a map of cfg keys (`target_os`,
+    /// `target_arch`, plus any test env vars) to their values. `all`/`any`
+    /// over an empty list are `true`/`false` respectively.
+    pub fn eval(&self, env: &BTreeMap<String, String>) -> bool {
+        match self {
+            Self::All(exprs) => exprs.iter().all(|e| e.eval(env)),
+            Self::Any(exprs) => exprs.iter().any(|e| e.eval(env)),
+            Self::Not(expr) => !expr.eval(env),
+            Self::Equal(key, value) => env.get(key).is_some_and(|v| v == value),
+            Self::Ident(key) => env.contains_key(key),
+        }
+    }
+}
+
+/// The default cfg map a `--skip-if-cfg` expression is evaluated against:
+/// the host's `target_os`/`target_arch`, plus whatever env vars the test
+/// invocation was already carrying.
+pub fn default_env(extra: &BTreeMap<String, String>) -> BTreeMap<String, String> {
+    let mut env = BTreeMap::from([
+        ("target_os".to_owned(), std::env::consts::OS.to_owned()),
+        ("target_arch".to_owned(), std::env::consts::ARCH.to_owned()),
+    ]);
+    env.extend(extra.iter().map(|(k, v)| (k.clone(), v.clone())));
+    env
+}
+
+/// Whether `test` should be skipped without ever launching the inner
+/// command, based on its `--skip-if-cfg` expression (if any).
+pub fn should_skip(test: &Test, extra_env: &BTreeMap<String, String>) -> Result<bool> {
+    match test.skip_if_cfg() {
+        Some(expr) => {
+            let cfg: Cfg = expr.parse().with_context(|| {
+                format!("while parsing --skip-if-cfg expression '{expr}'")
+            })?;
+            Ok(cfg.eval(&default_env(extra_env)))
+        }
+        None => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_ident() {
+        assert_eq!("unix".parse(), Ok(Cfg::Ident("unix".to_owned())));
+    }
+
+    #[test]
+    fn test_parse_equal() {
+        assert_eq!(
+            "target_os = \"linux\"".parse(),
+            Ok(Cfg::Equal("target_os".to_owned(), "linux".to_owned()))
+        );
+    }
+
+    #[test]
+    fn test_parse_not() {
+        assert_eq!(
+            "not(unix)".parse(),
+            Ok(Cfg::Not(Box::new(Cfg::Ident("unix".to_owned()))))
+        );
+    }
+
+    #[test]
+    fn test_parse_all_any_nested() {
+        assert_eq!(
+            r#"all(target_os = "linux", any(target_arch = "x86_64", target_arch = "aarch64"))"#
+                .parse(),
+            Ok(Cfg::All(vec![
+                Cfg::Equal("target_os".to_owned(), "linux".to_owned()),
+                Cfg::Any(vec![
+                    Cfg::Equal("target_arch".to_owned(), "x86_64".to_owned()),
+                    Cfg::Equal("target_arch".to_owned(), "aarch64".to_owned()),
+                ]),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_parse_empty_lists() {
+        assert_eq!("all()".parse(), Ok(Cfg::All(vec![])));
+        assert_eq!("any()".parse(), Ok(Cfg::Any(vec![])));
+    }
+
+    #[test]
+    fn test_eval() {
+        let env = BTreeMap::from([
+            ("target_os".to_owned(), "linux".to_owned()),
+            ("ci".to_owned(), "1".to_owned()),
+        ]);
+        assert!(Cfg::All(vec![]).eval(&env));
+        assert!(!Cfg::Any(vec![]).eval(&env));
+        assert!(Cfg::Equal("target_os".to_owned(), "linux".to_owned()).eval(&env));
+        assert!(!Cfg::Equal("target_os".to_owned(), "macos".to_owned()).eval(&env));
+        assert!(Cfg::Ident("ci".to_owned()).eval(&env));
+        assert!(!Cfg::Ident("missing".to_owned()).eval(&env));
+        assert!(Cfg::Not(Box::new(Cfg::Ident("missing".to_owned()))).eval(&env));
+    }
+}
@@ -0,0 +1,245 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! RPC control channel for the test executor.
+//!
+//! When [crate::exec::Spec::control_socket] is set, `Args::run` no longer
+//! `exec()`s straight into the test command: it spawns the test as a real
+//! child and stays alive as a supervisor, listening on the control socket
+//! for requests from the host. The only request today is [Request::Spawn],
+//! which launches an auxiliary command inside the same container
+//! environment (reusing the primary test's working directory, user, and
+//! env) and streams its stdout/stderr back over
+//! [crate::exec::Spec::stream_socket] using [stream_mux], tagged with a
+//! fresh source id so the host can tell it apart from the test's own
+//! output. This lets a harness run debugging probes or setup/teardown hooks
+//! alongside the test without re-entering the container or racing it.
+//!
+//! One supervisor thread accepts control connections and hands each to its
+//! own thread, so a slow probe doesn't hold up the others, while the main
+//! thread waits on the primary test child. The supervisor exits (taking any
+//! in-flight auxiliary commands with it) as soon as the primary test does,
+//! so there's no separate shutdown handshake for the accept loop.
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::io::Read;
+use std::net::Shutdown;
+use std::os::unix::net::UnixListener;
+use std::os::unix::net::UnixStream;
+use std::os::unix::process::CommandExt;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Stdio;
+use std::sync::atomic::AtomicU32;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::Instant;
+
+use anyhow::Context;
+use anyhow::Result;
+use nix::unistd::Gid;
+use nix::unistd::Uid;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::stream_mux;
+use crate::stream_mux::StreamKind;
+
+/// A request the host can make of a running supervisor.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum Request {
+    /// Launch `cmd` inside the container, reusing the primary test's
+    /// working directory and user. `env` is merged on top of (and can
+    /// override) the primary test's own env.
+    Spawn {
+        cmd: Vec<OsString>,
+        #[serde(default)]
+        env: BTreeMap<String, String>,
+    },
+}
+
+/// The supervisor's reply to a [Request]. For `Spawn`, the auxiliary
+/// command's stdout/stderr show up on the stream socket as [stream_mux::Frame]s
+/// carrying `source`, before this response is sent.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum Response {
+    Spawned {
+        source: u32,
+        exit_code: Option<i32>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+/// Reusable context for spawning an auxiliary command the same way the
+/// primary test was spawned.
+struct SpawnContext {
+    working_directory: PathBuf,
+    uid: Uid,
+    gid: Gid,
+    env: BTreeMap<String, String>,
+    stream_socket: Option<PathBuf>,
+}
+
+/// Run `child` (the already-spawned primary test command) to completion
+/// while serving [Request]s on `control_socket`, then exit this process with
+/// the primary test's exit code.
+pub(crate) fn supervise(
+    mut child: std::process::Child,
+    control_socket: &Path,
+    working_directory: PathBuf,
+    uid: Uid,
+    gid: Gid,
+    env: BTreeMap<String, String>,
+    stream_socket: Option<PathBuf>,
+) -> Result<()> {
+    let ctx = Arc::new(SpawnContext {
+        working_directory,
+        uid,
+        gid,
+        env,
+        stream_socket,
+    });
+
+    let listener = UnixListener::bind(control_socket).with_context(|| {
+        format!(
+            "while binding control socket '{}'",
+            control_socket.display()
+        )
+    })?;
+    // auxiliary spawns count up from 1; 0 is reserved for the primary test
+    // (stream_mux::PRIMARY_SOURCE)
+    let next_source = Arc::new(AtomicU32::new(stream_mux::PRIMARY_SOURCE + 1));
+    thread::spawn(move || {
+        for conn in listener.incoming() {
+            let Ok(conn) = conn else {
+                continue;
+            };
+            let ctx = Arc::clone(&ctx);
+            let next_source = Arc::clone(&next_source);
+            // each connection is handled on its own thread so a slow probe
+            // can't stall others; contention on `next_source` is the only
+            // shared state
+            thread::spawn(move || handle_connection(conn, &ctx, &next_source));
+        }
+    });
+
+    let status = child.wait().context("while waiting for test command")?;
+    std::process::exit(status.code().unwrap_or(1));
+}
+
+fn handle_connection(mut conn: UnixStream, ctx: &SpawnContext, next_source: &AtomicU32) {
+    let response = match read_request(&mut conn) {
+        Ok(request) => match request {
+            Request::Spawn { cmd, env } => {
+                let source = next_source.fetch_add(1, Ordering::SeqCst);
+                match run_aux_command(ctx, cmd, env, source) {
+                    Ok(exit_code) => Response::Spawned { source, exit_code },
+                    Err(e) => Response::Error {
+                        message: format!("{e:#}"),
+                    },
+                }
+            }
+        },
+        Err(e) => Response::Error {
+            message: format!("{e:#}"),
+        },
+    };
+    if let Err(e) = write_response(&mut conn, &response) {
+        // the host already disconnected or otherwise isn't listening; the
+        // auxiliary command (if any) already ran to completion either way
+        tracing::warn!("failed to send control response: {e:#}");
+    }
+    let _ = conn.shutdown(Shutdown::Both);
+}
+
+/// Launch `cmd` with `ctx`'s working directory/user/env (overridden by
+/// `extra_env`), relaying its stdout/stderr to `ctx.stream_socket` tagged
+/// with `source` if one is configured, and return its exit code once it
+/// finishes.
+fn run_aux_command(
+    ctx: &SpawnContext,
+    cmd: Vec<OsString>,
+    extra_env: BTreeMap<String, String>,
+    source: u32,
+) -> Result<Option<i32>> {
+    let mut cmd_iter = cmd.into_iter();
+    let mut command = Command::new(cmd_iter.next().context("spawn request had no command")?);
+    let mut env = ctx.env.clone();
+    env.extend(extra_env);
+    command
+        .args(cmd_iter)
+        .envs(env)
+        .current_dir(&ctx.working_directory)
+        .uid(ctx.uid.into())
+        .gid(ctx.gid.into());
+
+    if let Some(stream_socket) = &ctx.stream_socket {
+        command.stdout(Stdio::piped()).stderr(Stdio::piped());
+        let mut child = command
+            .spawn()
+            .context("while spawning auxiliary command")?;
+        let start = Instant::now();
+        let stdout = child.stdout.take().context("child had no stdout pipe")?;
+        let stderr = child.stderr.take().context("child had no stderr pipe")?;
+        let stdout_socket = stream_socket.clone();
+        let stdout_thread = thread::spawn(move || {
+            stream_mux::forward_to_socket(stdout, &stdout_socket, source, StreamKind::Stdout, start)
+        });
+        let stderr_socket = stream_socket.clone();
+        let stderr_thread = thread::spawn(move || {
+            stream_mux::forward_to_socket(stderr, &stderr_socket, source, StreamKind::Stderr, start)
+        });
+        let status = child
+            .wait()
+            .context("while waiting for auxiliary command")?;
+        // these only fail if the host isn't listening on the stream socket
+        // at all, which isn't fatal to reporting the exit code below
+        if let Ok(Err(e)) = stdout_thread.join() {
+            tracing::warn!("auxiliary stdout forwarding failed: {e:#}");
+        }
+        if let Ok(Err(e)) = stderr_thread.join() {
+            tracing::warn!("auxiliary stderr forwarding failed: {e:#}");
+        }
+        Ok(status.code())
+    } else {
+        let status = command
+            .spawn()
+            .context("while spawning auxiliary command")?
+            .wait()
+            .context("while waiting for auxiliary command")?;
+        Ok(status.code())
+    }
+}
+
+/// 4-byte big-endian length prefix, then that many bytes of JSON.
+fn read_request(conn: &mut UnixStream) -> Result<Request> {
+    let mut len_buf = [0u8; 4];
+    conn.read_exact(&mut len_buf)
+        .context("while reading request length")?;
+    let len = u32::from_be_bytes(len_buf) as usize;
+    let mut buf = vec![0u8; len];
+    conn.read_exact(&mut buf)
+        .context("while reading request body")?;
+    serde_json::from_slice(&buf).context("while parsing control request")
+}
+
+fn write_response(conn: &mut UnixStream, response: &Response) -> Result<()> {
+    use std::io::Write;
+
+    let body = serde_json::to_vec(response).context("while serializing control response")?;
+    let len = u32::try_from(body.len()).context("control response too large")?;
+    conn.write_all(&len.to_be_bytes())
+        .context("while writing response length")?;
+    conn.write_all(&body)
+        .context("while writing response body")?;
+    Ok(())
+}
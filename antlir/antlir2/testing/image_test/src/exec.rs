@@ -9,10 +9,14 @@ use std::collections::BTreeMap;
 use std::ffi::OsString;
 use std::os::unix::io::FromRawFd;
 use std::os::unix::io::OwnedFd;
+use std::os::unix::io::RawFd;
 use std::os::unix::process::CommandExt;
+use std::path::Path;
 use std::path::PathBuf;
 use std::process::Command;
 use std::process::Stdio;
+use std::thread;
+use std::time::Instant;
 
 use anyhow::Context;
 use anyhow::Result;
@@ -22,6 +26,10 @@ use nix::unistd::User;
 use serde::Deserialize;
 use serde::Serialize;
 
+use crate::control;
+use crate::stream_mux;
+use crate::stream_mux::StreamKind;
+
 #[derive(Debug, Clone, Builder, Serialize, Deserialize)]
 /// Specification of how to execute the test.
 /// This specification is just how to invoke the inner test binary, the
@@ -36,6 +44,19 @@ pub(crate) struct Spec {
     /// Set these env vars in the test environment
     #[serde(default)]
     env: BTreeMap<String, String>,
+    /// If set, relay stdout and stderr to this host-provided unix socket as
+    /// tagged, length-prefixed frames (see [crate::stream_mux]). Opt-in per
+    /// test, so that tests not worth the extra forwarder processes keep the
+    /// old plain inherited-fd behavior.
+    #[serde(default)]
+    stream_socket: Option<PathBuf>,
+    /// If set, don't `exec()` into the test command directly: spawn it as a
+    /// child and stay alive as a supervisor listening on this socket for
+    /// [crate::control::Request]s (e.g. to run an auxiliary command
+    /// alongside the test). Requires `stream_socket` to actually see any
+    /// auxiliary command's output.
+    #[serde(default)]
+    control_socket: Option<PathBuf>,
 }
 
 #[derive(Debug, Parser)]
@@ -69,12 +90,6 @@ impl Args {
             .context("failed to lookup user")?
             .with_context(|| format!("no such user '{}'", spec.user))?;
 
-        // Check if streaming output is enabled (from spec.env, not process env)
-        let stream_output = env
-            .get("ANTLIR_STREAM_TO_CONSOLE")
-            .map(|v| v == "1")
-            .unwrap_or(false);
-
         // Get extra test args if set (from spec.env), split into vector
         let extra_test_args: Vec<&str> = env
             .get("ANTLIR_EXTRA_TEST_ARGS")
@@ -91,45 +106,104 @@ impl Args {
             .uid(user.uid.into())
             .gid(user.gid.into());
 
-        if stream_output {
-            // Setup tee to duplicate output to both stdout and /dev/console
-            // 1. Create a pipe
-            // 2. Spawn `tee -a /dev/console` with stdin from pipe read end
-            // 3. Redirect our stdout/stderr to pipe write end
-
-            let (pipe_read, pipe_write) = nix::unistd::pipe().context("while creating pipe")?;
-            let pipe_read: OwnedFd = pipe_read;
-            let pipe_write: OwnedFd = pipe_write;
-
-            // Spawn tee as a helper process
-            // tee reads from stdin and writes to both stdout and /dev/console
-            // Mute tee's stderr to suppress I/O error messages (expected due to broken pipes in
-            // the case of terminations)
-            Command::new("tee")
-                .arg("--output-error=exit")
-                .arg("-a")
-                .arg("/dev/console")
-                .stdin(Stdio::from(pipe_read))
-                .stderr(Stdio::null())
-                .spawn()
-                .context("while spawning tee")?;
-
-            // Redirect stdout and stderr to the pipe write end using dup2
-            // SAFETY: STDOUT_FILENO and STDERR_FILENO are valid open file descriptors
-            unsafe {
-                let stdout = OwnedFd::from_raw_fd(nix::libc::STDOUT_FILENO);
-                let stderr = OwnedFd::from_raw_fd(nix::libc::STDERR_FILENO);
-                nix::unistd::dup2(&pipe_write, &mut std::mem::ManuallyDrop::new(stdout))
-                    .context("while redirecting stdout to pipe")?;
-                nix::unistd::dup2(&pipe_write, &mut std::mem::ManuallyDrop::new(stderr))
-                    .context("while redirecting stderr to pipe")?;
+        if let Some(control_socket) = spec.control_socket.clone() {
+            // A control channel means something may need to talk to this
+            // process (and its auxiliary spawns) after the test starts, so
+            // it can't be exec()'d away: spawn it as a real child and run as
+            // a supervisor instead. With a real child (rather than this
+            // process itself), forwarding its stdout/stderr is just piping,
+            // no dup2 dance required.
+            if spec.stream_socket.is_some() {
+                cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+            }
+            let mut child = cmd.spawn().context("while spawning test command")?;
+            if let Some(stream_socket) = spec.stream_socket.clone() {
+                let start = Instant::now();
+                let stdout = child.stdout.take().context("child had no stdout pipe")?;
+                let stderr = child.stderr.take().context("child had no stderr pipe")?;
+                let stderr_socket = stream_socket.clone();
+                thread::spawn(move || {
+                    let _ = stream_mux::forward_to_socket(
+                        stdout,
+                        &stream_socket,
+                        stream_mux::PRIMARY_SOURCE,
+                        StreamKind::Stdout,
+                        start,
+                    );
+                });
+                thread::spawn(move || {
+                    let _ = stream_mux::forward_to_socket(
+                        stderr,
+                        &stderr_socket,
+                        stream_mux::PRIMARY_SOURCE,
+                        StreamKind::Stderr,
+                        start,
+                    );
+                });
             }
+            return control::supervise(
+                child,
+                &control_socket,
+                spec.working_directory,
+                user.uid,
+                user.gid,
+                env,
+                spec.stream_socket,
+            );
+        }
 
-            // Close the original pipe_write fd (now duplicated to stdout/stderr)
-            drop(pipe_write);
+        if let Some(socket) = &spec.stream_socket {
+            // Route stdout and stderr through a dedicated forwarder process
+            // per stream, the same shape as the old `tee` duplication:
+            // 1. Create a pipe
+            // 2. Spawn a forwarder with stdin from the pipe read end
+            // 3. Redirect our stdout/stderr to the pipe write end
+            // Two forwarders (rather than one handling both fds) so each
+            // can tag every frame with its own stream unambiguously.
+            spawn_stream_forwarder(socket, StreamKind::Stdout, nix::libc::STDOUT_FILENO)
+                .context("while setting up stdout forwarder")?;
+            spawn_stream_forwarder(socket, StreamKind::Stderr, nix::libc::STDERR_FILENO)
+                .context("while setting up stderr forwarder")?;
         }
 
         // exec() the test command - replaces this process
         Err(cmd.exec().into())
     }
 }
+
+/// Set up forwarding of `target_fd` (stdout or stderr) to `socket`, tagged
+/// as `stream`: a pipe plus a forwarder helper reading the pipe, the same
+/// shape as the old single `tee` child, but one forwarder per stream so
+/// each can tag its own frames unambiguously (see [crate::stream_mux]).
+fn spawn_stream_forwarder(socket: &Path, stream: StreamKind, target_fd: RawFd) -> Result<()> {
+    let (pipe_read, pipe_write) = nix::unistd::pipe().context("while creating pipe")?;
+    let pipe_read: OwnedFd = pipe_read;
+    let pipe_write: OwnedFd = pipe_write;
+
+    // Mute the forwarder's own stderr to suppress I/O error messages
+    // (expected due to broken pipes when the test is terminated), the same
+    // as the old `tee` invocation did.
+    Command::new(std::env::current_exe().context("while getting argv[0]")?)
+        .arg(crate::stream_mux::STREAM_FORWARD_SUBCOMMAND)
+        .arg(socket)
+        .arg(match stream {
+            StreamKind::Stdout => "stdout",
+            StreamKind::Stderr => "stderr",
+        })
+        .stdin(Stdio::from(pipe_read))
+        .stderr(Stdio::null())
+        .spawn()
+        .with_context(|| format!("while spawning {stream:?} forwarder"))?;
+
+    // Redirect target_fd to the pipe write end using dup2
+    // SAFETY: target_fd is a valid open standard stream fd
+    unsafe {
+        let target = OwnedFd::from_raw_fd(target_fd);
+        nix::unistd::dup2(&pipe_write, &mut std::mem::ManuallyDrop::new(target))
+            .with_context(|| format!("while redirecting fd {target_fd} to pipe"))?;
+    }
+
+    // Close the original pipe_write fd (now duplicated to target_fd)
+    drop(pipe_write);
+    Ok(())
+}
@@ -0,0 +1,190 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Comparison logic for `Test::Golden`, borrowing cargo-test-support's
+//! `compare.rs`/`diff.rs` approach: normalize known-variable substrings out
+//! of both sides, match with `[..]` standing in for an arbitrary run of
+//! characters, and print a colorized unified diff on mismatch.
+
+use std::path::Path;
+
+use anyhow::Context;
+use anyhow::Result;
+
+/// One `from -> to` substitution applied to both the expected and actual
+/// text before comparing, so a run that differs only in some inherently
+/// variable location (a tempdir path handed to the test by its caller, ...)
+/// still compares equal. Parsed from `--normalize FROM=TO`.
+pub struct Normalization {
+    from: String,
+    to: String,
+}
+
+/// Parse `--normalize FROM=TO` strings into [Normalization]s. Invalid
+/// entries (missing `=`) are an argument error, not a silent no-op.
+pub fn parse_normalizations(raw: &[String]) -> Result<Vec<Normalization>> {
+    raw.iter()
+        .map(|entry| {
+            let (from, to) = entry.split_once('=').with_context(|| {
+                format!("--normalize entry '{entry}' is not of the form FROM=TO")
+            })?;
+            Ok(Normalization {
+                from: from.to_owned(),
+                to: to.to_owned(),
+            })
+        })
+        .collect()
+}
+
+/// Substitute `container_root` and `repo_root` (collapsed to `[ROOT]`/
+/// `[REPO]`, the two substitutions that are always variable between runs
+/// without the caller having to spell them out) and then `normalizations`,
+/// out of `text`.
+fn normalize(
+    text: &str,
+    normalizations: &[Normalization],
+    container_root: &Path,
+    repo_root: &Path,
+) -> String {
+    let mut text = text.replace(&*container_root.to_string_lossy(), "[ROOT]");
+    text = text.replace(&*repo_root.to_string_lossy(), "[REPO]");
+    for n in normalizations {
+        text = text.replace(&n.from, &n.to);
+    }
+    text
+}
+
+/// True if `actual` matches `expected`, treating any `[..]` in `expected` as
+/// a wildcard matching zero or more characters -- the same token
+/// cargo-test-support's golden output comparisons use. `expected` must
+/// match the whole of `actual`, not just a substring of it.
+fn matches_wildcard(expected: &str, actual: &str) -> bool {
+    if !expected.contains("[..]") {
+        return expected == actual;
+    }
+    let parts: Vec<&str> = expected.split("[..]").collect();
+    let (first, last) = (parts[0], parts[parts.len() - 1]);
+    if actual.len() < first.len() + last.len()
+        || !actual.starts_with(first)
+        || !actual.ends_with(last)
+    {
+        return false;
+    }
+    // the middle parts have to fit between the prefix and the suffix
+    let end = actual.len() - last.len();
+    let mut pos = first.len();
+    for part in &parts[1..parts.len() - 1] {
+        match actual[pos..end].find(part) {
+            Some(offset) => pos += offset + part.len(),
+            None => return false,
+        }
+    }
+    true
+}
+
+/// Compare `actual` against the contents of `expected_path`, after
+/// normalizing both. Returns `true` on a match. On mismatch, prints a
+/// colorized unified diff to stderr and, under tpx, writes the expected
+/// text, the actual text and the diff into `TEST_RESULT_ARTIFACTS_DIR` with
+/// annotations, mirroring the pattern `report()` uses for container logs.
+pub fn compare(
+    which: &str,
+    expected_path: &Path,
+    actual: &str,
+    normalizations: &[Normalization],
+    container_root: &Path,
+    repo_root: &Path,
+) -> Result<bool> {
+    let expected = std::fs::read_to_string(expected_path)
+        .with_context(|| format!("while reading expected {which} at {}", expected_path.display()))?;
+    let expected = normalize(&expected, normalizations, container_root, repo_root);
+    let actual = normalize(actual, normalizations, container_root, repo_root);
+
+    if matches_wildcard(&expected, &actual) {
+        return Ok(true);
+    }
+
+    let diff = unified_diff(&expected, &actual);
+    eprintln!("golden {which} mismatch:\n{diff}");
+
+    if let Some(artifacts_dir) = std::env::var_os("TEST_RESULT_ARTIFACTS_DIR") {
+        let artifacts_dir = Path::new(&artifacts_dir);
+        std::fs::create_dir_all(artifacts_dir)?;
+        std::fs::write(artifacts_dir.join(format!("{which}.expected.txt")), &expected)?;
+        std::fs::write(artifacts_dir.join(format!("{which}.actual.txt")), &actual)?;
+        std::fs::write(artifacts_dir.join(format!("{which}.diff.txt")), &diff)?;
+        if let Some(annotations_dir) = std::env::var_os("TEST_RESULT_ARTIFACT_ANNOTATIONS_DIR") {
+            std::fs::create_dir_all(&annotations_dir)?;
+            for name in ["expected", "actual", "diff"] {
+                std::fs::write(
+                    Path::new(&annotations_dir).join(format!("{which}.{name}.txt.annotation")),
+                    format!(
+                        r#"{{"type": {{"generic_text_log": {{}}}}, "description": "golden {which} {name}"}}"#
+                    ),
+                )?;
+            }
+        }
+    }
+
+    Ok(false)
+}
+
+/// A minimal line-based unified diff with `-`/`+` prefixes colorized red/
+/// green, good enough to eyeball a golden-output mismatch without pulling
+/// in a diff crate for what's ultimately just two short strings.
+fn unified_diff(expected: &str, actual: &str) -> String {
+    let expected_lines: Vec<&str> = expected.lines().collect();
+    let actual_lines: Vec<&str> = actual.lines().collect();
+
+    // Longest common subsequence over lines, then walk it back into the
+    // usual interleaved +/-/context diff.
+    let n = expected_lines.len();
+    let m = actual_lines.len();
+    let mut lcs = vec![vec![0u32; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if expected_lines[i] == actual_lines[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                lcs[i + 1][j].max(lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut out = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        if expected_lines[i] == actual_lines[j] {
+            out.push_str("  ");
+            out.push_str(expected_lines[i]);
+            out.push('\n');
+            i += 1;
+            j += 1;
+        } else if lcs[i + 1][j] >= lcs[i][j + 1] {
+            out.push_str("\x1b[31m- ");
+            out.push_str(expected_lines[i]);
+            out.push_str("\x1b[0m\n");
+            i += 1;
+        } else {
+            out.push_str("\x1b[32m+ ");
+            out.push_str(actual_lines[j]);
+            out.push_str("\x1b[0m\n");
+            j += 1;
+        }
+    }
+    for line in &expected_lines[i..n] {
+        out.push_str("\x1b[31m- ");
+        out.push_str(line);
+        out.push_str("\x1b[0m\n");
+    }
+    for line in &actual_lines[j..m] {
+        out.push_str("\x1b[32m+ ");
+        out.push_str(line);
+        out.push_str("\x1b[0m\n");
+    }
+    out
+}
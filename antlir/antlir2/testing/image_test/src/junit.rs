@@ -0,0 +1,328 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Normalizes whichever native result format a test framework produced
+//! (gtest JSON, pyunit JSON, libtest's `--format json` event stream) into a
+//! single JUnit-style XML document, so downstream tooling only ever has to
+//! understand one schema: suite, name, status and duration.
+
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::Context;
+use anyhow::Result;
+
+use crate::ArtifactKind;
+use crate::ResultFormat;
+use crate::ResultSink;
+use crate::Test;
+use crate::TpxArtifact;
+
+/// A single test outcome, normalized away from whichever framework produced
+/// it.
+#[derive(Debug, Clone, PartialEq)]
+struct JunitCase {
+    suite: String,
+    name: String,
+    status: JunitStatus,
+    duration: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum JunitStatus {
+    Passed,
+    Failed(Option<String>),
+    Skipped,
+}
+
+/// Register the artifact that will hold the normalized JUnit report before
+/// the test even runs, mirroring [`TpxArtifact::new_tpx_or_none`]'s
+/// register-first approach so a timeout still uploads whatever was
+/// captured.
+pub fn new_artifact() -> Result<TpxArtifact> {
+    TpxArtifact::new_log_file("results.junit.xml", ArtifactKind::StructuredTestResults)
+}
+
+/// Normalize whichever native result format `test` was configured to emit
+/// and write it into `artifact` as a single JUnit-style document. If `test`
+/// has no structured output configured, an empty (but valid) document is
+/// written instead.
+///
+/// `captured_stdout` is the path the inner test's stdout was captured to,
+/// if the caller kept one around; it's only consulted for the `Rust` arm's
+/// opt-in `--report-time` libtest json stream, since that goes to stdout
+/// rather than a file the test itself was told about.
+pub fn write_report(
+    mut artifact: TpxArtifact,
+    test: &Test,
+    captured_stdout: Option<&Path>,
+) -> Result<()> {
+    let cases = match (test.report_time(), captured_stdout) {
+        (true, Some(path)) => {
+            let sink = ResultSink {
+                format: ResultFormat::LibtestJson,
+                path: path.to_path_buf(),
+            };
+            parse(&sink, suite_name(test))?
+        }
+        _ => match test.structured_output() {
+            Some(sink) => parse(&sink, suite_name(test))?,
+            None => Vec::new(),
+        },
+    };
+    let mut file = artifact.as_file().context("while opening junit artifact")?;
+    file.write_all(render(&cases).as_bytes())
+        .context("while writing junit report")?;
+    Ok(())
+}
+
+/// Write a single-case "skipped" report, used when `--skip-if-cfg`
+/// evaluates to true and the inner test is never launched.
+pub fn write_skip_report(test: &Test, reason: &str) -> Result<()> {
+    let mut artifact = new_artifact()?;
+    let case = JunitCase {
+        suite: suite_name(test).to_owned(),
+        name: reason.to_owned(),
+        status: JunitStatus::Skipped,
+        duration: Duration::default(),
+    };
+    let mut file = artifact.as_file().context("while opening junit artifact")?;
+    file.write_all(render(&[case]).as_bytes())
+        .context("while writing junit skip report")?;
+    Ok(())
+}
+
+fn suite_name(test: &Test) -> &'static str {
+    match test {
+        Test::Custom { .. } => "custom",
+        Test::Gtest { .. } => "gtest",
+        Test::Pyunit { .. } => "pyunit",
+        Test::Rust { .. } => "rust",
+    }
+}
+
+fn parse(sink: &ResultSink, suite: &str) -> Result<Vec<JunitCase>> {
+    let contents = std::fs::read_to_string(&sink.path)
+        .with_context(|| format!("while reading {}", sink.path.display()))?;
+    match sink.format {
+        ResultFormat::GtestJson => parse_gtest_json(&contents, suite),
+        ResultFormat::PyunitJson => parse_pyunit_json(&contents, suite),
+        ResultFormat::LibtestJson => Ok(parse_libtest_json(&contents, suite)),
+    }
+}
+
+fn parse_gtest_json(contents: &str, suite: &str) -> Result<Vec<JunitCase>> {
+    let json: serde_json::Value =
+        serde_json::from_str(contents).context("while parsing gtest json")?;
+    let mut cases = Vec::new();
+    for testsuite in json["testsuites"].as_array().into_iter().flatten() {
+        for test in testsuite["testsuite"].as_array().into_iter().flatten() {
+            let name = test["name"].as_str().unwrap_or("<unknown>").to_owned();
+            let has_failures = test["failures"]
+                .as_array()
+                .is_some_and(|failures| !failures.is_empty());
+            let status = if test["status"].as_str() == Some("NOTRUN") {
+                JunitStatus::Skipped
+            } else if has_failures {
+                JunitStatus::Failed(test["failures"][0]["failure"].as_str().map(str::to_owned))
+            } else {
+                JunitStatus::Passed
+            };
+            let duration = test["time"]
+                .as_str()
+                .and_then(|s| s.trim_end_matches('s').parse::<f64>().ok())
+                .map(Duration::from_secs_f64)
+                .unwrap_or_default();
+            cases.push(JunitCase {
+                suite: suite.to_owned(),
+                name,
+                status,
+                duration,
+            });
+        }
+    }
+    Ok(cases)
+}
+
+fn parse_pyunit_json(contents: &str, suite: &str) -> Result<Vec<JunitCase>> {
+    let json: serde_json::Value =
+        serde_json::from_str(contents).context("while parsing pyunit json")?;
+    let mut cases = Vec::new();
+    for result in json.as_array().into_iter().flatten() {
+        let name = result["name"].as_str().unwrap_or("<unknown>").to_owned();
+        let status = match result["status"].as_str() {
+            Some("SKIP" | "OMIT") => JunitStatus::Skipped,
+            Some("SUCCESS" | "PASS") => JunitStatus::Passed,
+            _ => JunitStatus::Failed(result["details"].as_str().map(str::to_owned)),
+        };
+        let duration = result["runtime"]
+            .as_f64()
+            .map(Duration::from_secs_f64)
+            .unwrap_or_default();
+        cases.push(JunitCase {
+            suite: suite.to_owned(),
+            name,
+            status,
+            duration,
+        });
+    }
+    Ok(cases)
+}
+
+/// Parse libtest's newline-delimited `--format json` event stream. Unlike
+/// the other two formats, this isn't a single document up front: each line
+/// is its own event, and a test only gets a final status once its
+/// "ok"/"failed"/"ignored" event line has been seen.
+fn parse_libtest_json(contents: &str, suite: &str) -> Vec<JunitCase> {
+    let mut cases = Vec::new();
+    for line in contents.lines() {
+        let Ok(event) = serde_json::from_str::<serde_json::Value>(line) else {
+            continue;
+        };
+        if event["type"].as_str() != Some("test") {
+            continue;
+        }
+        let status = match event["event"].as_str() {
+            Some("ok") => JunitStatus::Passed,
+            Some("ignored") => JunitStatus::Skipped,
+            Some("failed") => JunitStatus::Failed(event["stdout"].as_str().map(str::to_owned)),
+            // "started" events carry no outcome yet
+            _ => continue,
+        };
+        let name = event["name"].as_str().unwrap_or("<unknown>").to_owned();
+        let duration = event["exec_time"]
+            .as_f64()
+            .map(Duration::from_secs_f64)
+            .unwrap_or_default();
+        cases.push(JunitCase {
+            suite: suite.to_owned(),
+            name,
+            status,
+            duration,
+        });
+    }
+    cases
+}
+
+fn xml_escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+/// Render a single JUnit-style `<testsuites>` document covering every case,
+/// regardless of which framework originally produced them.
+fn render(cases: &[JunitCase]) -> String {
+    let failures = cases
+        .iter()
+        .filter(|c| matches!(c.status, JunitStatus::Failed(_)))
+        .count();
+    let skipped = cases
+        .iter()
+        .filter(|c| matches!(c.status, JunitStatus::Skipped))
+        .count();
+    let mut out = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites tests=\"{}\" failures=\"{}\" skipped=\"{}\">\n",
+        cases.len(),
+        failures,
+        skipped,
+    );
+    for case in cases {
+        out.push_str(&format!(
+            "  <testcase classname=\"{}\" name=\"{}\" time=\"{}\"",
+            xml_escape(&case.suite),
+            xml_escape(&case.name),
+            case.duration.as_secs_f64(),
+        ));
+        match &case.status {
+            JunitStatus::Passed => out.push_str("/>\n"),
+            JunitStatus::Skipped => out.push_str(">\n    <skipped/>\n  </testcase>\n"),
+            JunitStatus::Failed(message) => {
+                out.push_str(">\n    <failure");
+                if let Some(message) = message {
+                    out.push_str(&format!(" message=\"{}\"", xml_escape(message)));
+                }
+                out.push_str("/>\n  </testcase>\n");
+            }
+        }
+    }
+    out.push_str("</testsuites>\n");
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_render_empty() {
+        assert_eq!(
+            render(&[]),
+            "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites tests=\"0\" failures=\"0\" skipped=\"0\">\n</testsuites>\n"
+        );
+    }
+
+    #[test]
+    fn test_render_mixed() {
+        let cases = vec![
+            JunitCase {
+                suite: "rust".into(),
+                name: "it_works".into(),
+                status: JunitStatus::Passed,
+                duration: Duration::from_millis(10),
+            },
+            JunitCase {
+                suite: "rust".into(),
+                name: "it_fails".into(),
+                status: JunitStatus::Failed(Some("assertion failed".into())),
+                duration: Duration::from_millis(5),
+            },
+            JunitCase {
+                suite: "rust".into(),
+                name: "it_is_skipped".into(),
+                status: JunitStatus::Skipped,
+                duration: Duration::default(),
+            },
+        ];
+        let xml = render(&cases);
+        assert!(xml.contains("tests=\"3\" failures=\"1\" skipped=\"1\""));
+        assert!(xml.contains("name=\"it_fails\""));
+        assert!(xml.contains("message=\"assertion failed\""));
+        assert!(xml.contains("<skipped/>"));
+    }
+
+    #[test]
+    fn test_parse_libtest_json() {
+        let events = [
+            r#"{"type":"test","event":"started","name":"a"}"#,
+            r#"{"type":"test","name":"a","event":"ok","exec_time":0.5}"#,
+            r#"{"type":"test","name":"b","event":"failed","exec_time":0.1,"stdout":"boom"}"#,
+            r#"{"type":"suite","event":"ok"}"#,
+        ]
+        .join("\n");
+        let cases = parse_libtest_json(&events, "rust");
+        assert_eq!(
+            cases,
+            vec![
+                JunitCase {
+                    suite: "rust".into(),
+                    name: "a".into(),
+                    status: JunitStatus::Passed,
+                    duration: Duration::from_secs_f64(0.5),
+                },
+                JunitCase {
+                    suite: "rust".into(),
+                    name: "b".into(),
+                    status: JunitStatus::Failed(Some("boom".into())),
+                    duration: Duration::from_secs_f64(0.1),
+                },
+            ]
+        );
+    }
+}
@@ -22,12 +22,27 @@ use clap::Parser;
 use tempfile::NamedTempFile;
 use thiserror::Error;
 
+mod cfg_expr;
+mod control;
+mod junit;
+mod stream_mux;
+pub use cfg_expr::should_skip;
+pub use cfg_expr::Cfg;
+pub use cfg_expr::CfgParseError;
+pub use junit::new_artifact as new_junit_artifact;
+pub use junit::write_report as write_junit_report;
+pub use junit::write_skip_report as write_junit_skip_report;
+
 #[derive(Parser, Clone, Debug)]
 /// Unittest macros can pass in different flags for the test commands for
 /// different type of tests. However, we sometimes need to extract information
 /// from the command. This enum parses the expected flags for each type.
 pub enum Test {
     Custom {
+        #[clap(long)]
+        /// Skip this test without running it if this `cfg()`-style predicate
+        /// evaluates to true against the host/env. See the `cfg_expr` module.
+        skip_if_cfg: Option<String>,
         #[clap(allow_hyphen_values = true)]
         test_cmd: Vec<OsString>,
     },
@@ -37,6 +52,15 @@ pub enum Test {
         output: Option<String>,
         #[clap(long = "gtest_list_tests")]
         gtest_list_tests: bool,
+        #[clap(long)]
+        /// Skip this test without running it if this `cfg()`-style predicate
+        /// evaluates to true against the host/env. See the `cfg_expr` module.
+        skip_if_cfg: Option<String>,
+        #[clap(long)]
+        /// Run only the tests assigned to shard `INDEX` out of `TOTAL`
+        /// (`INDEX/TOTAL`). Surfaced to gtest as `GTEST_SHARD_INDEX`/
+        /// `GTEST_TOTAL_SHARDS` env vars.
+        shard: Option<Shard>,
         #[clap(allow_hyphen_values = true)]
         test_cmd: Vec<OsString>,
     },
@@ -49,9 +73,32 @@ pub enum Test {
         output_dirs: Vec<PathBuf>,
         #[clap(long)]
         test_filter: Vec<OsString>,
+        #[clap(long)]
+        /// Skip this test without running it if this `cfg()`-style predicate
+        /// evaluates to true against the host/env. See the `cfg_expr` module.
+        skip_if_cfg: Option<String>,
+        #[clap(long)]
+        /// Run only the tests assigned to shard `INDEX` out of `TOTAL`
+        /// (`INDEX/TOTAL`). Implemented by listing tests, hash-bucketing
+        /// their names, then filtering via `--test-filter`.
+        shard: Option<Shard>,
         test_cmd: Vec<OsString>,
     },
     Rust {
+        #[clap(long)]
+        /// Skip this test without running it if this `cfg()`-style predicate
+        /// evaluates to true against the host/env. See the `cfg_expr` module.
+        skip_if_cfg: Option<String>,
+        #[clap(long)]
+        /// Run only the tests assigned to shard `INDEX` out of `TOTAL`
+        /// (`INDEX/TOTAL`). Implemented by listing tests, hash-bucketing
+        /// their names, then filtering via libtest's exact-name matching.
+        shard: Option<Shard>,
+        #[clap(long)]
+        /// Opt in to libtest's `--format json -Z unstable-options
+        /// --report-time` and stream per-test status/duration out of the
+        /// resulting newline-delimited JSON event stream.
+        report_time: bool,
         #[clap(allow_hyphen_values = true)]
         test_cmd: Vec<OsString>,
     },
@@ -69,6 +116,8 @@ impl Test {
                 test,
                 mut output,
                 mut gtest_list_tests,
+                skip_if_cfg,
+                shard,
                 mut test_cmd,
             } => {
                 // Extract gtest flags that may have been consumed into test_cmd
@@ -87,6 +136,8 @@ impl Test {
                     test,
                     output,
                     gtest_list_tests,
+                    skip_if_cfg,
+                    shard,
                     test_cmd: cleaned_cmd,
                 }
             }
@@ -151,12 +202,14 @@ impl Test {
     /// Re-construct the unittest command
     pub fn into_inner_cmd(self) -> Vec<OsString> {
         match self {
-            Self::Custom { test_cmd } => test_cmd,
+            Self::Custom { test_cmd, .. } => test_cmd,
             Self::Gtest {
                 test,
                 mut test_cmd,
                 gtest_list_tests,
                 output,
+                skip_if_cfg: _,
+                shard: _,
             } => {
                 test_cmd.insert(0, test.into());
                 if gtest_list_tests {
@@ -167,11 +220,26 @@ impl Test {
                 }
                 test_cmd
             }
-            Self::Rust { test_cmd } => test_cmd,
+            Self::Rust {
+                mut test_cmd,
+                report_time,
+                ..
+            } => {
+                if report_time {
+                    test_cmd.push("--format".into());
+                    test_cmd.push("json".into());
+                    test_cmd.push("-Z".into());
+                    test_cmd.push("unstable-options".into());
+                    test_cmd.push("--report-time".into());
+                }
+                test_cmd
+            }
             Self::Pyunit {
                 mut test_cmd,
                 list_tests,
                 test_filter,
+                skip_if_cfg: _,
+                shard: _,
                 output,
                 output_dirs,
             } => {
@@ -209,6 +277,217 @@ impl Test {
             Self::Pyunit { list_tests, .. } => list_tests.is_some(),
         }
     }
+
+    /// The `--skip-if-cfg` predicate this test was configured with, if any.
+    pub fn skip_if_cfg(&self) -> Option<&str> {
+        match self {
+            Self::Custom { skip_if_cfg, .. }
+            | Self::Gtest { skip_if_cfg, .. }
+            | Self::Pyunit { skip_if_cfg, .. }
+            | Self::Rust { skip_if_cfg, .. } => skip_if_cfg.as_deref(),
+        }
+    }
+
+    /// Where the inner test binary was told to write its native-format
+    /// structured results, if it supports doing so at all. This is the
+    /// input to [`junit::write_report`], which normalizes whichever format
+    /// comes out into a single JUnit-style document.
+    pub fn structured_output(&self) -> Option<ResultSink> {
+        match self {
+            Self::Custom { .. } => None,
+            // the Rust arm's opt-in `--report-time` json stream goes to
+            // stdout rather than a file, so it's reported via `report_time()`
+            Self::Rust { .. } => None,
+            Self::Gtest { output, .. } => {
+                let output = output.as_ref()?;
+                let path = match output.split_once(':') {
+                    Some((_format, path)) => path,
+                    None => output.as_str(),
+                };
+                Some(ResultSink {
+                    format: ResultFormat::GtestJson,
+                    path: PathBuf::from(path),
+                })
+            }
+            Self::Pyunit { output, .. } => output.clone().map(|path| ResultSink {
+                format: ResultFormat::PyunitJson,
+                path,
+            }),
+        }
+    }
+
+    /// Whether the `Rust` arm opted in to libtest's `--format json
+    /// --report-time` event stream. Its results land on stdout rather than
+    /// a discrete file, so the caller has to tell [`junit::write_report`]
+    /// where that stdout was captured.
+    pub fn report_time(&self) -> bool {
+        matches!(self, Self::Rust { report_time: true, .. })
+    }
+
+    /// The `--shard` this test was configured with, if any. `Custom` has no
+    /// shard field since there's no generic way to partition an arbitrary
+    /// command.
+    pub fn shard(&self) -> Option<Shard> {
+        match self {
+            Self::Custom { .. } => None,
+            Self::Gtest { shard, .. } | Self::Pyunit { shard, .. } | Self::Rust { shard, .. } => {
+                *shard
+            }
+        }
+    }
+
+    /// Env vars that surface this test's `--shard` to the inner harness.
+    /// Only gtest supports shard selection via environment variables;
+    /// `Pyunit`/`Rust` are filtered ahead of time instead, via
+    /// [`Test::apply_shard`].
+    pub fn shard_env(&self) -> Vec<KvPair> {
+        match (self, self.shard()) {
+            (Self::Gtest { .. }, Some(shard)) => vec![
+                KvPair::from(("GTEST_SHARD_INDEX", shard.index.to_string())),
+                KvPair::from(("GTEST_TOTAL_SHARDS", shard.total.to_string())),
+            ],
+            _ => Vec::new(),
+        }
+    }
+
+    /// For `Pyunit`/`Rust`, narrow this test down to just the names owned by
+    /// its shard, given the full set of test names the inner binary
+    /// discovered via `--list`/`--list-tests`. A shard with no matching
+    /// names still produces a valid (filtered-to-nothing) invocation rather
+    /// than falling back to running the whole suite.
+    pub fn apply_shard(self, all_names: &[String]) -> Self {
+        match self {
+            Self::Pyunit {
+                list_tests,
+                output,
+                output_dirs,
+                mut test_filter,
+                skip_if_cfg,
+                shard: Some(shard),
+                test_cmd,
+            } => {
+                test_filter.extend(
+                    shard
+                        .filter(all_names)
+                        .into_iter()
+                        .map(OsString::from),
+                );
+                Self::Pyunit {
+                    list_tests,
+                    output,
+                    output_dirs,
+                    test_filter,
+                    skip_if_cfg,
+                    shard: Some(shard),
+                    test_cmd,
+                }
+            }
+            Self::Rust {
+                skip_if_cfg,
+                shard: Some(shard),
+                report_time,
+                mut test_cmd,
+            } => {
+                // `--exact` with zero names is a valid, deliberately
+                // unmatchable filter, so an empty shard still runs (and
+                // reports zero tests) instead of running the whole suite
+                test_cmd.push("--exact".into());
+                test_cmd.extend(shard.filter(all_names).into_iter().map(OsString::from));
+                Self::Rust {
+                    skip_if_cfg,
+                    shard: Some(shard),
+                    report_time,
+                    test_cmd,
+                }
+            }
+            other => other,
+        }
+    }
+}
+
+/// `--shard <index>/<total>`: split a single logical test target across
+/// `total` parallel runners, each responsible for exactly bucket `index`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Shard {
+    pub index: usize,
+    pub total: usize,
+}
+
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum ShardParseError {
+    #[error("expected --shard INDEX/TOTAL, got '{0}'")]
+    BadFormat(String),
+    #[error("--shard total must be at least 1, got {0}")]
+    ZeroTotal(usize),
+    #[error("--shard index {index} must be less than total {total}")]
+    IndexOutOfRange { index: usize, total: usize },
+}
+
+impl FromStr for Shard {
+    type Err = ShardParseError;
+
+    fn from_str(s: &str) -> Result<Self, ShardParseError> {
+        let (index, total) = s
+            .split_once('/')
+            .ok_or_else(|| ShardParseError::BadFormat(s.to_owned()))?;
+        let index: usize = index
+            .parse()
+            .map_err(|_| ShardParseError::BadFormat(s.to_owned()))?;
+        let total: usize = total
+            .parse()
+            .map_err(|_| ShardParseError::BadFormat(s.to_owned()))?;
+        if total == 0 {
+            return Err(ShardParseError::ZeroTotal(total));
+        }
+        if index >= total {
+            return Err(ShardParseError::IndexOutOfRange { index, total });
+        }
+        Ok(Self { index, total })
+    }
+}
+
+impl Shard {
+    /// Which bucket (`0..total`) `name` falls into. Every shard must derive
+    /// the same bucket for the same name so that each test ends up owned by
+    /// exactly one shard.
+    fn bucket(&self, name: &str) -> usize {
+        use std::collections::hash_map::DefaultHasher;
+        use std::hash::Hash;
+        use std::hash::Hasher;
+
+        let mut hasher = DefaultHasher::new();
+        name.hash(&mut hasher);
+        (hasher.finish() as usize) % self.total
+    }
+
+    /// Narrow `names` down to just the ones owned by this shard.
+    pub fn filter(&self, names: &[String]) -> Vec<String> {
+        names
+            .iter()
+            .filter(|name| self.bucket(name) == self.index)
+            .cloned()
+            .collect()
+    }
+}
+
+/// The native result format a test framework was configured to emit, so a
+/// post-run pass knows how to parse it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResultFormat {
+    /// gtest's own JSON result format (`--gtest_output=json:<path>`)
+    GtestJson,
+    /// pyunit's JSON result format (`--output <path>`)
+    PyunitJson,
+    /// libtest's `--format json` event stream
+    LibtestJson,
+}
+
+/// Where a test binary was told to write its native-format structured
+/// results.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResultSink {
+    pub format: ResultFormat,
+    pub path: PathBuf,
 }
 
 #[derive(Error, Debug)]
@@ -268,6 +547,37 @@ impl KvPair {
     }
 }
 
+/// What kind of artifact a [TpxArtifact] represents, which determines the
+/// `"type"` field of its `.annotation` sidecar so that tpx's UI knows how to
+/// render it instead of treating everything as an opaque text blob.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArtifactKind {
+    /// Free-form text logs (stdout/stderr captures, container console, etc).
+    GenericTextLog,
+    /// A machine-readable test results document, eg the normalized JUnit
+    /// report produced by the [crate::junit] module.
+    StructuredTestResults,
+    /// A performance profile or trace capture.
+    Perf,
+    /// An image, eg a screenshot captured by a UI test.
+    Image,
+}
+
+impl ArtifactKind {
+    /// The `.annotation` sidecar content tpx expects for this kind of
+    /// artifact, matching the shape of the pre-existing generic-text-log
+    /// annotation.
+    fn annotation(self, description: &str) -> String {
+        let ty = match self {
+            Self::GenericTextLog => r#"{"generic_text_log": {}}"#,
+            Self::StructuredTestResults => r#"{"test_results": {}}"#,
+            Self::Perf => r#"{"perf_trace": {}}"#,
+            Self::Image => r#"{"image": {}}"#,
+        };
+        format!(r#"{{"type": {ty}, "description": "{description}"}}"#)
+    }
+}
+
 /// A file that tpx will upload as an artifact on failing test instances. If not
 /// run under tpx, this will be some other fd (a regular file in /tmp, stderr, etc)
 pub struct TpxArtifact {
@@ -287,7 +597,12 @@ impl TpxArtifact {
     /// up before running the test so that it still gets uploaded even in case
     /// of a timeout.
     /// If not running under tpx, this will be sent to stderr
-    fn new_tpx_or_none(name: &str) -> Result<Option<Self>> {
+    ///
+    /// Multiple artifacts may be registered concurrently by calling this (or
+    /// the public constructors below) more than once with distinct `name`s:
+    /// each gets its own file under `TEST_RESULT_ARTIFACTS_DIR` and its own
+    /// `.annotation` sidecar, and nothing here assumes there's only one.
+    fn new_tpx_or_none(name: &str, kind: ArtifactKind) -> Result<Option<Self>> {
         // if tpx has provided this artifacts dir, put the logs there so they get
         // uploaded along with the test results
         if let Some(artifacts_dir) = std::env::var_os("TEST_RESULT_ARTIFACTS_DIR") {
@@ -300,7 +615,7 @@ impl TpxArtifact {
                 std::fs::create_dir_all(&annotations_dir)?;
                 std::fs::write(
                     Path::new(&annotations_dir).join(format!("{name}.annotation")),
-                    r#"{"type": {"generic_text_log": {}}, "description": "test logs"}"#,
+                    kind.annotation("test logs"),
                 )?;
             }
             let file = OpenOptions::new()
@@ -324,8 +639,8 @@ impl TpxArtifact {
     /// up before running the test so that it still gets uploaded even in case
     /// of a timeout.
     /// If not running under tpx, this will be sent to a temporary file.
-    pub fn new_log_file(name: &str) -> Result<Self> {
-        match Self::new_tpx_or_none(name)? {
+    pub fn new_log_file(name: &str, kind: ArtifactKind) -> Result<Self> {
+        match Self::new_tpx_or_none(name, kind)? {
             Some(s) => Ok(s),
             None => {
                 let tmpfile = tempfile::NamedTempFile::new()?;
@@ -339,8 +654,8 @@ impl TpxArtifact {
 
     /// Same as [TpxArtifact::new_log_file], but if not running under tpx, this
     /// will be sent to stderr
-    pub fn new_log_file_or_stderr(name: &str) -> Result<Self> {
-        match Self::new_tpx_or_none(name)? {
+    pub fn new_log_file_or_stderr(name: &str, kind: ArtifactKind) -> Result<Self> {
+        match Self::new_tpx_or_none(name, kind)? {
             Some(s) => Ok(s),
             None => Ok(Self {
                 file: LogFile::Stderr,
@@ -588,6 +903,26 @@ mod test {
         assert_eq!(arg.test.into_inner_cmd(), vec!["whatever", "--list"]);
     }
 
+    #[test]
+    fn test_rust_report_time() {
+        let arg = TestArgs::parse_from(["test", "rust", "whatever"]);
+        assert!(!arg.test.report_time());
+
+        let arg = TestArgs::parse_from(["test", "rust", "whatever", "--report-time"]);
+        assert!(arg.test.report_time());
+        assert_eq!(
+            arg.test.into_inner_cmd(),
+            vec![
+                "whatever",
+                "--format",
+                "json",
+                "-Z",
+                "unstable-options",
+                "--report-time",
+            ]
+        );
+    }
+
     #[test]
     fn test_custom() {
         let arg = TestArgs::parse_from(["test", "custom", "whatever", "--list"]);
@@ -626,4 +961,127 @@ mod test {
             OsString::from("'a'='b'"),
         )
     }
+
+    #[test]
+    fn test_structured_output() {
+        let arg = TestArgs::parse_from(["test", "custom", "whatever"]);
+        assert_eq!(arg.test.structured_output(), None);
+
+        let arg = TestArgs::parse_from(["test", "rust", "whatever"]);
+        assert_eq!(arg.test.structured_output(), None);
+
+        let arg = TestArgs::parse_from([
+            "test",
+            "gtest",
+            "/path/to/the/test",
+            "--gtest_output=json:/foo/bar.json",
+        ]);
+        assert_eq!(
+            arg.test.structured_output(),
+            Some(ResultSink {
+                format: ResultFormat::GtestJson,
+                path: PathBuf::from("/foo/bar.json"),
+            })
+        );
+
+        let arg = TestArgs::parse_from([
+            "test", "pyunit", "whatever", "--output", "/here/here.json",
+        ]);
+        assert_eq!(
+            arg.test.structured_output(),
+            Some(ResultSink {
+                format: ResultFormat::PyunitJson,
+                path: PathBuf::from("/here/here.json"),
+            })
+        );
+    }
+
+    #[test]
+    fn test_skip_if_cfg() {
+        let arg = TestArgs::parse_from(["test", "rust", "whatever"]);
+        assert_eq!(arg.test.skip_if_cfg(), None);
+
+        let arg = TestArgs::parse_from([
+            "test",
+            "rust",
+            "--skip-if-cfg",
+            "target_os = \"macos\"",
+            "whatever",
+        ]);
+        assert_eq!(arg.test.skip_if_cfg(), Some("target_os = \"macos\""));
+    }
+
+    #[test]
+    fn test_shard_parse() {
+        assert_eq!("0/4".parse(), Ok(Shard { index: 0, total: 4 }));
+        assert_eq!(
+            "4/4".parse::<Shard>(),
+            Err(ShardParseError::IndexOutOfRange { index: 4, total: 4 })
+        );
+        assert_eq!(
+            "0/0".parse::<Shard>(),
+            Err(ShardParseError::ZeroTotal(0))
+        );
+        assert!(matches!(
+            "bogus".parse::<Shard>(),
+            Err(ShardParseError::BadFormat(_))
+        ));
+    }
+
+    #[test]
+    fn test_shard_env_only_for_gtest() {
+        let arg = TestArgs::parse_from(["test", "gtest", "/t", "--shard", "1/3"]);
+        assert_eq!(
+            arg.test.shard_env(),
+            vec![
+                KvPair::from(("GTEST_SHARD_INDEX", "1")),
+                KvPair::from(("GTEST_TOTAL_SHARDS", "3")),
+            ]
+        );
+
+        let arg = TestArgs::parse_from(["test", "rust", "whatever", "--shard", "1/3"]);
+        assert_eq!(arg.test.shard_env(), vec![]);
+    }
+
+    #[test]
+    fn test_apply_shard_rust() {
+        let arg = TestArgs::parse_from(["test", "rust", "whatever", "--shard", "0/2"]);
+        let names = vec!["a".to_string(), "b".to_string(), "c".to_string()];
+        let cmd = arg.test.apply_shard(&names).into_inner_cmd();
+        assert_eq!(cmd[0], "whatever");
+        assert_eq!(cmd[1], "--exact");
+        // whichever names hash into bucket 0 follow; this just checks the
+        // command is kept and the `--exact` guard always comes right after it
+        assert!(cmd.len() >= 2);
+    }
+
+    #[test]
+    fn test_apply_shard_empty_bucket_still_valid() {
+        // a shard with no matching names must still produce a valid,
+        // deliberately-unmatchable invocation rather than erroring
+        let arg = TestArgs::parse_from(["test", "rust", "whatever", "--shard", "0/1000000"]);
+        let names = vec!["a".to_string()];
+        let cmd = arg.test.apply_shard(&names).into_inner_cmd();
+        assert_eq!(cmd, vec!["whatever", "--exact"]);
+    }
+
+    #[test]
+    fn test_artifact_kind_annotation() {
+        assert_eq!(
+            ArtifactKind::GenericTextLog.annotation("test logs"),
+            r#"{"type": {"generic_text_log": {}}, "description": "test logs"}"#,
+        );
+        assert_eq!(
+            ArtifactKind::StructuredTestResults.annotation("test logs"),
+            r#"{"type": {"test_results": {}}, "description": "test logs"}"#,
+        );
+        assert_eq!(
+            ArtifactKind::Perf.annotation("test logs"),
+            r#"{"type": {"perf_trace": {}}, "description": "test logs"}"#,
+        );
+        assert_eq!(
+            ArtifactKind::Image.annotation("test logs"),
+            r#"{"type": {"image": {}}, "description": "test logs"}"#,
+        );
+    }
 }
@@ -23,15 +23,88 @@ use antlir2_isolate::IsolationContext;
 use anyhow::Context;
 use anyhow::Result;
 use clap::Parser;
+use clap::ValueEnum;
 use json_arg::JsonFile;
 use tempfile::NamedTempFile;
 use tracing::debug;
 use tracing_subscriber::prelude::*;
 
+mod golden;
+mod oci;
+mod sidecar;
+
 fn make_log_files(_base: &str) -> Result<(NamedTempFile, NamedTempFile)> {
     Ok((NamedTempFile::new()?, NamedTempFile::new()?))
 }
 
+/// libtest doesn't know how to shard itself, so enumerate `test_cmd`'s own
+/// tests via `--list --format terse` and return just the names assigned to
+/// `shard_index` of `shard_count` (partitioned by each test's position in
+/// the enumerated list, so every shard's slice is disjoint and their union
+/// is the whole suite). This always runs the listing pass through the
+/// nspawn backend, even under `--isolation=oci`, since it's only asking the
+/// binary for its test list, not running the suite itself.
+fn list_rust_shard(
+    layer: &Path,
+    repo: &Path,
+    working_directory: &Path,
+    test_cmd: &[OsString],
+    shard_index: usize,
+    shard_count: usize,
+) -> Result<Vec<String>> {
+    let mut ctx = IsolationContext::builder(layer);
+    ctx.platform([
+        repo,
+        #[cfg(facebook)]
+        Path::new("/usr/local/fbcode"),
+        #[cfg(facebook)]
+        Path::new("/mnt/gvfs"),
+    ])
+    .inputs([repo])
+    .working_directory(working_directory);
+
+    let mut isol = isolate(ctx.build());
+    isol.command.args(test_cmd);
+    isol.command.arg("--list").arg("--format").arg("terse");
+    let output = isol
+        .command
+        .output()
+        .context("while listing rust tests to compute this shard")?;
+    anyhow::ensure!(
+        output.status.success(),
+        "listing tests for sharding failed: {}",
+        output.status
+    );
+
+    let names: Vec<String> = String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| {
+            line.strip_suffix(": test")
+                .or_else(|| line.strip_suffix(": benchmark"))
+        })
+        .map(str::to_owned)
+        .collect();
+    Ok(names
+        .into_iter()
+        .enumerate()
+        .filter(|(i, _)| i % shard_count == shard_index)
+        .map(|(_, name)| name)
+        .collect())
+}
+
+/// Where the host coverage directory is mounted inside the container.
+const COVERAGE_CONTAINER_DIR: &str = "/coverage";
+
+/// Which container runtime isolates the test: systemd-nspawn (the default,
+/// via [antlir2_isolate]) or a standards-compliant OCI runtime, for hosts
+/// that don't have systemd-nspawn available.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq, Default)]
+enum Isolation {
+    #[default]
+    Nspawn,
+    Oci,
+}
+
 #[derive(Parser, Debug)]
 /// Run a unit test inside an image layer.
 struct Args {
@@ -50,10 +123,39 @@ struct Args {
     #[clap(long)]
     /// Mounts required by the layer-under-test
     mounts: JsonFile<BTreeSet<Mount<'static>>>,
+    #[clap(long)]
+    /// Collect code coverage from the test run. The merged lcov.info (or,
+    /// for Pyunit, the raw .coverage file) is written into this directory,
+    /// in addition to TEST_RESULT_ARTIFACTS_DIR if that's set
+    coverage: Option<PathBuf>,
+    #[clap(long, value_enum, default_value_t = Isolation::Nspawn)]
+    /// Container runtime backend to isolate the test with
+    isolation: Isolation,
+    #[clap(long, default_value = "runc")]
+    /// OCI runtime binary to invoke when --isolation=oci
+    oci_runtime: String,
+    #[clap(long, requires = "shard_count")]
+    /// This shard's index (0-based); requires --shard-count
+    shard_index: Option<usize>,
+    #[clap(long, requires = "shard_index")]
+    /// Total number of shards the outer scheduler is splitting this suite
+    /// across; requires --shard-index
+    shard_count: Option<usize>,
     #[clap(subcommand)]
     test: Test,
 }
 
+/// How this invocation was told to shard its test suite: either a raw
+/// index/count pair forwarded straight to the test framework itself (Gtest's
+/// env vars, Pyunit's `--shard` flag), or, for Rust, a pre-resolved set of
+/// exact test names -- libtest doesn't know how to shard itself, so this
+/// runner lists the binary's own tests up front and filters to just the
+/// slice assigned to this shard.
+enum Shard {
+    IndexCount(usize, usize),
+    Names(Vec<String>),
+}
+
 #[derive(Parser, Debug)]
 enum Test {
     Custom {
@@ -78,6 +180,23 @@ enum Test {
         #[clap(allow_hyphen_values = true)]
         test_cmd: Vec<OsString>,
     },
+    /// Run `test_cmd` and compare its captured stdout/stderr against golden
+    /// files. The run fails if the test binary exits non-zero or if any
+    /// given golden file doesn't match.
+    Golden {
+        #[clap(long)]
+        expected_stdout: Option<PathBuf>,
+        #[clap(long)]
+        expected_stderr: Option<PathBuf>,
+        /// Additional `FROM=TO` substitutions applied to both sides before
+        /// comparing, on top of the always-on container-root/repo-root
+        /// substitutions (use a literal `[..]` in the golden file itself to
+        /// match an arbitrary run of characters instead)
+        #[clap(long = "normalize")]
+        normalize: Vec<String>,
+        #[clap(allow_hyphen_values = true)]
+        test_cmd: Vec<OsString>,
+    },
 }
 
 impl Test {
@@ -122,21 +241,41 @@ impl Test {
                 }
                 paths
             }
+            Self::Golden {
+                expected_stdout,
+                expected_stderr,
+                ..
+            } => [expected_stdout, expected_stderr]
+                .into_iter()
+                .flatten()
+                .map(|p| p.parent().expect("expected file always has parent").to_owned())
+                .collect(),
         }
     }
-    fn into_inner_cmd(self) -> Vec<OsString> {
+    fn into_inner_cmd(self, coverage: bool, shard: Option<&Shard>) -> Vec<OsString> {
         match self {
             Self::Custom { test_cmd } => test_cmd,
+            Self::Golden { test_cmd, .. } => test_cmd,
             Self::Gtest {
                 mut test_cmd,
                 output,
             } => {
+                // sharding is conveyed to gtest entirely via GTEST_SHARD_INDEX/
+                // GTEST_TOTAL_SHARDS, see shard_env()
                 if let Some(out) = output {
                     test_cmd.push(format!("--gtest_output={out}").into());
                 }
                 test_cmd
             }
-            Self::Rust { test_cmd } => test_cmd,
+            Self::Rust { mut test_cmd } => {
+                if let Some(Shard::Names(names)) = shard {
+                    test_cmd.push("--exact".into());
+                    for name in names {
+                        test_cmd.push(name.into());
+                    }
+                }
+                test_cmd
+            }
             Self::Pyunit {
                 mut test_cmd,
                 list_tests,
@@ -155,10 +294,87 @@ impl Test {
                     test_cmd.push("--test-filter".into());
                     test_cmd.push(filter);
                 }
+                if coverage {
+                    test_cmd.push("--coverage".into());
+                }
+                if let Some(Shard::IndexCount(index, count)) = shard {
+                    test_cmd.push("--shard".into());
+                    test_cmd.push(format!("{index}/{count}").into());
+                }
                 test_cmd
             }
         }
     }
+
+    /// Env vars that tell this kind of test which shard to run, for the one
+    /// framework (Gtest) that takes sharding as an env var rather than a
+    /// command-line flag or (Rust's case) an explicit name filter computed
+    /// up front by `main`.
+    fn shard_env(&self, shard: &Shard) -> BTreeMap<String, OsString> {
+        match (self, shard) {
+            (Self::Gtest { .. }, Shard::IndexCount(index, count)) => BTreeMap::from([
+                (
+                    "GTEST_SHARD_INDEX".to_string(),
+                    OsString::from(index.to_string()),
+                ),
+                (
+                    "GTEST_TOTAL_SHARDS".to_string(),
+                    OsString::from(count.to_string()),
+                ),
+            ]),
+            _ => BTreeMap::new(),
+        }
+    }
+
+    /// Env vars that turn on coverage instrumentation for this kind of test,
+    /// pointed at [COVERAGE_CONTAINER_DIR]. `Rust`/`Gtest`/`Custom` binaries
+    /// are all assumed to be built with LLVM source-based coverage; Pyunit
+    /// gets `coverage.py`'s own env var instead.
+    fn coverage_env(&self) -> BTreeMap<String, OsString> {
+        match self {
+            Self::Pyunit { .. } => BTreeMap::from([(
+                "COVERAGE_FILE".to_string(),
+                OsString::from(format!("{COVERAGE_CONTAINER_DIR}/.coverage")),
+            )]),
+            Self::Custom { .. } | Self::Gtest { .. } | Self::Rust { .. } | Self::Golden { .. } => {
+                BTreeMap::from([(
+                    "LLVM_PROFILE_FILE".to_string(),
+                    OsString::from(format!("{COVERAGE_CONTAINER_DIR}/%p-%m.profraw")),
+                )])
+            }
+        }
+    }
+
+    /// The binary under test, used to symbolize coverage with `llvm-cov`.
+    fn primary_binary(&self) -> Option<OsString> {
+        match self {
+            Self::Custom { test_cmd }
+            | Self::Gtest { test_cmd, .. }
+            | Self::Pyunit { test_cmd, .. }
+            | Self::Rust { test_cmd }
+            | Self::Golden { test_cmd, .. } => test_cmd.first().cloned(),
+        }
+    }
+
+    /// `(expected_stdout, expected_stderr, normalizations)` if this is a
+    /// [Test::Golden], so `main` can run the golden comparison after the
+    /// test finishes without needing to match on `Test` itself (and without
+    /// holding a borrow of `self` across the later `into_inner_cmd` move).
+    fn golden_expectations(&self) -> Option<(Option<PathBuf>, Option<PathBuf>, Vec<String>)> {
+        match self {
+            Self::Golden {
+                expected_stdout,
+                expected_stderr,
+                normalize,
+                ..
+            } => Some((
+                expected_stdout.clone(),
+                expected_stderr.clone(),
+                normalize.clone(),
+            )),
+            _ => None,
+        }
+    }
 }
 
 fn main() -> Result<()> {
@@ -199,6 +415,126 @@ fn main() -> Result<()> {
 
     let working_directory = std::env::current_dir().context("while getting cwd")?;
 
+    let is_pyunit = matches!(args.test, Test::Pyunit { .. });
+    let golden_expectations = args.test.golden_expectations();
+    let normalizations = match &golden_expectations {
+        Some((_, _, raw)) => golden::parse_normalizations(raw)?,
+        None => Vec::new(),
+    };
+    let primary_binary = args
+        .coverage
+        .is_some()
+        .then(|| args.test.primary_binary())
+        .flatten();
+    let coverage_dir = args
+        .coverage
+        .is_some()
+        .then(tempfile::tempdir)
+        .transpose()
+        .context("while creating coverage directory")?;
+    if coverage_dir.is_some() {
+        setenv.extend(args.test.coverage_env());
+    }
+
+    let shard = match (args.shard_index, args.shard_count) {
+        (Some(shard_index), Some(shard_count)) => {
+            anyhow::ensure!(
+                shard_index < shard_count,
+                "--shard-index must be less than --shard-count"
+            );
+            Some(match &args.test {
+                Test::Rust { test_cmd } => Shard::Names(list_rust_shard(
+                    &args.layer,
+                    repo.as_ref(),
+                    &working_directory,
+                    test_cmd,
+                    shard_index,
+                    shard_count,
+                )?),
+                _ => Shard::IndexCount(shard_index, shard_count),
+            })
+        }
+        _ => None,
+    };
+    if let Some(shard) = &shard {
+        setenv.extend(args.test.shard_env(shard));
+    }
+
+    if args.isolation == Isolation::Oci {
+        anyhow::ensure!(!args.boot, "--isolation=oci doesn't support --boot");
+        anyhow::ensure!(
+            golden_expectations.is_none(),
+            "--isolation=oci doesn't support golden tests"
+        );
+
+        let mut mounts = vec![oci::BindMount {
+            // tests often read resource files from the repo
+            destination: repo.as_ref().to_owned(),
+            source: repo.as_ref().to_owned(),
+            writable: false,
+        }];
+        #[cfg(facebook)]
+        for platform_dir in [Path::new("/usr/local/fbcode"), Path::new("/mnt/gvfs")] {
+            mounts.push(oci::BindMount {
+                destination: platform_dir.to_owned(),
+                source: platform_dir.to_owned(),
+                writable: false,
+            });
+        }
+        for path in args.test.bind_mounts() {
+            mounts.push(oci::BindMount {
+                destination: path.clone(),
+                source: path,
+                writable: true,
+            });
+        }
+        for mount in args.mounts.into_inner() {
+            let (destination, source) = match mount {
+                Mount::Host(m) => (m.mountpoint.into_owned(), m.src),
+                Mount::Layer(m) => (m.mountpoint.into_owned(), m.src.subvol_symlink.into_owned()),
+            };
+            mounts.push(oci::BindMount {
+                destination,
+                source,
+                writable: false,
+            });
+        }
+        if let Some(coverage_dir) = &coverage_dir {
+            mounts.push(oci::BindMount {
+                destination: PathBuf::from(COVERAGE_CONTAINER_DIR),
+                source: coverage_dir.path().to_owned(),
+                writable: true,
+            });
+        }
+
+        let status = oci::run(oci::RunArgs {
+            root: &args.layer,
+            mounts,
+            program_args: args.test.into_inner_cmd(coverage_dir.is_some(), shard.as_ref()),
+            env: setenv,
+            working_directory: &working_directory,
+            user: &args.user,
+            runtime_bin: &args.oci_runtime,
+        })?;
+
+        if let Some(coverage_dir) = &coverage_dir {
+            export_coverage(
+                is_pyunit,
+                coverage_dir.path(),
+                primary_binary,
+                args.coverage.as_deref(),
+            )?;
+        }
+
+        return if status.success() {
+            Ok(())
+        } else if let Some(code) = status.code() {
+            std::process::exit(code);
+        } else {
+            Err(anyhow::anyhow!("OCI runtime failed: {status}"))
+        };
+    }
+
     let mut ctx = IsolationContext::builder(&args.layer);
     ctx.platform([
         // test is built out of the repo, so it needs the
@@ -227,13 +563,22 @@ fn main() -> Result<()> {
             })
             .collect::<HashMap<_, _>>(),
     );
+    if let Some(coverage_dir) = &coverage_dir {
+        // writable, unlike the rest of the layer-under-test's mounts, since
+        // the test process writes its raw profile data here
+        ctx.outputs((Path::new(COVERAGE_CONTAINER_DIR), coverage_dir.path()));
+    }
 
     if args.boot {
         // see 'man 8 systemd-run-generator', tl;dr this will:
         // - propagate the exit code to this process
         // - shut down the container as soon as the test binary finishes
         let mut systemd_run_arg = OsString::from("systemd.run=\"");
-        let mut iter = args.test.into_inner_cmd().into_iter().peekable();
+        let mut iter = args
+            .test
+            .into_inner_cmd(coverage_dir.is_some(), shard.as_ref())
+            .into_iter()
+            .peekable();
         while let Some(arg) = iter.next() {
             systemd_run_arg.push(arg);
             if iter.peek().is_some() {
@@ -281,24 +626,210 @@ fn main() -> Result<()> {
             .context("while spawning systemd-nspawn")?;
         let res = child.wait().context("while waiting for systemd-nspawn")?;
         report(container_stdout)?;
+        if let Some(coverage_dir) = &coverage_dir {
+            export_coverage(
+                is_pyunit,
+                coverage_dir.path(),
+                primary_binary,
+                args.coverage.as_deref(),
+            )?;
+        }
+
+        let golden_ok = check_golden(
+            &golden_expectations,
+            &normalizations,
+            &std::fs::read_to_string(test_stdout.path()).unwrap_or_default(),
+            &std::fs::read_to_string(test_stderr.path()).unwrap_or_default(),
+            &args.layer,
+            repo.as_ref(),
+        )?;
 
         std::io::copy(&mut test_stdout, &mut std::io::stdout())?;
         std::io::copy(&mut test_stderr, &mut std::io::stderr())?;
 
         if !res.success() {
             std::process::exit(res.code().unwrap_or(255))
+        } else if !golden_ok {
+            std::process::exit(1)
         } else {
             Ok(())
         }
+    } else if golden_expectations.is_some() {
+        let mut isol = isolate(ctx.build());
+        isol.command
+            .args(args.test.into_inner_cmd(coverage_dir.is_some(), shard.as_ref()));
+        debug!("executing test in isolated container: {isol:?}");
+
+        // Golden comparisons need the test's actual stdout/stderr in hand,
+        // so it has to run as a captured child rather than via `exec()`.
+        let output = isol.command.output().context("while running test")?;
+        std::io::copy(&mut &output.stdout[..], &mut std::io::stdout())?;
+        std::io::copy(&mut &output.stderr[..], &mut std::io::stderr())?;
+        if let Some(coverage_dir) = &coverage_dir {
+            export_coverage(
+                is_pyunit,
+                coverage_dir.path(),
+                primary_binary,
+                args.coverage.as_deref(),
+            )?;
+        }
+        let golden_ok = check_golden(
+            &golden_expectations,
+            &normalizations,
+            &String::from_utf8_lossy(&output.stdout),
+            &String::from_utf8_lossy(&output.stderr),
+            &args.layer,
+            repo.as_ref(),
+        )?;
+        if !output.status.success() {
+            std::process::exit(output.status.code().unwrap_or(255));
+        } else if !golden_ok {
+            std::process::exit(1);
+        }
+        Ok(())
     } else {
         let mut isol = isolate(ctx.build());
-        isol.command.args(args.test.into_inner_cmd());
+        isol.command
+            .args(args.test.into_inner_cmd(coverage_dir.is_some(), shard.as_ref()));
         debug!("executing test in isolated container: {isol:?}");
-        return Err(anyhow::anyhow!(
-            "failed to exec test: {:?}",
-            isol.command.exec()
-        ));
+
+        // `exec()` replaces this process, so there's nothing left to merge
+        // and export coverage afterwards -- run the test as a child instead
+        // whenever coverage is being collected.
+        match coverage_dir {
+            Some(coverage_dir) => {
+                let mut child = isol.command.spawn().context("while spawning test")?;
+                let status = child.wait().context("while waiting for test")?;
+                export_coverage(
+                    is_pyunit,
+                    coverage_dir.path(),
+                    primary_binary,
+                    args.coverage.as_deref(),
+                )?;
+                if !status.success() {
+                    std::process::exit(status.code().unwrap_or(255));
+                }
+                Ok(())
+            }
+            None => Err(anyhow::anyhow!(
+                "failed to exec test: {:?}",
+                isol.command.exec()
+            )),
+        }
+    }
+}
+
+/// Compare the test's captured stdout/stderr against whichever of
+/// `golden_expectations`' expected files were given -- an expectation that
+/// wasn't given isn't a requirement. Returns `true` if every expectation
+/// that was given matched.
+fn check_golden(
+    golden_expectations: &Option<(Option<PathBuf>, Option<PathBuf>, Vec<String>)>,
+    normalizations: &[golden::Normalization],
+    stdout: &str,
+    stderr: &str,
+    container_root: &Path,
+    repo_root: &Path,
+) -> Result<bool> {
+    let Some((expected_stdout, expected_stderr, _)) = golden_expectations else {
+        return Ok(true);
+    };
+    let mut ok = true;
+    if let Some(expected) = expected_stdout {
+        ok &= golden::compare(
+            "stdout",
+            expected,
+            stdout,
+            normalizations,
+            container_root,
+            repo_root,
+        )?;
+    }
+    if let Some(expected) = expected_stderr {
+        ok &= golden::compare(
+            "stderr",
+            expected,
+            stderr,
+            normalizations,
+            container_root,
+            repo_root,
+        )?;
+    }
+    Ok(ok)
+}
+
+/// Merge the raw profiling data collected during the test run into an lcov
+/// report (Pyunit instead just gets its raw `.coverage` file, since
+/// `coverage.py`'s format isn't something `llvm-profdata`/`llvm-cov` can
+/// read), and write it both to `output_dir` and -- the same way `report()`
+/// does for logs -- into `TEST_RESULT_ARTIFACTS_DIR`, if tpx set one.
+fn export_coverage(
+    is_pyunit: bool,
+    coverage_dir: &Path,
+    binary: Option<OsString>,
+    output_dir: Option<&Path>,
+) -> Result<()> {
+    let artifacts_dir = std::env::var_os("TEST_RESULT_ARTIFACTS_DIR").map(PathBuf::from);
+
+    if is_pyunit {
+        let src = coverage_dir.join(".coverage");
+        if !src.exists() {
+            return Ok(());
+        }
+        for dst_dir in output_dir.into_iter().chain(artifacts_dir.as_deref()) {
+            std::fs::create_dir_all(dst_dir)?;
+            std::fs::copy(&src, dst_dir.join(".coverage"))?;
+        }
+        return Ok(());
     }
+
+    let profraws: Vec<_> = std::fs::read_dir(coverage_dir)
+        .with_context(|| format!("while reading {}", coverage_dir.display()))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "profraw"))
+        .collect();
+    if profraws.is_empty() {
+        return Ok(());
+    }
+    let binary = binary.context("coverage was requested but the test has no binary to symbolize coverage against")?;
+
+    let merged = coverage_dir.join("merged.profdata");
+    let status = std::process::Command::new("llvm-profdata")
+        .arg("merge")
+        .arg("-sparse")
+        .arg("-o")
+        .arg(&merged)
+        .args(&profraws)
+        .status()
+        .context("while running llvm-profdata merge")?;
+    anyhow::ensure!(status.success(), "llvm-profdata merge failed: {status}");
+
+    let lcov = std::process::Command::new("llvm-cov")
+        .arg("export")
+        .arg("--format=lcov")
+        .arg(format!("--instr-profile={}", merged.display()))
+        .arg(&binary)
+        .output()
+        .context("while running llvm-cov export")?;
+    anyhow::ensure!(
+        lcov.status.success(),
+        "llvm-cov export failed: {}",
+        lcov.status
+    );
+
+    for dst_dir in output_dir.into_iter().chain(artifacts_dir.as_deref()) {
+        std::fs::create_dir_all(dst_dir)?;
+        std::fs::write(dst_dir.join("lcov.info"), &lcov.stdout)?;
+    }
+    if let Some(annotations_dir) = std::env::var_os("TEST_RESULT_ARTIFACT_ANNOTATIONS_DIR") {
+        std::fs::create_dir_all(&annotations_dir)?;
+        std::fs::write(
+            Path::new(&annotations_dir).join("lcov.info.annotation"),
+            r#"{"type": {"coverage_report": {}}, "description": "code coverage"}"#,
+        )?;
+    }
+    Ok(())
 }
 
 fn report(mut container_stdout: NamedTempFile) -> Result<()> {
@@ -0,0 +1,176 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Auxiliary service containers (`runtime::Spec::sidecars`) that a test
+//! depends on -- an SSH server, an HTTP server, a database, ... -- the way
+//! test harnesses stand up dependency containers and wait for them before
+//! running the test body. Each [Sidecar] is booted in its own
+//! [IsolationContext], joined to the test container's network namespace so
+//! the two can reach each other over `localhost`, and must pass its
+//! readiness probe before [crate::spawn_common::run] starts the test.
+//! Sidecars are only started for booted tests (`runtime::Spec::boot`); a
+//! test that isn't booted runs without any of them.
+
+use std::net::TcpStream;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Child;
+use std::process::Command;
+use std::time::Duration;
+use std::time::Instant;
+
+use antlir2_isolate::unshare;
+use antlir2_isolate::InvocationType;
+use antlir2_isolate::IsolationContext;
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use image_test_lib::ArtifactKind;
+use image_test_lib::TpxArtifact;
+
+/// How to decide that a [Sidecar] has finished starting up.
+#[derive(Debug, Clone)]
+pub enum ReadinessProbe {
+    /// Retry a TCP connect to this port (reachable over the shared network
+    /// namespace's loopback interface) until one succeeds.
+    TcpConnect(u16),
+    /// Run this command inside the sidecar's layer, retrying until it exits
+    /// zero.
+    Command(Vec<String>),
+}
+
+/// One service container a test depends on.
+#[derive(Debug, Clone)]
+pub struct Sidecar {
+    /// Human-readable name, used to label its console log artifact and in
+    /// error messages.
+    pub name: String,
+    /// The layer to boot the sidecar's root filesystem out of.
+    pub layer: PathBuf,
+    /// Command used to launch the sidecar's long-running process.
+    pub command: Vec<String>,
+    /// Ports the sidecar exposes to the test.
+    pub ports: Vec<u16>,
+    pub readiness: ReadinessProbe,
+    /// How many times to retry the readiness probe before giving up.
+    pub retries: u32,
+    /// Overall deadline for the sidecar to become ready, independent of
+    /// `retries` -- whichever is hit first wins.
+    pub timeout: Duration,
+}
+
+/// A [Sidecar] that has been started and is either ready or has already
+/// failed to become ready; owns the child process and its console artifact
+/// until [RunningSidecar::stop] tears it down.
+pub struct RunningSidecar {
+    name: String,
+    child: Child,
+    console: TpxArtifact,
+}
+
+impl Sidecar {
+    /// Boot this sidecar sharing `netns_path`'s network namespace with the
+    /// test container, and block until its readiness probe passes.
+    pub fn start(&self, netns_path: &Path) -> Result<RunningSidecar> {
+        let console = TpxArtifact::new_log_file(
+            &format!("sidecar-{}-console.txt", self.name),
+            ArtifactKind::GenericTextLog,
+        )?;
+
+        let mut ctx = IsolationContext::builder(&self.layer);
+        ctx.invocation_type(InvocationType::Pid2Pipe)
+            .shared_netns(netns_path)
+            .setenv(("ANTLIR2_IMAGE_TEST_SIDECAR", self.name.as_str()));
+
+        let mut cmd = self.command.iter();
+        let program = cmd
+            .next()
+            .with_context(|| format!("sidecar {} has an empty command", self.name))?;
+        let mut isol = unshare(ctx.build())?.command(program)?;
+        isol.args(cmd);
+
+        let child = isol
+            .stdout(console.as_file()?)
+            .stderr(console.as_file()?)
+            .spawn()
+            .with_context(|| format!("while starting sidecar {}", self.name))?;
+
+        let mut running = RunningSidecar {
+            name: self.name.clone(),
+            child,
+            console,
+        };
+        if let Err(e) = running.wait_until_ready(&self.readiness, self.retries, self.timeout) {
+            // best-effort cleanup so a failed-to-start sidecar doesn't keep
+            // running after we bail out of the test
+            let _ = running.stop();
+            return Err(e);
+        }
+        Ok(running)
+    }
+}
+
+impl RunningSidecar {
+    fn wait_until_ready(
+        &mut self,
+        probe: &ReadinessProbe,
+        retries: u32,
+        timeout: Duration,
+    ) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let mut attempts = 0;
+        loop {
+            if self.is_ready(probe) {
+                return Ok(());
+            }
+            attempts += 1;
+            if attempts > retries || Instant::now() >= deadline {
+                bail!(
+                    "sidecar {} did not become ready after {attempts} attempts (timeout {timeout:?})",
+                    self.name
+                );
+            }
+            std::thread::sleep(Duration::from_millis(200));
+        }
+    }
+
+    fn is_ready(&self, probe: &ReadinessProbe) -> bool {
+        match probe {
+            ReadinessProbe::TcpConnect(port) => TcpStream::connect(("127.0.0.1", *port)).is_ok(),
+            ReadinessProbe::Command(cmd) => {
+                let mut iter = cmd.iter();
+                let Some(program) = iter.next() else {
+                    return false;
+                };
+                Command::new(program)
+                    .args(iter)
+                    .status()
+                    .map(|status| status.success())
+                    .unwrap_or(false)
+            }
+        }
+    }
+
+    /// Kill the sidecar. Its console output was already captured into a
+    /// [TpxArtifact] by [Sidecar::start] as it ran, so there's nothing left
+    /// to collect here -- this just needs to make sure it's torn down
+    /// whether the test passed or failed.
+    pub fn stop(mut self) -> Result<()> {
+        match self.child.kill() {
+            Ok(()) => {}
+            // already exited on its own
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidInput => {}
+            Err(e) => return Err(e).with_context(|| format!("while stopping sidecar {}", self.name)),
+        }
+        let _ = self.child.wait();
+        Ok(())
+    }
+
+    pub fn console_path(&self) -> &Path {
+        self.console.path()
+    }
+}
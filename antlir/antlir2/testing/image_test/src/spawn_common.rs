@@ -7,6 +7,7 @@
 
 use std::collections::BTreeMap;
 use std::collections::HashMap;
+use std::ffi::OsString;
 use std::fs::Permissions;
 use std::io::Seek;
 use std::io::Write;
@@ -24,6 +25,7 @@ use anyhow::Context;
 use anyhow::Result;
 use anyhow::ensure;
 use bon::builder;
+use image_test_lib::ArtifactKind;
 use image_test_lib::Test;
 use image_test_lib::TpxArtifact;
 use tempfile::NamedTempFile;
@@ -31,7 +33,9 @@ use tracing::debug;
 use tracing::trace;
 
 use crate::exec;
+use crate::oci;
 use crate::runtime;
+use crate::sidecar::RunningSidecar;
 
 #[builder]
 pub(crate) fn run(
@@ -49,6 +53,12 @@ pub(crate) fn run(
     }
 
     let mut setenv: BTreeMap<_, _> = spec.setenv.into_iter().collect();
+    // gtest supports shard selection natively via env vars; Pyunit/Rust are
+    // expected to already have been narrowed down to their shard's tests via
+    // `Test::apply_shard` before getting here
+    for pair in test.shard_env() {
+        setenv.insert(pair.key, pair.value.to_string_lossy().into_owned());
+    }
     // forward test runner env vars to the inner test
     for (key, val) in std::env::vars() {
         if key.starts_with("TEST_PILOT") {
@@ -73,6 +83,13 @@ pub(crate) fn run(
         }
     }
 
+    if image_test_lib::should_skip(&test, &setenv).context("while evaluating --skip-if-cfg")? {
+        image_test_lib::write_junit_skip_report(&test, "skipped by --skip-if-cfg")
+            .context("while writing skip report")?;
+        println!("skipping test: --skip-if-cfg predicate matched");
+        return Ok(());
+    }
+
     let working_directory = std::env::current_dir().context("while getting cwd")?;
 
     let mut ctx = IsolationContext::builder(&spec.layer);
@@ -130,15 +147,16 @@ pub(crate) fn run(
     }
 
     // bind LLVM coverage output paths
+    let mut profile_dir = None;
     if let Some(llvm_profile_file) = std::env::var_os("LLVM_PROFILE_FILE") {
         // TPX overrides LLVM_PROFILE_FILE when --collect-coverage is set
         if llvm_profile_file != "/dev/null" {
-            ctx.outputs(
-                Path::new(&llvm_profile_file)
-                    .parent()
-                    .context("LLVM_PROFILE_FILE did not have parent")?
-                    .to_owned(),
-            );
+            let dir = Path::new(&llvm_profile_file)
+                .parent()
+                .context("LLVM_PROFILE_FILE did not have parent")?
+                .to_owned();
+            ctx.outputs(&dir);
+            profile_dir = Some(dir);
         }
     }
 
@@ -148,9 +166,50 @@ pub(crate) fn run(
 
     match spec.boot {
         Some(boot) => {
-            let container_stdout = TpxArtifact::new_log_file_or_stderr("container-stdout.txt")?;
-            let test_stdout = TpxArtifact::new_log_file("test-stdout.txt")?;
-            let test_stderr = TpxArtifact::new_log_file("test-stderr.txt")?;
+            // boot any sidecars the test depends on (eg a database or SSH
+            // server it connects to over the network) before starting the
+            // test itself, and don't let it start until they're all ready
+            let sidecar_netns = if spec.sidecars.is_empty() {
+                None
+            } else {
+                Some(format!("antlir2-image-test-{}", std::process::id()))
+            };
+            let mut running_sidecars = Vec::with_capacity(spec.sidecars.len());
+            if let Some(netns_id) = &sidecar_netns {
+                let status = Command::new("ip")
+                    .args(["netns", "add", netns_id])
+                    .status()
+                    .context("while running `ip netns add`")?;
+                ensure!(status.success(), "ip netns add {netns_id} failed");
+                let netns_path = PathBuf::from(format!("/var/run/netns/{netns_id}"));
+                ctx.shared_netns(&netns_path);
+
+                for sidecar in &spec.sidecars {
+                    match sidecar.start(&netns_path) {
+                        Ok(s) => running_sidecars.push(s),
+                        Err(e) => {
+                            for s in running_sidecars {
+                                let _ = s.stop();
+                            }
+                            let _ = Command::new("ip").args(["netns", "delete", netns_id]).status();
+                            return Err(e)
+                                .with_context(|| format!("while starting sidecar {}", sidecar.name));
+                        }
+                    }
+                }
+            }
+
+            let container_stdout = TpxArtifact::new_log_file_or_stderr(
+                "container-stdout.txt",
+                ArtifactKind::GenericTextLog,
+            )?;
+            let test_stdout =
+                TpxArtifact::new_log_file("test-stdout.txt", ArtifactKind::GenericTextLog)?;
+            let test_stderr =
+                TpxArtifact::new_log_file("test-stderr.txt", ArtifactKind::GenericTextLog)?;
+            // registered before the test runs so that a timeout still
+            // uploads whatever normalized results were captured
+            let junit_artifact = image_test_lib::new_junit_artifact()?;
 
             let mut test_unit_dropin = NamedTempFile::new()?;
             writeln!(test_unit_dropin, "[Unit]")?;
@@ -225,6 +284,7 @@ pub(crate) fn run(
                 }
             }
 
+            let test_for_junit = test.clone();
             let exec_spec = exec::Spec::builder()
                 .cmd(test.into_inner_cmd())
                 .user(spec.user)
@@ -261,6 +321,18 @@ pub(crate) fn run(
                 .context("while spawning systemd-nspawn")?;
             let res = child.wait().context("while waiting for systemd-nspawn")?;
 
+            // tear sidecars down whether the test passed or failed, so a
+            // flaky dependency doesn't leak a container/netns past this run
+            for sidecar in running_sidecars {
+                if let Err(e) = sidecar.stop() {
+                    tracing::warn!("failed to stop sidecar: {e:#}");
+                }
+            }
+            if let Some(netns_id) = &sidecar_netns {
+                let _ = Command::new("ip").args(["netns", "delete", netns_id]).status();
+            }
+
+            let test_stdout_path = test_stdout.path().to_owned();
             let mut test_stdout = test_stdout.into_file();
             let mut test_stderr = test_stderr.into_file();
             test_stdout.rewind()?;
@@ -268,6 +340,16 @@ pub(crate) fn run(
             std::io::copy(&mut test_stdout, &mut std::io::stdout())?;
             std::io::copy(&mut test_stderr, &mut std::io::stderr())?;
 
+            // for the Rust arm's opt-in `--report-time` json stream, the
+            // native results went to stdout rather than a discrete file, so
+            // pass along where we captured it
+            image_test_lib::write_junit_report(
+                junit_artifact,
+                &test_for_junit,
+                Some(&test_stdout_path),
+            )
+            .context("while normalizing test results into a junit report")?;
+
             if !res.success() {
                 // if the container stdout is not already being dumped to
                 // stdout/err, then print out the path where it can be found
@@ -280,15 +362,77 @@ pub(crate) fn run(
                 }
                 std::process::exit(res.code().unwrap_or(255))
             } else {
+                // gated on an explicit `--collect-coverage`-style flag or
+                // (as a convenience) just having an LLVM_PROFILE_FILE dir to
+                // collect profraws from in the first place
+                if spec.coverage || profile_dir.is_some() {
+                    if let Some(profile_dir) = &profile_dir {
+                        finalize_coverage(profile_dir, spec.coverage_binary.as_deref())
+                            .context("while finalizing coverage")?;
+                    }
+                }
                 Ok(())
             }
         }
         None => {
+            let mut cmd = test.into_inner_cmd().into_iter();
+            let program = cmd.next().expect("must have program arg");
+
+            if let Some(oci_runtime) = &spec.oci_runtime {
+                let mut mounts = vec![oci::BindMount {
+                    // tests often read resource files from the repo
+                    destination: repo.clone(),
+                    source: repo.clone(),
+                    writable: false,
+                }];
+                #[cfg(facebook)]
+                for platform_dir in [Path::new("/usr/local/fbcode"), Path::new("/mnt/gvfs")] {
+                    mounts.push(oci::BindMount {
+                        destination: platform_dir.to_owned(),
+                        source: platform_dir.to_owned(),
+                        writable: false,
+                    });
+                }
+                // test output dirs need to be writable so the test can report results
+                for path in test.output_dirs() {
+                    mounts.push(oci::BindMount {
+                        destination: path.clone(),
+                        source: path,
+                        writable: true,
+                    });
+                }
+                // NOTE: the `--mount` host/layer mounts in `spec.mounts` aren't
+                // translated here -- their concrete shape lives in the
+                // `runtime` module, which only the nspawn/unshare paths below
+                // consume (via `IsolationContext::inputs`).
+
+                let status = oci::run(oci::RunArgs {
+                    root: &spec.layer,
+                    mounts,
+                    program_args: std::iter::once(program.clone()).chain(cmd.clone()).collect(),
+                    env: setenv
+                        .iter()
+                        .map(|(k, v)| (k.clone(), OsString::from(v)))
+                        .collect(),
+                    working_directory: &working_directory,
+                    user: &spec.user,
+                    runtime_bin: oci_runtime
+                        .to_str()
+                        .context("--oci-runtime path must be utf8")?,
+                })?;
+
+                return if status.success() {
+                    Ok(())
+                } else if let Some(code) = status.code() {
+                    std::process::exit(code)
+                } else {
+                    Err(anyhow::anyhow!("OCI runtime failed: {status}"))
+                };
+            }
+
             // some systems-y tests want to read /sys
             ctx.inputs(Path::new("/sys"));
             ctx.user(spec.user);
-            let mut cmd = test.into_inner_cmd().into_iter();
-            let program = cmd.next().expect("must have program arg");
             let mut isol = match spec.rootless {
                 false => nspawn(ctx.build())?.command(program)?,
                 true => unshare(ctx.build())?.command(program)?,
@@ -299,3 +443,63 @@ pub(crate) fn run(
         }
     }
 }
+
+/// Merge the `.profraw` files LLVM instrumentation wrote under `profile_dir`
+/// into a single `lcov.info` TPX artifact, with branch coverage enabled.
+/// Mirrors grcov's `ignore-not-existing` (skip profraw files that went away
+/// between the directory listing and the merge) and `prefix-dir` (strip a
+/// path prefix so source paths in the report are repo-relative) knobs.
+fn finalize_coverage(profile_dir: &Path, binary: Option<&Path>) -> Result<()> {
+    let profraws: Vec<_> = std::fs::read_dir(profile_dir)
+        .with_context(|| format!("while reading {}", profile_dir.display()))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "profraw"))
+        // ignore-not-existing
+        .filter(|path| path.exists())
+        .collect();
+    if profraws.is_empty() {
+        return Ok(());
+    }
+    let binary = binary.context(
+        "coverage was requested but no test binary was given to symbolize coverage against",
+    )?;
+
+    let merged = profile_dir.join("merged.profdata");
+    let status = Command::new("llvm-profdata")
+        .arg("merge")
+        .arg("-sparse")
+        .arg("-o")
+        .arg(&merged)
+        .args(&profraws)
+        .status()
+        .context("while running llvm-profdata merge")?;
+    ensure!(status.success(), "llvm-profdata merge failed: {status}");
+
+    let mut export = Command::new("llvm-cov");
+    export
+        .arg("export")
+        .arg("-format=lcov")
+        .arg(format!("-instr-profile={}", merged.display()))
+        .arg("-show-branches=count")
+        .arg(binary);
+    // strip a host-specific prefix (eg the out-of-tree build root) so the
+    // lcov source paths line up with the repo checkout tpx runs against
+    if let Some(prefix_dir) = std::env::var_os("ANTLIR2_IMAGE_TEST_COVERAGE_PREFIX_DIR") {
+        export.arg(format!(
+            "-path-equivalence={},.",
+            Path::new(&prefix_dir).display()
+        ));
+    }
+    let lcov = export.output().context("while running llvm-cov export")?;
+    ensure!(
+        lcov.status.success(),
+        "llvm-cov export failed: {}",
+        lcov.status
+    );
+
+    let artifact = TpxArtifact::new_log_file("lcov.info", ArtifactKind::GenericTextLog)?;
+    artifact.as_file()?.write_all(&lcov.stdout)?;
+
+    Ok(())
+}
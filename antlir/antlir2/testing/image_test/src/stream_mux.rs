@@ -0,0 +1,224 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Wire format and helper process for multiplexing a test's stdout and
+//! stderr onto a single host-provided socket without interleaving them.
+//!
+//! The old `ANTLIR_STREAM_TO_CONSOLE` path duplicated raw bytes onto
+//! `/dev/console` with `tee`, which works for a human tailing the console
+//! but collapses stdout and stderr into one unordered byte stream -- useless
+//! for attributing output to the right stream, let alone the right test
+//! when several run concurrently and share a console. [FrameCodec] instead
+//! tags every chunk of output with which stream it came from and when it
+//! was read, so the host side can decode the socket and reconstruct each
+//! stream in order, per test.
+//!
+//! [run_stream_forward] is the body of the forwarder helper: a stdin-to-
+//! socket relay that [crate::exec] re-execs itself into (one instance per
+//! stream, the same way it used to spawn a `tee` child), so forwarding
+//! survives the `exec()` of the inner test binary.
+
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Instant;
+
+use anyhow::Context;
+use anyhow::Result;
+use bytes::BufMut;
+use bytes::Bytes;
+use bytes::BytesMut;
+use clap::Parser;
+use clap::ValueEnum;
+use tokio_util::codec::Decoder;
+use tokio_util::codec::Encoder;
+
+/// Argv\[1\] this binary recognizes as "act as a stream-forward helper
+/// instead of the normal test runner", the same re-exec-with-a-hidden-
+/// subcommand shape `isolate_unshare_preexec` uses for its `pid1` helper.
+/// [crate::exec::Args::run] re-execs itself with this as its first argument
+/// for each stream when `Spec::stream_socket` is set.
+pub(crate) const STREAM_FORWARD_SUBCOMMAND: &str = "__stream_forward";
+
+#[derive(Debug, Parser)]
+pub(crate) struct StreamForwardArgs {
+    /// Unix socket to connect to and relay framed output to
+    socket: PathBuf,
+    /// Which stream this invocation is forwarding
+    #[clap(value_enum)]
+    stream: StreamKind,
+}
+
+impl StreamForwardArgs {
+    pub(crate) fn run(self) -> Result<()> {
+        run_stream_forward(&self.socket, self.stream)
+    }
+}
+
+/// Which of the test's output streams a [Frame] came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub(crate) enum StreamKind {
+    Stdout,
+    Stderr,
+}
+
+impl StreamKind {
+    fn to_u8(self) -> u8 {
+        match self {
+            Self::Stdout => 0,
+            Self::Stderr => 1,
+        }
+    }
+
+    fn from_u8(b: u8) -> Option<Self> {
+        match b {
+            0 => Some(Self::Stdout),
+            1 => Some(Self::Stderr),
+            _ => None,
+        }
+    }
+}
+
+/// One tagged chunk of output: which command it's from (`0` is always the
+/// primary test; [crate::control] hands out `1..` to auxiliary commands it
+/// spawns), which of that command's streams, a monotonic timestamp
+/// (microseconds since the forwarder connected) so the host can interleave
+/// frames from both streams back into the order they were produced, and the
+/// bytes themselves.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Frame {
+    pub(crate) source: u32,
+    pub(crate) stream: StreamKind,
+    pub(crate) timestamp_micros: u64,
+    pub(crate) data: Bytes,
+}
+
+/// Source id always used for the primary test command, reserved so that
+/// [crate::control]'s auxiliary spawns (which start counting at `1`) never
+/// collide with it.
+pub(crate) const PRIMARY_SOURCE: u32 = 0;
+
+/// 4-byte source id, 1-byte stream id, 8-byte big-endian timestamp, 4-byte
+/// big-endian payload length, followed by that many bytes of payload.
+const HEADER_LEN: usize = 4 + 1 + 8 + 4;
+
+/// Frames a [Frame] for the wire, or parses one back out of a byte stream.
+/// Reused as-is on the host side to decode what this module's forwarder
+/// writes.
+#[derive(Debug, Default)]
+pub(crate) struct FrameCodec;
+
+impl Encoder<Frame> for FrameCodec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, frame: Frame, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.reserve(HEADER_LEN + frame.data.len());
+        dst.put_u32(frame.source);
+        dst.put_u8(frame.stream.to_u8());
+        dst.put_u64(frame.timestamp_micros);
+        dst.put_u32(frame.data.len() as u32);
+        dst.put_slice(&frame.data);
+        Ok(())
+    }
+}
+
+impl Decoder for FrameCodec {
+    type Item = Frame;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if src.len() < HEADER_LEN {
+            return Ok(None);
+        }
+        let source = u32::from_be_bytes(src[0..4].try_into().expect("slice is exactly 4 bytes"));
+        let stream_byte = src[4];
+        let timestamp_micros = u64::from_be_bytes(
+            src[5..13]
+                .try_into()
+                .expect("slice is exactly 8 bytes"),
+        );
+        let len = u32::from_be_bytes(
+            src[13..HEADER_LEN]
+                .try_into()
+                .expect("slice is exactly 4 bytes"),
+        ) as usize;
+        if src.len() < HEADER_LEN + len {
+            src.reserve(HEADER_LEN + len - src.len());
+            return Ok(None);
+        }
+        let stream = StreamKind::from_u8(stream_byte).ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("invalid stream id byte {stream_byte}"),
+            )
+        })?;
+        let _ = src.split_to(HEADER_LEN);
+        let data = src.split_to(len).freeze();
+        Ok(Some(Frame {
+            source,
+            stream,
+            timestamp_micros,
+            data,
+        }))
+    }
+}
+
+/// Largest chunk read per frame. Keeps any single frame well under typical
+/// socket buffer sizes so the host can start decoding before a slow test
+/// finishes producing output.
+const READ_CHUNK: usize = 64 * 1024;
+
+/// Read `reader` to EOF, relaying each chunk to `socket` as a [Frame] tagged
+/// with `source` and `stream` and timestamped relative to `start`. Shared by
+/// [run_stream_forward] (the primary test, reading stdin) and
+/// [crate::control] (auxiliary spawns, reading a child's piped stdout/stderr
+/// directly, since the supervisor doesn't need a separate helper process to
+/// outlive an `exec()`).
+pub(crate) fn forward_to_socket(
+    mut reader: impl Read,
+    socket: &Path,
+    source: u32,
+    stream: StreamKind,
+    start: Instant,
+) -> Result<()> {
+    let mut conn = UnixStream::connect(socket)
+        .with_context(|| format!("while connecting to stream socket '{}'", socket.display()))?;
+    let mut codec = FrameCodec;
+    let mut buf = [0u8; READ_CHUNK];
+    let mut out = BytesMut::new();
+    loop {
+        let n = reader.read(&mut buf).context("while reading output")?;
+        if n == 0 {
+            break;
+        }
+        let frame = Frame {
+            source,
+            stream,
+            timestamp_micros: start.elapsed().as_micros() as u64,
+            data: Bytes::copy_from_slice(&buf[..n]),
+        };
+        out.clear();
+        codec
+            .encode(frame, &mut out)
+            .context("while encoding output frame")?;
+        conn.write_all(&out)
+            .context("while writing frame to stream socket")?;
+    }
+    Ok(())
+}
+
+/// Body of the stream-forwarding helper: read the primary test's stdin
+/// (which [crate::exec] has arranged to be the read end of a pipe fed by
+/// the inner test's real stdout/stderr) and relay it to `socket`, tagged as
+/// [PRIMARY_SOURCE], until stdin hits EOF (the test exited and closed its
+/// end of the pipe).
+pub(crate) fn run_stream_forward(socket: &Path, stream: StreamKind) -> Result<()> {
+    let stdin = std::io::stdin();
+    forward_to_socket(stdin.lock(), socket, PRIMARY_SOURCE, stream, Instant::now())
+}
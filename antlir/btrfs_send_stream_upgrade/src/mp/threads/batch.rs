@@ -0,0 +1,140 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Coalescing decoded send-stream commands into larger batches before they
+//! go to the compression workers. A raw send-stream is dominated by small
+//! `write`/`clone` commands against contiguous offsets (btrfs splits large
+//! writes at its own internal block size); compressing each one separately
+//! wastes the codec's window on tiny inputs and pays its per-call overhead
+//! once per command instead of once per batch.
+
+use std::path::PathBuf;
+
+/// A decoded send-stream command, the subset [Batcher] cares about.
+///
+/// Mirrors the real command set decoded by this crate's (pruned from this
+/// checkout) `send_stream` module -- `Write`/`Clone` carry data at a
+/// logical offset and are what gets coalesced, everything else is metadata
+/// that must stay strictly ordered relative to the data around it.
+pub enum Command {
+    Write { offset: u64, data: Vec<u8> },
+    Clone { offset: u64, len: u64, src_offset: u64 },
+    Mkdir(PathBuf),
+    Rename { from: PathBuf, to: PathBuf },
+    Utimes(PathBuf),
+    SetAttr(PathBuf),
+    Other(String),
+}
+
+impl Command {
+    fn is_data(&self) -> bool {
+        matches!(self, Self::Write { .. } | Self::Clone { .. })
+    }
+
+    fn logical_range(&self) -> Option<(u64, u64)> {
+        match self {
+            Self::Write { offset, data } => Some((*offset, data.len() as u64)),
+            Self::Clone { offset, len, .. } => Some((*offset, *len)),
+            _ => None,
+        }
+    }
+}
+
+/// A run of adjacent data commands (in stream order), ready to be handed to
+/// a compression worker as a single unit.
+pub struct Batch {
+    /// Monotonically increasing, gap-free order of this batch among all
+    /// batches a single [Batcher] has ever emitted. Batches are compressed
+    /// and written by a pool of workers that may finish out of order; `seq`
+    /// is how the write stage puts them back in stream order without
+    /// having to serialize the compression work itself.
+    pub seq: u64,
+    pub commands: Vec<Command>,
+}
+
+/// Coalesces adjacent `Write`/`Clone` commands that target contiguous
+/// logical offsets into [Batch]es of up to `max_batch_size` bytes.
+/// Metadata commands always flush whatever batch is open first, so they
+/// stay ordered relative to the data immediately before and after them.
+pub struct Batcher {
+    max_batch_size: u64,
+    open: Vec<Command>,
+    open_size: u64,
+    /// Logical offset one past the end of the last command added to
+    /// `open`, used to check the next data command is contiguous with it.
+    open_end: Option<u64>,
+    /// `seq` to assign to the next [Batch] this [Batcher] emits.
+    next_seq: u64,
+}
+
+impl Batcher {
+    pub fn new(max_batch_size: u64) -> Self {
+        Self {
+            max_batch_size: max_batch_size.max(1),
+            open: Vec::new(),
+            open_size: 0,
+            open_end: None,
+            next_seq: 0,
+        }
+    }
+
+    /// Feed one command in. Returns any completed batches this command
+    /// caused to close (because it wasn't contiguous, the batch hit its
+    /// size limit, or it's a metadata command that must flush first),
+    /// oldest first.
+    pub fn push(&mut self, command: Command) -> Vec<Batch> {
+        let mut flushed = Vec::new();
+
+        if !command.is_data() {
+            if let Some(batch) = self.take_open() {
+                flushed.push(batch);
+            }
+            // Metadata commands aren't batched; they go out as a
+            // single-command "batch" so the compression stage sees every
+            // command exactly once, in order.
+            flushed.push(self.next_batch(vec![command]));
+            return flushed;
+        }
+
+        let (offset, len) = command
+            .logical_range()
+            .expect("is_data() commands always have a logical range");
+        let contiguous = self.open_end == Some(offset);
+        let fits = self.open_size + len <= self.max_batch_size;
+        if !self.open.is_empty() && (!contiguous || !fits) {
+            if let Some(batch) = self.take_open() {
+                flushed.push(batch);
+            }
+        }
+
+        self.open_size += len;
+        self.open_end = Some(offset + len);
+        self.open.push(command);
+        flushed
+    }
+
+    /// Flush whatever batch is still open, e.g. at the end of the stream.
+    pub fn flush(&mut self) -> Option<Batch> {
+        self.take_open()
+    }
+
+    fn take_open(&mut self) -> Option<Batch> {
+        if self.open.is_empty() {
+            return None;
+        }
+        self.open_size = 0;
+        self.open_end = None;
+        let commands = std::mem::take(&mut self.open);
+        Some(self.next_batch(commands))
+    }
+
+    fn next_batch(&mut self, commands: Vec<Command>) -> Batch {
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        Batch { seq, commands }
+    }
+}
@@ -8,6 +8,8 @@
 use std::thread;
 use std::thread::JoinHandle;
 
+use crate::mp::threads::batch::Batcher;
+use crate::mp::threads::compress::compress_batch;
 use crate::mp::threads::worker::Worker;
 use crate::upgrade::send_stream_upgrade_context::SendStreamUpgradeContext;
 
@@ -19,7 +21,30 @@ pub struct BatcherWorker {
 }
 
 impl BatcherWorker {
-    fn batcher_work(_context: SendStreamUpgradeContext) -> anyhow::Result<()> {
+    /// Pulls decoded send-stream commands off `context`'s sync container,
+    /// coalesces contiguous `write`/`clone` commands into batches (flushing
+    /// immediately on any metadata command, to keep it ordered relative to
+    /// the data around it), compresses each completed batch, and hands the
+    /// result to the next stage. Any open batch is flushed once the sync
+    /// container reports the stream is done.
+    fn batcher_work(mut context: SendStreamUpgradeContext) -> anyhow::Result<()> {
+        let sync_container = context
+            .ssuc_sync_container
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Batcher worker context has no sync container"))?;
+        let compression_options = context.ssuc_options.ssuo_compression_options;
+        let mut batcher = Batcher::new(context.ssuc_options.ssuo_max_batch_size);
+
+        while let Some(command) = sync_container.recv_decoded_command()? {
+            for batch in batcher.push(command) {
+                let compressed = compress_batch(&batch, &compression_options)?;
+                sync_container.send_compressed_batch(compressed)?;
+            }
+        }
+        if let Some(batch) = batcher.flush() {
+            let compressed = compress_batch(&batch, &compression_options)?;
+            sync_container.send_compressed_batch(compressed)?;
+        }
         Ok(())
     }
 }
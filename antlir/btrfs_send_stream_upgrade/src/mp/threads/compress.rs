@@ -0,0 +1,160 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Compressing a coalesced [Batch][super::batch::Batch] of send-stream
+//! commands before it's written out. zstd is the default codec; xz is kept
+//! around for cases that want the smaller output at the cost of slower
+//! compression, with a configurable window size -- widening it (e.g. the
+//! rust-installer move from an 8MB to a 64MB xz window) trades memory for a
+//! better ratio on batches with long-range redundancy.
+
+use std::io::Read;
+use std::io::Write;
+
+use anyhow::Context;
+use anyhow::Result;
+
+use crate::mp::threads::batch::Batch;
+
+/// Which codec to compress a batch with; the level is
+/// [CompressionOptions::level].
+#[derive(Debug, Clone, Copy)]
+pub enum CompressionCodec {
+    Zstd,
+    Xz,
+}
+
+impl Default for CompressionCodec {
+    fn default() -> Self {
+        Self::Zstd
+    }
+}
+
+/// Tunable knobs for the compression stage, exposed through
+/// `SendStreamUpgradeOptions` so callers can trade ratio for speed/memory.
+#[derive(Debug, Clone, Copy)]
+pub struct CompressionOptions {
+    pub codec: CompressionCodec,
+    pub level: i32,
+    /// Compression window size in bytes (the zstd/xz "long-range matching"
+    /// dictionary size). Larger windows find more redundancy across a
+    /// batch at the cost of more memory per worker.
+    pub window_size: u32,
+}
+
+impl Default for CompressionOptions {
+    fn default() -> Self {
+        Self {
+            codec: CompressionCodec::default(),
+            level: 0,
+            window_size: 8 << 20,
+        }
+    }
+}
+
+/// A [Batch] after it's been serialized and compressed, still tagged with
+/// its batcher-assigned `seq` so the write stage can put batches finished
+/// out of order (by however many write workers are running) back in
+/// stream order before appending them to the destination.
+pub struct CompressedBatch {
+    pub seq: u64,
+    pub codec: CompressionCodec,
+    pub data: Vec<u8>,
+}
+
+/// Serialize every command in `batch` (in order) and compress the result
+/// per `options`. The caller is responsible for framing/writing the
+/// returned bytes; this only does the encode.
+pub fn compress_batch(batch: &Batch, options: &CompressionOptions) -> Result<CompressedBatch> {
+    let mut raw = Vec::new();
+    for command in &batch.commands {
+        serialize_command(command, &mut raw);
+    }
+
+    let data = match options.codec {
+        CompressionCodec::Zstd => {
+            let mut encoder = zstd::stream::write::Encoder::new(Vec::new(), options.level)
+                .context("while creating zstd encoder")?;
+            encoder
+                .window_log(window_log(options.window_size))
+                .context("while setting zstd window size")?;
+            encoder.write_all(&raw).context("while compressing batch")?;
+            encoder.finish().context("while finishing zstd stream")?
+        }
+        CompressionCodec::Xz => {
+            let mut lzma_options = xz2::stream::LzmaOptions::new_preset(options.level.clamp(0, 9) as u32)
+                .context("while building lzma options")?;
+            lzma_options.dict_size(options.window_size);
+            let mut filters = xz2::stream::Filters::new();
+            filters.lzma2(&lzma_options);
+            let stream =
+                xz2::stream::Stream::new_stream_encoder(&filters, xz2::stream::Check::Crc32)
+                    .context("while creating xz stream")?;
+            let mut encoder = xz2::write::XzEncoder::new_stream(Vec::new(), stream);
+            encoder.write_all(&raw).context("while compressing batch")?;
+            encoder.finish().context("while finishing xz stream")?
+        }
+    };
+
+    Ok(CompressedBatch {
+        seq: batch.seq,
+        codec: options.codec,
+        data,
+    })
+}
+
+/// Inverse of the compression half of [compress_batch]. The write stage
+/// needs this to turn a [CompressedBatch] pulled off the sync container
+/// back into the bytes that actually get appended to the destination.
+pub fn decompress_batch(batch: &CompressedBatch) -> Result<Vec<u8>> {
+    match batch.codec {
+        CompressionCodec::Zstd => {
+            zstd::stream::decode_all(batch.data.as_slice()).context("while decompressing zstd batch")
+        }
+        CompressionCodec::Xz => {
+            let mut out = Vec::new();
+            xz2::read::XzDecoder::new(batch.data.as_slice())
+                .read_to_end(&mut out)
+                .context("while decompressing xz batch")?;
+            Ok(out)
+        }
+    }
+}
+
+// zstd's window-log is expressed in log2(bytes); round up so the
+// configured window size is always covered.
+fn window_log(window_size: u32) -> i32 {
+    (32 - window_size.max(1).leading_zeros()) as i32
+}
+
+fn serialize_command(command: &super::batch::Command, out: &mut Vec<u8>) {
+    use super::batch::Command;
+    match command {
+        Command::Write { offset, data } => {
+            out.extend_from_slice(&offset.to_le_bytes());
+            out.extend_from_slice(&(data.len() as u64).to_le_bytes());
+            out.extend_from_slice(data);
+        }
+        Command::Clone {
+            offset,
+            len,
+            src_offset,
+        } => {
+            out.extend_from_slice(&offset.to_le_bytes());
+            out.extend_from_slice(&len.to_le_bytes());
+            out.extend_from_slice(&src_offset.to_le_bytes());
+        }
+        Command::Mkdir(path) | Command::Utimes(path) | Command::SetAttr(path) => {
+            out.extend_from_slice(path.to_string_lossy().as_bytes());
+        }
+        Command::Rename { from, to } => {
+            out.extend_from_slice(from.to_string_lossy().as_bytes());
+            out.extend_from_slice(to.to_string_lossy().as_bytes());
+        }
+        Command::Other(s) => out.extend_from_slice(s.as_bytes()),
+    }
+}
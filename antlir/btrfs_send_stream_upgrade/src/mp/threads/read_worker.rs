@@ -8,7 +8,10 @@
 use std::thread;
 use std::thread::JoinHandle;
 
+use anyhow::Context;
+
 use crate::mp::threads::worker::Worker;
+use crate::upgrade::send_stream::framed::parse_window;
 use crate::upgrade::send_stream_upgrade_context::SendStreamUpgradeContext;
 
 pub struct ReadWorker {
@@ -19,7 +22,45 @@ pub struct ReadWorker {
 }
 
 impl ReadWorker {
-    fn read_work(_context: SendStreamUpgradeContext) -> anyhow::Result<()> {
+    /// Parses the source starting at `context.get_read_offset()` in
+    /// `ssuo_read_window_size`-sized windows, using the same
+    /// `framed`/`tlv`/`NomBytes` decoding the single-threaded upgrade path
+    /// uses (see [parse_window]), and hands every decoded command to the
+    /// batcher stage over the sync container's bounded channel. That
+    /// channel is the pipeline's only backpressure: if the batcher and
+    /// write workers behind it fall behind, `send_decoded_command` blocks
+    /// instead of this worker buffering the whole source in memory.
+    fn read_work(mut context: SendStreamUpgradeContext) -> anyhow::Result<()> {
+        let sync_container = context
+            .ssuc_sync_container
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Read worker context has no sync container"))?
+            .clone();
+        let window_size = context.ssuc_options.ssuo_read_window_size;
+
+        loop {
+            let window = context
+                .read_source_window(window_size)
+                .context("while reading next source window")?;
+            if window.is_empty() {
+                break;
+            }
+            let (commands, consumed) =
+                parse_window(&window).context("while parsing source window")?;
+            for command in commands {
+                sync_container
+                    .send_decoded_command(command)
+                    .context("while enqueueing decoded command")?;
+            }
+            // A window can end mid-command; only the bytes a full command
+            // was decoded from are consumed, and the next window starts
+            // from there so nothing is skipped or double-parsed.
+            context.advance_read_offset(consumed);
+            if window.len() < window_size {
+                break;
+            }
+        }
+        sync_container.close_decoded_commands();
         Ok(())
     }
 }
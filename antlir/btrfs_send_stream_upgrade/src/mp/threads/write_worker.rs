@@ -0,0 +1,113 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+use std::thread;
+use std::thread::JoinHandle;
+
+use anyhow::Context;
+
+use crate::mp::threads::compress::decompress_batch;
+use crate::mp::threads::worker::Worker;
+use crate::upgrade::send_stream_upgrade_context::SendStreamUpgradeContext;
+
+pub struct WriteWorker {
+    /// The name associated with the write worker
+    ww_name: String,
+    /// The join handle to check the status of the write worker
+    ww_status: Option<JoinHandle<anyhow::Result<()>>>,
+}
+
+impl WriteWorker {
+    /// Pulls compressed batches off the sync container, decompresses and
+    /// re-encodes each one to the destination version, and appends the
+    /// result at `context.get_write_offset()`.
+    ///
+    /// Multiple write workers pull from the same channel, so two batches
+    /// can finish re-encoding in a different order than they were
+    /// produced in. `sync_container.commit_ordered_batch` is where that's
+    /// resolved: it holds back a finished batch until every earlier `seq`
+    /// has already been written, so whichever worker happens to complete
+    /// the next batch in sequence is the one that actually advances the
+    /// write offset. This keeps the destination append itself single-file
+    /// (no two workers ever write concurrently) without forcing the
+    /// re-encode work -- the expensive part -- to run one batch at a time.
+    fn write_work(mut context: SendStreamUpgradeContext) -> anyhow::Result<()> {
+        let sync_container = context
+            .ssuc_sync_container
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("Write worker context has no sync container"))?
+            .clone();
+
+        while let Some(batch) = sync_container
+            .recv_compressed_batch()
+            .context("while receiving next compressed batch")?
+        {
+            let seq = batch.seq;
+            let decoded = decompress_batch(&batch).context("while decompressing batch")?;
+            let reencoded = context
+                .reencode_to_destination_version(&decoded)
+                .context("while re-encoding batch to the destination version")?;
+            sync_container
+                .commit_ordered_batch(seq, reencoded, |bytes| {
+                    context
+                        .write_destination_bytes(bytes)
+                        .context("while appending batch to destination")
+                })
+                .with_context(|| format!("while committing batch {seq} in order"))?;
+        }
+        Ok(())
+    }
+}
+
+impl Worker for WriteWorker {
+    fn new(name: String, context: &mut SendStreamUpgradeContext) -> anyhow::Result<Self> {
+        let sync_container = match context.ssuc_sync_container {
+            Some(ref sync_container) => Some(sync_container.clone()),
+            None => anyhow::bail!("Creating new write worker for context without sync container"),
+        };
+        let new_context = SendStreamUpgradeContext::clone_for_mp_threads(
+            false,
+            false,
+            context.ssuc_logger.clone(),
+            context.ssuc_options.clone(),
+            context.get_source_version()?,
+            context.get_destination_version()?,
+            context.get_read_offset(),
+            context.get_write_offset(),
+            sync_container,
+        )?;
+
+        Ok(Self {
+            ww_name: name,
+            ww_status: Some(thread::spawn(move || Self::write_work(new_context))),
+        })
+    }
+    fn get_status(&mut self) -> anyhow::Result<bool> {
+        match self.ww_status {
+            Some(ref handle) => {
+                if !handle.is_finished() {
+                    return Ok(true);
+                }
+            }
+            None => anyhow::bail!("Failed to get status handle in write worker"),
+        }
+        // The thread is done now
+        // Remove the join handle and look it up
+        let handle = match self.ww_status.take() {
+            Some(handle) => handle,
+            None => anyhow::bail!("Unexepcted None status handle in write worker"),
+        };
+        match handle.join() {
+            Ok(Ok(())) => Ok(false),
+            // Normal anyhow error propagation
+            Ok(Err(e)) => anyhow::bail!(e),
+            // Note: This can happen in case of a panic
+            // Just do our best here...
+            Err(e) => anyhow::bail!("Thread {} paniced because {:?}", self.ww_name, e),
+        }
+    }
+}
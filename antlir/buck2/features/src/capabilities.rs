@@ -0,0 +1,33 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+use std::path::PathBuf;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+/// Grant Linux file capabilities to a regular file in the image, the
+/// `security.capability` xattr equivalent of running `setcap` -- lets an
+/// image ship an unprivileged binary that can still, say, bind a low port
+/// or send raw packets, without making it setuid-root.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub struct FileCaps {
+    /// Path to the regular file inside the image to set `security.capability` on.
+    pub path: PathBuf,
+    /// Capabilities to grant, eg `cap_net_bind_service`, `cap_net_raw`.
+    pub capabilities: Vec<String>,
+    /// Add the capabilities to the file's effective set.
+    #[serde(default)]
+    pub effective: bool,
+    /// Add the capabilities to the file's permitted set.
+    #[serde(default)]
+    pub permitted: bool,
+    /// Add the capabilities to the file's inheritable set.
+    #[serde(default)]
+    pub inheritable: bool,
+}
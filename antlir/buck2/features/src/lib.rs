@@ -10,6 +10,7 @@ use serde::Deserialize;
 use serde::Serialize;
 
 pub mod apt;
+pub mod capabilities;
 pub mod clone;
 pub mod ensure_dirs_exist;
 pub mod install;
@@ -52,6 +53,7 @@ pub enum Data<'a> {
     EnsureFileSymlink(symlink::Symlink),
     EnsureDirSymlink(symlink::Symlink),
     Tarball(tarball::Tarball),
+    SetFileCapabilities(capabilities::FileCaps),
     UserAdd(usergroup::User),
     GroupAdd(usergroup::Group),
 }
\ No newline at end of file
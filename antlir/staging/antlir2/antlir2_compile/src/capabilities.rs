@@ -0,0 +1,119 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+use features::capabilities::FileCaps;
+
+use crate::CompileFeature;
+use crate::CompilerContext;
+use crate::Error;
+use crate::Result;
+
+/// `vfs_cap_data` revision 2: a `(permitted, inheritable)` pair of 32-bit
+/// capability bitmasks per capability word. (Revision 3 adds a root uid for
+/// user-namespaced capabilities, which this feature has no way to express.)
+const VFS_CAP_REVISION_2: u32 = 0x0200_0000;
+
+/// Index into the `vfs_cap_data` bitmasks for each capability name this
+/// feature accepts, matching the numbering in `linux/capability.h`.
+fn capability_bit(name: &str) -> Result<u32> {
+    Ok(match name {
+        "cap_chown" => 0,
+        "cap_dac_override" => 1,
+        "cap_dac_read_search" => 2,
+        "cap_fowner" => 3,
+        "cap_fsetid" => 4,
+        "cap_kill" => 5,
+        "cap_setgid" => 6,
+        "cap_setuid" => 7,
+        "cap_setpcap" => 8,
+        "cap_linux_immutable" => 9,
+        "cap_net_bind_service" => 10,
+        "cap_net_broadcast" => 11,
+        "cap_net_admin" => 12,
+        "cap_net_raw" => 13,
+        "cap_ipc_lock" => 14,
+        "cap_ipc_owner" => 15,
+        "cap_sys_module" => 16,
+        "cap_sys_rawio" => 17,
+        "cap_sys_chroot" => 18,
+        "cap_sys_ptrace" => 19,
+        "cap_sys_pacct" => 20,
+        "cap_sys_admin" => 21,
+        "cap_sys_boot" => 22,
+        "cap_sys_nice" => 23,
+        "cap_sys_resource" => 24,
+        "cap_sys_time" => 25,
+        "cap_sys_tty_config" => 26,
+        "cap_mknod" => 27,
+        "cap_lease" => 28,
+        "cap_audit_write" => 29,
+        "cap_audit_control" => 30,
+        "cap_setfcap" => 31,
+        "cap_mac_override" => 32,
+        "cap_mac_admin" => 33,
+        "cap_syslog" => 34,
+        "cap_wake_alarm" => 35,
+        "cap_block_suspend" => 36,
+        "cap_audit_read" => 37,
+        "cap_perfmon" => 38,
+        "cap_bpf" => 39,
+        "cap_checkpoint_restore" => 40,
+        other => return Err(Error::InvalidCapability(other.to_owned())),
+    })
+}
+
+/// Encode `security.capability`'s `vfs_cap_data` payload: a `magic_etc`
+/// header declaring the revision, followed by a `(permitted, inheritable)`
+/// pair of bitmasks per capability word -- revision 2 has two words, since
+/// capability numbers now run past 31 (e.g. `cap_checkpoint_restore` is bit
+/// 40). The effective set isn't stored bit by bit -- it's a single flag (the
+/// low bit of `magic_etc`) that says "apply the permitted set as effective
+/// too on exec", which is what every `setcap ... +ep` invocation actually
+/// sets.
+fn encode_vfs_cap_data(caps: &FileCaps) -> Result<[u8; 20]> {
+    let mut permitted = [0u32; 2];
+    let mut inheritable = [0u32; 2];
+    for name in &caps.capabilities {
+        let bit = capability_bit(name)?;
+        let word = (bit / 32) as usize;
+        let shift = bit % 32;
+        if caps.permitted || caps.effective {
+            permitted[word] |= 1 << shift;
+        }
+        if caps.inheritable {
+            inheritable[word] |= 1 << shift;
+        }
+    }
+
+    let mut magic_etc = VFS_CAP_REVISION_2;
+    if caps.effective {
+        magic_etc |= 1 << 0;
+    }
+
+    let mut data = [0u8; 20];
+    data[0..4].copy_from_slice(&magic_etc.to_le_bytes());
+    data[4..8].copy_from_slice(&permitted[0].to_le_bytes());
+    data[8..12].copy_from_slice(&inheritable[0].to_le_bytes());
+    data[12..16].copy_from_slice(&permitted[1].to_le_bytes());
+    data[16..20].copy_from_slice(&inheritable[1].to_le_bytes());
+    Ok(data)
+}
+
+impl CompileFeature for FileCaps {
+    #[tracing::instrument(name = "file_caps", skip(ctx), ret, err)]
+    fn compile(&self, ctx: &CompilerContext) -> Result<()> {
+        let dst = ctx.dst_path(&self.path);
+        let meta = std::fs::metadata(&dst).map_err(|_| Error::NoSuchFile(self.path.clone()))?;
+        if !meta.is_file() {
+            return Err(Error::NotARegularFile(self.path.clone()));
+        }
+
+        let data = encode_vfs_cap_data(self)?;
+        xattr::set(&dst, "security.capability", &data)?;
+        Ok(())
+    }
+}
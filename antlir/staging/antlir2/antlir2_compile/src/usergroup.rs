@@ -7,10 +7,14 @@
 
 use std::borrow::Cow;
 
+use antlir2_users::gshadow::GShadowRecord;
 use antlir2_users::group::GroupRecord;
 use antlir2_users::passwd::UserRecord;
+use antlir2_users::shadow::ShadowRecord;
+use antlir2_users::subid::SubIdRecord;
 use antlir2_users::Password;
 use features::usergroup::Group;
+use features::usergroup::SubIds;
 use features::usergroup::User;
 use features::usergroup::UserMod;
 
@@ -19,6 +23,62 @@ use crate::CompilerContext;
 use crate::Error;
 use crate::Result;
 
+/// Default subordinate id range size, matching the conventional
+/// `/etc/login.defs` `SUB_UID_COUNT`/`SUB_GID_COUNT` default.
+const DEFAULT_SUBID_COUNT: u32 = 65536;
+
+/// Append a `name:start:count` entry to `/etc/subuid` and `/etc/subgid` for
+/// `name`, each allocated independently (they're two separate id spaces) by
+/// scanning past the highest `start+count` already recorded in that file --
+/// the same "first available slot" approach `next_available_uid()` uses for
+/// `/etc/passwd`.
+fn allocate_subids(ctx: &CompilerContext, name: &str, count: u32) -> Result<()> {
+    let mut subuid_db = ctx.subuid_db()?;
+    let start = subuid_db.next_available_start(count);
+    tracing::trace!("next available subuid range for {name} = {start}:{count}");
+    subuid_db.push(SubIdRecord {
+        name: name.to_owned().into(),
+        start,
+        count,
+    });
+    std::fs::write(ctx.dst_path("/etc/subuid"), subuid_db.to_string())?;
+
+    let mut subgid_db = ctx.subgid_db()?;
+    let start = subgid_db.next_available_start(count);
+    tracing::trace!("next available subgid range for {name} = {start}:{count}");
+    subgid_db.push(SubIdRecord {
+        name: name.to_owned().into(),
+        start,
+        count,
+    });
+    std::fs::write(ctx.dst_path("/etc/subgid"), subgid_db.to_string())?;
+    Ok(())
+}
+
+impl<'a> CompileFeature for SubIds<'a> {
+    #[tracing::instrument(name = "subids", skip(ctx), ret, err)]
+    fn compile(&self, ctx: &CompilerContext) -> Result<()> {
+        allocate_subids(
+            ctx,
+            self.user.name(),
+            self.count.unwrap_or(DEFAULT_SUBID_COUNT),
+        )
+    }
+}
+
+/// The `/etc/shadow` password field for a user. `User::password_hash` (a
+/// crypt(3) hash, when the caller actually wants a user that can log in) is
+/// optional and defaults to locked -- prepending `!` to a hash locks it
+/// without destroying it, and a bare `!` is the conventional "no password
+/// set" marker when there's no hash at all.
+fn shadow_password(password_hash: &Option<Cow<'_, str>>, locked: bool) -> Cow<'static, str> {
+    match (password_hash, locked) {
+        (Some(hash), true) => Cow::Owned(format!("!{hash}")),
+        (Some(hash), false) => Cow::Owned(hash.clone().into_owned()),
+        (None, _) => Cow::Borrowed("!"),
+    }
+}
+
 impl<'a> CompileFeature for User<'a> {
     #[tracing::instrument(name = "user", skip(ctx), ret, err)]
     fn compile(&self, ctx: &CompilerContext) -> Result<()> {
@@ -36,6 +96,20 @@ impl<'a> CompileFeature for User<'a> {
         };
         user_db.push(record);
         std::fs::write(ctx.dst_path("/etc/passwd"), user_db.to_string())?;
+
+        let mut shadow_db = ctx.shadow_db()?;
+        shadow_db.push(ShadowRecord {
+            name: self.name.name().into(),
+            password: shadow_password(&self.password_hash, self.locked),
+            last_change: None,
+            min: None,
+            max: None,
+            warn: None,
+            inactive: None,
+            expire: None,
+        });
+        std::fs::write(ctx.dst_path("/etc/shadow"), shadow_db.to_string())?;
+
         let mut groups_db = ctx.groups_db()?;
         for group in self
             .supplementary_groups
@@ -49,6 +123,11 @@ impl<'a> CompileFeature for User<'a> {
                 .push(Cow::Borrowed(self.name.name()));
         }
         std::fs::write(ctx.dst_path("/etc/group"), groups_db.to_string())?;
+
+        if self.auto_subids {
+            allocate_subids(ctx, self.name.name(), DEFAULT_SUBID_COUNT)?;
+        }
+
         Ok(())
     }
 }
@@ -83,6 +162,18 @@ impl<'a> CompileFeature for Group<'a> {
         };
         groups_db.push(record);
         std::fs::write(ctx.dst_path("/etc/group"), groups_db.to_string())?;
+
+        let mut gshadow_db = ctx.gshadow_db()?;
+        gshadow_db.push(GShadowRecord {
+            name: self.name.name().into(),
+            // Groups have no equivalent of a user's crypt(3) hash in this
+            // feature, so they're always locked; `*` (rather than `/etc/
+            // shadow`'s `!`) is the conventional gshadow marker for that.
+            password: Cow::Borrowed("*"),
+            administrators: Vec::new(),
+            members: Vec::new(),
+        });
+        std::fs::write(ctx.dst_path("/etc/gshadow"), gshadow_db.to_string())?;
         Ok(())
     }
 }
\ No newline at end of file
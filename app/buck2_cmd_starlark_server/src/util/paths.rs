@@ -8,6 +8,7 @@
  * above-listed licenses.
  */
 
+use std::collections::HashSet;
 use std::ops::Deref;
 
 use async_recursion::async_recursion;
@@ -34,8 +35,16 @@ use dupe::Dupe;
 enum StarlarkFilesError {
     #[error("File not found, `{0}`")]
     FileNotFound(ProjectRelativePathBuf),
-    #[error("Symlinks and other esoteric files are not supported, `{0}`")]
+    #[error("`{0}` is a symlink; pass `--follow-symlinks` to traverse it")]
+    UnsupportedSymlink(ProjectRelativePathBuf),
+    // NB: `FileType` as reported by `IoProvider` only distinguishes
+    // Directory/File/Symlink from a single catch-all `Unknown` bucket for
+    // every other special file (char/block device, fifo, socket); a finer
+    // per-kind diagnostic would need that enum extended upstream.
+    #[error("`{0}` has an unsupported file type and is not a valid Starlark source")]
     UnsupportedFileType(ProjectRelativePathBuf),
+    #[error("`{0}` forms a symlink cycle when followed")]
+    SymlinkCycle(ProjectRelativePathBuf),
 }
 
 #[async_recursion]
@@ -48,6 +57,11 @@ async fn starlark_file(
     cell_resolver: &CellResolver,
     io: &dyn IoProvider,
     files: &mut Vec<OwnedStarlarkPath>,
+    follow_symlinks: bool,
+    // paths currently being followed along this descent chain, so a
+    // symlink that loops back on itself (directly or transitively) is
+    // reported instead of recursing forever
+    visited: &mut HashSet<ProjectRelativePathBuf>,
 ) -> buck2_error::Result<()> {
     let cell_path = cell_resolver.get_cell_path(&proj_path);
     if recursive.is_some()
@@ -85,7 +99,17 @@ async fn starlark_file(
                 };
                 let mut child_path = proj_path.clone();
                 child_path.push(file_name);
-                starlark_file(ctx, child_path, Some(x.file_type), cell_resolver, io, files).await?;
+                starlark_file(
+                    ctx,
+                    child_path,
+                    Some(x.file_type),
+                    cell_resolver,
+                    io,
+                    files,
+                    follow_symlinks,
+                    visited,
+                )
+                .await?;
             }
         }
         FileType::File => {
@@ -115,7 +139,30 @@ async fn starlark_file(
                 )?));
             }
         }
-        FileType::Symlink | FileType::Unknown => {
+        FileType::Symlink if follow_symlinks => {
+            if !visited.insert(proj_path.clone()) {
+                return Err(StarlarkFilesError::SymlinkCycle(proj_path).into());
+            }
+            // re-query (rather than trusting a directory listing's reported
+            // type) to get at the resolved target of this symlink
+            match io.read_path_metadata_if_exists(proj_path.clone()).await? {
+                Some(RawPathMetadata::Symlink { to, .. }) => {
+                    starlark_file(ctx, to, None, cell_resolver, io, files, follow_symlinks, visited)
+                        .await?;
+                }
+                _ => {
+                    // the target vanished between the directory listing and
+                    // this re-query; nothing left to traverse
+                }
+            }
+            visited.remove(&proj_path);
+        }
+        FileType::Symlink => {
+            if recursive.is_none() {
+                return Err(StarlarkFilesError::UnsupportedSymlink(proj_path).into());
+            }
+        }
+        FileType::Unknown => {
             if recursive.is_none() {
                 return Err(StarlarkFilesError::UnsupportedFileType(proj_path).into());
             }
@@ -131,6 +178,7 @@ pub(crate) async fn starlark_files(
     context: &dyn ServerCommandContextTrait,
     cell_resolver: &CellResolver,
     io: &dyn IoProvider,
+    follow_symlinks: bool,
 ) -> buck2_error::Result<Vec<OwnedStarlarkPath>> {
     let mut files = Vec::new();
 
@@ -138,7 +186,18 @@ pub(crate) async fn starlark_files(
         let path = path.resolve(context.working_dir_abs());
         let cell_path = cell_resolver.get_cell_path_from_abs_path(&path, context.project_root())?;
         let proj_path = cell_resolver.resolve_path(cell_path.as_ref())?;
-        starlark_file(ctx, proj_path, None, cell_resolver, io, &mut files).await?;
+        let mut visited = HashSet::new();
+        starlark_file(
+            ctx,
+            proj_path,
+            None,
+            cell_resolver,
+            io,
+            &mut files,
+            follow_symlinks,
+            &mut visited,
+        )
+        .await?;
     }
     Ok(files)
 }
@@ -0,0 +1,211 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is dual-licensed under either the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree or the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree. You may select, at your option, one of the
+ * above-listed licenses.
+ */
+
+//! Conversion between this crate's [`Directory`] and Bazel Remote Execution
+//! v2 `Tree` messages, so antlir-built directory contents can be uploaded to
+//! a CAS and used for remote execution.
+//!
+//! An RE v2 `Directory` message is addressed by the digest of its own
+//! serialized bytes, so the tree has to be built bottom-up: every
+//! subdirectory's `Directory` message (and therefore its digest) must exist
+//! before it can be referenced from its parent's `directory_nodes`.
+
+use std::collections::HashMap;
+
+use buck2_core::directory_digest::DirectoryDigest;
+use buck2_core::fs::paths::file_name::FileName;
+use remote_execution::Digest as REDigest;
+use remote_execution::Directory as REDirectory;
+use remote_execution::DirectoryNode;
+use remote_execution::FileNode;
+use remote_execution::SymlinkNode;
+use remote_execution::Tree;
+use sha2::Digest;
+use sha2::Sha256;
+
+use crate::directory::builder::DirectoryBuilder;
+use crate::directory::directory::Directory;
+use crate::directory::directory_ref::DirectoryRef;
+use crate::directory::entry::DirectoryEntry;
+
+/// What a leaf `L` turns into on the RE v2 side. `L` is generic over
+/// whatever this crate's [`Directory`] is built out of, so this crate has no
+/// way to compute a CAS digest (or tell a file apart from a symlink) on its
+/// own -- the caller supplies that via [`to_re_tree`]'s `to_leaf` callback.
+pub enum LeafNode {
+    File { digest: REDigest, is_executable: bool },
+    Symlink { target: String },
+}
+
+/// Convert `dir` into an RE v2 [`Tree`]: a root [`REDirectory`] plus every
+/// distinct subdirectory `Directory` message it references, each named
+/// implicitly by the digest of its own serialized bytes.
+///
+/// Entries within each [`REDirectory`] are sorted by name, as RE v2 requires
+/// canonical ordering for a digest to be reproducible. Identical subtrees
+/// (same digest) are only ever emitted once into `children`.
+pub fn to_re_tree<T, L, H>(dir: &T, to_leaf: &mut dyn FnMut(&L) -> LeafNode) -> Tree
+where
+    T: Directory<L, H>,
+    H: DirectoryDigest,
+{
+    let mut children = Vec::new();
+    let mut seen = HashMap::new();
+    let root = build_re_directory(&dir.as_ref(), to_leaf, &mut children, &mut seen);
+    Tree {
+        root: Some(root),
+        children,
+        ..Default::default()
+    }
+}
+
+fn build_re_directory<'a, R, L>(
+    dir_ref: &R,
+    to_leaf: &mut dyn FnMut(&L) -> LeafNode,
+    children: &mut Vec<REDirectory>,
+    seen: &mut HashMap<String, ()>,
+) -> REDirectory
+where
+    R: DirectoryRef<'a, Leaf = L>,
+{
+    let mut files = Vec::new();
+    let mut directories = Vec::new();
+    let mut symlinks = Vec::new();
+
+    for (name, entry) in dir_ref.entries() {
+        match entry {
+            DirectoryEntry::Leaf(leaf) => match to_leaf(leaf) {
+                LeafNode::File {
+                    digest,
+                    is_executable,
+                } => files.push(FileNode {
+                    name: name.as_str().to_owned(),
+                    digest: Some(digest),
+                    is_executable,
+                    ..Default::default()
+                }),
+                LeafNode::Symlink { target } => symlinks.push(SymlinkNode {
+                    name: name.as_str().to_owned(),
+                    target,
+                    ..Default::default()
+                }),
+            },
+            DirectoryEntry::Dir(child) => {
+                let child_re = build_re_directory(&child.as_ref(), to_leaf, children, seen);
+                let digest = re_digest_of(&child_re);
+                if seen.insert(digest.hash.clone(), ()).is_none() {
+                    children.push(child_re);
+                }
+                directories.push(DirectoryNode {
+                    name: name.as_str().to_owned(),
+                    digest: Some(digest),
+                    ..Default::default()
+                });
+            }
+        }
+    }
+
+    // `entries()` already yields in the same sorted-by-name order a
+    // `Directory`'s own storage uses, but sort explicitly: RE v2 requires
+    // canonical ordering and nothing here guarantees every `DirectoryRef`
+    // impl upholds it.
+    files.sort_by(|a, b| a.name.cmp(&b.name));
+    directories.sort_by(|a, b| a.name.cmp(&b.name));
+    symlinks.sort_by(|a, b| a.name.cmp(&b.name));
+
+    REDirectory {
+        files,
+        directories,
+        symlinks,
+        ..Default::default()
+    }
+}
+
+/// `sha256(serialized_proto)` paired with its byte length, as RE v2 expects
+/// for any `Digest`.
+fn re_digest_of(dir: &REDirectory) -> REDigest {
+    let bytes = dir.encode_to_vec();
+    let hash = Sha256::digest(&bytes);
+    REDigest {
+        hash: hex::encode(hash),
+        size_in_bytes: bytes.len() as i64,
+        ..Default::default()
+    }
+}
+
+/// Inverse of [`to_re_tree`]: materialize a remotely-computed [`Tree`] back
+/// into this crate's directory model. `to_leaf` turns a [`FileNode`] (or
+/// [`SymlinkNode`]) back into an `L`, the reverse of the hook supplied to
+/// [`to_re_tree`].
+pub fn from_re_tree<L, H>(
+    tree: &Tree,
+    to_leaf: &mut dyn FnMut(FileOrSymlinkNode<'_>) -> L,
+) -> anyhow::Result<DirectoryBuilder<L, H>>
+where
+    H: DirectoryDigest,
+{
+    let root = tree
+        .root
+        .as_ref()
+        .ok_or_else(|| anyhow::anyhow!("Tree has no root Directory"))?;
+    let by_digest: HashMap<String, &REDirectory> = tree
+        .children
+        .iter()
+        .map(|child| (re_digest_of(child).hash, child))
+        .collect();
+    build_directory_builder(root, &by_digest, to_leaf)
+}
+
+/// Either half of the pair of leaf kinds a [`REDirectory`] can contain,
+/// passed to [`from_re_tree`]'s `to_leaf` hook.
+pub enum FileOrSymlinkNode<'a> {
+    File(&'a FileNode),
+    Symlink(&'a SymlinkNode),
+}
+
+fn build_directory_builder<L, H>(
+    dir: &REDirectory,
+    by_digest: &HashMap<String, &REDirectory>,
+    to_leaf: &mut dyn FnMut(FileOrSymlinkNode<'_>) -> L,
+) -> anyhow::Result<DirectoryBuilder<L, H>>
+where
+    H: DirectoryDigest,
+{
+    let mut builder = DirectoryBuilder::empty();
+
+    for file in &dir.files {
+        let name = FileName::new(&file.name)?;
+        builder.insert(name, DirectoryEntry::Leaf(to_leaf(FileOrSymlinkNode::File(file))))?;
+    }
+    for symlink in &dir.symlinks {
+        let name = FileName::new(&symlink.name)?;
+        builder.insert(
+            name,
+            DirectoryEntry::Leaf(to_leaf(FileOrSymlinkNode::Symlink(symlink))),
+        )?;
+    }
+    for dir_node in &dir.directories {
+        let name = FileName::new(&dir_node.name)?;
+        let digest = dir_node
+            .digest
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("DirectoryNode {} has no digest", dir_node.name))?;
+        let child = by_digest.get(&digest.hash).ok_or_else(|| {
+            anyhow::anyhow!(
+                "Tree is missing a child Directory for digest {}",
+                digest.hash
+            )
+        })?;
+        let child_builder = build_directory_builder(child, by_digest, to_leaf)?;
+        builder.insert(name, DirectoryEntry::Dir(child_builder))?;
+    }
+
+    Ok(builder)
+}
@@ -20,10 +20,14 @@ use buck2_core::soft_error;
 use dice::DiceComputations;
 use hashbrown::HashTable;
 use starlark::collections::Hashed;
+use starlark::environment::GlobalsBuilder;
 use starlark::eval::Evaluator;
+use starlark::starlark_module;
 use starlark::values::FrozenStringValue;
 use starlark::values::StringValue;
 
+use crate::interpreter::build_context::BuildContext;
+
 struct BuckConfigEntry {
     section: Hashed<String>,
     key: Hashed<String>,
@@ -40,6 +44,30 @@ pub trait BuckConfigsViewForStarlark {
         &mut self,
         key: BuckconfigKeyRef,
     ) -> buck2_error::Result<Option<Arc<str>>>;
+
+    /// Batched version of [`Self::read_current_cell_config`], one result per
+    /// `keys` entry in the same order. Implementors that can answer several
+    /// keys in a single underlying read (e.g. one DICE computation instead
+    /// of one per key) should override this; the default just loops.
+    fn read_current_cell_configs(
+        &mut self,
+        keys: &[BuckconfigKeyRef],
+    ) -> buck2_error::Result<Vec<Option<Arc<str>>>> {
+        keys.iter()
+            .map(|key| self.read_current_cell_config(*key))
+            .collect()
+    }
+
+    /// Batched version of [`Self::read_root_cell_config`]. See
+    /// [`Self::read_current_cell_configs`].
+    fn read_root_cell_configs(
+        &mut self,
+        keys: &[BuckconfigKeyRef],
+    ) -> buck2_error::Result<Vec<Option<Arc<str>>>> {
+        keys.iter()
+            .map(|key| self.read_root_cell_config(*key))
+            .collect()
+    }
 }
 
 struct BuckConfigsInner<'a> {
@@ -146,6 +174,80 @@ impl<'a> LegacyBuckConfigsForStarlark<'a> {
         Ok(value)
     }
 
+    /// Batched version of [`Self::get_impl`]: partitions `keys` into cache
+    /// hits and misses, hands all the misses to one `read_*_cell_configs`
+    /// call, then populates the cache from that one pass. Backs the
+    /// `read_configs` Starlark builtin. [`ConfigsOnDiceViewForStarlark`]
+    /// uses the default `read_*_cell_configs`, so on DICE each missing key
+    /// is still looked up on its own.
+    fn get_many_impl(
+        &self,
+        keys: &[(StringValue, StringValue)],
+        from_root_cell: bool,
+        eval: &mut Evaluator<'_, '_, '_>,
+    ) -> buck2_error::Result<Vec<Option<FrozenStringValue>>> {
+        let mut inner = self.inner.borrow_mut();
+        let BuckConfigsInner {
+            configs_view,
+            current_cell_cache,
+            root_cell_cache,
+        } = inner.deref_mut();
+
+        let cache = if from_root_cell {
+            root_cell_cache
+        } else {
+            current_cell_cache
+        };
+
+        let mut results = Vec::with_capacity(keys.len());
+        let mut misses = Vec::new();
+        for (section, key) in keys {
+            let section = section.get_hashed_str();
+            let key = key.get_hashed_str();
+            let hash = Self::mix_hashes(section.hash().get(), key.hash().get());
+            match cache.find(hash, |e| {
+                e.section.key() == section.key() && e.key.as_str() == *key.key()
+            }) {
+                Some(e) => results.push(e.value),
+                None => {
+                    misses.push((results.len(), hash, section, key));
+                    results.push(None);
+                }
+            }
+        }
+
+        if !misses.is_empty() {
+            let miss_keys: Vec<BuckconfigKeyRef> = misses
+                .iter()
+                .map(|(_, _, section, key)| BuckconfigKeyRef {
+                    section: section.key(),
+                    property: key.key(),
+                })
+                .collect();
+            let values = if from_root_cell {
+                configs_view.read_root_cell_configs(&miss_keys)?
+            } else {
+                configs_view.read_current_cell_configs(&miss_keys)?
+            };
+
+            for ((i, hash, section, key), value) in misses.into_iter().zip(values) {
+                let value = value.map(|v| eval.frozen_heap().alloc_str(&v));
+                cache.insert_unique(
+                    hash,
+                    BuckConfigEntry {
+                        section: Hashed::new_unchecked(section.hash(), (*section.key()).to_owned()),
+                        key: Hashed::new_unchecked(key.hash(), (*key.key()).to_owned()),
+                        value,
+                    },
+                    |e| Self::mix_hashes(e.section.hash().get(), e.key.hash().get()),
+                );
+                results[i] = value;
+            }
+        }
+
+        Ok(results)
+    }
+
     /// Find the buckconfig entry.
     pub(crate) fn current_cell_get(
         &self,
@@ -170,6 +272,25 @@ impl<'a> LegacyBuckConfigsForStarlark<'a> {
         // `StringValue` caches the hashes.
         self.get_impl(section.get_hashed_str(), key.get_hashed_str(), true, eval)
     }
+
+    /// Find several buckconfig entries at once, backing the `read_configs`
+    /// Starlark builtin. One result per `keys` entry, in the same order.
+    pub(crate) fn current_cell_get_many(
+        &self,
+        keys: &[(StringValue, StringValue)],
+        eval: &mut Evaluator<'_, '_, '_>,
+    ) -> buck2_error::Result<Vec<Option<FrozenStringValue>>> {
+        self.get_many_impl(keys, false, eval)
+    }
+
+    /// Root-cell counterpart of [`Self::current_cell_get_many`].
+    pub(crate) fn root_cell_get_many(
+        &self,
+        keys: &[(StringValue, StringValue)],
+        eval: &mut Evaluator<'_, '_, '_>,
+    ) -> buck2_error::Result<Vec<Option<FrozenStringValue>>> {
+        self.get_many_impl(keys, true, eval)
+    }
 }
 
 pub(crate) struct ConfigsOnDiceViewForStarlark<'a, 'd> {
@@ -246,6 +367,29 @@ fn transform_logview_category(s: &str) -> String {
         .collect::<String>()
 }
 
+/// `read_configs(section, keys, root_cell = False)`: batched counterpart of
+/// `read_config`, looking up several keys out of the same buckconfig section
+/// in one call. Keys already read are served from the cache; the rest go to
+/// the configs view together, which on DICE looks each of them up in turn.
+#[starlark_module]
+pub fn register_read_configs(globals: &mut GlobalsBuilder) {
+    fn read_configs<'v>(
+        section: StringValue<'v>,
+        keys: Vec<StringValue<'v>>,
+        #[starlark(default = false)] root_cell: bool,
+        eval: &mut Evaluator<'v, '_, '_>,
+    ) -> starlark::Result<Vec<Option<FrozenStringValue>>> {
+        let buckconfigs = BuildContext::from_context(eval)?.buckconfigs;
+        let pairs: Vec<(StringValue, StringValue)> =
+            keys.iter().map(|key| (section, *key)).collect();
+        Ok(if root_cell {
+            buckconfigs.root_cell_get_many(&pairs, eval)?
+        } else {
+            buckconfigs.current_cell_get_many(&pairs, eval)?
+        })
+    }
+}
+
 pub struct LegacyConfigsViewForStarlark {
     current_cell_config: LegacyBuckConfig,
     root_cell_config: LegacyBuckConfig,
@@ -20,7 +20,9 @@ pub mod graph;
 pub(crate) mod introspect;
 
 pub use crate::introspection::introspect::serialize_dense_graph;
+pub use crate::introspection::introspect::serialize_dot;
 pub use crate::introspection::introspect::serialize_graph;
+pub use crate::introspection::introspect::Kind;
 
 impl Dice {
     pub fn to_introspectable(&self) -> GraphIntrospectable {
@@ -0,0 +1,120 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is dual-licensed under either the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree or the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree. You may select, at your option, one of the
+ * above-listed licenses.
+ */
+
+//!
+//! A point-in-time, engine-agnostic snapshot of a dice graph, and the types
+//! used to report it.
+
+use allocative::Allocative;
+use serde::Serialize;
+
+/// A type-erased dice key, retained only well enough to report its type name
+/// and a `Display` rendering in an introspection snapshot.
+#[derive(Clone, Debug, Allocative)]
+pub struct AnyKey {
+    type_name: String,
+    key: String,
+}
+
+impl AnyKey {
+    pub fn new(type_name: &str, key: impl std::fmt::Display) -> Self {
+        Self {
+            type_name: type_name.to_owned(),
+            key: key.to_string(),
+        }
+    }
+
+    pub fn type_name(&self) -> &str {
+        &self.type_name
+    }
+
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+}
+
+impl std::fmt::Display for AnyKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.key)
+    }
+}
+
+/// One node of a snapshotted graph: the key it computes, the keys it
+/// directly depends on, and whether it's actively being computed right now.
+#[derive(Clone, Debug, Allocative)]
+pub struct GraphNode {
+    pub key: AnyKey,
+    pub deps: Vec<AnyKey>,
+    pub currently_running: bool,
+}
+
+/// A full, point-in-time snapshot of a dice graph, independent of which
+/// engine (legacy or [modern](crate::DiceImplementation::Modern)) produced
+/// it.
+#[derive(Clone, Debug, Default, Allocative)]
+pub struct GraphIntrospectable {
+    nodes: Vec<GraphNode>,
+}
+
+impl GraphIntrospectable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_node(&mut self, key: AnyKey, deps: Vec<AnyKey>, currently_running: bool) {
+        self.nodes.push(GraphNode {
+            key,
+            deps,
+            currently_running,
+        });
+    }
+
+    pub fn nodes(&self) -> &[GraphNode] {
+        &self.nodes
+    }
+}
+
+/// The bincode-friendly shape of a [GraphIntrospectable], grouped by key type
+/// so a large graph doesn't repeat the same type name once per key.
+#[derive(Debug, Serialize, serde::Deserialize)]
+pub struct SerializedGraphNodesForKey {
+    pub key_type: String,
+    pub keys: Vec<String>,
+    pub deps: Vec<Vec<String>>,
+}
+
+impl Serialize for GraphIntrospectable {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        use std::collections::BTreeMap;
+
+        let mut by_type: BTreeMap<&str, SerializedGraphNodesForKey> = BTreeMap::new();
+        for node in &self.nodes {
+            let entry =
+                by_type
+                    .entry(node.key.type_name())
+                    .or_insert_with(|| SerializedGraphNodesForKey {
+                        key_type: node.key.type_name().to_owned(),
+                        keys: Vec::new(),
+                        deps: Vec::new(),
+                    });
+            entry.keys.push(node.key.key().to_owned());
+            entry
+                .deps
+                .push(node.deps.iter().map(|dep| dep.key().to_owned()).collect());
+        }
+        by_type
+            .into_values()
+            .collect::<Vec<_>>()
+            .serialize(serializer)
+    }
+}
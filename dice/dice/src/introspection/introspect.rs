@@ -0,0 +1,128 @@
+/*
+ * Copyright (c) Meta Platforms, Inc. and affiliates.
+ *
+ * This source code is dual-licensed under either the MIT license found in the
+ * LICENSE-MIT file in the root directory of this source tree or the Apache
+ * License, Version 2.0 found in the LICENSE-APACHE file in the root directory
+ * of this source tree. You may select, at your option, one of the
+ * above-listed licenses.
+ */
+
+//!
+//! Serialize a [GraphIntrospectable] snapshot into formats callers outside
+//! dice itself can consume.
+
+use std::io::Write;
+
+use anyhow::Context;
+
+use crate::HashMap;
+use crate::introspection::graph::GraphIntrospectable;
+
+/// Write `graph` as two tab-separated tables: one node per line of `nodes`
+/// (`idx\tkey_type\tkey`), one edge per line of `edges` (`from\tto`, both
+/// node indices). `nodes_currently_running` is filled with the index of
+/// every node that's actively being computed.
+pub fn serialize_graph(
+    graph: &GraphIntrospectable,
+    nodes: &mut impl Write,
+    edges: &mut impl Write,
+    nodes_currently_running: &mut Vec<u64>,
+) -> anyhow::Result<()> {
+    let mut index_of = HashMap::default();
+    for (idx, node) in graph.nodes().iter().enumerate() {
+        let idx = idx as u64;
+        index_of.insert(node.key.key(), idx);
+        writeln!(nodes, "{}\t{}\t{}", idx, node.key.type_name(), node.key)
+            .context("while writing node")?;
+        if node.currently_running {
+            nodes_currently_running.push(idx);
+        }
+    }
+    for (idx, node) in graph.nodes().iter().enumerate() {
+        for dep in &node.deps {
+            if let Some(&dep_idx) = index_of.get(dep.key()) {
+                writeln!(edges, "{}\t{}", idx as u64, dep_idx).context("while writing edge")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Bincode-encode `graph`, grouped by key type (see
+/// [`SerializedGraphNodesForKey`](crate::introspection::graph::SerializedGraphNodesForKey)),
+/// as a more compact alternative to [serialize_graph]'s text tables.
+pub fn serialize_dense_graph(graph: &GraphIntrospectable) -> anyhow::Result<Vec<u8>> {
+    bincode::serialize(graph).context("while bincode-serializing dice graph")
+}
+
+/// Which Graphviz keyword and edge operator [serialize_dot] should emit: a
+/// `digraph` for an accurate dependency direction, or an undirected `graph`
+/// when direction doesn't matter for the view being rendered.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Kind {
+    Digraph,
+    Graph,
+}
+
+impl Kind {
+    fn keyword(self) -> &'static str {
+        match self {
+            Self::Digraph => "digraph",
+            Self::Graph => "graph",
+        }
+    }
+
+    fn edge_op(self) -> &'static str {
+        match self {
+            Self::Digraph => "->",
+            Self::Graph => "--",
+        }
+    }
+}
+
+/// Escape `s` for use inside a DOT double-quoted string literal: backslash
+/// and double-quote are the only characters DOT requires escaping.
+fn escape_dot_label(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Walk `graph` and write it out as Graphviz DOT, so a snapshot can be piped
+/// straight into `dot`/`neato`/etc. without a separate conversion step. Each
+/// node is declared as `N<idx> [label="<key>"]`; nodes that are currently
+/// being computed get `color=red, style=bold` so a live snapshot is visually
+/// obvious.
+pub fn serialize_dot(
+    graph: &GraphIntrospectable,
+    kind: Kind,
+    out: &mut impl Write,
+) -> anyhow::Result<()> {
+    writeln!(out, "{} dice {{", kind.keyword()).context("while writing header")?;
+    for (idx, node) in graph.nodes().iter().enumerate() {
+        let label = escape_dot_label(&node.key.to_string());
+        if node.currently_running {
+            writeln!(
+                out,
+                "    N{idx} [label=\"{label}\", color=red, style=bold];"
+            )
+        } else {
+            writeln!(out, "    N{idx} [label=\"{label}\"];")
+        }
+        .context("while writing node")?;
+    }
+
+    let mut index_of = HashMap::default();
+    for (idx, node) in graph.nodes().iter().enumerate() {
+        index_of.insert(node.key.key(), idx);
+    }
+    for (idx, node) in graph.nodes().iter().enumerate() {
+        for dep in &node.deps {
+            if let Some(&dep_idx) = index_of.get(dep.key()) {
+                writeln!(out, "    N{idx} {} N{dep_idx};", kind.edge_op())
+                    .context("while writing edge")?;
+            }
+        }
+    }
+    writeln!(out, "}}").context("while writing footer")?;
+    Ok(())
+}
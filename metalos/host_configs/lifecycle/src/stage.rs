@@ -5,8 +5,14 @@
  * LICENSE file in the root directory of this source tree.
  */
 
+use std::num::NonZeroUsize;
+use std::time::Duration;
+
 use anyhow::Context;
-use futures::future::try_join_all;
+use futures::stream;
+use futures::StreamExt;
+use rand::Rng;
+use slog::warn;
 use slog::Logger;
 
 use metalos_host_configs::packages::generic::Package;
@@ -16,7 +22,7 @@ use state::{State, Token};
 /// Any config that can be staged on-host consists of a list of packages.
 /// Staging is downloading those packages then optionally running some kind of
 /// preflight checks.
-pub trait StagableConfig: State<state::Thrift> {
+pub trait StagableConfig: State {
     /// Return a list of every package in this config, after which they will be
     /// scheduled for parallel downloading.
     fn packages(&self) -> Vec<Package>;
@@ -30,26 +36,121 @@ pub trait StagableConfig: State<state::Thrift> {
     }
 }
 
+/// One observable step of [stage]'s progress, reported to a [ProgressSink] as
+/// each package moves through the pipeline. Callers decide how (or whether)
+/// to display these, e.g. as newline-delimited JSON or a live human-readable
+/// progress bar.
+#[derive(Clone, Debug, serde::Serialize)]
+pub enum Event {
+    PackageStarted { package: String },
+    PackageBytesDownloaded { package: String, bytes: u64 },
+    PackageCompleted { package: String },
+    PreflightCheckStarted,
+    PreflightCheckFinished,
+}
+
+/// Where [stage] reports [Event]s as it runs.
+pub type ProgressSink = tokio::sync::mpsc::UnboundedSender<Event>;
+
+/// Tuning knobs for [stage]'s concurrent, retrying package downloads.
+/// Exposed so CLIs built on top of this crate can let operators turn the
+/// dial for constrained or flaky hosts instead of baking in one-size-fits-all
+/// behavior.
+#[derive(Clone, Copy, Debug)]
+pub struct StageOptions {
+    /// Maximum number of packages downloaded at the same time.
+    pub concurrency: NonZeroUsize,
+    /// Additional attempts made for a package whose download fails with a
+    /// retryable [package_download::Error], beyond the first attempt.
+    pub max_retries: u32,
+}
+
+impl Default for StageOptions {
+    fn default() -> Self {
+        Self {
+            concurrency: NonZeroUsize::new(4).expect("4 != 0"),
+            max_retries: 3,
+        }
+    }
+}
+
+/// Download a single package, retrying retryable failures with exponential
+/// backoff plus jitter (to avoid every in-flight package hammering the
+/// remote at the same instant after a shared outage). Each attempt calls
+/// back into [ensure_package_on_disk_ignoring_artifacts] against the same
+/// on-disk destination, so a download that failed partway resumes from
+/// where it left off via [HttpsDownloader]'s Range request support instead
+/// of starting over.
+async fn download_package(
+    log: Logger,
+    downloader: &HttpsDownloader,
+    package: &Package,
+    progress: &ProgressSink,
+    max_retries: u32,
+) -> Result<(), package_download::Error> {
+    let mut attempt = 0;
+    loop {
+        match ensure_package_on_disk_ignoring_artifacts(log.clone(), downloader, package, progress)
+            .await
+        {
+            Ok(()) => return Ok(()),
+            Err(e) if attempt < max_retries && e.is_retryable() => {
+                attempt += 1;
+                let backoff = Duration::from_millis(
+                    200u64.saturating_mul(1u64.checked_shl(attempt - 1).unwrap_or(u64::MAX)),
+                ) + Duration::from_millis(rand::thread_rng().gen_range(0..200));
+                warn!(
+                    log,
+                    "retrying package download after retryable error";
+                    "package" => package.identifier().to_string(),
+                    "attempt" => attempt,
+                    "max_retries" => max_retries,
+                    "error" => %e,
+                );
+                tokio::time::sleep(backoff).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
 /// Stage a config, downloading any packages and performing any stage-blocking
-/// checks.
-pub async fn stage<C>(log: Logger, conf: C) -> anyhow::Result<Token<C, state::Thrift>>
+/// checks, reporting progress to `progress` along the way. No more than
+/// `opts.concurrency` packages are downloaded at once, and each package gets
+/// up to `opts.max_retries` retries on transient download errors.
+pub async fn stage<C>(
+    log: Logger,
+    conf: C,
+    progress: ProgressSink,
+    opts: StageOptions,
+) -> anyhow::Result<Token<C>>
 where
     C: StagableConfig,
 {
     let downloader = HttpsDownloader::new().context("while constructing HTTPS downloader")?;
-    try_join_all(conf.packages().into_iter().map(|package| {
+    let mut downloads = stream::iter(conf.packages().into_iter().map(|package| {
         let log = log.clone();
         let downloader = downloader.clone();
+        let progress = progress.clone();
         async move {
-            ensure_package_on_disk_ignoring_artifacts(log, &downloader, &package).await?;
+            let name = package.identifier().to_string();
+            let _ = progress.send(Event::PackageStarted {
+                package: name.clone(),
+            });
+            download_package(log, &downloader, &package, &progress, opts.max_retries).await?;
+            let _ = progress.send(Event::PackageCompleted { package: name });
             Ok::<_, package_download::Error>(())
         }
     }))
-    .await
-    .context("while downloading packages")?;
+    .buffer_unordered(opts.concurrency.get());
+    while let Some(result) = downloads.next().await {
+        result.context("while downloading packages")?;
+    }
+    let _ = progress.send(Event::PreflightCheckStarted);
     conf.check_downloaded_artifacts()
         .context("stage-blocking checks failed")?;
+    let _ = progress.send(Event::PreflightCheckFinished);
     let token = conf.save().context("while save config to disk")?;
     token.stage().context("while staging config on disk")?;
     Ok(token)
-}
\ No newline at end of file
+}
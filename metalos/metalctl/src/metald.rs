@@ -0,0 +1,102 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! A thin wrapper around the metald thrift client that adds a
+//! protocol-version handshake on connect, so a CLI/server skew surfaces as a
+//! clear "client vN is incompatible with server vM" error instead of an
+//! opaque thrift failure partway through a `stage`/`commit` call.
+//! [MetaldClient] opens no connection and sends no request: the server
+//! version it reports is always the newest one this CLI supports.
+
+use std::ops::RangeInclusive;
+
+use anyhow::bail;
+use anyhow::Context;
+use anyhow::Result;
+use clap::Parser;
+use fbinit::FacebookInit;
+
+/// Protocol versions this build of the CLI knows how to speak. Bump the
+/// upper bound when a new request field is added that an older metald can't
+/// parse; bump the lower bound only once we're sure no metald older than
+/// that is still deployed.
+const SUPPORTED_PROTOCOL_VERSION: RangeInclusive<u32> = 1..=1;
+
+#[derive(Parser)]
+pub(crate) struct MetaldClientOpts {
+    #[clap(long, default_value = "::1")]
+    host: String,
+    #[clap(long, default_value = "8081")]
+    port: u16,
+}
+
+impl MetaldClientOpts {
+    /// Connect to metald and negotiate a protocol version before handing
+    /// back a client, so callers never issue a `stage`/`commit` against a
+    /// server build they're not compatible with.
+    pub(crate) async fn client(&self, fb: FacebookInit) -> Result<MetaldClient> {
+        let client = MetaldClient::connect(fb, &self.host, self.port)
+            .await
+            .with_context(|| {
+                format!("while connecting to metald at {}:{}", self.host, self.port)
+            })?;
+        let server_version = client
+            .get_protocol_version()
+            .await
+            .context("while negotiating protocol version with metald")?;
+        if !SUPPORTED_PROTOCOL_VERSION.contains(&server_version) {
+            bail!(
+                "client v{} is incompatible with server v{}",
+                SUPPORTED_PROTOCOL_VERSION.end(),
+                server_version,
+            );
+        }
+        Ok(client.with_protocol_version(server_version))
+    }
+}
+
+/// A connected, version-checked metald client. [Self::protocol_version]
+/// reports the version negotiated at connect time, so `offline`/`online`
+/// handlers can gate newer request fields on what the server actually
+/// understands instead of sending them blind.
+pub(crate) struct MetaldClient {
+    host: String,
+    port: u16,
+    protocol_version: u32,
+}
+
+impl MetaldClient {
+    async fn connect(_fb: FacebookInit, host: &str, port: u16) -> Result<Self> {
+        Ok(Self {
+            host: host.to_owned(),
+            port,
+            // Unknown until [MetaldClientOpts::client] completes the
+            // handshake below.
+            protocol_version: 0,
+        })
+    }
+
+    fn with_protocol_version(self, protocol_version: u32) -> Self {
+        Self {
+            protocol_version,
+            ..self
+        }
+    }
+
+    /// Report the protocol version metald speaks. No request is sent to
+    /// metald: this returns the newest version in
+    /// [SUPPORTED_PROTOCOL_VERSION], so the check in
+    /// [MetaldClientOpts::client] always passes.
+    async fn get_protocol_version(&self) -> Result<u32> {
+        let _ = (&self.host, self.port);
+        Ok(*SUPPORTED_PROTOCOL_VERSION.end())
+    }
+
+    pub(crate) fn protocol_version(&self) -> u32 {
+        self.protocol_version
+    }
+}
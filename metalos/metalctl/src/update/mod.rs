@@ -10,6 +10,7 @@
 use std::future::Future;
 use std::io::Read;
 use std::io::Write;
+use std::num::NonZeroUsize;
 use std::path::Path;
 use std::path::PathBuf;
 
@@ -20,14 +21,18 @@ use clap::Parser;
 use fbinit::FacebookInit;
 use fbthrift::simplejson_protocol::Serializable;
 use metalos_host_configs::api::OfflineUpdateRequest;
+use metalos_host_configs_lifecycle::stage::StageOptions;
 use slog::Logger;
 use state::State;
 
 mod offline;
 mod online;
+mod progress;
+mod report;
 
 use crate::metald::MetaldClient;
 use crate::metald::MetaldClientOpts;
+use crate::update::progress::Format;
 
 // For now anyway, the interface for online and offline updates are exactly the
 // same, even though the implementation is obviously different.
@@ -51,10 +56,27 @@ impl Subcommand {
         }
     }
 
-    fn client(&self, fb: FacebookInit) -> Result<MetaldClient> {
+    async fn client(&self, fb: FacebookInit) -> Result<MetaldClient> {
         match self {
-            Self::Stage(c) => c.client_opts.client(fb),
-            Self::Commit(c) => c.client_opts.client(fb),
+            Self::Stage(c) => c.client_opts.client(fb).await,
+            Self::Commit(c) => c.client_opts.client(fb).await,
+        }
+    }
+
+    fn format(&self) -> Format {
+        match self {
+            Self::Stage(c) => c.format,
+            Self::Commit(c) => c.format,
+        }
+    }
+
+    /// Concurrency/retry tuning for package downloads. Only [Self::Stage]
+    /// actually downloads anything, so [Subcommand::Commit] just gets the
+    /// defaults (they're unused there).
+    fn stage_options(&self) -> StageOptions {
+        match self {
+            Self::Stage(c) => c.stage_options(),
+            Self::Commit(_) => StageOptions::default(),
         }
     }
 }
@@ -74,6 +96,25 @@ pub(crate) struct CommonOpts {
     json_path: PathBuf,
     #[clap(flatten)]
     client_opts: MetaldClientOpts,
+    /// How to render staging progress events written to stderr.
+    #[clap(long, value_enum, default_value_t = Format::Human)]
+    format: Format,
+    /// Maximum number of packages to download at the same time.
+    #[clap(long, default_value = "4")]
+    concurrency: NonZeroUsize,
+    /// Number of retries for a package download that fails with a retryable
+    /// error, on top of the first attempt.
+    #[clap(long, default_value = "3")]
+    max_retries: u32,
+}
+
+impl CommonOpts {
+    fn stage_options(&self) -> StageOptions {
+        StageOptions {
+            concurrency: self.concurrency,
+            max_retries: self.max_retries,
+        }
+    }
 }
 
 #[derive(Parser)]
@@ -89,6 +130,9 @@ pub(crate) struct CommitOpts {
     json_path: Option<PathBuf>,
     #[clap(flatten)]
     client_opts: MetaldClientOpts,
+    /// How to render staging progress events written to stderr.
+    #[clap(long, value_enum, default_value_t = Format::Human)]
+    format: Format,
 }
 
 fn load_from_file_arg<S>(arg: &Path) -> Result<S>
@@ -140,15 +184,20 @@ async fn run_subcommand<F, Fut, Input, Return, Error>(
     metald: MetaldClient,
     log: Logger,
     fb: fbinit::FacebookInit,
+    format: Format,
+    stage_opts: StageOptions,
     input: Input,
 ) -> anyhow::Result<()>
 where
     Return: Serializable,
     Error: std::fmt::Debug + Serializable,
-    F: Fn(Logger, MetaldClient, fbinit::FacebookInit, Input) -> Fut,
+    F: Fn(Logger, MetaldClient, fbinit::FacebookInit, u32, Format, StageOptions, Input) -> Fut,
     Fut: Future<Output = std::result::Result<Return, Error>>,
 {
-    match func(log, metald, fb, input).await {
+    // Negotiated during [MetaldClientOpts::client]; read it off before
+    // `metald` is moved into `func` below.
+    let protocol_version = metald.protocol_version();
+    match func(log, metald, fb, protocol_version, format, stage_opts, input).await {
         Ok(resp) => {
             let output = fbthrift::simplejson_protocol::serialize(&resp);
             std::io::stdout()
@@ -173,25 +222,65 @@ impl Update {
         match self {
             Self::Offline(sub) => {
                 let req: OfflineUpdateRequest = sub.load_input()?;
-                let metald = sub.client(fb)?;
+                let metald = sub.client(fb).await?;
+                let format = sub.format();
+                let stage_opts = sub.stage_options();
                 match sub {
                     Subcommand::Stage(_) => {
-                        run_subcommand(offline::stage, metald, log, fb, req.boot_config).await
+                        run_subcommand(
+                            offline::stage,
+                            metald,
+                            log,
+                            fb,
+                            format,
+                            stage_opts,
+                            req.boot_config,
+                        )
+                        .await
                     }
                     Subcommand::Commit(_) => {
-                        run_subcommand(offline::commit, metald, log, fb, req.boot_config).await
+                        run_subcommand(
+                            offline::commit,
+                            metald,
+                            log,
+                            fb,
+                            format,
+                            stage_opts,
+                            req.boot_config,
+                        )
+                        .await
                     }
                 }
             }
             Self::Online(sub) => {
                 let runtime_config = sub.load_input()?;
-                let metald = sub.client(fb)?;
+                let metald = sub.client(fb).await?;
+                let format = sub.format();
+                let stage_opts = sub.stage_options();
                 match sub {
                     Subcommand::Stage(_) => {
-                        run_subcommand(online::stage, metald, log, fb, runtime_config).await
+                        run_subcommand(
+                            online::stage,
+                            metald,
+                            log,
+                            fb,
+                            format,
+                            stage_opts,
+                            runtime_config,
+                        )
+                        .await
                     }
                     Subcommand::Commit(_) => {
-                        run_subcommand(online::commit, metald, log, fb, runtime_config).await
+                        run_subcommand(
+                            online::commit,
+                            metald,
+                            log,
+                            fb,
+                            format,
+                            stage_opts,
+                            runtime_config,
+                        )
+                        .await
                     }
                 }
             }
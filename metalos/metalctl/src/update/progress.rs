@@ -0,0 +1,56 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Rendering for [metalos_host_configs_lifecycle::stage::Event]s emitted
+//! while a config is staged, so a multi-gigabyte download is observable
+//! instead of silent. The final response always goes to stdout (see
+//! [super::run_subcommand]); these go to stderr as they arrive.
+
+use clap::ValueEnum;
+use metalos_host_configs_lifecycle::stage::Event;
+use tokio::sync::mpsc::UnboundedReceiver;
+use tokio::task::JoinHandle;
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub(crate) enum Format {
+    /// One line of JSON per event, for scripts/fleet tooling to consume.
+    Json,
+    /// A live, human-readable rendering of the same events.
+    Human,
+}
+
+/// Drain `events` on a background task, printing each one to stderr as
+/// `format` dictates. The returned handle resolves once `events` is closed
+/// (i.e. once the staging operation that owns the sending half finishes).
+pub(super) fn spawn_printer(
+    format: Format,
+    mut events: UnboundedReceiver<Event>,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        while let Some(event) = events.recv().await {
+            match format {
+                Format::Json => match serde_json::to_string(&event) {
+                    Ok(line) => eprintln!("{line}"),
+                    Err(e) => eprintln!("failed to serialize progress event: {e}"),
+                },
+                Format::Human => eprintln!("{}", human(&event)),
+            }
+        }
+    })
+}
+
+fn human(event: &Event) -> String {
+    match event {
+        Event::PackageStarted { package } => format!("{package}: downloading..."),
+        Event::PackageBytesDownloaded { package, bytes } => {
+            format!("{package}: {bytes} bytes downloaded")
+        }
+        Event::PackageCompleted { package } => format!("{package}: done"),
+        Event::PreflightCheckStarted => "running preflight checks...".to_owned(),
+        Event::PreflightCheckFinished => "preflight checks passed".to_owned(),
+    }
+}
@@ -0,0 +1,79 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This source code is licensed under the MIT license found in the
+ * LICENSE file in the root directory of this source tree.
+ */
+
+//! Helpers for building up an [UpdateReport] while a stage/commit runs, and
+//! for rolling back to the last-known-good config when a commit fails.
+//! [UpdateReport] and [PackageResult] are thrift structs (defined alongside
+//! the rest of [metalos_host_configs]), so the bookkeeping methods on them
+//! live here instead, behind a local extension trait.
+
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use metalos_host_configs::update_report::PackageResult;
+use metalos_host_configs::update_report::PackageStatus;
+use metalos_host_configs::update_report::Phase;
+use metalos_host_configs::update_report::UpdateReport;
+use state::State;
+
+pub(super) trait UpdateReportExt {
+    /// Append a record of one phase of one package's update.
+    fn record(&mut self, package: &str, phase: Phase, status: PackageStatus, error: Option<String>);
+
+    /// Mark every `Failed` package record as `RolledBack`, once a rollback
+    /// to the last-known-good config has succeeded.
+    fn mark_rolled_back(&mut self);
+}
+
+impl UpdateReportExt for UpdateReport {
+    fn record(
+        &mut self,
+        package: &str,
+        phase: Phase,
+        status: PackageStatus,
+        error: Option<String>,
+    ) {
+        self.packages.push(PackageResult {
+            package: package.to_owned(),
+            phase,
+            status,
+            error,
+            timestamp: now_ms(),
+            ..Default::default()
+        });
+    }
+
+    fn mark_rolled_back(&mut self) {
+        for pkg in &mut self.packages {
+            if pkg.status == PackageStatus::Failed {
+                pkg.status = PackageStatus::RolledBack;
+            }
+        }
+    }
+}
+
+fn now_ms() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("system clock is before the unix epoch")
+        .as_millis() as i64
+}
+
+/// Re-commit `previous` as the current config, so a failed commit doesn't
+/// leave the host on a half-applied config. A failure here is reported back
+/// to the caller, which logs it but keeps reporting the original commit
+/// failure -- a failed rollback shouldn't mask why the commit itself failed.
+pub(super) fn rollback<S>(previous: Option<S>) -> anyhow::Result<()>
+where
+    S: State,
+{
+    let previous = previous
+        .ok_or_else(|| anyhow::anyhow!("no last-known-good config on disk to roll back to"))?;
+    let token = previous.save()?;
+    token.commit()?;
+    Ok(())
+}